@@ -4,14 +4,20 @@ use argon2::{
 };
 use axum::{
     Json, Router,
+    body::{Body, Bytes},
     error_handling::HandleErrorLayer,
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{
+        Path, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, HeaderValue, StatusCode, header::CONTENT_TYPE},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post},
 };
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use http_body_util::{BodyExt, Full};
+use prost::Message as _;
 
 use rand_core::OsRng;
 use serde::{Deserialize, Serialize};
@@ -48,6 +54,26 @@ struct UpdateUser {
     password: Option<String>,
 }
 
+/// `users.v1.GetUserRequest` of fixtures/grpc/users.proto.
+#[derive(Clone, PartialEq, prost::Message)]
+struct GetUserRequest {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+}
+
+/// `users.v1.User` of fixtures/grpc/users.proto, without the fields the servers do not have.
+#[derive(Clone, PartialEq, prost::Message)]
+struct UserMessage {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "3")]
+    email: String,
+    #[prost(int64, tag = "4")]
+    updated_at: i64,
+}
+
 #[derive(Debug, Clone)]
 struct AppState {
     inner: Arc<AppStateInner>,
@@ -165,6 +191,8 @@ async fn main() {
             get(get_user).put(update_user).delete(delete_user),
         )
         .route("/health", get(health_check))
+        .route("/ws", get(echo))
+        .route("/users.v1.UserService/GetUser", post(grpc_get_user))
         // Add middleware to all routes
         .layer(
             ServiceBuilder::new()
@@ -255,6 +283,42 @@ async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
     }
 }
 
+/// Unary gRPC call `users.v1.UserService/GetUser`, answers `NOT_FOUND` for unknown users.
+async fn grpc_get_user(State(state): State<AppState>, body: Bytes) -> impl IntoResponse {
+    let id = body
+        .get(5..)
+        .and_then(|message| GetUserRequest::decode(message).ok())
+        .map_or(0, |request| request.id);
+    let (status, message) = match state.get_user(id) {
+        Some(user) => {
+            let message = UserMessage {
+                id: user.id,
+                name: user.name,
+                email: user.email,
+                updated_at: user.updated_at.timestamp_millis(),
+            };
+            ("0", simple_proxy::grpc::frame(&message.encode_to_vec()))
+        }
+        None => ("5", Bytes::new()),
+    };
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from_static(status));
+    let body = Full::new(message).with_trailers(async move { Some(Ok(trailers)) });
+    ([(CONTENT_TYPE, "application/grpc")], Body::new(body))
+}
+
+/// Echoes the WebSocket messages back.
+async fn echo(upgrade: WebSocketUpgrade) -> impl IntoResponse {
+    upgrade.on_upgrade(|mut socket: WebSocket| async move {
+        while let Some(Ok(message)) = socket.recv().await {
+            tracing::info!("ws message: {:?}", message);
+            if matches!(message, Message::Close(_)) || socket.send(message).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
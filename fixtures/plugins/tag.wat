@@ -0,0 +1,29 @@
+;; Sets the `x-plugin` response header to the plugin config, e.g. `config: "tag"` gives
+;; `x-plugin: "tag"`, and vetoes mirrored requests carrying `x-no-mirror`.
+(module
+  (import "proxy" "set_header" (func $set_header (param i32 i32 i32 i32) (result i32)))
+  (import "proxy" "get_header" (func $get_header (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (global $config_ptr (mut i32) (i32.const 0))
+  (global $config_len (mut i32) (i32.const 0))
+  (data (i32.const 0) "x-plugin")
+  (data (i32.const 16) "x-no-mirror")
+
+  ;; The configuration is written once per instance, right after the static data.
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+
+  (func (export "init") (param $ptr i32) (param $len i32) (result i32)
+    (global.set $config_ptr (local.get $ptr))
+    (global.set $config_len (local.get $len))
+    (i32.const 0))
+
+  (func (export "on_response") (result i32)
+    (drop (call $set_header
+      (i32.const 0) (i32.const 8)
+      (global.get $config_ptr) (global.get $config_len)))
+    (i32.const 0))
+
+  (func (export "on_mirror") (result i32)
+    (i32.ge_s
+      (call $get_header (i32.const 16) (i32.const 11) (i32.const 0) (i32.const 0))
+      (i32.const 0))))
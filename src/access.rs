@@ -0,0 +1,249 @@
+//! Client IP access control.
+//!
+//! The client address is the peer of the connection, taken from the PROXY protocol header when
+//! [`crate::proxy_protocol`] is enabled. When the peer is a trusted proxy, the address is taken
+//! from `Forwarded` (or `X-Forwarded-For` without it), walking the hops from right to left and
+//! skipping the trusted ones, so a client cannot choose its address by sending the header itself.
+//!
+//! Allow and deny lists are CIDR ranges, checked globally and per route. A deny match wins, a
+//! non-empty allow list requires a match.
+
+use crate::config::{AccessControlConfig, ClientIpConfig};
+use anyhow::{Context, Result};
+use http::HeaderMap;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Allow and deny lists compiled from an [`AccessControlConfig`].
+#[derive(Debug, Default)]
+pub struct IpAccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpAccessList {
+    pub fn new(config: &AccessControlConfig) -> Result<Self> {
+        Ok(Self {
+            allow: parse_ranges(&config.allow)?,
+            deny: parse_ranges(&config.deny)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// A client without an IP address (Unix socket) only passes when there is no allow list.
+    pub fn allows(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip else {
+            return self.allow.is_empty();
+        };
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|range| range.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(&ip))
+    }
+}
+
+/// Resolves the client address behind the trusted proxies.
+#[derive(Debug, Default)]
+pub struct ClientIpResolver {
+    trusted: Vec<IpNet>,
+}
+
+impl ClientIpResolver {
+    pub fn new(config: &ClientIpConfig) -> Result<Self> {
+        Ok(Self {
+            trusted: parse_ranges(&config.trusted_proxies)?,
+        })
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted.iter().any(|range| range.contains(ip))
+    }
+
+    /// Client address of a request received from `peer`. The forwarding headers are ignored
+    /// unless the peer is trusted, the first untrusted hop from the right is the client. When
+    /// every hop is trusted the leftmost one is.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        if !self.is_trusted(&client) {
+            return client;
+        }
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            // 无法解析的地址（unknown 或混淆标识）之后的跳数不可信
+            let Some(hop) = hop else { break };
+            client = hop.to_canonical();
+            if !self.is_trusted(&client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// `10.0.0.0/8` style ranges, a bare address is a single host range.
+pub fn parse_range(range: &str) -> Result<IpNet> {
+    let range = range.trim();
+    range
+        .parse::<IpNet>()
+        .or_else(|_| range.parse::<IpAddr>().map(IpNet::from))
+        .with_context(|| format!("invalid CIDR range {range:?}"))
+}
+
+fn parse_ranges(ranges: &[String]) -> Result<Vec<IpNet>> {
+    ranges.iter().map(|range| parse_range(range)).collect()
+}
+
+/// Addresses of the hops in the `Forwarded` header, or `X-Forwarded-For` without it, in the
+/// order they were added. `None` for a hop without an IP address.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(http::header::FORWARDED);
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .find_map(|pair| {
+                        let (name, value) = pair.split_once('=')?;
+                        name.trim()
+                            .eq_ignore_ascii_case("for")
+                            .then(|| parse_node(value))
+                    })
+                    .flatten()
+            })
+            .collect();
+    }
+    values(http::HeaderName::from_static("x-forwarded-for"))
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// `192.0.2.1`, `192.0.2.1:4711`, `"[2001:db8::1]:4711"` or `2001:db8::1`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(allow: &[&str], deny: &[&str]) -> IpAccessList {
+        IpAccessList::new(&AccessControlConfig {
+            allow: allow.iter().map(|range| range.to_string()).collect(),
+            deny: deny.iter().map(|range| range.to_string()).collect(),
+        })
+        .expect("Failed to build access list")
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().expect("Failed to parse ip"))
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().expect("Failed to parse header"));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_access_list() {
+        let access = list(&["10.0.0.0/8", "2001:db8::/32"], &["10.1.0.0/16"]);
+        assert!(access.allows(ip("10.2.3.4")));
+        assert!(access.allows(ip("2001:db8::1")));
+        assert!(!access.allows(ip("10.1.2.3")));
+        assert!(!access.allows(ip("192.168.1.1")));
+        assert!(access.allows(ip("::ffff:10.2.3.4")));
+        assert!(!access.allows(None));
+
+        let access = list(&[], &["192.168.1.1"]);
+        assert!(!access.allows(ip("192.168.1.1")));
+        assert!(access.allows(ip("192.168.1.2")));
+        assert!(access.allows(None));
+        assert!(list(&[], &[]).is_empty());
+
+        let err = IpAccessList::new(&AccessControlConfig {
+            allow: vec!["10.0.0.0/33".to_string()],
+            deny: Vec::new(),
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), r#"invalid CIDR range "10.0.0.0/33""#);
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let resolver = ClientIpResolver::new(&ClientIpConfig {
+            trusted_proxies: vec!["10.0.0.0/8".to_string(), "127.0.0.1".to_string()],
+        })
+        .expect("Failed to build resolver");
+        let peer = "10.0.0.2".parse().unwrap();
+        let resolve = |peer, pairs: &[(&'static str, &str)]| {
+            resolver.resolve(peer, &headers(pairs)).to_string()
+        };
+
+        // 不可信的对端发送的转发头被忽略
+        assert_eq!(
+            resolve(
+                "203.0.113.9".parse().unwrap(),
+                &[("x-forwarded-for", "1.2.3.4")]
+            ),
+            "203.0.113.9"
+        );
+        assert_eq!(resolve(peer, &[]), "10.0.0.2");
+        assert_eq!(
+            resolve(
+                peer,
+                &[("x-forwarded-for", "1.2.3.4, 198.51.100.7, 10.0.0.1")]
+            ),
+            "198.51.100.7"
+        );
+        assert_eq!(
+            resolve(
+                peer,
+                &[
+                    ("x-forwarded-for", "10.0.0.5"),
+                    ("x-forwarded-for", "10.0.0.1")
+                ]
+            ),
+            "10.0.0.5"
+        );
+        assert_eq!(
+            resolve(
+                peer,
+                &[
+                    (
+                        "forwarded",
+                        r#"for=192.0.2.60;proto=http, for="[2001:db8::1]:4711""#
+                    ),
+                    ("x-forwarded-for", "1.2.3.4"),
+                ]
+            ),
+            "2001:db8::1"
+        );
+        assert_eq!(
+            resolve(peer, &[("forwarded", "for=unknown, for=10.0.0.1:80")]),
+            "10.0.0.1"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+use crate::cache;
+use crate::config::{AccessLogConfig, AccessLogField, AccessLogOutput};
+use crate::ctx::ProxyCtx;
+use crate::metrics::LOG_LINES_DROPPED;
+use crate::mirror::MirrorOutcome;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use pingora::proxy::Session;
+use serde_json::{Map, Value, json};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::time::Duration;
+use tracing::warn;
+
+/// One line of the access log, collected at the end of the request.
+#[derive(Debug, Clone)]
+pub struct AccessLogRecord {
+    pub timestamp: DateTime<Utc>,
+    pub client_addr: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: Option<u16>,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub upstream: Option<String>,
+    pub upstream_latency: Option<Duration>,
+    pub duration: Duration,
+    pub mirrors: Vec<(String, MirrorOutcome)>,
+    pub request_id: Option<String>,
+    pub error: Option<String>,
+    pub cache: Option<&'static str>,
+}
+
+impl AccessLogRecord {
+    pub fn new(session: &Session, e: Option<&pingora::Error>, ctx: &ProxyCtx) -> Self {
+        let req = session.req_header();
+        Self {
+            timestamp: ctx.timestamp,
+            // 地址来自转发头时没有端口
+            client_addr: match (ctx.client_addr, ctx.client_ip) {
+                (Some(addr), Some(ip)) if addr.ip().to_canonical() != ip => Some(ip.to_string()),
+                (Some(addr), _) => Some(addr.to_string()),
+                (None, _) => session.client_addr().map(|addr| addr.to_string()),
+            },
+            method: req.method.to_string(),
+            path: req.uri.path().to_string(),
+            status: session.response_written().map(|resp| resp.status.as_u16()),
+            bytes_in: session.body_bytes_read(),
+            bytes_out: session.body_bytes_sent(),
+            upstream: ctx.backend.as_ref().map(|backend| backend.addr.to_string()),
+            upstream_latency: ctx.upstream_latency,
+            duration: ctx.started_at.elapsed(),
+            mirrors: ctx.mirror_outcomes.clone(),
+            request_id: (!ctx.request_id.is_empty()).then(|| ctx.request_id.clone()),
+            error: e.map(|e| e.to_string()),
+            cache: ctx
+                .cache_lookup
+                .then(|| cache::status(&session.cache.phase())),
+        }
+    }
+
+    /// Serializes the selected fields, in the configured order, as a single JSON line.
+    pub fn to_json(&self, fields: &[AccessLogField]) -> String {
+        let mut map = Map::new();
+        for field in fields {
+            let value = match field {
+                AccessLogField::Timestamp => {
+                    json!(self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
+                }
+                AccessLogField::ClientAddr => json!(self.client_addr),
+                AccessLogField::Method => json!(self.method),
+                AccessLogField::Path => json!(self.path),
+                AccessLogField::Status => json!(self.status),
+                AccessLogField::BytesIn => json!(self.bytes_in),
+                AccessLogField::BytesOut => json!(self.bytes_out),
+                AccessLogField::Upstream => json!(self.upstream),
+                AccessLogField::UpstreamLatencyMs => {
+                    json!(self.upstream_latency.map(millis))
+                }
+                AccessLogField::DurationMs => json!(millis(self.duration)),
+                AccessLogField::Mirror => Value::Object(
+                    self.mirrors
+                        .iter()
+                        .map(|(name, outcome)| (name.clone(), json!(outcome)))
+                        .collect(),
+                ),
+                AccessLogField::RequestId => json!(self.request_id),
+                AccessLogField::Error => json!(self.error),
+                AccessLogField::Cache => json!(self.cache),
+            };
+            map.insert(field.key().to_string(), value);
+        }
+        Value::Object(map).to_string()
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+/// Lines queued for the writer thread of the access log, later lines are dropped.
+const QUEUE_SIZE: usize = 8192;
+
+/// Writes access log lines to stdout or a size rotated file.
+pub struct AccessLog {
+    fields: Vec<AccessLogField>,
+    writer: LineWriter,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<Self> {
+        let sink = match &config.output {
+            AccessLogOutput::Stdout => Sink::Stdout(BufWriter::new(std::io::stdout())),
+            AccessLogOutput::File {
+                path,
+                max_size,
+                max_files,
+            } => Sink::File(RotatingFile::open(path.clone(), *max_size, *max_files)?),
+        };
+        Ok(Self {
+            fields: config.fields.clone(),
+            writer: LineWriter::new("access_log", QUEUE_SIZE, sink),
+        })
+    }
+
+    /// Starts the writer thread, lines written before wait in its queue.
+    pub fn start_writer(&self) -> Result<()> {
+        self.writer.start()
+    }
+
+    pub fn writer_started(&self) -> bool {
+        self.writer.is_started()
+    }
+
+    /// Queues the line of the request, it is dropped when the writer falls behind.
+    pub fn log(&self, record: &AccessLogRecord) {
+        let mut line = record.to_json(&self.fields);
+        line.push('\n');
+        self.writer.write(line.into_bytes());
+    }
+
+    /// Waits until the queued lines are written, once the writer is started.
+    pub fn flush(&self) {
+        self.writer.flush();
+    }
+}
+
+pub(crate) enum Sink {
+    Stdout(BufWriter<Stdout>),
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        match self {
+            Sink::Stdout(stdout) => stdout.write_all(line)?,
+            Sink::File(file) => file.write_line(line)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Sink::Stdout(stdout) => stdout.flush()?,
+            Sink::File(file) => file.flush()?,
+        }
+        Ok(())
+    }
+}
+
+enum Command {
+    Line(Vec<u8>),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Hands lines to a dedicated thread writing them to a [`Sink`], so that request handling
+/// never waits on the disk. The sink is buffered and flushed whenever the queue is empty.
+pub(crate) struct LineWriter {
+    name: &'static str,
+    sender: SyncSender<Command>,
+    // 线程启动前保存接收端和输出
+    pending: Mutex<Option<(Sink, Receiver<Command>)>>,
+}
+
+impl LineWriter {
+    pub(crate) fn new(name: &'static str, queue_size: usize, sink: Sink) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        Self {
+            name,
+            sender,
+            pending: Mutex::new(Some((sink, receiver))),
+        }
+    }
+
+    /// Starts the writer thread, lines written before wait in the queue. Called from a
+    /// background service: a daemon forks in `Server::run_forever` and threads started
+    /// before are lost.
+    pub(crate) fn start(&self) -> Result<()> {
+        let Some((sink, receiver)) = self.pending.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let name = self.name;
+        std::thread::Builder::new()
+            .name(format!("{name}-writer"))
+            .spawn(move || write_lines(name, sink, receiver))
+            .with_context(|| format!("failed to start the {name} writer"))?;
+        Ok(())
+    }
+
+    pub(crate) fn is_started(&self) -> bool {
+        self.pending.lock().unwrap().is_none()
+    }
+
+    pub(crate) fn write(&self, line: Vec<u8>) {
+        if self.sender.try_send(Command::Line(line)).is_err() {
+            LOG_LINES_DROPPED
+                .with_label_values(&[self.name, "queue_full"])
+                .inc();
+        }
+    }
+
+    pub(crate) fn flush(&self) {
+        if !self.is_started() {
+            return;
+        }
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(Command::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+fn write_lines(name: &'static str, mut sink: Sink, receiver: Receiver<Command>) {
+    while let Ok(command) = receiver.recv() {
+        let mut waiting = Vec::new();
+        // 一次写完队列中的所有行再刷新缓冲
+        for command in std::iter::once(command).chain(receiver.try_iter()) {
+            match command {
+                Command::Line(line) => {
+                    if let Err(e) = sink.write_line(&line) {
+                        warn!(log = name, "failed to write line: {:?}", e);
+                        LOG_LINES_DROPPED
+                            .with_label_values(&[name, "write_error"])
+                            .inc();
+                    }
+                }
+                Command::Flush(done) => waiting.push(done),
+            }
+        }
+        if let Err(e) = sink.flush() {
+            warn!(log = name, "failed to flush: {:?}", e);
+        }
+        for done in waiting {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Log file rotated by size: `access.log` is renamed to `access.log.1`, `access.log.1` to
+/// `access.log.2` and so on, keeping at most `max_files` rotated files.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    envelope: Option<Envelope>,
+    file: BufWriter<File>,
+    /// Size of the file once the footer is written.
+    size: u64,
+    // 写入一批行时暂不写结尾，刷新时补上
+    footer_written: bool,
+}
+
+/// Text written around the lines of a [`RotatingFile`], so every file is a complete document.
+pub(crate) struct Envelope {
+    pub header: &'static [u8],
+    pub separator: &'static [u8],
+    pub footer: &'static [u8],
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<Self> {
+        Self::open_with_envelope(path, max_size, max_files, None)
+    }
+
+    pub(crate) fn open_with_envelope(
+        path: PathBuf,
+        max_size: u64,
+        max_files: usize,
+        envelope: Option<Envelope>,
+    ) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let (file, size) = Self::open_file(&path, envelope.as_ref())
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            envelope,
+            file,
+            size,
+            footer_written: true,
+        })
+    }
+
+    fn open_file(path: &Path, envelope: Option<&Envelope>) -> Result<(BufWriter<File>, u64)> {
+        // 带外层结构的文件需要回退到结尾之前写入，不能使用追加模式
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .append(envelope.is_none())
+            .open(path)?;
+        let mut size = file.metadata()?.len();
+        let mut file = BufWriter::new(file);
+        if let Some(envelope) = envelope
+            && size == 0
+        {
+            file.write_all(envelope.header)?;
+            file.write_all(envelope.footer)?;
+            size = (envelope.header.len() + envelope.footer.len()) as u64;
+        }
+        Ok((file, size))
+    }
+
+    /// Size of a file without any line.
+    fn empty_size(&self) -> u64 {
+        self.envelope.as_ref().map_or(0, |envelope| {
+            (envelope.header.len() + envelope.footer.len()) as u64
+        })
+    }
+
+    /// Buffers the line, it is on disk after [`RotatingFile::flush`].
+    pub(crate) fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        let empty = self.empty_size();
+        if self.max_size > 0 && self.size > empty && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        match &self.envelope {
+            None => {
+                self.file.write_all(line)?;
+                self.size += line.len() as u64;
+            }
+            Some(envelope) => {
+                if self.footer_written {
+                    let offset = self.size - envelope.footer.len() as u64;
+                    self.file.seek(SeekFrom::Start(offset))?;
+                    self.footer_written = false;
+                }
+                if self.size > empty {
+                    self.file.write_all(envelope.separator)?;
+                    self.size += envelope.separator.len() as u64;
+                }
+                self.file.write_all(line)?;
+                self.size += line.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if let Some(envelope) = &self.envelope
+            && !self.footer_written
+        {
+            self.file.write_all(envelope.footer)?;
+            self.footer_written = true;
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                if rotated(n).exists() {
+                    std::fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        (self.file, self.size) = Self::open_file(&self.path, self.envelope.as_ref())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> AccessLogRecord {
+        AccessLogRecord {
+            timestamp: DateTime::parse_from_rfc3339("2025-01-02T03:04:05.678Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            client_addr: Some("127.0.0.1:52000".to_string()),
+            method: "POST".to_string(),
+            path: "/users".to_string(),
+            status: Some(201),
+            bytes_in: 42,
+            bytes_out: 128,
+            upstream: Some("127.0.0.1:3000".to_string()),
+            upstream_latency: Some(Duration::from_micros(1500)),
+            duration: Duration::from_millis(2),
+            mirrors: vec![("secondary".to_string(), MirrorOutcome::Sent)],
+            request_id: Some("abc".to_string()),
+            error: None,
+            cache: Some("miss"),
+        }
+    }
+
+    #[test]
+    fn test_record_to_json_all_fields() {
+        let line = record().to_json(&AccessLogConfig::default().fields);
+        let value: Value = serde_json::from_str(&line).expect("Invalid json");
+        assert_eq!(value["timestamp"], "2025-01-02T03:04:05.678Z");
+        assert_eq!(value["status"], 201);
+        assert_eq!(value["bytes_in"], 42);
+        assert_eq!(value["upstream"], "127.0.0.1:3000");
+        assert_eq!(value["upstream_latency_ms"], 1.5);
+        assert_eq!(value["mirror"]["secondary"], "sent");
+        assert_eq!(value["request_id"], "abc");
+        assert!(value["error"].is_null());
+        assert_eq!(value["cache"], "miss");
+    }
+
+    #[test]
+    fn test_record_to_json_selected_fields() {
+        let line = record().to_json(&[AccessLogField::Method, AccessLogField::Status]);
+        assert_eq!(line, r#"{"method":"POST","status":201}"#);
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("access.log");
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).expect("Failed to open");
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            file.write_line(line.as_bytes()).expect("Failed to write");
+        }
+        file.flush().expect("Failed to flush");
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("access.log"), "dddddd\n");
+        assert_eq!(read("access.log.1"), "cccccc\n");
+        assert_eq!(read("access.log.2"), "bbbbbb\n");
+        // 超过 max_files 的文件被丢弃
+        assert!(!dir.join("access.log.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotating_file_with_envelope() {
+        let dir = std::env::temp_dir().join(format!("access-log-envelope-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("entries.json");
+        let envelope = || Envelope {
+            header: b"[",
+            separator: b",",
+            footer: b"]",
+        };
+
+        let mut file = RotatingFile::open_with_envelope(path.clone(), 8, 1, Some(envelope()))
+            .expect("Failed to open");
+        for line in ["1", "22", "333"] {
+            file.write_line(line.as_bytes()).expect("Failed to write");
+        }
+        file.flush().expect("Failed to flush");
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("entries.json"), "[333]");
+        assert_eq!(read("entries.json.1"), "[1,22]");
+
+        // 重新打开已有文件时继续追加到结尾之前
+        let mut file = RotatingFile::open_with_envelope(path, 0, 1, Some(envelope()))
+            .expect("Failed to reopen");
+        file.write_line(b"4").expect("Failed to write");
+        file.write_line(b"5").expect("Failed to write");
+        file.flush().expect("Failed to flush");
+        assert_eq!(read("entries.json"), "[333,4,5]");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_access_log_writer() {
+        let dir = std::env::temp_dir().join(format!("access-log-writer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("access.log");
+        let access_log = AccessLog::new(&AccessLogConfig {
+            enabled: true,
+            output: AccessLogOutput::File {
+                path: path.clone(),
+                max_size: 0,
+                max_files: 1,
+            },
+            fields: vec![AccessLogField::Method, AccessLogField::Status],
+        })
+        .expect("Failed to create access log");
+        access_log.start_writer().expect("Failed to start writer");
+        for _ in 0..3 {
+            access_log.log(&record());
+        }
+        access_log.flush();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "{\"method\":\"POST\",\"status\":201}\n".repeat(3));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
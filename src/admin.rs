@@ -0,0 +1,514 @@
+//! Admin HTTP listener used by the dashboard under `ui/`.
+//!
+//! | Endpoint | |
+//! |----------|--|
+//! | `GET /api/events` | Server-Sent Events of the [`ProxyEvent`]s, `?types=request,mismatch` filters them |
+//! | `GET /api/mismatches` | most recent stored mismatches, `?limit=100` |
+//! | `GET /api/mismatches/summary` | mismatch rate per route and mirror, `?top=10` fields |
+//! | `GET /api/status` | mirrors with their circuit state |
+//! | `GET /api/config` | the loaded configuration |
+//! | `GET /api/cache` | number and size of the cached responses |
+//! | `POST /api/cache/purge` | removes cached responses, `{"path_prefix": "/users", "host": "..."}` |
+//! | `GET /metrics` | Prometheus metrics |
+//!
+//! With `token` set, the `/api` endpoints require an `Authorization: Bearer <token>` header.
+//! Any other path is served from `ui_dir` when set, falling back to its `index.html`.
+
+use crate::DualWriteProxy;
+use crate::cache::{CacheStats, ResponseCache};
+use crate::config::ProxyConfig;
+use crate::events::{EventBus, ProxyEvent};
+use crate::mirror::MirrorTarget;
+use crate::mismatch::MismatchStore;
+use async_trait::async_trait;
+use axum::extract::{Query, Request, State};
+use axum::http::StatusCode;
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::Stream;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use prometheus::{Encoder, TextEncoder};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tower_http::services::{ServeDir, ServeFile};
+use tracing::{info, warn};
+
+pub struct AdminService {
+    listen: String,
+    ui_dir: Option<PathBuf>,
+    token: Option<String>,
+    state: AdminState,
+}
+
+#[derive(Clone)]
+struct AdminState {
+    config: Arc<ProxyConfig>,
+    events: EventBus,
+    store: Option<Arc<MismatchStore>>,
+    mirrors: Vec<Arc<MirrorTarget>>,
+    cache: Option<Arc<ResponseCache>>,
+}
+
+impl AdminService {
+    /// Returns `None` when the admin listener is disabled.
+    pub fn new(config: &ProxyConfig, proxy: &DualWriteProxy) -> Option<Self> {
+        let events = proxy.events()?.clone();
+        Some(Self {
+            listen: config.admin.listen.clone(),
+            ui_dir: config.admin.ui_dir.clone(),
+            token: config.admin.token.clone(),
+            state: AdminState {
+                config: Arc::new(config.clone()),
+                events,
+                store: proxy
+                    .comparator()
+                    .map(|comparator| comparator.store().clone()),
+                mirrors: proxy.mirrors().to_vec(),
+                cache: proxy.cache().cloned(),
+            },
+        })
+    }
+
+    pub fn router(&self) -> Router {
+        let mut api = Router::new()
+            .route("/api/events", get(events))
+            .route("/api/mismatches", get(mismatches))
+            .route("/api/mismatches/summary", get(summary))
+            .route("/api/status", get(status))
+            .route("/api/config", get(config))
+            .route("/api/cache", get(cache_stats))
+            .route("/api/cache/purge", post(purge_cache));
+        if let Some(token) = &self.token {
+            api = api.route_layer(middleware::from_fn_with_state(token.clone(), authorize));
+        }
+        let router = api
+            .route("/metrics", get(metrics))
+            .with_state(self.state.clone());
+        match &self.ui_dir {
+            Some(dir) => router.fallback_service(
+                ServeDir::new(dir).fallback(ServeFile::new(dir.join("index.html"))),
+            ),
+            None => router,
+        }
+    }
+
+    pub async fn serve(&self, listener: TcpListener, mut shutdown: ShutdownWatch) {
+        let result = axum::serve(listener, self.router())
+            .with_graceful_shutdown(async move {
+                let _ = shutdown.changed().await;
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("admin listener failed: {:?}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for AdminService {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        let listener = match TcpListener::bind(&self.listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("failed to bind admin listener on {}: {:?}", self.listen, e);
+                return;
+            }
+        };
+        info!("Admin listening on {}", self.listen);
+        self.serve(listener, shutdown).await;
+    }
+}
+
+/// Error response of the api, `{"error": "..."}`.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+/// Rejects requests without the admin bearer token.
+async fn authorize(State(token): State<String>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()));
+    if !authorized {
+        let error = ApiError(
+            StatusCode::UNAUTHORIZED,
+            "missing or invalid admin token".to_string(),
+        );
+        return ([(WWW_AUTHENTICATE, "Bearer")], error).into_response();
+    }
+    next.run(request).await
+}
+
+// 比较耗时与第一个不同字节的位置无关
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct EventsQuery {
+    /// Comma separated event types, all when unset.
+    types: Option<String>,
+}
+
+async fn events(
+    State(state): State<AdminState>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let types: Option<Vec<String>> = query
+        .types
+        .map(|types| types.split(',').map(|t| t.trim().to_string()).collect());
+    let receiver = state.events.subscribe();
+    let stream = futures::stream::unfold(receiver, move |mut receiver| {
+        let types = types.clone();
+        async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    // 订阅者处理太慢，通知它丢失了多少事件
+                    Err(RecvError::Lagged(missed)) => {
+                        let event = Event::default().event("lagged").data(missed.to_string());
+                        return Some((Ok(event), receiver));
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                if types
+                    .as_ref()
+                    .is_some_and(|types| !types.iter().any(|t| t == event.kind()))
+                {
+                    continue;
+                }
+                let event = Event::default()
+                    .event(event.kind())
+                    .json_data::<&ProxyEvent>(&event);
+                return Some((event, receiver));
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+impl AdminState {
+    fn store(&self) -> Result<Arc<MismatchStore>, ApiError> {
+        self.store.clone().ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                "response comparison is disabled".to_string(),
+            )
+        })
+    }
+}
+
+impl AdminState {
+    fn cache(&self) -> Result<&ResponseCache, ApiError> {
+        self.cache.as_deref().ok_or_else(|| {
+            ApiError(
+                StatusCode::NOT_FOUND,
+                "response cache is disabled".to_string(),
+            )
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MismatchesQuery {
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+async fn mismatches(
+    State(state): State<AdminState>,
+    Query(query): Query<MismatchesQuery>,
+) -> Result<Response, ApiError> {
+    let store = state.store()?;
+    let mismatches = blocking(move || store.list(Some(query.limit))).await?;
+    Ok(Json(mismatches).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct SummaryQuery {
+    #[serde(default = "default_top")]
+    top: usize,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+async fn summary(
+    State(state): State<AdminState>,
+    Query(query): Query<SummaryQuery>,
+) -> Result<Response, ApiError> {
+    let store = state.store()?;
+    let summary = blocking(move || store.summary(query.top)).await?;
+    Ok(Json(summary).into_response())
+}
+
+/// Runs a query of the SQLite store off the async workers.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(ApiError::from)
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    comparison: bool,
+    mirrors: Vec<MirrorStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct MirrorStatus {
+    name: String,
+    url: String,
+    circuit: String,
+}
+
+async fn status(State(state): State<AdminState>) -> Json<Status> {
+    Json(Status {
+        comparison: state.store.is_some(),
+        mirrors: state
+            .mirrors
+            .iter()
+            .map(|mirror| MirrorStatus {
+                name: mirror.name().to_string(),
+                url: mirror.base_url().to_string(),
+                circuit: mirror.breaker().state().to_string(),
+            })
+            .collect(),
+    })
+}
+
+async fn config(State(state): State<AdminState>) -> Json<ProxyConfig> {
+    let mut config = state.config.as_ref().clone();
+    if config.admin.token.is_some() {
+        config.admin.token = Some("<redacted>".to_string());
+    }
+    Json(config)
+}
+
+async fn cache_stats(State(state): State<AdminState>) -> Result<Json<CacheStats>, ApiError> {
+    Ok(Json(state.cache()?.stats()))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PurgeRequest {
+    /// Every cached response when empty.
+    path_prefix: String,
+    /// Every host when unset.
+    host: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeResponse {
+    purged: usize,
+}
+
+async fn purge_cache(
+    State(state): State<AdminState>,
+    Json(request): Json<PurgeRequest>,
+) -> Result<Json<PurgeResponse>, ApiError> {
+    let purged = state
+        .cache()?
+        .purge(request.host.as_deref(), &request.path_prefix)
+        .await;
+    info!(purged, path_prefix = %request.path_prefix, "purged cached responses");
+    Ok(Json(PurgeResponse { purged }))
+}
+
+async fn metrics() -> Result<Response, ApiError> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        [("content-type", encoder.format_type().to_string())],
+        buffer,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mirror::MirrorOutcome;
+    use chrono::Utc;
+    use std::collections::BTreeMap;
+
+    async fn start(
+        name: &str,
+        token: Option<&str>,
+    ) -> (String, DualWriteProxy, tokio::sync::watch::Sender<bool>) {
+        let store = std::env::temp_dir().join(format!("admin-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&store);
+        let mut config = ProxyConfig::default();
+        config.admin.enabled = true;
+        config.admin.token = token.map(str::to_string);
+        config.comparison.enabled = true;
+        config.comparison.store = store;
+        config.cache.enabled = true;
+        let proxy = DualWriteProxy::new(&config).expect("Failed to create proxy");
+        let admin = AdminService::new(&config, &proxy).expect("admin enabled");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, watch) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move { admin.serve(listener, watch).await });
+        (format!("http://{addr}"), proxy, shutdown)
+    }
+
+    async fn get_json(url: String) -> serde_json::Value {
+        let response = reqwest::get(url).await.expect("Failed to send request");
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&response.bytes().await.unwrap()).expect("Failed to parse json")
+    }
+
+    #[tokio::test]
+    async fn test_rest_endpoints() {
+        let (base, _proxy, _shutdown) = start("rest", None).await;
+
+        let status = get_json(format!("{base}/api/status")).await;
+        assert_eq!(status["comparison"], true);
+        assert_eq!(status["mirrors"][0]["circuit"], "closed");
+
+        let config = get_json(format!("{base}/api/config")).await;
+        assert_eq!(config["listen"], "0.0.0.0:8080");
+        assert_eq!(config["comparison"]["retention"]["max_age"], "7days");
+
+        let mismatches = get_json(format!("{base}/api/mismatches?limit=5")).await;
+        assert_eq!(mismatches, serde_json::json!([]));
+
+        let summary = get_json(format!("{base}/api/mismatches/summary")).await;
+        assert_eq!(summary["stored"], 0);
+
+        let cache = get_json(format!("{base}/api/cache")).await;
+        assert_eq!(cache["entries"], 0);
+        let response = reqwest::Client::new()
+            .post(format!("{base}/api/cache/purge"))
+            .header("content-type", "application/json")
+            .body(r#"{"path_prefix": "/users"}"#)
+            .send()
+            .await
+            .expect("Failed to purge");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), r#"{"purged":0}"#);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let (base, proxy, _shutdown) = start("events", None).await;
+        let mut response = reqwest::get(format!("{base}/api/events?types=mismatch"))
+            .await
+            .expect("Failed to subscribe");
+        assert_eq!(
+            response.headers()["content-type"].to_str().unwrap(),
+            "text/event-stream"
+        );
+
+        let events = proxy.events().unwrap();
+        events.publish(ProxyEvent::Request {
+            timestamp: Utc::now(),
+            request_id: "skipped".to_string(),
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            route: None,
+            status: Some(200),
+            upstream: None,
+            duration_ms: 1.0,
+            mirrors: BTreeMap::from([("secondary".to_string(), MirrorOutcome::Sent)]),
+            error: None,
+        });
+        events.publish(ProxyEvent::Mismatch {
+            timestamp: Utc::now(),
+            request_id: "abc".to_string(),
+            route: Some("users".to_string()),
+            mirror: "secondary".to_string(),
+            fields: vec!["body.name".to_string()],
+        });
+
+        let chunk = response
+            .chunk()
+            .await
+            .unwrap()
+            .expect("Failed to read event");
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(chunk.starts_with("event: mismatch\ndata: "));
+        assert!(chunk.contains(r#""request_id":"abc""#));
+    }
+
+    #[tokio::test]
+    async fn test_token_required() {
+        let (base, _proxy, _shutdown) = start("token", Some("s3cret")).await;
+        let client = reqwest::Client::new();
+        let status = |path: &'static str, token: Option<&'static str>| {
+            let mut request = client.get(format!("{base}{path}"));
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            async move {
+                request
+                    .send()
+                    .await
+                    .expect("Failed to send request")
+                    .status()
+            }
+        };
+
+        assert_eq!(status("/api/status", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status("/api/config", Some("wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status("/api/status", Some("s3cret")).await, StatusCode::OK);
+        assert_eq!(status("/metrics", None).await, StatusCode::OK);
+
+        let response = client
+            .post(format!("{base}/api/cache/purge"))
+            .header("content-type", "application/json")
+            .body("{}")
+            .send()
+            .await
+            .expect("Failed to purge");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+
+        let response = client
+            .get(format!("{base}/api/config"))
+            .bearer_auth("s3cret")
+            .send()
+            .await
+            .expect("Failed to get config");
+        let config: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).expect("Failed to parse json");
+        assert_eq!(config["admin"]["token"], "<redacted>");
+    }
+}
@@ -0,0 +1,413 @@
+use crate::mirror::{MirrorClient, MirrorRequest};
+use anyhow::{Context, Result, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// A mirrored write that could not be delivered, stored as one JSON line.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BacklogEntry {
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub request_id: String,
+    pub mirror: String,
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, BacklogHeaderValue)>,
+    /// Request body, base64 encoded.
+    pub body: String,
+}
+
+/// Header value of a [`BacklogEntry`]: the text when it is visible ASCII, the base64 encoded
+/// bytes otherwise, so that opaque values are replayed unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum BacklogHeaderValue {
+    Text(String),
+    Bytes { base64: String },
+}
+
+impl From<&HeaderValue> for BacklogHeaderValue {
+    fn from(value: &HeaderValue) -> Self {
+        match value.to_str() {
+            Ok(text) => BacklogHeaderValue::Text(text.to_string()),
+            Err(_) => BacklogHeaderValue::Bytes {
+                base64: STANDARD.encode(value.as_bytes()),
+            },
+        }
+    }
+}
+
+impl BacklogHeaderValue {
+    fn to_header_value(&self) -> Result<HeaderValue> {
+        Ok(match self {
+            BacklogHeaderValue::Text(text) => HeaderValue::from_str(text)?,
+            BacklogHeaderValue::Bytes { base64 } => {
+                HeaderValue::from_bytes(&STANDARD.decode(base64)?)?
+            }
+        })
+    }
+}
+
+impl BacklogEntry {
+    pub fn new(mirror: &str, request: &MirrorRequest) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            request_id: request.request_id.clone(),
+            mirror: mirror.to_string(),
+            method: request.method.to_string(),
+            uri: request.path_and_query.clone(),
+            headers: request
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.into()))
+                .collect(),
+            body: STANDARD.encode(&request.body),
+        }
+    }
+
+    pub fn into_request(self) -> Result<MirrorRequest> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes())?,
+                value.to_header_value()?,
+            );
+        }
+        Ok(MirrorRequest {
+            request_id: self.request_id,
+            method: Method::from_bytes(self.method.as_bytes())?,
+            path_and_query: self.uri,
+            headers,
+            body: STANDARD.decode(self.body)?.into(),
+            trace_link: None,
+            route: None,
+            record: false,
+            primary_response: None,
+        })
+    }
+}
+
+/// Entries of a backlog file for one mirror.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct BacklogStats {
+    pub mirror: String,
+    pub entries: usize,
+    pub oldest: DateTime<Utc>,
+    pub newest: DateTime<Utc>,
+}
+
+/// Where [`Backlog::drain`] sends the entries of a mirror.
+pub struct DrainTarget {
+    pub url: String,
+    pub client: MirrorClient,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DrainReport {
+    /// Entries delivered, per mirror.
+    pub sent: BTreeMap<String, usize>,
+    /// First error per mirror, its entries from this one on stay in the backlog.
+    pub errors: BTreeMap<String, String>,
+    /// Entries left in the backlog.
+    pub remaining: usize,
+}
+
+/// Append-only JSONL file holding skipped mirror writes.
+///
+/// Writes take an exclusive lock on the file so that [`Backlog::drain`], run from another
+/// process, can rewrite it while the proxy is running.
+pub struct Backlog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl Backlog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &BacklogEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let opened = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .with_context(|| format!("failed to open backlog {}", self.path.display()))?;
+            *file = Some(opened);
+        }
+        let file = file.as_mut().expect("backlog file is open");
+        file.lock()?;
+        let written = file.write_all(&line);
+        file.unlock()?;
+        Ok(written?)
+    }
+
+    pub fn read_all(path: impl AsRef<Path>) -> Result<Vec<BacklogEntry>> {
+        let content = match std::fs::read_to_string(path.as_ref()) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    /// Counts the entries per mirror, in order of first appearance.
+    pub fn stats(entries: &[BacklogEntry]) -> Vec<BacklogStats> {
+        let mut stats: Vec<BacklogStats> = Vec::new();
+        for entry in entries {
+            match stats.iter_mut().find(|stats| stats.mirror == entry.mirror) {
+                Some(stats) => {
+                    stats.entries += 1;
+                    stats.oldest = stats.oldest.min(entry.timestamp);
+                    stats.newest = stats.newest.max(entry.timestamp);
+                }
+                None => stats.push(BacklogStats {
+                    mirror: entry.mirror.clone(),
+                    entries: 1,
+                    oldest: entry.timestamp,
+                    newest: entry.timestamp,
+                }),
+            }
+        }
+        stats
+    }
+
+    /// Sends the entries of the mirrors in `targets` in file order and removes the delivered
+    /// ones. Entries are sent as stored, the route and mirror rules were applied before they
+    /// were written. A mirror is not sent anything after its first failure so that its writes
+    /// stay in order. Entries appended by a running proxy meanwhile are kept.
+    pub async fn drain(path: &Path, targets: &HashMap<String, DrainTarget>) -> Result<DrainReport> {
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(DrainReport::default());
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to open backlog {}", path.display()));
+            }
+        };
+        let mut content = String::new();
+        file.lock()?;
+        let read = file.read_to_string(&mut content);
+        file.unlock()?;
+        read?;
+
+        let mut report = DrainReport::default();
+        let mut kept = Vec::new();
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let entry: BacklogEntry = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("keeping invalid backlog entry: {:?}", e);
+                    kept.push(line);
+                    continue;
+                }
+            };
+            let target = targets
+                .get(&entry.mirror)
+                .filter(|_| !report.errors.contains_key(&entry.mirror));
+            let Some(target) = target else {
+                kept.push(line);
+                continue;
+            };
+            let mirror = entry.mirror.clone();
+            match Self::send(target, entry).await {
+                Ok(()) => *report.sent.entry(mirror).or_default() += 1,
+                Err(e) => {
+                    report.errors.insert(mirror, e.to_string());
+                    kept.push(line);
+                }
+            }
+        }
+
+        // 重写文件时保留 drain 期间追加的条目
+        let mut rewritten = kept.join("\n");
+        if !rewritten.is_empty() {
+            rewritten.push('\n');
+        }
+        file.lock()?;
+        let result = (|| {
+            let mut appended = String::new();
+            file.seek(SeekFrom::Start(content.len() as u64))?;
+            file.read_to_string(&mut appended)?;
+            rewritten.push_str(&appended);
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(rewritten.as_bytes())?;
+            std::io::Result::Ok(appended)
+        })();
+        file.unlock()?;
+        let appended = result?;
+        report.remaining = kept.len() + appended.lines().filter(|l| !l.trim().is_empty()).count();
+        Ok(report)
+    }
+
+    async fn send(target: &DrainTarget, entry: BacklogEntry) -> Result<()> {
+        let request = entry.into_request()?;
+        let url = request.url(&target.url)?;
+        let response = request.into_http(&target.client, url).send().await?;
+        if response.status().is_server_error() {
+            bail!("mirror responded with {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog_round_trip() {
+        let path = std::env::temp_dir().join(format!("backlog-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        // 非 UTF-8 的头部值
+        let opaque = HeaderValue::from_bytes(&[b'a', 0x80, 0xff]).unwrap();
+        headers.insert("x-opaque", opaque.clone());
+        let request = MirrorRequest {
+            request_id: "req-1".to_string(),
+            method: Method::POST,
+            path_and_query: "/users?x=1".to_string(),
+            headers,
+            body: r#"{"name":"Alice"}"#.into(),
+            trace_link: None,
+            route: None,
+            record: false,
+            primary_response: None,
+        };
+
+        let backlog = Backlog::new(&path);
+        backlog
+            .append(&BacklogEntry::new("secondary", &request))
+            .expect("Failed to append");
+        backlog
+            .append(&BacklogEntry::new("secondary", &request))
+            .expect("Failed to append");
+
+        let entries = Backlog::read_all(&path).expect("Failed to read backlog");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].mirror, "secondary");
+
+        let restored = entries[0].clone().into_request().expect("Invalid entry");
+        assert_eq!(restored.request_id, "req-1");
+        assert_eq!(restored.method, Method::POST);
+        assert_eq!(restored.path_and_query, "/users?x=1");
+        assert_eq!(restored.body, request.body);
+        assert_eq!(restored.headers["content-type"], "application/json");
+        assert_eq!(restored.headers["x-opaque"], opaque);
+        let line = std::fs::read_to_string(&path).unwrap();
+        assert!(line.contains(r#"["x-opaque",{"base64":"YYD/"}]"#));
+        assert!(line.contains(r#"["content-type","application/json"]"#));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn entry(mirror: &str, uri: &str) -> BacklogEntry {
+        BacklogEntry {
+            timestamp: Utc::now(),
+            request_id: uri.to_string(),
+            mirror: mirror.to_string(),
+            method: "POST".to_string(),
+            uri: uri.to_string(),
+            headers: vec![(
+                "x-rewritten".to_string(),
+                BacklogHeaderValue::Text("true".to_string()),
+            )],
+            body: STANDARD.encode("{}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_keeps_failed_and_other_mirrors() {
+        use axum::{Router, http::StatusCode, routing::post};
+
+        let app = Router::new()
+            .route("/ok", post(|| async { StatusCode::CREATED }))
+            .route("/fail", post(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let path = std::env::temp_dir().join(format!("backlog-drain-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let backlog = Backlog::new(&path);
+        for entry in [
+            entry("secondary", "/ok"),
+            entry("other", "/ok"),
+            entry("secondary", "/fail"),
+            // 失败之后的条目不再发送，保证顺序
+            entry("secondary", "/ok"),
+        ] {
+            backlog.append(&entry).expect("Failed to append");
+        }
+        let stats = Backlog::stats(&Backlog::read_all(&path).unwrap());
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].entries, 3);
+
+        let targets = HashMap::from([(
+            "secondary".to_string(),
+            DrainTarget {
+                url: format!("http://{addr}"),
+                client: MirrorClient::new(
+                    std::time::Duration::from_secs(5),
+                    crate::config::HttpVersion::Auto,
+                )
+                .unwrap(),
+            },
+        )]);
+        let report = Backlog::drain(&path, &targets)
+            .await
+            .expect("Failed to drain");
+        assert_eq!(report.sent["secondary"], 1);
+        assert!(report.errors["secondary"].contains("503"));
+        assert_eq!(report.remaining, 3);
+
+        let left: Vec<_> = Backlog::read_all(&path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.mirror, entry.uri))
+            .collect();
+        assert_eq!(
+            left,
+            [
+                ("other".to_string(), "/ok".to_string()),
+                ("secondary".to_string(), "/fail".to_string()),
+                ("secondary".to_string(), "/ok".to_string()),
+            ]
+        );
+
+        // 代理继续追加的条目写在重写后的文件末尾
+        backlog.append(&entry("secondary", "/new")).unwrap();
+        assert_eq!(Backlog::read_all(&path).unwrap().len(), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use crate::config::BodyRule;
+use crate::template::{Template, TemplateVars};
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use http::{HeaderMap, header::CONTENT_TYPE};
+use serde_json::{Map, Value};
+
+/// Compiled list of [`BodyRule`]s transforming the JSON body of mirrored requests.
+#[derive(Debug, Clone, Default)]
+pub struct BodyTransform {
+    rules: Vec<CompiledRule>,
+}
+
+#[derive(Debug, Clone)]
+enum CompiledRule {
+    Rename(Vec<String>, Vec<String>),
+    Remove(Vec<String>),
+    Set(Vec<String>, SetValue),
+    Each(Vec<String>, Vec<CompiledRule>),
+}
+
+#[derive(Debug, Clone)]
+enum SetValue {
+    Constant(Value),
+    Template(Template),
+}
+
+impl BodyTransform {
+    pub fn new(rules: &[BodyRule]) -> Result<Self> {
+        Ok(Self {
+            rules: compile(rules)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether the body is JSON according to its content type.
+    pub fn is_json(headers: &HeaderMap) -> bool {
+        headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|mime| {
+                let mime = mime.trim().to_ascii_lowercase();
+                mime == "application/json" || mime.ends_with("+json")
+            })
+            .unwrap_or(false)
+    }
+
+    pub fn apply(&self, body: &[u8], vars: &TemplateVars) -> Result<Bytes> {
+        let mut value: Value = serde_json::from_slice(body)?;
+        apply_rules(&self.rules, &mut value, vars);
+        Ok(serde_json::to_vec(&value)?.into())
+    }
+}
+
+fn compile(rules: &[BodyRule]) -> Result<Vec<CompiledRule>> {
+    rules
+        .iter()
+        .map(|rule| {
+            Ok(match rule {
+                BodyRule::Rename { from, to } => CompiledRule::Rename(path(from)?, path(to)?),
+                BodyRule::Remove { path: p } => CompiledRule::Remove(path(p)?),
+                BodyRule::Set { path: p, value } => {
+                    let value = match value {
+                        Value::String(template) => SetValue::Template(Template::parse(template)?),
+                        value => SetValue::Constant(value.clone()),
+                    };
+                    CompiledRule::Set(path(p)?, value)
+                }
+                BodyRule::Each { path: p, rules } => CompiledRule::Each(path(p)?, compile(rules)?),
+            })
+        })
+        .collect()
+}
+
+fn path(path: &str) -> Result<Vec<String>> {
+    let segments: Vec<String> = path.split('.').map(str::to_string).collect();
+    if segments.iter().any(String::is_empty) {
+        bail!("invalid body path {path:?}");
+    }
+    Ok(segments)
+}
+
+fn apply_rules(rules: &[CompiledRule], value: &mut Value, vars: &TemplateVars) {
+    for rule in rules {
+        match rule {
+            CompiledRule::Rename(from, to) => {
+                if let Some(field) = take(value, from) {
+                    insert(value, to, field);
+                }
+            }
+            CompiledRule::Remove(path) => {
+                take(value, path);
+            }
+            CompiledRule::Set(path, SetValue::Constant(constant)) => {
+                insert(value, path, constant.clone());
+            }
+            CompiledRule::Set(path, SetValue::Template(template)) => {
+                insert(value, path, Value::String(template.render(vars)));
+            }
+            CompiledRule::Each(path, rules) => {
+                if let Some(Value::Array(items)) = get_mut(value, path) {
+                    for item in items {
+                        apply_rules(rules, item, vars);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn get_mut<'a>(value: &'a mut Value, path: &[String]) -> Option<&'a mut Value> {
+    path.iter()
+        .try_fold(value, |value, key| value.as_object_mut()?.get_mut(key))
+}
+
+fn take(value: &mut Value, path: &[String]) -> Option<Value> {
+    let (key, parent) = path.split_last()?;
+    get_mut(value, parent)?.as_object_mut()?.remove(key)
+}
+
+/// Inserts `field` at `path`, creating the missing intermediate objects. Nothing is inserted
+/// when a segment of the path is not an object.
+fn insert(value: &mut Value, path: &[String], field: Value) {
+    let Some((key, parent)) = path.split_last() else {
+        return;
+    };
+    let mut current = value;
+    for segment in parent {
+        let Some(object) = current.as_object_mut() else {
+            return;
+        };
+        current = object
+            .entry(segment.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    if let Some(object) = current.as_object_mut() {
+        object.insert(key.clone(), field);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn transform(yaml: &str) -> BodyTransform {
+        let rules: Vec<BodyRule> = serde_yaml::from_str(yaml).expect("Failed to parse rules");
+        BodyTransform::new(&rules).expect("Failed to compile rules")
+    }
+
+    fn apply(transform: &BodyTransform, body: Value) -> Value {
+        let vars = TemplateVars {
+            request_id: "abc".to_string(),
+            ..Default::default()
+        };
+        let body = transform
+            .apply(body.to_string().as_bytes(), &vars)
+            .expect("Failed to transform body");
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_transform_fields() {
+        let transform = transform(
+            r#"
+- { action: rename, from: name, to: full_name }
+- { action: rename, from: address.city, to: city }
+- { action: remove, path: password }
+- { action: set, path: source, value: shadow }
+- { action: set, path: meta.request_id, value: "{request_id}" }
+- { action: set, path: meta.version, value: 2 }
+"#,
+        );
+        let body = apply(
+            &transform,
+            json!({
+                "name": "Alice",
+                "password": "secret",
+                "address": {"city": "Paris", "zip": "75001"},
+            }),
+        );
+        assert_eq!(
+            body,
+            json!({
+                "full_name": "Alice",
+                "address": {"zip": "75001"},
+                "city": "Paris",
+                "source": "shadow",
+                "meta": {"request_id": "abc", "version": 2},
+            })
+        );
+    }
+
+    #[test]
+    fn test_transform_each_array_element() {
+        let transform = transform(
+            r#"
+- action: each
+  path: order.items
+  rules:
+    - { action: rename, from: sku, to: product.id }
+    - { action: remove, path: internal }
+"#,
+        );
+        let body = apply(
+            &transform,
+            json!({"order": {"items": [{"sku": "a", "internal": true}, {"sku": "b"}]}}),
+        );
+        assert_eq!(
+            body,
+            json!({"order": {"items": [{"product": {"id": "a"}}, {"product": {"id": "b"}}]}})
+        );
+    }
+
+    #[test]
+    fn test_missing_fields_and_invalid_body() {
+        let transform = transform("- { action: rename, from: missing, to: other }");
+        assert_eq!(apply(&transform, json!([1, 2])), json!([1, 2]));
+        assert!(
+            transform
+                .apply(b"not json", &TemplateVars::default())
+                .is_err()
+        );
+        assert!(
+            BodyTransform::new(&[BodyRule::Remove {
+                path: "a..b".to_string()
+            }])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_is_json() {
+        let mut headers = HeaderMap::new();
+        assert!(!BodyTransform::is_json(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            "application/json; charset=utf-8".parse().unwrap(),
+        );
+        assert!(BodyTransform::is_json(&headers));
+        headers.insert(CONTENT_TYPE, "application/vnd.api+json".parse().unwrap());
+        assert!(BodyTransform::is_json(&headers));
+        headers.insert(CONTENT_TYPE, "text/plain".parse().unwrap());
+        assert!(!BodyTransform::is_json(&headers));
+    }
+}
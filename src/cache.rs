@@ -0,0 +1,778 @@
+//! HTTP cache of the primary responses to GET and HEAD requests, on top of pingora's cache.
+//!
+//! Responses are cached as their `Cache-Control` (`max-age`, `s-maxage`, `no-store`,
+//! `private`, `no-cache`) or `Expires` header allows, a route `cache.ttl` replaces their
+//! freshness. Responses setting a cookie or with `Vary: *` are never cached, other `Vary`
+//! headers keep one response per value of the request headers. Stale responses are revalidated
+//! with the primary through their `ETag` or `Last-Modified`, and conditional client requests are
+//! answered with `304 Not Modified`.
+//!
+//! The key is the host, the path, the query parameters sorted by name and the values of
+//! `cache.key.headers`. Cache hits do not reach the primary, so they are neither mirrored nor
+//! compared. A successful unsafe request (`POST`, `PUT`, `PATCH`, `DELETE`) removes the cached
+//! responses of its path, whatever their query.
+
+use crate::config::{CacheConfig, CacheKeyConfig, CacheStorageConfig};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::{Bytes, BytesMut};
+use http::header::{HOST, SET_COOKIE, VARY};
+use http::{HeaderName, Method, StatusCode};
+use pingora::ErrorType;
+use pingora::cache::cache_control::{CacheControl, Cacheable, InterpretCacheControl};
+use pingora::cache::eviction::{EvictionManager, simple_lru};
+use pingora::cache::filters::{calculate_serve_stale_sec, resp_cacheable};
+use pingora::cache::key::{CacheHashKey, CompactCacheKey, HashBinary};
+use pingora::cache::lock::{CacheKeyLockImpl, CacheLock};
+use pingora::cache::storage::{HandleHit, HandleMiss, MissFinishType};
+use pingora::cache::trace::SpanHandle;
+use pingora::cache::{
+    CacheKey, CacheMeta, CacheMetaDefaults, CachePhase, HitHandler, HttpCache, MissHandler,
+    NoCacheReason, PurgeType, RespCacheable, Storage, VarianceBuilder,
+};
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Responses without an explicit freshness are not cached.
+const DEFAULTS: CacheMetaDefaults = CacheMetaDefaults::new(|_| None, 0, 0);
+
+/// Statuses cacheable by default (RFC 9110 section 15.1), those cached with a route `ttl`.
+const HEURISTIC_STATUSES: [u16; 10] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414];
+
+/// Cache of the proxy, shared by its requests and the admin api.
+pub struct ResponseCache {
+    storage: &'static CacheStorage,
+    eviction: &'static simple_lru::Manager,
+    lock: &'static CacheKeyLockImpl,
+    key: CacheKeyConfig,
+    headers: Vec<HeaderName>,
+    max_object_size: usize,
+    status_header: Option<HeaderName>,
+}
+
+/// Size of the cache, for the admin api.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub size: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Result<Self> {
+        let headers = config
+            .key
+            .headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("invalid cache key header {name:?}"))
+            })
+            .collect::<Result<_>>()?;
+        let status_header = (!config.status_header.is_empty())
+            .then(|| HeaderName::from_bytes(config.status_header.as_bytes()))
+            .transpose()
+            .with_context(|| format!("invalid cache status header {:?}", config.status_header))?;
+        // pingora 的缓存接口需要 'static 引用，缓存与进程的生命周期相同
+        let eviction: &'static _ = Box::leak(Box::new(simple_lru::Manager::new(config.max_size)));
+        let storage = match &config.storage {
+            CacheStorageConfig::Memory => CacheStorage::memory(),
+            CacheStorageConfig::Disk { path } => CacheStorage::open(path, eviction)?,
+        };
+        let lock: &'static CacheLock = Box::leak(Box::new(CacheLock::new(config.lock_timeout)));
+        Ok(Self {
+            storage: Box::leak(Box::new(storage)),
+            eviction,
+            lock,
+            key: config.key.clone(),
+            headers,
+            max_object_size: config.max_object_size,
+            status_header,
+        })
+    }
+
+    /// Enables the cache of a GET or HEAD request.
+    pub fn enable(&self, cache: &mut HttpCache) {
+        cache.enable(self.storage, Some(self.eviction), None, Some(self.lock));
+        cache.set_max_file_size_bytes(self.max_object_size);
+    }
+
+    /// Header carrying the [`status`] of the response, when configured.
+    pub fn status_header(&self) -> Option<&HeaderName> {
+        self.status_header.as_ref()
+    }
+
+    /// Key of the request: host, path, the kept query parameters sorted by name and the values of
+    /// the key headers.
+    pub fn key(&self, req: &RequestHeader) -> CacheKey {
+        let mut primary = req.uri.path().to_string();
+        let mut params: Vec<(String, String)> =
+            form_urlencoded::parse(req.uri.query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .filter(|(name, _)| {
+                    (self.key.query_params.is_empty() || self.key.query_params.contains(name))
+                        && !self.key.ignore_query_params.contains(name)
+                })
+                .collect();
+        if !params.is_empty() {
+            // 稳定排序，同名参数保持原来的顺序
+            params.sort_by(|a, b| a.0.cmp(&b.0));
+            primary.push('?');
+            primary.push_str(
+                &form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(params)
+                    .finish(),
+            );
+        }
+        for name in &self.headers {
+            let values: Vec<&str> = req
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            primary.push_str(&format!("\n{name}: {}", values.join(", ")));
+        }
+        CacheKey::new(host(req), primary, "")
+    }
+
+    /// Removes the cached responses whose path starts with `path_prefix`, of every host when
+    /// `host` is unset. Returns how many were removed.
+    pub async fn purge(&self, host: Option<&str>, path_prefix: &str) -> usize {
+        self.remove(|entry| {
+            host.is_none_or(|host| entry.host.eq_ignore_ascii_case(host))
+                && entry.path().starts_with(path_prefix)
+        })
+        .await
+    }
+
+    /// Removes the cached responses of the request path after a successful unsafe request.
+    pub async fn invalidate(&self, req: &RequestHeader) -> usize {
+        let host = host(req);
+        let path = req.uri.path();
+        self.remove(|entry| entry.host == host && entry.path() == path)
+            .await
+    }
+
+    async fn remove(&self, matches: impl Fn(&Entry) -> bool) -> usize {
+        let removed = self.storage.remove_matching(matches);
+        for (hash, entry) in &removed {
+            self.eviction.remove(&entry.key);
+            self.storage.delete_files(hash).await;
+        }
+        removed.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.storage.stats()
+    }
+}
+
+/// Whether the request may be answered from the cache.
+pub fn is_cacheable(req: &RequestHeader) -> bool {
+    matches!(req.method, Method::GET | Method::HEAD)
+}
+
+/// Whether a response to the request removes the cached responses of its path.
+pub fn invalidates(req: &RequestHeader, status: StatusCode) -> bool {
+    !matches!(
+        req.method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) && (status.is_success() || status.is_redirection())
+}
+
+/// Cache status of the response, as logged and sent in the status header.
+pub fn status(phase: &CachePhase) -> &'static str {
+    match phase {
+        CachePhase::Disabled(_) => "uncacheable",
+        phase => phase.as_str(),
+    }
+}
+
+/// Whether the response can be cached and until when it is fresh. `ttl` is the route override of
+/// the freshness, it does not make cacheable the responses `Cache-Control` forbids to store.
+pub fn response_cacheable(
+    resp: &ResponseHeader,
+    authorization: bool,
+    ttl: Option<Duration>,
+) -> RespCacheable {
+    let vary_all = resp.headers.get_all(VARY).iter().any(|value| {
+        value
+            .as_bytes()
+            .split(|b| *b == b',')
+            .any(|v| v.trim_ascii() == b"*")
+    });
+    if vary_all || resp.headers.contains_key(SET_COOKIE) {
+        return RespCacheable::Uncacheable(NoCacheReason::OriginNotCache);
+    }
+    let cache_control = CacheControl::from_resp_headers(resp);
+    let Some(ttl) = ttl.filter(|_| HEURISTIC_STATUSES.contains(&resp.status.as_u16())) else {
+        return resp_cacheable(
+            cache_control.as_ref(),
+            resp.clone(),
+            authorization,
+            &DEFAULTS,
+        );
+    };
+    let allowed = match &cache_control {
+        Some(cc) => {
+            cc.is_cacheable() != Cacheable::No
+                && (!authorization || cc.allow_caching_authorized_req())
+        }
+        None => !authorization,
+    };
+    if !allowed {
+        return RespCacheable::Uncacheable(NoCacheReason::OriginNotCache);
+    }
+    let now = SystemTime::now();
+    // no-cache 的响应每次使用前都要向主后端重新验证
+    let fresh_until = if cache_control.as_ref().is_some_and(|cc| cc.no_cache()) {
+        now - Duration::from_secs(1)
+    } else {
+        now + ttl
+    };
+    let (stale_while_revalidate, stale_if_error) =
+        calculate_serve_stale_sec(cache_control.as_ref(), &DEFAULTS);
+    let mut header = resp.clone();
+    if let Some(cc) = &cache_control {
+        cc.strip_private_headers(&mut header);
+    }
+    RespCacheable::Cacheable(CacheMeta::new(
+        fresh_until,
+        now,
+        stale_while_revalidate,
+        stale_if_error,
+        header,
+    ))
+}
+
+/// Variance of the request for the headers listed in the `Vary` of the cached response.
+pub fn variance(meta: &CacheMeta, req: &RequestHeader) -> Option<HashBinary> {
+    let names: Vec<String> = meta
+        .headers()
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let mut builder = VarianceBuilder::new();
+    for name in &names {
+        let value = req
+            .headers
+            .get(name.as_str())
+            .map_or(&b""[..], |value| value.as_bytes());
+        builder.add_value(name, value);
+    }
+    builder.finalize()
+}
+
+fn host(req: &RequestHeader) -> String {
+    req.headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri.host())
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+fn storage_error(
+    context: &'static str,
+    e: impl std::error::Error + Send + Sync + 'static,
+) -> Box<pingora::Error> {
+    pingora::Error::because(ErrorType::InternalError, context, e)
+}
+
+/// Cached responses indexed in memory by the hash of their key. With a directory, the bodies and
+/// the metadata are written to it and read again when the proxy starts.
+pub struct CacheStorage {
+    entries: RwLock<HashMap<String, Entry>>,
+    dir: Option<PathBuf>,
+}
+
+/// Cached response, also the content of its `.json` file on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    key: CompactCacheKey,
+    host: String,
+    primary: String,
+    /// Serialized [`CacheMeta`]: its internal part and the response header, base64 encoded on
+    /// disk.
+    #[serde(with = "base64_bytes")]
+    internal: Vec<u8>,
+    #[serde(with = "base64_bytes")]
+    header: Vec<u8>,
+    size: usize,
+    /// Body of the in-memory storage, read from its `.body` file on disk.
+    #[serde(skip)]
+    body: Option<Bytes>,
+}
+
+impl Entry {
+    /// Path of the request, without the query and the key headers.
+    fn path(&self) -> &str {
+        self.primary.split(['?', '\n']).next().unwrap_or_default()
+    }
+
+    fn meta(&self) -> pingora::Result<CacheMeta> {
+        CacheMeta::deserialize(&self.internal, &self.header)
+    }
+}
+
+impl CacheStorage {
+    pub fn memory() -> Self {
+        Self {
+            entries: RwLock::new(HashMap::new()),
+            dir: None,
+        }
+    }
+
+    /// Opens the disk storage, admitting the responses already in `dir` to the eviction manager.
+    pub fn open(dir: &Path, eviction: &dyn EvictionManager) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create cache directory {}", dir.display()))?;
+        let mut entries: HashMap<String, Entry> = HashMap::new();
+        for file in std::fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(hash) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let entry = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|content| Ok(serde_json::from_slice::<Entry>(&content)?));
+            let meta = entry.as_ref().ok().and_then(|entry| entry.meta().ok());
+            match (entry, meta) {
+                (Ok(entry), Some(meta)) if path.with_extension("body").exists() => {
+                    for evicted in eviction.admit(entry.key.clone(), entry.size, meta.fresh_until())
+                    {
+                        if let Some(evicted) = entries.remove(&evicted.combined()) {
+                            remove_files(dir, &evicted.key.combined());
+                        }
+                    }
+                    entries.insert(hash.to_string(), entry);
+                }
+                // 写入一半的缓存项直接删除
+                _ => {
+                    warn!("removing invalid cache entry {}", path.display());
+                    remove_files(dir, hash);
+                }
+            }
+        }
+        Ok(Self {
+            entries: RwLock::new(entries),
+            dir: Some(dir.to_path_buf()),
+        })
+    }
+
+    fn stats(&self) -> CacheStats {
+        let entries = self.entries.read().unwrap();
+        CacheStats {
+            entries: entries.len(),
+            size: entries.values().map(|entry| entry.size).sum(),
+        }
+    }
+
+    fn remove_matching(&self, matches: impl Fn(&Entry) -> bool) -> Vec<(String, Entry)> {
+        let mut entries = self.entries.write().unwrap();
+        let hashes: Vec<String> = entries
+            .iter()
+            .filter(|(_, entry)| matches(entry))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        hashes
+            .into_iter()
+            .filter_map(|hash| entries.remove(&hash).map(|entry| (hash, entry)))
+            .collect()
+    }
+
+    async fn delete_files(&self, hash: &str) {
+        if let Some(dir) = &self.dir {
+            for extension in ["json", "body"] {
+                let _ = tokio::fs::remove_file(dir.join(format!("{hash}.{extension}"))).await;
+            }
+        }
+    }
+
+    async fn write_entry(&self, hash: &str, entry: &Entry) -> pingora::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec(entry)
+            .map_err(|e| storage_error("failed to serialize cache entry", e))?;
+        write_file(&dir.join(format!("{hash}.json")), &json).await
+    }
+
+    async fn insert(&self, hash: String, mut entry: Entry, body: Bytes) -> pingora::Result<()> {
+        match &self.dir {
+            Some(dir) => {
+                // 先写响应体，元数据文件存在即表示缓存项完整
+                write_file(&dir.join(format!("{hash}.body")), &body).await?;
+                self.write_entry(&hash, &entry).await?;
+            }
+            None => entry.body = Some(body),
+        }
+        self.entries.write().unwrap().insert(hash, entry);
+        Ok(())
+    }
+}
+
+fn remove_files(dir: &Path, hash: &str) {
+    for extension in ["json", "body"] {
+        let _ = std::fs::remove_file(dir.join(format!("{hash}.{extension}")));
+    }
+}
+
+/// Writes through a temporary file, so a crash never leaves a truncated file.
+async fn write_file(path: &Path, content: &[u8]) -> pingora::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, content)
+        .await
+        .map_err(|e| storage_error("failed to write cache file", e))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .map_err(|e| storage_error("failed to write cache file", e))
+}
+
+#[async_trait]
+impl Storage for CacheStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> pingora::Result<Option<(CacheMeta, HitHandler)>> {
+        let hash = key.combined();
+        let Some(entry) = self.entries.read().unwrap().get(&hash).cloned() else {
+            return Ok(None);
+        };
+        let meta = entry.meta()?;
+        let body = match (entry.body, &self.dir) {
+            (Some(body), _) => body,
+            (None, Some(dir)) => match tokio::fs::read(dir.join(format!("{hash}.body"))).await {
+                Ok(body) => Bytes::from(body),
+                Err(e) => {
+                    warn!("failed to read cached body {}: {}", hash, e);
+                    self.entries.write().unwrap().remove(&hash);
+                    self.delete_files(&hash).await;
+                    return Ok(None);
+                }
+            },
+            (None, None) => Bytes::new(),
+        };
+        Ok(Some((meta, Box::new(Hit { body: Some(body) }))))
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> pingora::Result<MissHandler> {
+        let (internal, header) = meta.serialize()?;
+        Ok(Box::new(Miss {
+            storage: self,
+            hash: key.combined(),
+            entry: Entry {
+                key: key.to_compact(),
+                host: key.namespace().to_string(),
+                primary: key.primary_key().to_string(),
+                internal,
+                header,
+                size: 0,
+                body: None,
+            },
+            body: BytesMut::new(),
+        }))
+    }
+
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        _purge_type: PurgeType,
+        _trace: &SpanHandle,
+    ) -> pingora::Result<bool> {
+        let hash = key.combined();
+        let removed = self.entries.write().unwrap().remove(&hash).is_some();
+        if removed {
+            self.delete_files(&hash).await;
+        }
+        Ok(removed)
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> pingora::Result<bool> {
+        let (internal, header) = meta.serialize()?;
+        let hash = key.combined();
+        let entry = {
+            let mut entries = self.entries.write().unwrap();
+            let Some(entry) = entries.get_mut(&hash) else {
+                return Ok(false);
+            };
+            entry.internal = internal;
+            entry.header = header;
+            entry.clone()
+        };
+        self.write_entry(&hash, &entry).await?;
+        Ok(true)
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
+
+struct Hit {
+    body: Option<Bytes>,
+}
+
+#[async_trait]
+impl HandleHit for Hit {
+    async fn read_body(&mut self) -> pingora::Result<Option<Bytes>> {
+        Ok(self.body.take())
+    }
+
+    async fn finish(
+        self: Box<Self>,
+        _storage: &'static (dyn Storage + Sync),
+        _key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> pingora::Result<()> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+struct Miss {
+    storage: &'static CacheStorage,
+    hash: String,
+    entry: Entry,
+    body: BytesMut,
+}
+
+#[async_trait]
+impl HandleMiss for Miss {
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> pingora::Result<()> {
+        self.body.extend_from_slice(&data);
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> pingora::Result<MissFinishType> {
+        let Miss {
+            storage,
+            hash,
+            mut entry,
+            body,
+        } = *self;
+        entry.size = entry.internal.len() + entry.header.len() + body.len();
+        let size = entry.size;
+        storage.insert(hash, entry, body.freeze()).await?;
+        Ok(MissFinishType::Created(size))
+    }
+}
+
+mod base64_bytes {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora::cache::trace::Span;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        req.insert_header("host", "example.com").unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn response(headers: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            resp.append_header(name.to_string(), *value).unwrap();
+        }
+        resp
+    }
+
+    fn cache(storage: CacheStorageConfig) -> ResponseCache {
+        let config = CacheConfig {
+            enabled: true,
+            storage,
+            key: CacheKeyConfig {
+                headers: vec!["accept-language".to_string()],
+                query_params: Vec::new(),
+                ignore_query_params: vec!["utm_source".to_string()],
+            },
+            ..Default::default()
+        };
+        ResponseCache::new(&config).expect("Failed to create cache")
+    }
+
+    async fn fill(cache: &ResponseCache, uri: &str, body: &str) {
+        let key = cache.key(&request(uri, &[]));
+        let RespCacheable::Cacheable(meta) =
+            response_cacheable(&response(&[("cache-control", "max-age=60")]), false, None)
+        else {
+            panic!("response not cacheable");
+        };
+        let trace = Span::inactive().handle();
+        let mut miss = cache
+            .storage
+            .get_miss_handler(&key, &meta, &trace)
+            .await
+            .expect("Failed to get miss handler");
+        miss.write_body(Bytes::from(body.to_string()), true)
+            .await
+            .expect("Failed to write body");
+        miss.finish().await.expect("Failed to finish miss");
+    }
+
+    async fn lookup(cache: &ResponseCache, uri: &str) -> Option<Bytes> {
+        let key = cache.key(&request(uri, &[]));
+        let trace = Span::inactive().handle();
+        let (_, mut hit) = cache.storage.lookup(&key, &trace).await.unwrap()?;
+        hit.read_body().await.unwrap()
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let cache = cache(CacheStorageConfig::Memory);
+        let key = cache.key(&request(
+            "/users?b=2&utm_source=mail&a=1&a=0",
+            &[("accept-language", "fr")],
+        ));
+        assert_eq!(key.namespace(), "example.com");
+        assert_eq!(key.primary_key(), "/users?a=1&a=0&b=2\naccept-language: fr");
+        // 参数顺序和忽略的参数不影响缓存键
+        let same = cache.key(&request("/users?a=1&b=2&a=0", &[("accept-language", "fr")]));
+        assert_eq!(key.combined(), same.combined());
+        let other = cache.key(&request("/users?a=1&b=2&a=0", &[("accept-language", "en")]));
+        assert_ne!(key.combined(), other.combined());
+    }
+
+    #[test]
+    fn test_response_cacheable() {
+        let cacheable = |resp: &ResponseHeader, authorization: bool, ttl: Option<u64>| {
+            match response_cacheable(resp, authorization, ttl.map(Duration::from_secs)) {
+                RespCacheable::Cacheable(meta) => Some(meta.fresh_sec()),
+                RespCacheable::Uncacheable(_) => None,
+            }
+        };
+        assert_eq!(cacheable(&response(&[]), false, None), None);
+        assert_eq!(
+            cacheable(&response(&[("cache-control", "max-age=60")]), false, None),
+            Some(60)
+        );
+        // 路由的 TTL 替换响应的有效期
+        assert_eq!(cacheable(&response(&[]), false, Some(5)), Some(5));
+        assert_eq!(
+            cacheable(
+                &response(&[("cache-control", "max-age=60")]),
+                false,
+                Some(5)
+            ),
+            Some(5)
+        );
+        assert_eq!(
+            cacheable(&response(&[("cache-control", "no-store")]), false, Some(5)),
+            None
+        );
+        assert_eq!(cacheable(&response(&[]), true, Some(5)), None);
+        assert_eq!(
+            cacheable(
+                &response(&[("cache-control", "public, max-age=60")]),
+                true,
+                None
+            ),
+            Some(60)
+        );
+        assert_eq!(
+            cacheable(&response(&[("set-cookie", "session=1")]), false, Some(5)),
+            None
+        );
+        assert_eq!(cacheable(&response(&[("vary", "*")]), false, Some(5)), None);
+        let mut error = response(&[]);
+        error.set_status(500).unwrap();
+        assert_eq!(cacheable(&error, false, Some(5)), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let cache = cache(CacheStorageConfig::Memory);
+        fill(&cache, "/users?page=1", "page 1").await;
+        fill(&cache, "/users?page=2", "page 2").await;
+        fill(&cache, "/orders", "orders").await;
+        assert_eq!(lookup(&cache, "/users?page=1").await.unwrap(), "page 1");
+        assert!(lookup(&cache, "/users?page=3").await.is_none());
+        assert_eq!(cache.stats().entries, 3);
+
+        let mut post = request("/users", &[]);
+        post.set_method(Method::POST);
+        assert!(invalidates(&post, StatusCode::CREATED));
+        assert!(!invalidates(&post, StatusCode::BAD_REQUEST));
+        assert_eq!(cache.invalidate(&post).await, 2);
+        assert!(lookup(&cache, "/users?page=2").await.is_none());
+        assert_eq!(cache.purge(Some("other.com"), "/").await, 0);
+        assert_eq!(cache.purge(None, "/").await, 1);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 0,
+                size: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disk_storage() {
+        let dir = std::env::temp_dir().join(format!("cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = || CacheStorageConfig::Disk { path: dir.clone() };
+
+        let first = cache(storage());
+        fill(&first, "/users/1", "alice").await;
+        fill(&first, "/users/2", "bob").await;
+        assert_eq!(lookup(&first, "/users/1").await.unwrap(), "alice");
+        // 中断的写入留下的文件在启动时被清理
+        std::fs::write(dir.join("partial.json"), "{").unwrap();
+
+        // 重启后从磁盘加载
+        let second = cache(storage());
+        assert_eq!(second.stats().entries, 2);
+        assert!(!dir.join("partial.json").exists());
+        assert_eq!(lookup(&second, "/users/2").await.unwrap(), "bob");
+        assert_eq!(second.purge(Some("example.com"), "/users/1").await, 1);
+        assert!(lookup(&second, "/users/1").await.is_none());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
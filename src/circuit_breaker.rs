@@ -0,0 +1,341 @@
+use crate::config::CircuitBreakerConfig;
+use crate::metrics::{CIRCUIT_STATE, CIRCUIT_TRANSITIONS};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn as_gauge(self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::Open => 1,
+            CircuitState::HalfOpen => 2,
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Permission to send one request to the mirror, handed back with its outcome.
+///
+/// Every state change starts a new generation. Outcomes of requests let through in an earlier
+/// generation are ignored, so a slow request sent while the circuit was closed does not count
+/// as a half-open probe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permit {
+    generation: u64,
+}
+
+/// Circuit breaker guarding a single mirror target.
+///
+/// Closed: every request is mirrored, outcomes are tracked. Open: mirroring is paused until
+/// the cooldown elapses. Half-open: a limited number of probes are let through, the circuit
+/// closes once enough of them succeed and re-opens on the first failure.
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    state: CircuitState,
+    generation: u64,
+    consecutive_failures: u32,
+    // 最近的请求结果，true 表示失败
+    window: VecDeque<bool>,
+    opened_at: Option<Instant>,
+    probes_in_flight: u32,
+    probe_successes: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: impl Into<String>, config: CircuitBreakerConfig) -> Self {
+        let name = name.into();
+        CIRCUIT_STATE
+            .with_label_values(&[&name])
+            .set(CircuitState::Closed.as_gauge());
+        Self {
+            name,
+            config,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                generation: 0,
+                consecutive_failures: 0,
+                window: VecDeque::new(),
+                opened_at: None,
+                probes_in_flight: 0,
+                probe_successes: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Returns a permit when a request may be sent to the mirror right now.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    pub fn record_success(&self, permit: Permit) {
+        self.record(permit, false, Instant::now());
+    }
+
+    pub fn record_failure(&self, permit: Permit) {
+        self.record(permit, true, Instant::now());
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> Option<Permit> {
+        if !self.config.enabled {
+            return Some(Permit { generation: 0 });
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::Open {
+            let cooled_down = inner
+                .opened_at
+                .is_some_and(|opened_at| now.duration_since(opened_at) >= self.config.cooldown);
+            if !cooled_down {
+                return None;
+            }
+            self.transition(&mut inner, CircuitState::HalfOpen, now);
+        }
+        let permit = Permit {
+            generation: inner.generation,
+        };
+        match inner.state {
+            CircuitState::Closed => Some(permit),
+            CircuitState::HalfOpen if inner.probes_in_flight < self.config.half_open_probes => {
+                inner.probes_in_flight += 1;
+                Some(permit)
+            }
+            _ => None,
+        }
+    }
+
+    fn record(&self, permit: Permit, failed: bool, now: Instant) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        // 状态切换之前发出的请求结果不影响当前状态
+        if permit.generation != inner.generation {
+            return;
+        }
+        match inner.state {
+            CircuitState::Closed => {
+                inner.window.push_back(failed);
+                while inner.window.len() > self.config.window.max(1) {
+                    inner.window.pop_front();
+                }
+                if !failed {
+                    inner.consecutive_failures = 0;
+                    return;
+                }
+                inner.consecutive_failures += 1;
+                let failures = inner.window.iter().filter(|failed| **failed).count();
+                let error_rate = failures as f64 / inner.window.len() as f64;
+                if inner.consecutive_failures >= self.config.consecutive_failures
+                    || (inner.window.len() >= self.config.min_requests
+                        && error_rate >= self.config.error_rate)
+                {
+                    self.transition(&mut inner, CircuitState::Open, now);
+                }
+            }
+            CircuitState::HalfOpen => {
+                inner.probes_in_flight = inner.probes_in_flight.saturating_sub(1);
+                if failed {
+                    self.transition(&mut inner, CircuitState::Open, now);
+                    return;
+                }
+                inner.probe_successes += 1;
+                if inner.probe_successes >= self.config.half_open_probes {
+                    self.transition(&mut inner, CircuitState::Closed, now);
+                }
+            }
+            // 熔断期间不会发放许可
+            CircuitState::Open => {}
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: CircuitState, now: Instant) {
+        let from = inner.state;
+        inner.state = to;
+        inner.generation += 1;
+        inner.consecutive_failures = 0;
+        inner.probes_in_flight = 0;
+        inner.probe_successes = 0;
+        match to {
+            CircuitState::Open => {
+                inner.opened_at = Some(now);
+                warn!(
+                    mirror = %self.name,
+                    %from,
+                    %to,
+                    cooldown = ?self.config.cooldown,
+                    "circuit breaker opened, pausing mirroring"
+                );
+            }
+            CircuitState::HalfOpen => {
+                info!(mirror = %self.name, %from, %to, "circuit breaker probing mirror");
+            }
+            CircuitState::Closed => {
+                inner.window.clear();
+                inner.opened_at = None;
+                info!(mirror = %self.name, %from, %to, "circuit breaker closed, resuming mirroring");
+            }
+        }
+        CIRCUIT_STATE
+            .with_label_values(&[&self.name])
+            .set(to.as_gauge());
+        CIRCUIT_TRANSITIONS
+            .with_label_values(&[&self.name, &from.to_string(), &to.to_string()])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn breaker(name: &str) -> CircuitBreaker {
+        CircuitBreaker::new(
+            name,
+            CircuitBreakerConfig {
+                consecutive_failures: 3,
+                error_rate: 0.5,
+                window: 10,
+                min_requests: 4,
+                cooldown: Duration::from_secs(10),
+                half_open_probes: 2,
+                ..Default::default()
+            },
+        )
+    }
+
+    // 在 now 时刻发出请求并立即记录结果
+    fn call(cb: &CircuitBreaker, failed: bool, now: Instant) {
+        let permit = cb.try_acquire_at(now).expect("Failed to acquire permit");
+        cb.record(permit, failed, now);
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let cb = breaker("test_consecutive");
+        let now = Instant::now();
+        call(&cb, true, now);
+        call(&cb, true, now);
+        assert_eq!(cb.state(), CircuitState::Closed);
+        call(&cb, true, now);
+        assert_eq!(cb.state(), CircuitState::Open);
+        assert!(cb.try_acquire_at(now + Duration::from_secs(1)).is_none());
+    }
+
+    #[test]
+    fn test_opens_on_error_rate() {
+        let cb = breaker("test_error_rate");
+        let now = Instant::now();
+        for failed in [false, true, false, true] {
+            call(&cb, failed, now);
+        }
+        assert_eq!(cb.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_half_open_closes_after_successful_probes() {
+        let cb = breaker("test_half_open_close");
+        let now = Instant::now();
+        for _ in 0..3 {
+            call(&cb, true, now);
+        }
+        let later = now + Duration::from_secs(10);
+        let first = cb.try_acquire_at(later).expect("Failed to acquire probe");
+        assert_eq!(cb.state(), CircuitState::HalfOpen);
+        let second = cb.try_acquire_at(later).expect("Failed to acquire probe");
+        // 探测请求数量达到上限
+        assert!(cb.try_acquire_at(later).is_none());
+        cb.record(first, false, later);
+        cb.record(second, false, later);
+        assert_eq!(cb.state(), CircuitState::Closed);
+        assert!(cb.try_acquire_at(later).is_some());
+    }
+
+    #[test]
+    fn test_half_open_reopens_on_failure() {
+        let cb = breaker("test_half_open_reopen");
+        let now = Instant::now();
+        for _ in 0..3 {
+            call(&cb, true, now);
+        }
+        let later = now + Duration::from_secs(11);
+        let probe = cb.try_acquire_at(later).expect("Failed to acquire probe");
+        cb.record(probe, true, later);
+        assert_eq!(cb.state(), CircuitState::Open);
+        assert!(cb.try_acquire_at(later + Duration::from_secs(1)).is_none());
+        assert!(cb.try_acquire_at(later + Duration::from_secs(10)).is_some());
+    }
+
+    #[test]
+    fn test_stale_results_are_not_probes() {
+        let cb = breaker("test_stale_results");
+        let now = Instant::now();
+        // 熔断之前发出、半开期间才完成的请求
+        let slow = cb.try_acquire_at(now).expect("Failed to acquire permit");
+        let slow_failure = cb.try_acquire_at(now).expect("Failed to acquire permit");
+        for _ in 0..3 {
+            call(&cb, true, now);
+        }
+        assert_eq!(cb.state(), CircuitState::Open);
+
+        let later = now + Duration::from_secs(10);
+        let first = cb.try_acquire_at(later).expect("Failed to acquire probe");
+        assert_eq!(cb.state(), CircuitState::HalfOpen);
+        cb.record(slow, false, later);
+        cb.record(slow_failure, true, later);
+        assert_eq!(cb.state(), CircuitState::HalfOpen);
+        // 过期的结果不释放探测名额
+        let second = cb.try_acquire_at(later).expect("Failed to acquire probe");
+        assert!(cb.try_acquire_at(later).is_none());
+
+        cb.record(first, false, later);
+        assert_eq!(cb.state(), CircuitState::HalfOpen);
+        cb.record(second, false, later);
+        assert_eq!(cb.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_disabled_breaker_never_opens() {
+        let cb = CircuitBreaker::new(
+            "test_disabled",
+            CircuitBreakerConfig {
+                enabled: false,
+                ..Default::default()
+            },
+        );
+        for _ in 0..100 {
+            let permit = cb.try_acquire().expect("Failed to acquire permit");
+            cb.record_failure(permit);
+        }
+        assert!(cb.try_acquire().is_some());
+        assert_eq!(cb.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,695 @@
+//! Command line interface of the `simple_proxy` binary.
+
+use crate::DualWriteProxy;
+use crate::admin::AdminService;
+use crate::backlog::{Backlog, DrainTarget};
+use crate::config::{HeaderRule, ProxyConfig, SkippedWrites};
+use crate::mirror::MirrorClient;
+use crate::mismatch::MismatchStore;
+use crate::proxy_protocol::ProxyProtocolRelay;
+use crate::recording::Exchange;
+use crate::replay::{ReplayOptions, Replayer};
+use crate::shutdown::DrainService;
+use crate::telemetry::TracerService;
+use crate::validate::{self, Severity};
+use anyhow::{Context, Result, bail};
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use pingora::apps::HttpServerOptions;
+use pingora::listeners::tls::TlsSettings;
+use pingora::prelude::Server;
+use pingora::proxy::http_proxy_service;
+use pingora::server::configuration::{Opt, ServerConf};
+use pingora::services::{background::background_service, listening::Service};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Dual-write HTTP proxy: serves the primary response and mirrors requests to secondaries.
+#[derive(Debug, Parser)]
+#[clap(name = "simple_proxy", version)]
+pub struct Cli {
+    #[clap(flatten)]
+    pub log: LogArgs,
+    /// `serve` with the default configuration when omitted.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Args)]
+pub struct LogArgs {
+    /// Log filter such as `debug` or `simple_proxy=debug,pingora=warn`, `RUST_LOG` or `info`
+    /// when unset.
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+    #[clap(long, global = true, arg_enum, default_value = "text")]
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the proxy.
+    Serve(ServeArgs),
+    /// Checks a configuration file and prints a report, exits with an error when it has errors.
+    ValidateConfig(ValidateArgs),
+    /// Replays recorded traffic against one or two targets and compares the responses.
+    Replay(ReplayArgs),
+    /// Exports and summarizes the primary/mirror response mismatches.
+    Diff(DiffArgs),
+    /// Inspects or drains the backlogs of mirror writes skipped while a circuit was open or
+    /// still pending on shutdown.
+    Queue(QueueArgs),
+    /// Prints the version.
+    Version,
+}
+
+#[derive(Debug, Args)]
+pub struct ValidateArgs {
+    #[clap(long)]
+    pub config: PathBuf,
+    /// Also connects to every primary backend and mirror.
+    #[clap(long)]
+    pub probe: bool,
+    #[clap(long, default_value = "2s", parse(try_from_str = humantime::parse_duration))]
+    pub probe_timeout: Duration,
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// YAML configuration, the built-in defaults when unset.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// Overrides `listen`.
+    #[clap(long)]
+    pub listen: Option<String>,
+    /// Overrides `metrics_listen`.
+    #[clap(long)]
+    pub metrics_listen: Option<String>,
+    /// Overrides `admin.listen` and enables the admin listener.
+    #[clap(long)]
+    pub admin_listen: Option<String>,
+    /// Runs in the background.
+    #[clap(short, long)]
+    pub daemon: bool,
+    /// Takes over the listening sockets of a running instance (graceful upgrade).
+    #[clap(short, long)]
+    pub upgrade: bool,
+    /// Checks the configuration and the server setup, then exits.
+    #[clap(short, long)]
+    pub test: bool,
+    /// Pingora server configuration (threads, grace period, ...).
+    #[clap(long)]
+    pub server_conf: Option<PathBuf>,
+    /// Overrides the pingora `pid_file`.
+    #[clap(long)]
+    pub pid_file: Option<String>,
+    /// Overrides the pingora `upgrade_sock`, used to hand the sockets over on upgrade.
+    #[clap(long)]
+    pub upgrade_sock: Option<String>,
+    /// Overrides the pingora `error_log`, where a daemon writes its logs.
+    #[clap(long)]
+    pub error_log: Option<String>,
+    /// Overrides the pingora worker `threads` per service.
+    #[clap(long)]
+    pub threads: Option<usize>,
+}
+
+impl ServeArgs {
+    pub fn load_config(&self) -> Result<ProxyConfig> {
+        let mut config = match &self.config {
+            Some(path) => ProxyConfig::load(path)?,
+            None => ProxyConfig::default(),
+        };
+        if let Some(listen) = &self.listen {
+            config.listen = listen.clone();
+        }
+        if let Some(listen) = &self.metrics_listen {
+            config.metrics_listen = Some(listen.clone());
+        }
+        if let Some(listen) = &self.admin_listen {
+            config.admin.enabled = true;
+            config.admin.listen = listen.clone();
+        }
+        // 中继自己绑定 listen，这个套接字不在 pingora 的升级交接之内
+        if self.upgrade && config.proxy_protocol.enabled {
+            bail!(
+                "--upgrade cannot be used with proxy_protocol.enabled, the PROXY protocol listener is not handed over"
+            );
+        }
+        Ok(config)
+    }
+
+    fn server(&self, config: &ProxyConfig) -> Result<Server> {
+        let mut conf = match &self.server_conf {
+            Some(path) => ServerConf::load_from_yaml(path.display().to_string())?,
+            None => ServerConf::new().context("invalid default server configuration")?,
+        };
+        // pingora 在宽限期结束后关闭运行时，宽限期需覆盖 mirror 写入的排空时间
+        let drain_timeout = config.shutdown.drain_timeout.as_secs_f64().ceil() as u64;
+        match conf.grace_period_seconds {
+            Some(grace_period) if grace_period <= drain_timeout => warn!(
+                "grace_period_seconds {} is shorter than the drain timeout, pending mirror writes may be lost",
+                grace_period
+            ),
+            Some(_) => {}
+            None => conf.grace_period_seconds = Some(drain_timeout + 1),
+        }
+        if let Some(pid_file) = &self.pid_file {
+            conf.pid_file = pid_file.clone();
+        }
+        if let Some(upgrade_sock) = &self.upgrade_sock {
+            conf.upgrade_sock = upgrade_sock.clone();
+        }
+        if let Some(error_log) = &self.error_log {
+            conf.error_log = Some(error_log.clone());
+        }
+        if let Some(threads) = self.threads {
+            conf.threads = threads;
+        }
+        let opt = Opt {
+            upgrade: self.upgrade,
+            daemon: self.daemon,
+            nocapture: false,
+            test: self.test,
+            conf: None,
+        };
+        Ok(Server::new_with_opt_and_conf(opt, conf))
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// JSONL recording written by the proxy.
+    pub recording: PathBuf,
+    /// Base url of a target; give it twice to compare two targets with each other instead of
+    /// with the recorded responses.
+    #[clap(long = "target", required = true, max_occurrences = 2)]
+    pub targets: Vec<String>,
+    /// Requests in flight at most.
+    #[clap(long, default_value = "10")]
+    pub concurrency: usize,
+    /// Requests started per second at most.
+    #[clap(long)]
+    pub rate: Option<f64>,
+    /// Keep the original spacing of the requests, divided by this factor.
+    #[clap(long)]
+    pub speed: Option<f64>,
+    /// Header set on every request.
+    #[clap(long = "set-header", value_name = "NAME:VALUE")]
+    pub set_headers: Vec<String>,
+    /// Header removed from every request.
+    #[clap(long = "remove-header", value_name = "NAME")]
+    pub remove_headers: Vec<String>,
+    /// Host header of the replayed requests, the recorded one by default.
+    #[clap(long)]
+    pub host: Option<String>,
+    /// Recorded upstream to replay, `primary` or a mirror name.
+    #[clap(long, default_value = "primary")]
+    pub upstream: String,
+    #[clap(long, default_value = "10s", parse(try_from_str = humantime::parse_duration))]
+    pub timeout: Duration,
+    /// Print the report as JSON.
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// Configuration whose `comparison.store` is read.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// SQLite database written by the proxy, overrides the configuration.
+    #[clap(long)]
+    pub store: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: DiffCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DiffCommand {
+    /// Writes all stored mismatches.
+    Export {
+        #[clap(long, arg_enum, default_value = "json")]
+        format: ExportFormat,
+        /// Output file, stdout by default.
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Mismatch rate per route and mirror, and the fields differing most often.
+    Summary {
+        /// Number of fields listed.
+        #[clap(long, default_value = "10")]
+        top: usize,
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Args)]
+pub struct QueueArgs {
+    /// Configuration listing the mirrors and their backlog files.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
+    /// Backlog file, instead of the ones of the configuration.
+    #[clap(long)]
+    pub backlog: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: QueueCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum QueueCommand {
+    /// Counts the queued writes per mirror.
+    Inspect {
+        #[clap(long)]
+        json: bool,
+    },
+    /// Sends the queued writes to their mirror, as they were stored, and removes the delivered
+    /// ones. Needs `--config` for the mirror urls.
+    Drain {
+        /// Only drains these mirrors.
+        #[clap(long = "mirror")]
+        mirrors: Vec<String>,
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+impl Cli {
+    pub fn run(self) -> Result<()> {
+        init_logging(&self.log)?;
+        match self.command.unwrap_or(Command::Serve(ServeArgs::default())) {
+            Command::Serve(args) => serve(args),
+            Command::ValidateConfig(args) => runtime()?.block_on(validate_config(args)),
+            Command::Replay(args) => runtime()?.block_on(replay(args)),
+            Command::Diff(args) => diff(args),
+            Command::Queue(args) => runtime()?.block_on(queue(args)),
+            Command::Version => {
+                println!("simple_proxy {}", env!("CARGO_PKG_VERSION"));
+                Ok(())
+            }
+        }
+    }
+}
+
+fn init_logging(args: &LogArgs) -> Result<()> {
+    let filter = match &args.log_level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match args.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+    Ok(())
+}
+
+/// Runtime of the subcommands, `serve` runs on the runtimes of pingora.
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?)
+}
+
+fn serve(args: ServeArgs) -> Result<()> {
+    let config = args.load_config()?;
+    let mut my_server = args.server(&config)?;
+    my_server.bootstrap();
+    let proxy_addr = config.listen.as_str();
+    let proxy = DualWriteProxy::new(&config)?;
+    let admin = AdminService::new(&config, &proxy);
+    let drain = DrainService(proxy.drain().clone());
+    // 所有线程由后台服务在 run_forever（以及 daemon 的 fork）之后启动
+    let threads = proxy.threads();
+    let tracer = TracerService::new(&config.tracing, proxy.drain().clone())?;
+    let relay = proxy
+        .proxied_clients()
+        .map(|clients| {
+            ProxyProtocolRelay::bind(proxy_addr, &config.proxy_protocol, clients.clone())
+        })
+        .transpose()?;
+    let mut lb = http_proxy_service(&my_server.configuration, proxy);
+    if config.h2c {
+        let mut options = HttpServerOptions::default();
+        options.h2c = true;
+        lb.app_logic_mut().expect("proxy app").server_options = Some(options);
+    }
+    if relay.is_some() {
+        // PROXY 协议由中继在 listen 上解析，代理只监听内部地址
+        lb.add_tcp(&config.proxy_protocol.internal_listen);
+        info!(
+            "DualWriteProxy listening on {} (PROXY protocol relay)",
+            config.proxy_protocol.internal_listen
+        );
+    } else {
+        lb.add_tcp(proxy_addr);
+        info!("DualWriteProxy listening on {}", proxy_addr);
+    }
+    if let Some(tls) = &config.tls {
+        let mut settings = TlsSettings::intermediate(
+            &tls.cert.display().to_string(),
+            &tls.key.display().to_string(),
+        )?;
+        if tls.http2 {
+            settings.enable_h2();
+        }
+        lb.add_tls_with_settings(&tls.listen, None, settings);
+        info!("DualWriteProxy listening on {} (TLS)", tls.listen);
+    }
+    my_server.add_service(lb);
+    if let Some(metrics_addr) = &config.metrics_listen {
+        let mut prometheus = Service::prometheus_http_service();
+        prometheus.add_tcp(metrics_addr);
+        info!("Prometheus metrics listening on {}", metrics_addr);
+        my_server.add_service(prometheus);
+    }
+    if let Some(admin) = admin {
+        my_server.add_service(background_service("admin", admin));
+    }
+    if let Some(relay) = relay {
+        my_server.add_service(background_service("proxy protocol", relay));
+    }
+    my_server.add_service(background_service("proxy threads", threads));
+    my_server.add_service(background_service("shutdown drain", drain));
+    if let Some(tracer) = tracer {
+        my_server.add_service(background_service("tracer", tracer));
+    }
+    my_server.run_forever();
+}
+
+async fn validate_config(args: ValidateArgs) -> Result<()> {
+    let config = ProxyConfig::load(&args.config)?;
+    let mut report = validate::validate(&config);
+    if args.probe {
+        validate::probe(&config, args.probe_timeout, &mut report).await;
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+    if report.has_errors() {
+        bail!(
+            "{} has {} errors",
+            args.config.display(),
+            report.count(Severity::Error)
+        );
+    }
+    Ok(())
+}
+
+async fn replay(args: ReplayArgs) -> Result<()> {
+    let mut headers = Vec::new();
+    for header in &args.set_headers {
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("invalid header {header:?}, expected NAME:VALUE"))?;
+        headers.push(HeaderRule::Set {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        });
+    }
+    headers.extend(
+        args.remove_headers
+            .iter()
+            .map(|name| HeaderRule::Remove { name: name.clone() }),
+    );
+
+    let exchanges = Exchange::read_all(&args.recording)?;
+    let replayer = Arc::new(Replayer::new(ReplayOptions {
+        targets: args.targets,
+        concurrency: args.concurrency,
+        rate: args.rate,
+        speed: args.speed,
+        headers,
+        host: args.host,
+        upstream: args.upstream,
+        timeout: args.timeout,
+    })?);
+    let report = replayer.run(exchanges).await;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+    if report.has_mismatches() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn diff(args: DiffArgs) -> Result<()> {
+    let config = match &args.config {
+        Some(path) => ProxyConfig::load(path)?,
+        None => ProxyConfig::default(),
+    };
+    let path = args.store.unwrap_or(config.comparison.store);
+    if !path.exists() {
+        bail!("mismatch store {} does not exist", path.display());
+    }
+    // 只读取，不会触发写入时的清理
+    let store = MismatchStore::open(&path, config.comparison.retention)?;
+    match args.command {
+        DiffCommand::Export { format, output } => {
+            let out: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            match format {
+                ExportFormat::Json => store.export_json(out)?,
+                ExportFormat::Csv => store.export_csv(out)?,
+            }
+        }
+        DiffCommand::Summary { top, json } => {
+            let summary = store.summary(top)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                print!("{summary}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn queue(args: QueueArgs) -> Result<()> {
+    let config = args.config.as_ref().map(ProxyConfig::load).transpose()?;
+    let mut paths = Vec::new();
+    match (&args.backlog, &config) {
+        (Some(path), _) => paths.push(path.clone()),
+        (None, Some(config)) => {
+            for mirror in &config.mirrors {
+                if let SkippedWrites::Backlog { path } = &mirror.circuit_breaker.on_open
+                    && !paths.contains(path)
+                {
+                    paths.push(path.clone());
+                }
+            }
+            // 关闭时未发送完的写入
+            if !paths.contains(&config.shutdown.backlog) {
+                paths.push(config.shutdown.backlog.clone());
+            }
+        }
+        (None, None) => bail!("pass --backlog or a --config"),
+    }
+
+    match args.command {
+        QueueCommand::Inspect { json } => {
+            let mut backlogs = Vec::new();
+            for path in paths {
+                let stats = Backlog::stats(&Backlog::read_all(&path)?);
+                backlogs.push(serde_json::json!({ "path": path, "mirrors": stats }));
+                if !json {
+                    println!("{}", path.display());
+                    if stats.is_empty() {
+                        println!("  empty");
+                    }
+                    for stats in stats {
+                        println!(
+                            "  {}: {} writes, {} .. {}",
+                            stats.mirror, stats.entries, stats.oldest, stats.newest
+                        );
+                    }
+                }
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&backlogs)?);
+            }
+        }
+        QueueCommand::Drain { mirrors, json } => {
+            let Some(config) = config else {
+                bail!("drain needs --config for the mirror urls");
+            };
+            let mut targets = HashMap::new();
+            for mirror in &config.mirrors {
+                if mirrors.is_empty() || mirrors.contains(&mirror.name) {
+                    targets.insert(
+                        mirror.name.clone(),
+                        DrainTarget {
+                            url: mirror.url.clone(),
+                            client: MirrorClient::new(mirror.timeout, mirror.http_version)?,
+                        },
+                    );
+                }
+            }
+            if let Some(unknown) = mirrors.iter().find(|name| !targets.contains_key(*name)) {
+                bail!("unknown mirror {unknown}");
+            }
+            let mut failed = false;
+            for path in paths {
+                let report = Backlog::drain(&path, &targets).await?;
+                failed |= !report.errors.is_empty();
+                if json {
+                    println!("{}", serde_json::json!({ "path": path, "report": report }));
+                    continue;
+                }
+                println!("{}", path.display());
+                for (mirror, sent) in &report.sent {
+                    println!("  {mirror}: {sent} sent");
+                }
+                for (mirror, error) in &report.errors {
+                    println!("  {mirror}: stopped, {error}");
+                }
+                println!("  {} left", report.remaining);
+            }
+            if failed {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_serve() {
+        let cli = Cli::try_parse_from([
+            "simple_proxy",
+            "--log-format",
+            "json",
+            "serve",
+            "--config",
+            "fixtures/proxy.yml",
+            "--listen",
+            "127.0.0.1:18080",
+            "--admin-listen",
+            "127.0.0.1:19000",
+            "-d",
+            "--pid-file",
+            "/tmp/simple_proxy.pid",
+        ])
+        .expect("Failed to parse arguments");
+        assert_eq!(cli.log.log_format, LogFormat::Json);
+        let Some(Command::Serve(args)) = cli.command else {
+            panic!("expected serve");
+        };
+        assert!(args.daemon && !args.upgrade);
+        assert_eq!(args.pid_file.as_deref(), Some("/tmp/simple_proxy.pid"));
+
+        let config = args.load_config().expect("Failed to load config");
+        assert_eq!(config.listen, "127.0.0.1:18080");
+        assert!(config.admin.enabled);
+        assert_eq!(config.admin.listen, "127.0.0.1:19000");
+        assert_eq!(config.metrics_listen.as_deref(), Some("127.0.0.1:6192"));
+    }
+
+    #[test]
+    fn test_upgrade_with_proxy_protocol() {
+        let path = std::env::temp_dir().join("simple_proxy_test_upgrade.yml");
+        std::fs::write(&path, "proxy_protocol:\n  enabled: true\n")
+            .expect("Failed to write config");
+        let mut args = ServeArgs {
+            config: Some(path.clone()),
+            ..Default::default()
+        };
+        assert!(args.load_config().is_ok());
+        args.upgrade = true;
+        let err = args.load_config().unwrap_err();
+        assert!(err.to_string().contains("--upgrade cannot be used"));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        let cli = Cli::try_parse_from([
+            "simple_proxy",
+            "queue",
+            "--config",
+            "fixtures/proxy.yml",
+            "drain",
+            "--mirror",
+            "secondary",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Queue(QueueArgs {
+                command: QueueCommand::Drain { ref mirrors, json: false },
+                ..
+            })) if mirrors == &["secondary"]
+        ));
+
+        let cli = Cli::try_parse_from(["simple_proxy", "diff", "summary", "--top", "3"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Diff(DiffArgs {
+                command: DiffCommand::Summary {
+                    top: 3,
+                    json: false
+                },
+                ..
+            }))
+        ));
+
+        let cli = Cli::try_parse_from([
+            "simple_proxy",
+            "validate-config",
+            "--config",
+            "fixtures/proxy.yml",
+            "--probe",
+            "--probe-timeout",
+            "500ms",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::ValidateConfig(ValidateArgs {
+                probe: true,
+                probe_timeout,
+                json: false,
+                ..
+            })) if probe_timeout == Duration::from_millis(500)
+        ));
+
+        assert!(
+            Cli::try_parse_from(["simple_proxy"])
+                .unwrap()
+                .command
+                .is_none()
+        );
+        // replay 至少需要一个目标
+        assert!(Cli::try_parse_from(["simple_proxy", "replay", "rec.jsonl"]).is_err());
+    }
+}
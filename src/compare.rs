@@ -0,0 +1,633 @@
+//! Comparison of the primary and mirror responses.
+//!
+//! The primary response is captured by the proxy and published on a watch channel that the
+//! mirrored copies of the request carry. Each mirror task waits for it once its own response
+//! arrived, diffs both responses and stores the mismatches in a [`MismatchStore`].
+
+use crate::compression;
+use crate::config::ComparisonConfig;
+use crate::grpc::{self, GrpcDescriptors};
+use crate::metrics::MIRROR_COMPARISONS;
+use crate::mirror::MirrorRequest;
+use crate::mismatch::{MismatchRecord, MismatchStore};
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bytes::{Bytes, BytesMut};
+use chrono::Utc;
+use http::header::CONTENT_ENCODING;
+use http::{HeaderMap, HeaderName};
+use pingora::http::ResponseHeader;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+/// Primary response as seen by the mirror tasks, `None` until it is complete.
+pub type PrimaryResponse = watch::Receiver<Option<Arc<CapturedResponse>>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Set when the body was larger than `max_body_size` and was not kept.
+    pub truncated: bool,
+    /// Trailers of gRPC responses.
+    pub trailers: HeaderMap,
+}
+
+impl CapturedResponse {
+    pub fn new(status: u16, headers: HeaderMap, body: Bytes, max_body_size: usize) -> Self {
+        let truncated = body.len() > max_body_size;
+        Self {
+            status,
+            headers,
+            body: if truncated { Bytes::new() } else { body },
+            truncated,
+            trailers: HeaderMap::new(),
+        }
+    }
+}
+
+/// Primary response being read.
+pub struct ResponseCapture {
+    header: Option<(u16, HeaderMap)>,
+    body: BytesMut,
+    trailers: HeaderMap,
+    size: usize,
+    max_body_size: usize,
+    sender: watch::Sender<Option<Arc<CapturedResponse>>>,
+}
+
+impl ResponseCapture {
+    pub fn set_header(&mut self, header: &ResponseHeader) {
+        self.header = Some((header.status.as_u16(), header.headers.clone()));
+    }
+
+    /// Whether the response is complete only once its trailers are read.
+    pub fn expects_trailers(&self) -> bool {
+        self.header
+            .as_ref()
+            .is_some_and(|(_, headers)| grpc::is_grpc(headers))
+    }
+
+    pub fn set_trailers(&mut self, trailers: &HeaderMap) {
+        self.trailers = trailers.clone();
+    }
+
+    pub fn push_body(&mut self, chunk: &[u8]) {
+        self.size += chunk.len();
+        if self.size <= self.max_body_size {
+            self.body.extend_from_slice(chunk);
+        }
+    }
+
+    /// Publishes the response to the mirror tasks. Without a response header nothing is
+    /// published and the mirrors give up when the capture is dropped.
+    pub fn finish(self) {
+        if let Some((status, headers)) = self.header {
+            let response = CapturedResponse {
+                status,
+                headers,
+                body: if self.size <= self.max_body_size {
+                    self.body.freeze()
+                } else {
+                    Bytes::new()
+                },
+                truncated: self.size > self.max_body_size,
+                trailers: self.trailers,
+            };
+            self.sender.send_replace(Some(Arc::new(response)));
+        }
+    }
+}
+
+/// A difference between the primary and a mirror response. Paths are `status`, `grpc-status`,
+/// `headers.<name>`, `body` for non JSON bodies and `body.<field>` for JSON bodies and decoded
+/// gRPC messages, with array indices in brackets (`body.items[0].id`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FieldDiff {
+    pub path: String,
+    pub primary: Value,
+    pub mirror: Value,
+}
+
+impl FieldDiff {
+    /// Path without the array indices, used to aggregate the differences of a field.
+    pub fn field(&self) -> String {
+        let mut field = String::with_capacity(self.path.len());
+        let mut in_index = false;
+        for c in self.path.chars() {
+            match c {
+                '[' => {
+                    in_index = true;
+                    field.push_str("[]");
+                }
+                ']' => in_index = false,
+                c if !in_index => field.push(c),
+                _ => {}
+            }
+        }
+        field
+    }
+}
+
+/// Request summary stored with a mismatch.
+#[derive(Debug, Clone)]
+pub struct RequestSummary {
+    pub request_id: String,
+    pub route: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub body: Bytes,
+}
+
+impl RequestSummary {
+    pub fn new(request: &MirrorRequest) -> Self {
+        Self {
+            request_id: request.request_id.clone(),
+            route: request.route.clone(),
+            method: request.method.to_string(),
+            path: request.path_and_query.clone(),
+            body: request.body.clone(),
+        }
+    }
+}
+
+pub struct Comparator {
+    max_body_size: usize,
+    timeout: Duration,
+    ignore_fields: Vec<String>,
+    compare_headers: Vec<HeaderName>,
+    routes: Vec<String>,
+    store: Arc<MismatchStore>,
+    grpc: Arc<GrpcDescriptors>,
+}
+
+impl Comparator {
+    pub fn new(config: &ComparisonConfig) -> Result<Self> {
+        let compare_headers = config
+            .compare_headers
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            max_body_size: config.max_body_size,
+            timeout: config.timeout,
+            ignore_fields: config.ignore_fields.clone(),
+            compare_headers,
+            routes: config.routes.clone(),
+            store: Arc::new(MismatchStore::open(
+                &config.store,
+                config.retention.clone(),
+            )?),
+            grpc: Arc::new(GrpcDescriptors::default()),
+        })
+    }
+
+    /// Decodes the gRPC messages with the descriptor sets.
+    pub fn with_grpc(mut self, grpc: Arc<GrpcDescriptors>) -> Self {
+        self.grpc = grpc;
+        self
+    }
+
+    pub fn store(&self) -> &Arc<MismatchStore> {
+        &self.store
+    }
+
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// Whether the responses of requests of the route are compared.
+    pub fn compares(&self, route: Option<&str>) -> bool {
+        self.routes.is_empty() || route.is_some_and(|route| self.routes.iter().any(|r| r == route))
+    }
+
+    pub fn capture(&self) -> (ResponseCapture, PrimaryResponse) {
+        let (sender, receiver) = watch::channel(None);
+        let capture = ResponseCapture {
+            header: None,
+            body: BytesMut::new(),
+            trailers: HeaderMap::new(),
+            size: 0,
+            max_body_size: self.max_body_size,
+            sender,
+        };
+        (capture, receiver)
+    }
+
+    /// `path` is the request path, it selects the message type of gRPC responses.
+    pub fn diff(
+        &self,
+        path: &str,
+        primary: &CapturedResponse,
+        mirror: &CapturedResponse,
+    ) -> Vec<FieldDiff> {
+        let mut diffs = Vec::new();
+        if primary.status != mirror.status {
+            diffs.push(FieldDiff {
+                path: "status".to_string(),
+                primary: primary.status.into(),
+                mirror: mirror.status.into(),
+            });
+        }
+        let is_grpc = grpc::is_grpc(&primary.headers) || grpc::is_grpc(&mirror.headers);
+        if is_grpc {
+            let status = |response: &CapturedResponse| {
+                grpc::status(&response.headers, &response.trailers).map_or(Value::Null, Value::from)
+            };
+            let (primary, mirror) = (status(primary), status(mirror));
+            if primary != mirror {
+                diffs.push(FieldDiff {
+                    path: "grpc-status".to_string(),
+                    primary,
+                    mirror,
+                });
+            }
+        }
+        for name in &self.compare_headers {
+            let value = |headers: &HeaderMap| {
+                headers.get(name).map_or(Value::Null, |value| {
+                    String::from_utf8_lossy(value.as_bytes()).into()
+                })
+            };
+            let (primary, mirror) = (value(&primary.headers), value(&mirror.headers));
+            if primary != mirror {
+                diffs.push(FieldDiff {
+                    path: format!("headers.{name}"),
+                    primary,
+                    mirror,
+                });
+            }
+        }
+        if primary.truncated || mirror.truncated {
+            return diffs;
+        }
+        let (Some(primary_body), Some(mirror_body)) = (self.body(primary), self.body(mirror))
+        else {
+            return diffs;
+        };
+        if primary_body == mirror_body {
+            return diffs;
+        }
+        if is_grpc {
+            match (
+                self.grpc.compared_response(path, &primary_body),
+                self.grpc.compared_response(path, &mirror_body),
+            ) {
+                (Some(primary), Some(mirror)) => {
+                    self.diff_json("body", "", &primary, &mirror, &mut diffs)
+                }
+                // 没有描述符的消息按字节比较
+                _ => diffs.push(FieldDiff {
+                    path: "body".to_string(),
+                    primary: BASE64.encode(&primary_body).into(),
+                    mirror: BASE64.encode(&mirror_body).into(),
+                }),
+            }
+            return diffs;
+        }
+        match (
+            serde_json::from_slice::<Value>(&primary_body),
+            serde_json::from_slice::<Value>(&mirror_body),
+        ) {
+            (Ok(primary), Ok(mirror)) => self.diff_json("body", "", &primary, &mirror, &mut diffs),
+            _ => diffs.push(FieldDiff {
+                path: "body".to_string(),
+                primary: String::from_utf8_lossy(&primary_body).into(),
+                mirror: String::from_utf8_lossy(&mirror_body).into(),
+            }),
+        }
+        diffs
+    }
+
+    /// Body without its `Content-Encoding`, `None` when it cannot be decoded within
+    /// `max_body_size`.
+    fn body(&self, response: &CapturedResponse) -> Option<Bytes> {
+        if !compression::is_encoded(&response.headers) {
+            return Some(response.body.clone());
+        }
+        let encoding = response.headers.get(CONTENT_ENCODING)?.to_str().ok()?;
+        compression::decode(encoding, &response.body, self.max_body_size)
+            .inspect_err(|e| debug!("body not compared, failed to decode: {:#}", e))
+            .ok()
+    }
+
+    /// `field` is the dot separated path of object keys used to match `ignore_fields`.
+    fn diff_json(
+        &self,
+        path: &str,
+        field: &str,
+        primary: &Value,
+        mirror: &Value,
+        diffs: &mut Vec<FieldDiff>,
+    ) {
+        match (primary, mirror) {
+            (Value::Object(primary), Value::Object(mirror)) => {
+                let keys = primary
+                    .keys()
+                    .chain(mirror.keys().filter(|key| !primary.contains_key(*key)));
+                for key in keys {
+                    let field = if field.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{field}.{key}")
+                    };
+                    if self.ignored(key, &field) {
+                        continue;
+                    }
+                    self.diff_json(
+                        &format!("{path}.{key}"),
+                        &field,
+                        primary.get(key).unwrap_or(&Value::Null),
+                        mirror.get(key).unwrap_or(&Value::Null),
+                        diffs,
+                    );
+                }
+            }
+            (Value::Array(primary), Value::Array(mirror)) => {
+                for index in 0..primary.len().max(mirror.len()) {
+                    self.diff_json(
+                        &format!("{path}[{index}]"),
+                        field,
+                        primary.get(index).unwrap_or(&Value::Null),
+                        mirror.get(index).unwrap_or(&Value::Null),
+                        diffs,
+                    );
+                }
+            }
+            (primary, mirror) if primary != mirror => diffs.push(FieldDiff {
+                path: path.to_string(),
+                primary: primary.clone(),
+                mirror: mirror.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    /// Body stored with a mismatch, gRPC messages as JSON when they can be decoded.
+    fn body_text(&self, path: &str, body: &Bytes, request: bool) -> String {
+        let decoded = if request {
+            self.grpc.decode_request(path, body)
+        } else {
+            self.grpc.decode_response(path, body)
+        };
+        match decoded {
+            Some(message) => message.to_string(),
+            None => String::from_utf8_lossy(body).into_owned(),
+        }
+    }
+
+    fn ignored(&self, key: &str, field: &str) -> bool {
+        self.ignore_fields
+            .iter()
+            .any(|ignored| ignored == key || ignored == field)
+    }
+
+    /// Waits for the primary response, compares it with the mirror response and stores the
+    /// mismatch. Gives up when the primary response does not arrive within `timeout`. Returns
+    /// the differences, empty when the responses matched or were not compared.
+    pub async fn compare(
+        &self,
+        mirror: &str,
+        request: RequestSummary,
+        mut primary: PrimaryResponse,
+        response: CapturedResponse,
+    ) -> Vec<FieldDiff> {
+        // 引用在 await 之前释放，否则 future 不是 Send
+        let waited = tokio::time::timeout(self.timeout, primary.wait_for(Option::is_some))
+            .await
+            .ok()
+            .and_then(|primary| primary.ok().and_then(|primary| primary.clone()));
+        let Some(primary) = waited else {
+            debug!(%mirror, request_id = %request.request_id, "no primary response to compare with");
+            return Vec::new();
+        };
+
+        let path = request
+            .path
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let diff = self.diff(&path, &primary, &response);
+        let result = if diff.is_empty() { "match" } else { "mismatch" };
+        MIRROR_COMPARISONS
+            .with_label_values(&[mirror, result])
+            .inc();
+        let record = (!diff.is_empty()).then(|| MismatchRecord {
+            id: 0,
+            timestamp: Utc::now(),
+            request_id: request.request_id.clone(),
+            route: request.route.clone(),
+            mirror: mirror.to_string(),
+            method: request.method,
+            path: request.path,
+            request_body: self.body_text(&path, &request.body, true),
+            primary_status: primary.status,
+            primary_body: self.body_text(&path, &self.body(&primary).unwrap_or_default(), false),
+            mirror_status: response.status,
+            mirror_body: self.body_text(&path, &self.body(&response).unwrap_or_default(), false),
+            diff: diff.clone(),
+        });
+
+        let store = self.store.clone();
+        let (route, mirror) = (request.route.unwrap_or_default(), mirror.to_string());
+        let stored = tokio::task::spawn_blocking(move || {
+            store.record_comparison(&route, &mirror, record.as_ref())
+        })
+        .await;
+        match stored {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!(request_id = %request.request_id, "failed to store comparison: {:?}", e)
+            }
+            Err(e) => {
+                warn!(request_id = %request.request_id, "failed to store comparison: {:?}", e)
+            }
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetentionConfig;
+    use http::HeaderValue;
+
+    fn comparator(name: &str) -> Comparator {
+        let store = std::env::temp_dir().join(format!("compare-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&store);
+        Comparator::new(&ComparisonConfig {
+            enabled: true,
+            store,
+            max_body_size: 256,
+            ignore_fields: vec!["updated_at".to_string(), "user.id".to_string()],
+            retention: RetentionConfig::default(),
+            ..Default::default()
+        })
+        .expect("Failed to create comparator")
+    }
+
+    fn response(status: u16, content_type: &str, body: &str) -> CapturedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_str(content_type).unwrap());
+        CapturedResponse::new(status, headers, Bytes::from(body.to_string()), 256)
+    }
+
+    #[test]
+    fn test_diff_json_bodies() {
+        let comparator = comparator("diff");
+        let primary = response(
+            200,
+            "application/json",
+            r#"{"user":{"id":1,"name":"a"},"items":[1,2],"updated_at":"x"}"#,
+        );
+        let mirror = response(
+            201,
+            "application/json",
+            r#"{"items":[1,3,4],"user":{"id":2,"name":"a","extra":true},"updated_at":"y"}"#,
+        );
+        let diff = comparator.diff("/users", &primary, &mirror);
+        let paths: Vec<_> = diff.iter().map(|diff| diff.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "status",
+                "body.user.extra",
+                "body.items[1]",
+                "body.items[2]"
+            ]
+        );
+        assert_eq!(diff[2].field(), "body.items[]");
+        assert_eq!(diff[3].primary, Value::Null);
+    }
+
+    #[test]
+    fn test_diff_headers_and_raw_bodies() {
+        let comparator = comparator("raw");
+        let diff = comparator.diff(
+            "/",
+            &response(200, "text/plain", "a"),
+            &response(200, "text/html", "b"),
+        );
+        let paths: Vec<_> = diff.iter().map(|diff| diff.path.as_str()).collect();
+        assert_eq!(paths, ["headers.content-type", "body"]);
+
+        // 超过大小限制的响应体不参与比较
+        let large = "x".repeat(300);
+        let diff = comparator.diff(
+            "/",
+            &response(200, "text/plain", &large),
+            &response(200, "text/plain", "b"),
+        );
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn test_diff_decodes_bodies() {
+        use std::io::Write;
+
+        let comparator = comparator("encoded");
+        let gzip = |body: &str| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body.as_bytes()).unwrap();
+            let mut response = response(200, "application/json", "");
+            response.body = encoder.finish().unwrap().into();
+            response
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            response
+        };
+        // 主上游返回 gzip，镜像返回未压缩的 JSON
+        let diff = comparator.diff(
+            "/users",
+            &gzip(r#"{"id":1,"name":"alice"}"#),
+            &response(200, "application/json", r#"{"name":"alice","id":1}"#),
+        );
+        assert!(diff.is_empty());
+
+        let diff = comparator.diff(
+            "/users",
+            &gzip(r#"{"id":1,"name":"alice"}"#),
+            &response(200, "application/json", r#"{"id":1,"name":"bob"}"#),
+        );
+        assert_eq!(diff[0].path, "body.name");
+        assert_eq!(diff[0].primary, "alice");
+
+        // 解码后超过大小限制的响应体不参与比较
+        let diff = comparator.diff(
+            "/users",
+            &gzip(&"x".repeat(300)),
+            &response(200, "application/json", "b"),
+        );
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn test_diff_grpc_responses() {
+        let grpc = GrpcDescriptors::load(&crate::config::GrpcConfig {
+            descriptor_sets: vec!["fixtures/grpc/users.pb".into()],
+            ignore_fields: vec!["users.v1.User.updated_at".to_string()],
+        })
+        .expect("Failed to load descriptor sets");
+        let comparator = comparator("grpc").with_grpc(Arc::new(grpc));
+        let response = |message: &[u8], status: &'static str| {
+            let mut response = response(200, "application/grpc", "");
+            response.body = grpc::frame(message);
+            response
+                .trailers
+                .insert(grpc::GRPC_STATUS, HeaderValue::from_static(status));
+            response
+        };
+        // User { id: 1, name: "a", updated_at: 5 } 和 User { id: 1, name: "b", updated_at: 6 }
+        let primary = response(b"\x08\x01\x12\x01a\x20\x05", "0");
+        let mirror = response(b"\x08\x01\x12\x01b\x20\x06", "14");
+
+        let diff = comparator.diff("/users.v1.UserService/GetUser", &primary, &mirror);
+        let paths: Vec<_> = diff.iter().map(|diff| diff.path.as_str()).collect();
+        assert_eq!(paths, ["grpc-status", "body.name"]);
+        assert_eq!(diff[0].mirror, 14);
+
+        // 未知的方法按字节比较
+        let diff = comparator.diff("/other.Service/Get", &primary, &mirror);
+        assert_eq!(diff[1].path, "body");
+    }
+
+    #[tokio::test]
+    async fn test_compare_stores_mismatch() {
+        let comparator = comparator("store");
+        let (mut capture, primary) = comparator.capture();
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header
+            .insert_header("content-type", "application/json")
+            .unwrap();
+        capture.set_header(&header);
+        capture.push_body(br#"{"name":"#);
+        capture.push_body(br#""a"}"#);
+        capture.finish();
+
+        let request = RequestSummary {
+            request_id: "abc".to_string(),
+            route: Some("users".to_string()),
+            method: "POST".to_string(),
+            path: "/users".to_string(),
+            body: Bytes::from_static(b"{}"),
+        };
+        let response = response(200, "application/json", r#"{"name":"b"}"#);
+        let diff = comparator
+            .compare("secondary", request, primary, response)
+            .await;
+        assert_eq!(diff.len(), 1);
+
+        let mismatches = comparator.store.list(None).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].request_id, "abc");
+        assert_eq!(mismatches[0].diff[0].path, "body.name");
+    }
+}
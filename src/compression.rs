@@ -0,0 +1,572 @@
+//! Compression of the responses sent to the clients and decoding of the upstream responses.
+//!
+//! The responses are compressed by a pingora downstream module, after the response filters and
+//! for cache hits too, so the cache keeps the uncompressed responses. A response is compressed
+//! when the client accepts one of `compression.algorithms`, its `Content-Type` matches
+//! `compression.content_types`, its `Content-Length` (when known) is at least
+//! `compression.min_size` and it is not encoded already. Among the accepted encodings the one
+//! with the highest quality wins, ties go to the first one of `compression.algorithms`.
+//!
+//! [`decode`] undoes the `Content-Encoding` of the primary and mirror responses before their
+//! bodies are compared.
+
+use crate::config::{CompressionAlgorithm, CompressionConfig};
+use crate::metrics::COMPRESSED_RESPONSES;
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, TRANSFER_ENCODING, VARY,
+};
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use pingora::OrErr;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::modules::http::{HttpModule, HttpModuleBuilder, Module, ModuleBuilder};
+use pingora::protocols::http::compression::{Algorithm, COMPRESSION_ERROR, Encode};
+use std::any::Any;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Compression settings shared by the requests.
+pub struct Compression {
+    algorithms: Vec<(CompressionAlgorithm, u32)>,
+    content_types: Vec<(String, String)>,
+    min_size: usize,
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        let algorithms = config
+            .algorithms
+            .iter()
+            .map(|algorithm| (*algorithm, config.levels.get(*algorithm)))
+            .collect();
+        // `*` 之前为前缀，之后为后缀
+        let content_types = config
+            .content_types
+            .iter()
+            .map(|pattern| {
+                let pattern = pattern.to_ascii_lowercase();
+                match pattern.split_once('*') {
+                    Some((prefix, suffix)) => (prefix.to_string(), suffix.to_string()),
+                    None => (pattern, String::new()),
+                }
+            })
+            .collect();
+        Self {
+            algorithms,
+            content_types,
+            min_size: config.min_size,
+        }
+    }
+
+    /// Downstream module compressing the responses, for `ProxyHttp::init_downstream_modules`.
+    pub fn module(self: &Arc<Self>) -> ModuleBuilder {
+        Box::new(CompressionBuilder(self.clone()))
+    }
+
+    /// Encoding and level used for a client sending `accept_encoding`, `None` when it accepts
+    /// none of the configured ones.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<(CompressionAlgorithm, u32)> {
+        let accepted = parse_accept_encoding(accept_encoding);
+        let quality = |name: &str| {
+            accepted
+                .iter()
+                .find(|(coding, _)| coding == name)
+                .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+                .map_or(0.0, |(_, q)| *q)
+        };
+        let mut best = None;
+        let mut best_quality = 0.0;
+        for (algorithm, level) in &self.algorithms {
+            let q = quality(algorithm.as_str());
+            if q > best_quality {
+                best = Some((*algorithm, *level));
+                best_quality = q;
+            }
+        }
+        best
+    }
+
+    /// Whether the response is worth compressing, whatever the client accepts.
+    pub fn compressible(&self, resp: &ResponseHeader) -> bool {
+        let status = resp.status;
+        if status.is_informational()
+            || matches!(
+                status,
+                StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+            )
+            || resp.headers.contains_key(CONTENT_RANGE)
+            || is_encoded(&resp.headers)
+            || no_transform(&resp.headers)
+        {
+            return false;
+        }
+        if let Some(length) = header_str(&resp.headers, &CONTENT_LENGTH)
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            && (length == 0 || length < self.min_size)
+        {
+            return false;
+        }
+        let Some(content_type) = header_str(&resp.headers, &CONTENT_TYPE) else {
+            return false;
+        };
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.content_types.iter().any(|(prefix, suffix)| {
+            if suffix.is_empty() && !prefix.ends_with('/') {
+                essence == *prefix
+            } else {
+                essence.len() >= prefix.len() + suffix.len()
+                    && essence.starts_with(prefix.as_str())
+                    && essence.ends_with(suffix.as_str())
+            }
+        })
+    }
+}
+
+impl CompressionAlgorithm {
+    /// Content coding name, as in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gzip",
+            CompressionAlgorithm::Brotli => "br",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+
+    fn encoder(&self, level: u32) -> Option<Box<dyn Encode + Send + Sync>> {
+        match self {
+            CompressionAlgorithm::Gzip => Algorithm::Gzip.compressor(level),
+            // pingora 的 brotli 压缩器结束时只 flush，不写入结束块
+            CompressionAlgorithm::Brotli => Some(Box::new(BrotliEncoder::new(level))),
+            CompressionAlgorithm::Zstd => Algorithm::Zstd.compressor(level),
+        }
+    }
+}
+
+/// Brotli stream closed with its last meta-block at the end of the body.
+struct BrotliEncoder {
+    writer: Option<brotli::CompressorWriter<Vec<u8>>>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+}
+
+impl BrotliEncoder {
+    fn new(level: u32) -> Self {
+        Self {
+            writer: Some(brotli::CompressorWriter::new(Vec::new(), 4096, level, 19)),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+impl Encode for BrotliEncoder {
+    fn encode(&mut self, input: &[u8], end: bool) -> pingora::Result<Bytes> {
+        let started = Instant::now();
+        let Some(writer) = &mut self.writer else {
+            return Ok(Bytes::new());
+        };
+        self.total_in += input.len();
+        writer
+            .write_all(input)
+            .or_err(COMPRESSION_ERROR, "while compressing brotli")?;
+        let output = if end {
+            // into_inner 写入结束块
+            self.writer
+                .take()
+                .map(|writer| writer.into_inner())
+                .unwrap_or_default()
+        } else {
+            std::mem::take(writer.get_mut())
+        };
+        self.total_out += output.len();
+        self.duration += started.elapsed();
+        Ok(output.into())
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("br", self.total_in, self.total_out, self.duration)
+    }
+}
+
+struct CompressionBuilder(Arc<Compression>);
+
+impl HttpModuleBuilder for CompressionBuilder {
+    fn init(&self) -> Module {
+        Box::new(ResponseCompressor {
+            compression: self.0.clone(),
+            accept_encoding: None,
+            head: false,
+            encoder: None,
+            finished: false,
+        })
+    }
+
+    fn order(&self) -> i16 {
+        // 与 pingora 自带的压缩模块一样，在其他模块之后运行
+        i16::MIN / 2
+    }
+}
+
+/// Per request state of the compression module.
+pub struct ResponseCompressor {
+    compression: Arc<Compression>,
+    accept_encoding: Option<String>,
+    head: bool,
+    encoder: Option<(CompressionAlgorithm, Box<dyn Encode + Send + Sync>)>,
+    finished: bool,
+}
+
+impl ResponseCompressor {
+    /// Encoding applied to the response, with the size of the body before and after.
+    pub fn stats(&self) -> Option<(CompressionAlgorithm, usize, usize)> {
+        self.encoder.as_ref().map(|(algorithm, encoder)| {
+            let (_, input, output, _) = encoder.stat();
+            (*algorithm, input, output)
+        })
+    }
+
+    fn encode(&mut self, chunk: &[u8], end: bool) -> pingora::Result<Option<Bytes>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let Some((_, encoder)) = &mut self.encoder else {
+            return Ok(None);
+        };
+        self.finished = end;
+        encoder.encode(chunk, end).map(Some)
+    }
+}
+
+#[async_trait]
+impl HttpModule for ResponseCompressor {
+    async fn request_header_filter(&mut self, req: &mut RequestHeader) -> pingora::Result<()> {
+        self.accept_encoding = header_str(&req.headers, &ACCEPT_ENCODING).map(str::to_string);
+        self.head = req.method == Method::HEAD;
+        Ok(())
+    }
+
+    async fn response_header_filter(
+        &mut self,
+        resp: &mut ResponseHeader,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if resp.status.is_informational() || !self.compression.compressible(resp) {
+            return Ok(());
+        }
+        // 响应体取决于 Accept-Encoding，不压缩时也要告诉下游缓存
+        add_vary(resp)?;
+        if end_of_stream || self.head {
+            return Ok(());
+        }
+        let Some((algorithm, level)) = self
+            .accept_encoding
+            .as_deref()
+            .and_then(|accept_encoding| self.compression.negotiate(accept_encoding))
+        else {
+            return Ok(());
+        };
+        let Some(encoder) = algorithm.encoder(level) else {
+            return Ok(());
+        };
+        resp.remove_header(&CONTENT_LENGTH);
+        resp.remove_header(&ACCEPT_RANGES);
+        resp.insert_header(CONTENT_ENCODING, algorithm.as_str())?;
+        resp.insert_header(TRANSFER_ENCODING, "chunked")?;
+        weaken_etag(resp)?;
+        COMPRESSED_RESPONSES
+            .with_label_values(&[algorithm.as_str()])
+            .inc();
+        self.encoder = Some((algorithm, encoder));
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        let chunk = body.as_deref().unwrap_or_default();
+        if let Some(encoded) = self.encode(chunk, end_of_stream)? {
+            *body = Some(encoded);
+        }
+        Ok(())
+    }
+
+    fn response_done_filter(&mut self) -> pingora::Result<Option<Bytes>> {
+        // 缓存命中的响应体以 Done 结束，没有 end_of_stream
+        self.encode(&[], true)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Decodes a body encoded with `encoding` (a `Content-Encoding` value, possibly a list).
+/// Fails for unknown encodings and when the decoded body exceeds `limit` bytes.
+pub fn decode(encoding: &str, body: &[u8], limit: usize) -> Result<Bytes> {
+    let mut body = Bytes::copy_from_slice(body);
+    // 按编码的相反顺序解码
+    for coding in encoding.rsplit(',').map(str::trim) {
+        let reader: Box<dyn Read + '_> = match coding.to_ascii_lowercase().as_str() {
+            "" | "identity" => continue,
+            "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(&body[..])),
+            "deflate" => Box::new(flate2::read::ZlibDecoder::new(&body[..])),
+            "br" => Box::new(brotli::Decompressor::new(&body[..], 4096)),
+            "zstd" => Box::new(zstd::stream::read::Decoder::new(&body[..])?),
+            other => bail!("unsupported content encoding {other:?}"),
+        };
+        let mut decoded = Vec::new();
+        reader.take(limit as u64 + 1).read_to_end(&mut decoded)?;
+        if decoded.len() > limit {
+            bail!("decoded body is larger than {limit} bytes");
+        }
+        body = decoded.into();
+    }
+    Ok(body)
+}
+
+/// Whether the body is encoded, `identity` aside.
+pub fn is_encoded(headers: &HeaderMap) -> bool {
+    header_str(headers, &CONTENT_ENCODING).is_some_and(|encoding| {
+        encoding.split(',').any(|coding| {
+            !coding.trim().is_empty() && !coding.trim().eq_ignore_ascii_case("identity")
+        })
+    })
+}
+
+/// Codings of an `Accept-Encoding` header, lowercased, with their quality.
+fn parse_accept_encoding(value: &str) -> Vec<(String, f32)> {
+    value
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let q = parts
+                .filter_map(|param| param.trim().split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map_or(1.0, |(_, q)| q.trim().parse().unwrap_or(0.0));
+            Some((coding, q))
+        })
+        .collect()
+}
+
+fn no_transform(headers: &HeaderMap) -> bool {
+    headers.get_all(CACHE_CONTROL).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+        })
+    })
+}
+
+fn add_vary(resp: &mut ResponseHeader) -> pingora::Result<()> {
+    let present = resp.headers.get_all(VARY).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|name| name == "*" || name.eq_ignore_ascii_case(ACCEPT_ENCODING.as_str()))
+        })
+    });
+    if !present {
+        resp.append_header(VARY, HeaderValue::from_name(ACCEPT_ENCODING))?;
+    }
+    Ok(())
+}
+
+/// A strong `ETag` becomes weak, the compressed body is not the same bytes.
+fn weaken_etag(resp: &mut ResponseHeader) -> pingora::Result<()> {
+    let Some(etag) = resp.headers.get(ETAG) else {
+        return Ok(());
+    };
+    if etag.as_bytes().starts_with(b"\"") {
+        let weak = HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat())
+            .expect("a quoted etag is a valid header value");
+        resp.insert_header(ETAG, weak)?;
+    } else if !etag.as_bytes().starts_with(b"W/") {
+        resp.remove_header(&ETAG);
+    }
+    Ok(())
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &http::HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compression() -> Compression {
+        Compression::new(&CompressionConfig {
+            enabled: true,
+            min_size: 32,
+            ..Default::default()
+        })
+    }
+
+    fn response(content_type: &str, length: Option<usize>) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(CONTENT_TYPE, content_type).unwrap();
+        if let Some(length) = length {
+            resp.insert_header(CONTENT_LENGTH, length).unwrap();
+        }
+        resp
+    }
+
+    fn module(compression: Compression) -> ResponseCompressor {
+        ResponseCompressor {
+            compression: Arc::new(compression),
+            accept_encoding: None,
+            head: false,
+            encoder: None,
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let compression = compression();
+        let negotiate = |value| compression.negotiate(value).map(|(algorithm, _)| algorithm);
+        assert_eq!(negotiate("gzip"), Some(CompressionAlgorithm::Gzip));
+        // 质量相同时按配置的顺序
+        assert_eq!(
+            negotiate("gzip, deflate, br, zstd"),
+            Some(CompressionAlgorithm::Zstd)
+        );
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.5"),
+            Some(CompressionAlgorithm::Gzip)
+        );
+        assert_eq!(
+            negotiate("*;q=0.1, zstd;q=0"),
+            Some(CompressionAlgorithm::Brotli)
+        );
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("identity, deflate"), None);
+    }
+
+    #[test]
+    fn test_compressible() {
+        let compression = compression();
+        assert!(compression.compressible(&response("application/json; charset=utf-8", Some(64))));
+        assert!(compression.compressible(&response("text/html", None)));
+        assert!(compression.compressible(&response("application/problem+json", Some(64))));
+        assert!(!compression.compressible(&response("application/json", Some(16))));
+        assert!(!compression.compressible(&response("image/png", Some(64))));
+        assert!(!compression.compressible(&response("application/grpc", None)));
+
+        let mut encoded = response("application/json", Some(64));
+        encoded.insert_header(CONTENT_ENCODING, "gzip").unwrap();
+        assert!(!compression.compressible(&encoded));
+        let mut no_transform = response("application/json", Some(64));
+        no_transform
+            .insert_header(CACHE_CONTROL, "public, no-transform")
+            .unwrap();
+        assert!(!compression.compressible(&no_transform));
+    }
+
+    #[tokio::test]
+    async fn test_module_compresses_response() {
+        let body = r#"{"users":[{"id":1,"name":"alice"},{"id":2,"name":"bob"}]}"#.repeat(4);
+        let mut module = module(compression());
+        let mut req = RequestHeader::build("GET", b"/api/users", None).unwrap();
+        req.insert_header(ACCEPT_ENCODING, "gzip, br").unwrap();
+        module
+            .request_header_filter(&mut req)
+            .await
+            .expect("Failed to filter request");
+
+        let mut resp = response("application/json", Some(body.len()));
+        resp.insert_header(ETAG, "\"v1\"").unwrap();
+        module
+            .response_header_filter(&mut resp, false)
+            .await
+            .expect("Failed to filter response header");
+        assert_eq!(resp.headers[CONTENT_ENCODING], "br");
+        assert_eq!(resp.headers[VARY], "accept-encoding");
+        assert_eq!(resp.headers[ETAG], "W/\"v1\"");
+        assert!(!resp.headers.contains_key(CONTENT_LENGTH));
+
+        // 缓存命中时响应体以 Done 结束
+        let mut chunk = Some(Bytes::from(body.clone()));
+        module
+            .response_body_filter(&mut chunk, false)
+            .expect("Failed to compress body");
+        let mut encoded = chunk.unwrap().to_vec();
+        let rest = module
+            .response_done_filter()
+            .expect("Failed to finish compression");
+        encoded.extend_from_slice(&rest.unwrap());
+        assert_eq!(
+            module.stats().map(|(name, input, _)| (name, input)),
+            Some((CompressionAlgorithm::Brotli, body.len()))
+        );
+        let decoded = decode("br", &encoded, 1024).expect("Failed to decode body");
+        assert_eq!(decoded, body.as_bytes());
+        assert_eq!(module.response_done_filter().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_module_skips_unaccepted_response() {
+        let mut module = module(compression());
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        module
+            .request_header_filter(&mut req)
+            .await
+            .expect("Failed to filter request");
+        let mut resp = response("text/plain", Some(64));
+        module
+            .response_header_filter(&mut resp, false)
+            .await
+            .expect("Failed to filter response header");
+        assert!(!resp.headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(resp.headers[VARY], "accept-encoding");
+        assert_eq!(resp.headers[CONTENT_LENGTH], "64");
+        let mut chunk = Some(Bytes::from_static(b"plain"));
+        module
+            .response_body_filter(&mut chunk, true)
+            .expect("Failed to filter body");
+        assert_eq!(chunk.unwrap(), "plain");
+    }
+
+    #[test]
+    fn test_decode() {
+        let body = br#"{"id":1,"name":"alice"}"#;
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(body).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(decode("gzip", &gzip, 1024).unwrap(), &body[..]);
+
+        let zstd = zstd::encode_all(&body[..], 3).unwrap();
+        assert_eq!(decode("zstd", &zstd, 1024).unwrap(), &body[..]);
+        // 多层编码按相反顺序解码
+        let both = zstd::encode_all(&gzip[..], 3).unwrap();
+        assert_eq!(decode("gzip, zstd", &both, 1024).unwrap(), &body[..]);
+        assert_eq!(decode("identity", body, 1024).unwrap(), &body[..]);
+
+        assert!(decode("gzip", &gzip, 8).is_err());
+        assert!(decode("compress", body, 1024).is_err());
+        assert!(decode("gzip", body, 1024).is_err());
+    }
+}
@@ -0,0 +1,1172 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Top level proxy configuration, loaded from a YAML file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Address the proxy listens on.
+    pub listen: String,
+    /// Also accept cleartext HTTP/2 with prior knowledge (h2c) on `listen`.
+    pub h2c: bool,
+    /// TLS listener, disabled when unset.
+    pub tls: Option<TlsListenerConfig>,
+    /// Address of the prometheus metrics endpoint, disabled when unset.
+    pub metrics_listen: Option<String>,
+    /// Primary upstream, its response is returned to the client.
+    pub primary: PrimaryConfig,
+    /// Secondary upstreams that receive a copy of every request.
+    pub mirrors: Vec<MirrorConfig>,
+    pub access_log: AccessLogConfig,
+    pub request_id: RequestIdConfig,
+    pub tracing: TracingConfig,
+    /// Largest request body copied to the mirrors, larger requests are not mirrored.
+    pub mirror_max_body_size: usize,
+    /// Routes matched against the client request, the first matching route applies.
+    pub routes: Vec<RouteConfig>,
+    pub scripting: ScriptingConfig,
+    /// WebAssembly filters, run in this order after the script hooks.
+    pub plugins: Vec<PluginConfig>,
+    pub recording: RecordingConfig,
+    pub comparison: ComparisonConfig,
+    pub admin: AdminConfig,
+    pub shutdown: ShutdownConfig,
+    pub websocket: WebSocketConfig,
+    pub grpc: GrpcConfig,
+    pub cache: CacheConfig,
+    pub compression: CompressionConfig,
+    /// Client IP allow and deny lists applied to every request, see [`crate::access`].
+    pub access_control: AccessControlConfig,
+    pub client_ip: ClientIpConfig,
+    pub proxy_protocol: ProxyProtocolConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsListenerConfig {
+    pub listen: String,
+    /// PEM certificate chain.
+    pub cert: PathBuf,
+    /// PEM private key.
+    pub key: PathBuf,
+    /// Offer HTTP/2 through ALPN, clients that do not ask for it get HTTP/1.1.
+    #[serde(default = "default_true")]
+    pub http2: bool,
+}
+
+/// HTTP version spoken to an upstream.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    /// HTTP/1.1 only.
+    Http1,
+    /// HTTP/2 when a TLS upstream offers it through ALPN, else HTTP/1.1.
+    Auto,
+    /// HTTP/2 only: through ALPN over TLS, with prior knowledge (h2c) over cleartext.
+    Http2,
+}
+
+/// WebAssembly filter module, see [`crate::plugin`] for the ABI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    /// Name used in logs and metric labels.
+    pub name: String,
+    /// Compiled module (`.wasm`) or text format (`.wat`).
+    pub path: PathBuf,
+    /// Plugin specific configuration, passed as JSON to the `init` export.
+    #[serde(default)]
+    pub config: serde_json::Value,
+    /// Maximum linear memory of an instance, in bytes.
+    #[serde(default = "default_plugin_max_memory")]
+    pub max_memory: usize,
+    /// Fuel (roughly wasm instructions) available to a single hook call.
+    #[serde(default = "default_plugin_max_fuel")]
+    pub max_fuel: u64,
+}
+
+/// Rhai script hooks, see [`crate::scripting`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptingConfig {
+    /// Script defining the hooks, scripting is disabled when unset.
+    pub path: Option<PathBuf>,
+    /// Wall clock limit of a single hook call.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Limit of operations of a single hook call.
+    pub max_operations: u64,
+    /// How often the script file is checked for changes.
+    #[serde(with = "humantime_serde")]
+    pub reload_interval: Duration,
+}
+
+/// Request class selected by path prefix and method, carrying its own header rules.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Name used in logs and as the `{route}` header template variable.
+    pub name: String,
+    pub path_prefix: String,
+    /// Methods the route applies to, all methods when empty.
+    pub methods: Vec<String>,
+    /// Rules for the primary request and the response returned to the client.
+    pub headers: HeaderRules,
+    /// Rules for the copy sent to the mirrors.
+    pub mirror_headers: Vec<HeaderRule>,
+    /// Transformation of the JSON body sent to the mirrors.
+    pub mirror_body: Vec<BodyRule>,
+    pub cache: RouteCacheConfig,
+    /// CORS policy answering the preflight requests at the proxy, none when unset.
+    pub cors: Option<CorsConfig>,
+    /// Client IP allow and deny lists of the route, checked after the global ones.
+    pub access_control: AccessControlConfig,
+}
+
+/// Caching of the route responses, when `cache.enabled`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteCacheConfig {
+    pub enabled: bool,
+    /// Freshness of the cached responses, replacing the `max-age` and `Expires` of the
+    /// response. `no-store` and `private` responses are still not cached.
+    #[serde(with = "humantime_serde")]
+    pub ttl: Option<Duration>,
+}
+
+/// CORS policy of a route, see [`crate::cors`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins allowed to read the responses, `*` for any origin, `https://*.example.com` for
+    /// the subdomains.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers the browser may send, `*` for any.
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by the browser besides the safelisted ones.
+    pub exposed_headers: Vec<String>,
+    /// Allow cookies and `Authorization`, not allowed with the `*` origin.
+    pub allow_credentials: bool,
+    /// How long browsers cache the preflight answers.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+}
+
+/// JSON body transformation, applied in order. Paths are dot separated object keys
+/// (`user.address.city`).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BodyRule {
+    /// Move a field, to rename it or to change its nesting.
+    Rename {
+        from: String,
+        to: String,
+    },
+    Remove {
+        path: String,
+    },
+    /// Set a field to a constant value, string values may use template variables
+    /// (`{request_id}`, ...).
+    Set {
+        path: String,
+        value: serde_json::Value,
+    },
+    /// Apply `rules` to every element of the array at `path`.
+    Each {
+        path: String,
+        rules: Vec<BodyRule>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderRules {
+    pub request: Vec<HeaderRule>,
+    pub response: Vec<HeaderRule>,
+}
+
+/// Header manipulation, applied in order. Values may use the `{client_ip}`, `{request_id}`,
+/// `{route}`, `{method}` and `{host}` template variables.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum HeaderRule {
+    /// Replace any existing value.
+    Set {
+        name: String,
+        value: String,
+    },
+    /// Add a value, keeping the existing ones.
+    Append {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+}
+
+/// OpenTelemetry tracing, spans are exported over OTLP/HTTP (protobuf).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub enabled: bool,
+    /// Collector traces endpoint.
+    pub endpoint: String,
+    pub service_name: String,
+    /// Share of new traces that are sampled, traces started by the client follow its decision.
+    pub sample_ratio: f64,
+    #[serde(with = "humantime_serde")]
+    pub export_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestIdConfig {
+    /// Header carrying the request id, to the upstreams and back to the client.
+    pub header: String,
+    /// Keep a valid id sent by the client instead of generating a new one.
+    pub accept_from_client: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrimaryConfig {
+    /// Backend pool, requests are balanced round robin across it.
+    pub backends: Vec<String>,
+    pub tls: bool,
+    pub sni: String,
+    pub http_version: HttpVersion,
+    pub retry: RetryConfig,
+    /// Rules applied to every primary request and response, before the route rules.
+    pub headers: HeaderRules,
+    pub rewrite: UrlRewriteConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Maximum number of retries after the first attempt, 0 disables retries.
+    pub max_retries: usize,
+    /// Also retry non-idempotent methods when the upstream failed after the connection was
+    /// established. Connection failures are always retried since the request was never sent.
+    pub retry_non_idempotent: bool,
+    pub budget: RetryBudgetConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryBudgetConfig {
+    /// Share of the in flight requests that may be retrying at the same time.
+    pub ratio: f64,
+    /// Number of concurrent retries always allowed regardless of the ratio.
+    pub min_concurrency: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// Name used in logs and metric labels.
+    pub name: String,
+    /// Base url (scheme and authority) the request path is appended to.
+    pub url: String,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    pub http_version: HttpVersion,
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Rules applied to the request copy sent to this mirror, after the route rules.
+    pub headers: Vec<HeaderRule>,
+    pub rewrite: UrlRewriteConfig,
+}
+
+/// Path and query rewriting of the request sent to an upstream, rules are applied in order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UrlRewriteConfig {
+    pub path: Vec<PathRule>,
+    pub query: Vec<QueryRule>,
+}
+
+/// Replaces the first match of `pattern` in the path, `replacement` may reference capture
+/// groups as `$1` or `${name}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PathRule {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum QueryRule {
+    /// Replace all values of the parameter, adding it when missing.
+    Set {
+        name: String,
+        value: String,
+    },
+    /// Add a value, keeping the existing ones.
+    Add {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    /// Open the circuit after this many consecutive failures.
+    pub consecutive_failures: u32,
+    /// Open the circuit when the failure ratio over the window reaches this value.
+    pub error_rate: f64,
+    /// Number of most recent outcomes used to compute the error rate.
+    pub window: usize,
+    /// Minimum number of outcomes in the window before the error rate is evaluated.
+    pub min_requests: usize,
+    /// How long the circuit stays open before probing the mirror again.
+    #[serde(with = "humantime_serde")]
+    pub cooldown: Duration,
+    /// Number of successful probes needed in half-open state to close the circuit.
+    pub half_open_probes: u32,
+    /// What to do with writes skipped while the circuit is open.
+    pub on_open: SkippedWrites,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SkippedWrites {
+    /// Only count the skipped write.
+    Count,
+    /// Append the skipped write to a JSONL backlog file so it can be replayed later.
+    Backlog { path: PathBuf },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub enabled: bool,
+    pub output: AccessLogOutput,
+    /// Fields written for every request, in this order.
+    pub fields: Vec<AccessLogField>,
+}
+
+/// Recording of request/response pairs of the primary and the mirrors, see
+/// [`crate::recording`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub enabled: bool,
+    pub format: RecordingFormat,
+    pub path: PathBuf,
+    /// Size of the file before it is rotated, in bytes (0 disables rotation).
+    pub max_size: u64,
+    pub max_files: usize,
+    /// Bodies are truncated to this many bytes.
+    pub max_body_size: usize,
+    /// Headers whose value is replaced by `[REDACTED]`.
+    pub redact_headers: Vec<String>,
+    /// Names of the routes to record, all requests when empty.
+    pub routes: Vec<String>,
+}
+
+/// Comparison of the primary and mirror responses, see [`crate::compare`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComparisonConfig {
+    pub enabled: bool,
+    /// SQLite database holding the mismatches.
+    pub store: PathBuf,
+    /// Bodies larger than this are not compared, only the status and headers.
+    pub max_body_size: usize,
+    /// How long a mirror response waits for the primary response.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// JSON body fields left out of the comparison, by name (`updated_at`) or by dot
+    /// separated path (`user.id`).
+    pub ignore_fields: Vec<String>,
+    /// Response headers compared in addition to the status and body.
+    pub compare_headers: Vec<String>,
+    /// Names of the routes to compare, all requests when empty.
+    pub routes: Vec<String>,
+    pub retention: RetentionConfig,
+}
+
+/// Admin HTTP listener serving the live event stream, the REST api and the UI, see
+/// [`crate::admin`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    /// Exposes the configuration and the recorded mismatches, keep it on a private address.
+    pub listen: String,
+    /// Built UI assets (`ui/dist`), served on `/` when set.
+    pub ui_dir: Option<PathBuf>,
+    /// Bearer token required on the `/api` endpoints, they are open when unset.
+    pub token: Option<String>,
+    /// Events buffered per subscriber, slower subscribers miss events.
+    pub event_buffer: usize,
+}
+
+/// Proxying of WebSocket connections, see [`crate::websocket`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Opens a WebSocket connection to every mirror and sends it the client messages.
+    pub mirror: bool,
+    /// Larger client messages are not mirrored.
+    pub max_message_size: usize,
+    /// Messages waiting to be sent per mirror connection, later messages are dropped.
+    pub queue_size: usize,
+}
+
+/// Mirroring and comparison of gRPC calls, see [`crate::grpc`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Binary `FileDescriptorSet` files (`protoc --include_imports --descriptor_set_out`)
+    /// used to decode the messages.
+    pub descriptor_sets: Vec<PathBuf>,
+    /// Message fields left out of the comparison, by fully qualified name
+    /// (`users.v1.User.updated_at`).
+    pub ignore_fields: Vec<String>,
+}
+
+/// HTTP cache of the primary GET and HEAD responses, see [`crate::cache`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub storage: CacheStorageConfig,
+    /// Total size of the cached responses, the least recently used are evicted first.
+    pub max_size: usize,
+    /// Larger responses are not cached.
+    pub max_object_size: usize,
+    /// How long concurrent misses of the same key wait for the first one to fill the cache.
+    #[serde(with = "humantime_serde")]
+    pub lock_timeout: Duration,
+    pub key: CacheKeyConfig,
+    /// Response header telling the client whether the response came from the cache, not sent
+    /// when empty.
+    pub status_header: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum CacheStorageConfig {
+    Memory,
+    /// One file per cached response under `path`, kept across restarts.
+    Disk {
+        path: PathBuf,
+    },
+}
+
+/// What the cache key is made of, besides the host and the path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheKeyConfig {
+    /// Request headers whose values are part of the key.
+    pub headers: Vec<String>,
+    /// Query parameters part of the key, all of them when empty. Their order does not matter.
+    pub query_params: Vec<String>,
+    /// Query parameters left out of the key (tracking parameters, cache busters).
+    pub ignore_query_params: Vec<String>,
+}
+
+/// Compression of the responses sent to the clients, see [`crate::compression`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Encodings offered to the clients, ties between the encodings a client accepts go to the
+    /// first one.
+    pub algorithms: Vec<CompressionAlgorithm>,
+    pub levels: CompressionLevels,
+    /// Media types compressed, `text/*` and `application/*+json` style wildcards allowed.
+    pub content_types: Vec<String>,
+    /// Responses with a smaller `Content-Length` are sent as is.
+    pub min_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionLevels {
+    /// 1 to 9.
+    pub gzip: u32,
+    /// 1 to 11.
+    pub br: u32,
+    /// 1 to 22.
+    pub zstd: u32,
+}
+
+impl CompressionLevels {
+    pub fn get(&self, algorithm: CompressionAlgorithm) -> u32 {
+        match algorithm {
+            CompressionAlgorithm::Gzip => self.gzip,
+            CompressionAlgorithm::Brotli => self.br,
+            CompressionAlgorithm::Zstd => self.zstd,
+        }
+    }
+}
+
+/// CIDR ranges (`10.0.0.0/8`, `2001:db8::/32`) or single addresses. A client matching `deny`
+/// is rejected with a 403, and so is a client not matching a non-empty `allow`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessControlConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+/// Resolution of the client address used by the access control, the `{client_ip}` template
+/// variable and the access log.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientIpConfig {
+    /// Proxies whose `Forwarded` and `X-Forwarded-For` headers are believed, as CIDR ranges.
+    pub trusted_proxies: Vec<String>,
+}
+
+/// PROXY protocol v1 and v2 on `listen`, see [`crate::proxy_protocol`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    pub enabled: bool,
+    /// Loopback address the proxy itself listens on, the connections of `listen` are relayed
+    /// to it once their header is read.
+    pub internal_listen: String,
+    /// Connections without a complete header by then are closed.
+    #[serde(with = "humantime_serde")]
+    pub header_timeout: Duration,
+}
+
+/// Graceful shutdown of the mirrored writes, see [`crate::shutdown`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long the client requests and mirrored writes in flight are waited for.
+    #[serde(with = "humantime_serde")]
+    pub drain_timeout: Duration,
+    /// Backlog receiving the writes still pending at the deadline, for the mirrors without
+    /// an `on_open` backlog.
+    pub backlog: PathBuf,
+}
+
+/// Limits of the mismatch store, the oldest mismatches are deleted first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub max_entries: usize,
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingFormat {
+    /// One JSON object per line.
+    #[default]
+    Jsonl,
+    /// HTTP Archive 1.2, each file is a complete HAR document.
+    Har,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AccessLogOutput {
+    Stdout,
+    /// Size rotated file, `max_size` in bytes (0 disables rotation).
+    File {
+        path: PathBuf,
+        #[serde(default = "default_max_size")]
+        max_size: u64,
+        #[serde(default = "default_max_files")]
+        max_files: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogField {
+    Timestamp,
+    ClientAddr,
+    Method,
+    Path,
+    Status,
+    BytesIn,
+    BytesOut,
+    Upstream,
+    UpstreamLatencyMs,
+    DurationMs,
+    Mirror,
+    RequestId,
+    Error,
+    /// Cache status of the response (`hit`, `miss`, ...), null when the cache was not used.
+    Cache,
+}
+
+impl AccessLogField {
+    pub const ALL: [AccessLogField; 14] = [
+        AccessLogField::Timestamp,
+        AccessLogField::ClientAddr,
+        AccessLogField::Method,
+        AccessLogField::Path,
+        AccessLogField::Status,
+        AccessLogField::BytesIn,
+        AccessLogField::BytesOut,
+        AccessLogField::Upstream,
+        AccessLogField::UpstreamLatencyMs,
+        AccessLogField::DurationMs,
+        AccessLogField::Mirror,
+        AccessLogField::RequestId,
+        AccessLogField::Error,
+        AccessLogField::Cache,
+    ];
+
+    /// JSON key of the field in the access log line.
+    pub fn key(self) -> &'static str {
+        match self {
+            AccessLogField::Timestamp => "timestamp",
+            AccessLogField::ClientAddr => "client_addr",
+            AccessLogField::Method => "method",
+            AccessLogField::Path => "path",
+            AccessLogField::Status => "status",
+            AccessLogField::BytesIn => "bytes_in",
+            AccessLogField::BytesOut => "bytes_out",
+            AccessLogField::Upstream => "upstream",
+            AccessLogField::UpstreamLatencyMs => "upstream_latency_ms",
+            AccessLogField::DurationMs => "duration_ms",
+            AccessLogField::Mirror => "mirror",
+            AccessLogField::RequestId => "request_id",
+            AccessLogField::Error => "error",
+            AccessLogField::Cache => "cache",
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+fn default_plugin_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_plugin_max_fuel() -> u64 {
+    10_000_000
+}
+
+impl ProxyConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        Self::from_yaml(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Self> {
+        Ok(serde_yaml::from_str(content)?)
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:8080".to_string(),
+            h2c: false,
+            tls: None,
+            metrics_listen: None,
+            primary: PrimaryConfig::default(),
+            mirrors: vec![MirrorConfig::default()],
+            access_log: AccessLogConfig::default(),
+            request_id: RequestIdConfig::default(),
+            tracing: TracingConfig::default(),
+            mirror_max_body_size: 10 * 1024 * 1024,
+            routes: Vec::new(),
+            scripting: ScriptingConfig::default(),
+            plugins: Vec::new(),
+            recording: RecordingConfig::default(),
+            comparison: ComparisonConfig::default(),
+            admin: AdminConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            websocket: WebSocketConfig::default(),
+            grpc: GrpcConfig::default(),
+            cache: CacheConfig::default(),
+            compression: CompressionConfig::default(),
+            access_control: AccessControlConfig::default(),
+            client_ip: ClientIpConfig::default(),
+            proxy_protocol: ProxyProtocolConfig::default(),
+        }
+    }
+}
+
+impl Default for ScriptingConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            timeout: Duration::from_millis(20),
+            max_operations: 1_000_000,
+            reload_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://127.0.0.1:4318/v1/traces".to_string(),
+            service_name: "simple_proxy".to_string(),
+            sample_ratio: 1.0,
+            export_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self {
+            header: "x-request-id".to_string(),
+            accept_from_client: true,
+        }
+    }
+}
+
+impl Default for PrimaryConfig {
+    fn default() -> Self {
+        Self {
+            backends: vec!["127.0.0.1:3000".to_string()],
+            tls: false,
+            sni: "localhost".to_string(),
+            http_version: HttpVersion::Http1,
+            retry: RetryConfig::default(),
+            headers: HeaderRules {
+                request: vec![HeaderRule::Set {
+                    name: "user-content".to_string(),
+                    value: "dual-write".to_string(),
+                }],
+                response: vec![HeaderRule::Set {
+                    name: "user-content".to_string(),
+                    value: "response by kevin".to_string(),
+                }],
+            },
+            rewrite: UrlRewriteConfig::default(),
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            retry_non_idempotent: false,
+            budget: RetryBudgetConfig::default(),
+        }
+    }
+}
+
+impl Default for RetryBudgetConfig {
+    fn default() -> Self {
+        Self {
+            ratio: 0.2,
+            min_concurrency: 3,
+        }
+    }
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            name: "secondary".to_string(),
+            url: "http://127.0.0.1:3001".to_string(),
+            timeout: Duration::from_secs(10),
+            http_version: HttpVersion::Auto,
+            circuit_breaker: CircuitBreakerConfig::default(),
+            headers: Vec::new(),
+            rewrite: UrlRewriteConfig::default(),
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            output: AccessLogOutput::Stdout,
+            fields: AccessLogField::ALL.to_vec(),
+        }
+    }
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: RecordingFormat::Jsonl,
+            path: PathBuf::from("/tmp/simple_proxy/recording.jsonl"),
+            max_size: default_max_size(),
+            max_files: default_max_files(),
+            max_body_size: 64 * 1024,
+            redact_headers: [
+                "authorization",
+                "cookie",
+                "set-cookie",
+                "proxy-authorization",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:9000".to_string(),
+            ui_dir: None,
+            token: None,
+            event_buffer: 1024,
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            mirror: false,
+            max_message_size: 1024 * 1024,
+            queue_size: 1024,
+        }
+    }
+}
+
+impl Default for RouteCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl: None,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            allowed_headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            storage: CacheStorageConfig::Memory,
+            max_size: 256 * 1024 * 1024,
+            max_object_size: 8 * 1024 * 1024,
+            lock_timeout: Duration::from_secs(5),
+            key: CacheKeyConfig::default(),
+            status_header: "x-cache-status".to_string(),
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithms: vec![
+                CompressionAlgorithm::Zstd,
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Gzip,
+            ],
+            levels: CompressionLevels::default(),
+            content_types: [
+                "text/*",
+                "application/json",
+                "application/*+json",
+                "application/javascript",
+                "application/xml",
+                "application/*+xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+            min_size: 1024,
+        }
+    }
+}
+
+impl Default for CompressionLevels {
+    fn default() -> Self {
+        Self {
+            gzip: 6,
+            br: 5,
+            zstd: 3,
+        }
+    }
+}
+
+impl Default for ProxyProtocolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            internal_listen: "127.0.0.1:8081".to_string(),
+            header_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(30),
+            backlog: PathBuf::from("/tmp/simple_proxy/shutdown-backlog.jsonl"),
+        }
+    }
+}
+
+impl Default for ComparisonConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            store: PathBuf::from("/tmp/simple_proxy/mismatches.db"),
+            max_body_size: 1024 * 1024,
+            timeout: Duration::from_secs(30),
+            ignore_fields: Vec::new(),
+            compare_headers: vec!["content-type".to_string()],
+            routes: Vec::new(),
+            retention: RetentionConfig::default(),
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 100_000,
+            max_age: Duration::from_secs(7 * 24 * 3600),
+        }
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            window: 20,
+            min_requests: 10,
+            cooldown: Duration::from_secs(30),
+            half_open_probes: 3,
+            on_open: SkippedWrites::Count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_matches_builtin_topology() {
+        let config = ProxyConfig::default();
+        assert_eq!(config.listen, "0.0.0.0:8080");
+        assert_eq!(config.primary.backends, ["127.0.0.1:3000"]);
+        assert_eq!(config.mirrors.len(), 1);
+        assert_eq!(config.mirrors[0].url, "http://127.0.0.1:3001");
+    }
+
+    #[test]
+    fn test_load_fixture() {
+        let config = ProxyConfig::load("fixtures/proxy.yml").expect("Failed to load config");
+        let breaker = &config.mirrors[0].circuit_breaker;
+        assert_eq!(config.metrics_listen.as_deref(), Some("127.0.0.1:6192"));
+        assert_eq!(config.primary.backends.len(), 2);
+        assert!(config.h2c);
+        let tls = config.tls.as_ref().expect("tls listener");
+        assert_eq!(tls.listen, "0.0.0.0:8443");
+        assert!(tls.http2);
+        assert_eq!(config.primary.http_version, HttpVersion::Http1);
+        assert_eq!(config.mirrors[0].http_version, HttpVersion::Http2);
+        assert_eq!(config.primary.retry.max_retries, 3);
+        assert_eq!(breaker.consecutive_failures, 3);
+        assert_eq!(breaker.cooldown, Duration::from_secs(15));
+        assert_eq!(config.primary.headers.request.len(), 2);
+        assert_eq!(config.mirrors[0].headers.len(), 1);
+        assert_eq!(config.mirrors[0].rewrite.path[0].replacement, "/");
+        assert_eq!(
+            config.mirrors[0].rewrite.query,
+            [QueryRule::Remove {
+                name: "debug".to_string()
+            }]
+        );
+        assert_eq!(config.routes[0].name, "users");
+        assert_eq!(config.routes[0].mirror_body.len(), 2);
+        assert_eq!(config.mirror_max_body_size, 10 * 1024 * 1024);
+        assert_eq!(config.scripting.timeout, Duration::from_millis(20));
+        assert_eq!(config.plugins[0].name, "tag");
+        assert_eq!(config.plugins[0].config, "shadow-proxy");
+        assert_eq!(config.plugins[0].max_fuel, 1_000_000);
+        assert!(config.recording.enabled);
+        assert_eq!(config.recording.format, RecordingFormat::Jsonl);
+        assert_eq!(config.recording.routes, ["users"]);
+        assert!(config.comparison.enabled);
+        assert_eq!(config.comparison.ignore_fields, ["timestamp", "request_id"]);
+        assert_eq!(config.comparison.retention.max_entries, 10_000);
+        assert!(config.admin.enabled);
+        assert_eq!(config.admin.listen, "127.0.0.1:9000");
+        assert_eq!(config.admin.ui_dir, Some(PathBuf::from("ui/dist")));
+        assert_eq!(config.shutdown.drain_timeout, Duration::from_secs(10));
+        assert!(config.websocket.mirror);
+        assert_eq!(config.grpc.ignore_fields, ["users.v1.User.updated_at"]);
+        assert!(config.cache.enabled);
+        assert_eq!(config.cache.storage, CacheStorageConfig::Memory);
+        assert_eq!(config.cache.key.headers, ["accept-language"]);
+        assert_eq!(config.routes[0].cache.ttl, Some(Duration::from_secs(5)));
+        let cors = config.routes[0]
+            .cors
+            .as_ref()
+            .expect("users route has a CORS policy");
+        assert_eq!(cors.allowed_origins.len(), 2);
+        assert!(cors.allow_credentials);
+        assert_eq!(cors.max_age, Some(Duration::from_secs(600)));
+        assert!(config.compression.enabled);
+        assert_eq!(
+            config.compression.algorithms,
+            [
+                CompressionAlgorithm::Zstd,
+                CompressionAlgorithm::Brotli,
+                CompressionAlgorithm::Gzip
+            ]
+        );
+        assert_eq!(config.compression.min_size, 256);
+        assert_eq!(
+            config.routes[0].access_control.allow,
+            ["127.0.0.0/8", "::1", "10.0.0.0/8"]
+        );
+        assert_eq!(config.access_control.deny, ["192.0.2.0/24"]);
+        assert_eq!(
+            config.client_ip.trusted_proxies,
+            ["127.0.0.1", "10.0.0.0/8"]
+        );
+        assert!(!config.proxy_protocol.enabled);
+        assert_eq!(config.proxy_protocol.header_timeout, Duration::from_secs(5));
+        assert_eq!(
+            config.comparison.retention.max_age,
+            Duration::from_secs(7 * 24 * 3600)
+        );
+        assert_eq!(
+            breaker.on_open,
+            SkippedWrites::Backlog {
+                path: PathBuf::from("/tmp/simple_proxy/backlog.jsonl")
+            }
+        );
+    }
+
+    #[test]
+    fn test_access_log_config() {
+        let config = ProxyConfig::from_yaml(
+            r#"
+access_log:
+  output:
+    type: file
+    path: /var/log/simple_proxy/access.log
+  fields: [timestamp, method, path, status]
+"#,
+        )
+        .expect("Failed to parse config");
+        assert_eq!(
+            config.access_log.output,
+            AccessLogOutput::File {
+                path: PathBuf::from("/var/log/simple_proxy/access.log"),
+                max_size: 100 * 1024 * 1024,
+                max_files: 5,
+            }
+        );
+        assert_eq!(config.access_log.fields.len(), 4);
+        assert!(config.access_log.enabled);
+    }
+
+    #[test]
+    fn test_header_rules_config() {
+        let config = ProxyConfig::from_yaml(
+            r#"
+primary:
+  headers:
+    request:
+      - { action: set, name: x-forwarded-for, value: "{client_ip}" }
+routes:
+  - name: users
+    path_prefix: /users
+    methods: [POST]
+    headers:
+      response:
+        - { action: remove, name: server }
+    mirror_headers:
+      - { action: append, name: x-shadow, value: "true" }
+"#,
+        )
+        .expect("Failed to parse config");
+        assert_eq!(
+            config.primary.headers.request,
+            [HeaderRule::Set {
+                name: "x-forwarded-for".to_string(),
+                value: "{client_ip}".to_string(),
+            }]
+        );
+        assert!(config.primary.headers.response.is_empty());
+        let route = &config.routes[0];
+        assert_eq!(route.methods, ["POST"]);
+        assert_eq!(
+            route.headers.response,
+            [HeaderRule::Remove {
+                name: "server".to_string()
+            }]
+        );
+        assert_eq!(route.mirror_headers.len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        let result = ProxyConfig::from_yaml("listen: 0.0.0.0:8080\nlisten_port: 8080\n");
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,384 @@
+//! CORS policies of the routes, so the backends do not each implement CORS.
+//!
+//! Preflight requests (`OPTIONS` with `Origin` and `Access-Control-Request-Method`) of a route
+//! with a `cors` policy are answered by the proxy in `request_filter`: neither the primary nor
+//! the mirrors see them. They are matched to a route with the requested method rather than
+//! `OPTIONS`. Other requests are proxied whatever their origin, the browser enforces the policy
+//! from the headers added to the response; the `Access-Control-*` headers of the primary are
+//! replaced.
+
+use crate::config::CorsConfig;
+use anyhow::{Result, bail};
+use http::header::{
+    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_LENGTH, ORIGIN, VARY,
+};
+use http::{HeaderName, HeaderValue, Method, StatusCode};
+use pingora::http::{RequestHeader, ResponseHeader};
+
+/// Compiled [`CorsConfig`].
+#[derive(Debug)]
+pub struct CorsPolicy {
+    origins: Vec<OriginPattern>,
+    methods: Vec<Method>,
+    allow_methods: HeaderValue,
+    /// `None` when every requested header is allowed.
+    headers: Option<Vec<HeaderName>>,
+    expose_headers: Option<HeaderValue>,
+    credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+#[derive(Debug)]
+enum OriginPattern {
+    Any,
+    Exact(String),
+    /// `https://*.example.com`: the `*` stands for at least one character.
+    Wildcard(String, String),
+}
+
+impl CorsPolicy {
+    pub fn new(config: &CorsConfig) -> Result<Self> {
+        if config.allowed_origins.is_empty() {
+            bail!("cors.allowed_origins is empty");
+        }
+        let origins: Vec<_> = config
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+                match origin.split_once('*') {
+                    _ if origin == "*" => OriginPattern::Any,
+                    Some((prefix, suffix)) => {
+                        OriginPattern::Wildcard(prefix.to_string(), suffix.to_string())
+                    }
+                    None => OriginPattern::Exact(origin),
+                }
+            })
+            .collect();
+        // 允许凭据时回显任意来源等于关闭同源策略
+        if config.allow_credentials
+            && origins
+                .iter()
+                .any(|origin| matches!(origin, OriginPattern::Any))
+        {
+            bail!("cors.allow_credentials cannot be used with the * origin");
+        }
+        let methods = config
+            .allowed_methods
+            .iter()
+            .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = if config.allowed_headers.iter().any(|name| name == "*") {
+            None
+        } else {
+            Some(
+                config
+                    .allowed_headers
+                    .iter()
+                    .map(|name| HeaderName::from_bytes(name.as_bytes()))
+                    .collect::<Result<_, _>>()?,
+            )
+        };
+        let expose_headers = config
+            .exposed_headers
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).map(|name| name.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            origins,
+            allow_methods: list(methods.iter().map(Method::as_str))?,
+            methods,
+            headers,
+            expose_headers: (!expose_headers.is_empty())
+                .then(|| list(expose_headers.iter().map(String::as_str)))
+                .transpose()?,
+            credentials: config.allow_credentials,
+            max_age: config
+                .max_age
+                .map(|max_age| HeaderValue::from(max_age.as_secs())),
+        })
+    }
+
+    /// Answer to a preflight request, `204 No Content` when the origin, the method and the
+    /// headers are allowed, `403 Forbidden` otherwise.
+    pub fn preflight(&self, req: &RequestHeader) -> ResponseHeader {
+        let origin = req.headers.get(ORIGIN);
+        let allowed_origin = origin.and_then(|origin| self.allow_origin(origin));
+        let method_allowed =
+            preflight_method(req).is_some_and(|method| self.methods.contains(&method));
+        let requested_headers = req
+            .headers
+            .get(ACCESS_CONTROL_REQUEST_HEADERS)
+            .filter(|value| !value.as_bytes().trim_ascii().is_empty());
+        let headers_allowed = match (&self.headers, requested_headers) {
+            (None, _) | (_, None) => true,
+            (Some(allowed), Some(requested)) => requested.to_str().is_ok_and(|requested| {
+                requested.split(',').all(|name| {
+                    let name = name.trim();
+                    allowed
+                        .iter()
+                        .any(|allowed| allowed.as_str().eq_ignore_ascii_case(name))
+                })
+            }),
+        };
+
+        let mut resp = match allowed_origin {
+            Some(allowed_origin) if method_allowed && headers_allowed => {
+                let mut resp = ResponseHeader::build(StatusCode::NO_CONTENT, Some(8)).unwrap();
+                self.insert_origin(&mut resp, allowed_origin);
+                resp.insert_header(ACCESS_CONTROL_ALLOW_METHODS, self.allow_methods.clone())
+                    .unwrap();
+                let allow_headers = match &self.headers {
+                    None => requested_headers.cloned(),
+                    Some(allowed) if !allowed.is_empty() => {
+                        list(allowed.iter().map(HeaderName::as_str)).ok()
+                    }
+                    Some(_) => None,
+                };
+                if let Some(allow_headers) = allow_headers {
+                    resp.insert_header(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers)
+                        .unwrap();
+                }
+                if let Some(max_age) = &self.max_age {
+                    resp.insert_header(ACCESS_CONTROL_MAX_AGE, max_age.clone())
+                        .unwrap();
+                }
+                resp
+            }
+            _ => ResponseHeader::build(StatusCode::FORBIDDEN, Some(4)).unwrap(),
+        };
+        resp.insert_header(CONTENT_LENGTH, 0).unwrap();
+        resp.insert_header(
+            VARY,
+            "origin, access-control-request-method, access-control-request-headers",
+        )
+        .unwrap();
+        resp
+    }
+
+    /// Replaces the CORS headers of the response to a request from `origin`.
+    pub fn apply(&self, origin: Option<&HeaderValue>, resp: &mut ResponseHeader) {
+        for name in [
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS,
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_MAX_AGE,
+        ] {
+            resp.remove_header(&name);
+        }
+        // 回显来源时响应随 Origin 变化
+        if !self.any_origin() {
+            add_vary_origin(resp);
+        }
+        let Some(allowed_origin) = origin.and_then(|origin| self.allow_origin(origin)) else {
+            return;
+        };
+        self.insert_origin(resp, allowed_origin);
+        if let Some(expose_headers) = &self.expose_headers {
+            resp.insert_header(ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers.clone())
+                .unwrap();
+        }
+    }
+
+    /// Value of `Access-Control-Allow-Origin` for `origin`, `None` when it is not allowed.
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.any_origin() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let value = origin.to_str().ok()?.to_ascii_lowercase();
+        self.origins
+            .iter()
+            .any(|pattern| match pattern {
+                OriginPattern::Any => true,
+                OriginPattern::Exact(exact) => value == *exact,
+                OriginPattern::Wildcard(prefix, suffix) => {
+                    value.len() > prefix.len() + suffix.len()
+                        && value.starts_with(prefix.as_str())
+                        && value.ends_with(suffix.as_str())
+                }
+            })
+            .then(|| origin.clone())
+    }
+
+    fn any_origin(&self) -> bool {
+        self.origins
+            .iter()
+            .any(|origin| matches!(origin, OriginPattern::Any))
+    }
+
+    fn insert_origin(&self, resp: &mut ResponseHeader, allowed_origin: HeaderValue) {
+        resp.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin)
+            .unwrap();
+        if self.credentials {
+            resp.insert_header(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")
+                .unwrap();
+        }
+    }
+}
+
+/// Whether the request is a CORS preflight rather than a plain `OPTIONS` request.
+pub fn is_preflight(req: &RequestHeader) -> bool {
+    req.method == Method::OPTIONS
+        && req.headers.contains_key(ORIGIN)
+        && req.headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Method the preflight asks for, used to select the route.
+pub fn preflight_method(req: &RequestHeader) -> Option<Method> {
+    req.headers
+        .get(ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+}
+
+fn list<'a>(items: impl Iterator<Item = &'a str>) -> Result<HeaderValue> {
+    Ok(HeaderValue::from_str(
+        &items.collect::<Vec<_>>().join(", "),
+    )?)
+}
+
+fn add_vary_origin(resp: &mut ResponseHeader) {
+    let present = resp.headers.get_all(VARY).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|name| name == "*" || name.eq_ignore_ascii_case(ORIGIN.as_str()))
+        })
+    });
+    if !present {
+        resp.append_header(VARY, HeaderValue::from_name(ORIGIN))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn policy() -> CorsPolicy {
+        CorsPolicy::new(&CorsConfig {
+            allowed_origins: vec![
+                "https://app.example.com".to_string(),
+                "https://*.preview.example.com".to_string(),
+            ],
+            allowed_headers: vec!["content-type".to_string(), "x-request-id".to_string()],
+            exposed_headers: vec!["x-request-id".to_string()],
+            allow_credentials: true,
+            max_age: Some(Duration::from_secs(600)),
+            ..Default::default()
+        })
+        .expect("Failed to build CORS policy")
+    }
+
+    fn preflight(origin: &str, method: &str, headers: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("OPTIONS", b"/users", None).unwrap();
+        req.insert_header(ORIGIN, origin).unwrap();
+        req.insert_header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .unwrap();
+        if let Some(headers) = headers {
+            req.insert_header(ACCESS_CONTROL_REQUEST_HEADERS, headers)
+                .unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_preflight() {
+        let policy = policy();
+        let req = preflight("https://app.example.com", "PUT", Some("Content-Type"));
+        assert!(is_preflight(&req));
+        assert_eq!(preflight_method(&req), Some(Method::PUT));
+
+        let resp = policy.preflight(&req);
+        assert_eq!(resp.status, StatusCode::NO_CONTENT);
+        let header = |name| resp.headers.get(name).unwrap().to_str().unwrap();
+        assert_eq!(
+            header(ACCESS_CONTROL_ALLOW_ORIGIN),
+            "https://app.example.com"
+        );
+        assert_eq!(header(ACCESS_CONTROL_ALLOW_CREDENTIALS), "true");
+        assert_eq!(
+            header(ACCESS_CONTROL_ALLOW_METHODS),
+            "GET, HEAD, POST, PUT, PATCH, DELETE"
+        );
+        assert_eq!(
+            header(ACCESS_CONTROL_ALLOW_HEADERS),
+            "content-type, x-request-id"
+        );
+        assert_eq!(header(ACCESS_CONTROL_MAX_AGE), "600");
+
+        // 通配符至少匹配一个字符
+        let resp = policy.preflight(&preflight("https://pr-12.preview.example.com", "GET", None));
+        assert_eq!(resp.status, StatusCode::NO_CONTENT);
+        let resp = policy.preflight(&preflight("https://.preview.example.com", "GET", None));
+        assert_eq!(resp.status, StatusCode::FORBIDDEN);
+
+        for req in [
+            preflight("https://evil.example.com", "GET", None),
+            preflight("https://app.example.com", "TRACE", None),
+            preflight(
+                "https://app.example.com",
+                "POST",
+                Some("content-type, x-debug"),
+            ),
+        ] {
+            let resp = policy.preflight(&req);
+            assert_eq!(resp.status, StatusCode::FORBIDDEN);
+            assert!(!resp.headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        }
+        assert!(!is_preflight(
+            &RequestHeader::build("OPTIONS", b"/users", None).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_apply() {
+        let policy = policy();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .unwrap();
+        let origin = HeaderValue::from_static("https://app.example.com");
+        policy.apply(Some(&origin), &mut resp);
+        assert_eq!(resp.headers[ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(resp.headers[ACCESS_CONTROL_EXPOSE_HEADERS], "x-request-id");
+        assert_eq!(resp.headers[VARY], "origin");
+
+        // 不允许的来源不返回 CORS 响应头，上游的也被移除
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .unwrap();
+        let origin = HeaderValue::from_static("https://evil.example.com");
+        policy.apply(Some(&origin), &mut resp);
+        assert!(!resp.headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(resp.headers[VARY], "origin");
+    }
+
+    #[test]
+    fn test_any_origin() {
+        let config = CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            allowed_headers: vec!["*".to_string()],
+            ..Default::default()
+        };
+        let policy = CorsPolicy::new(&config).expect("Failed to build CORS policy");
+        let resp = policy.preflight(&preflight("https://any.test", "POST", Some("x-custom")));
+        assert_eq!(resp.headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(resp.headers[ACCESS_CONTROL_ALLOW_HEADERS], "x-custom");
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        policy.apply(None, &mut resp);
+        assert!(!resp.headers.contains_key(VARY));
+
+        let credentials = CorsConfig {
+            allow_credentials: true,
+            ..config
+        };
+        assert!(CorsPolicy::new(&credentials).is_err());
+        assert!(CorsPolicy::new(&CorsConfig::default()).is_err());
+    }
+}
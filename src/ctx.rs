@@ -0,0 +1,102 @@
+use crate::compare::ResponseCapture;
+use crate::mirror::{MirrorOutcome, MirrorRequest};
+use crate::recording::Recording;
+use crate::retry::{RequestGuard, RetryPermit};
+use crate::route::Route;
+use crate::shutdown::InFlightRequest;
+use crate::template::TemplateVars;
+use crate::websocket::WebSocketMirror;
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use http::Extensions;
+use opentelemetry::Context;
+use pingora::lb::Backend;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Per request state of [`crate::DualWriteProxy`].
+pub struct ProxyCtx {
+    /// Id correlating the client request, the primary request and its mirrored copies.
+    pub request_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub started_at: Instant,
+    /// Client of the connection, from the PROXY protocol header when it is enabled.
+    pub client_addr: Option<SocketAddr>,
+    /// Client address after the trusted proxies, see [`crate::access`].
+    pub client_ip: Option<IpAddr>,
+    /// Route matched by the client request.
+    pub route: Option<Arc<Route>>,
+    /// Values of the template variables for this request.
+    pub template_vars: TemplateVars,
+    /// Number of upstream attempts made so far.
+    pub tries: usize,
+    /// Backends that already failed for this request, skipped when selecting the next one.
+    pub failed_backends: Vec<Backend>,
+    /// Backend of the current attempt.
+    pub backend: Option<Backend>,
+    pub retry_permit: Option<RetryPermit>,
+    /// Start of the current upstream attempt, including the connect.
+    pub upstream_started_at: Option<Instant>,
+    /// Time until the upstream response header was received.
+    pub upstream_latency: Option<Duration>,
+    /// Set when a script hook disabled mirroring for this request.
+    pub skip_mirror: bool,
+    /// Copy of the request waiting for its body before being sent to the mirrors.
+    pub pending_mirror: Option<MirrorRequest>,
+    /// Request body read so far for the mirrored copy.
+    pub mirror_body: BytesMut,
+    pub mirror_outcomes: Vec<(String, MirrorOutcome)>,
+    /// Trace context holding the span of the client request.
+    pub trace: Context,
+    /// Trace context holding the span of the current primary upstream attempt.
+    pub upstream_trace: Option<Context>,
+    /// Exchange with the primary being recorded.
+    pub recording: Option<Recording>,
+    /// Primary response being captured for the comparison with the mirror responses.
+    pub response_capture: Option<ResponseCapture>,
+    /// Set when the response cache was looked up for this request.
+    pub cache_lookup: bool,
+    /// Freshness of the response when cached, overriding its `Cache-Control`.
+    pub cache_ttl: Option<Duration>,
+    /// Mirror connections of an upgraded WebSocket request.
+    pub websocket_mirror: Option<WebSocketMirror>,
+    /// State of the [`crate::filter::ProxyFilter`]s, keyed by type.
+    pub extensions: Extensions,
+    pub(crate) _request: RequestGuard,
+    pub(crate) _in_flight: InFlightRequest,
+}
+
+impl ProxyCtx {
+    pub(crate) fn new(request: RequestGuard, in_flight: InFlightRequest) -> Self {
+        Self {
+            request_id: String::new(),
+            timestamp: Utc::now(),
+            started_at: Instant::now(),
+            client_addr: None,
+            client_ip: None,
+            route: None,
+            template_vars: TemplateVars::default(),
+            tries: 0,
+            failed_backends: Vec::new(),
+            backend: None,
+            retry_permit: None,
+            upstream_started_at: None,
+            upstream_latency: None,
+            skip_mirror: false,
+            pending_mirror: None,
+            mirror_body: BytesMut::new(),
+            mirror_outcomes: Vec::new(),
+            trace: Context::new(),
+            upstream_trace: None,
+            recording: None,
+            response_capture: None,
+            cache_lookup: false,
+            cache_ttl: None,
+            websocket_mirror: None,
+            extensions: Extensions::new(),
+            _request: request,
+            _in_flight: in_flight,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Live events of the proxy, streamed by the admin listener (see [`crate::admin`]).
+//!
+//! Events are published on a broadcast channel. Publishing never blocks the proxy: without
+//! subscribers events are dropped, and a subscriber that falls behind misses the oldest ones.
+
+use crate::access_log::AccessLogRecord;
+use crate::mirror::MirrorOutcome;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProxyEvent {
+    /// A client request is done.
+    Request {
+        timestamp: DateTime<Utc>,
+        request_id: String,
+        method: String,
+        path: String,
+        route: Option<String>,
+        status: Option<u16>,
+        upstream: Option<String>,
+        duration_ms: f64,
+        mirrors: BTreeMap<String, MirrorOutcome>,
+        error: Option<String>,
+    },
+    /// The mirrored copy of a request got its response, or failed.
+    Mirror {
+        timestamp: DateTime<Utc>,
+        request_id: String,
+        mirror: String,
+        status: Option<u16>,
+        duration_ms: f64,
+        error: Option<String>,
+    },
+    /// A mirror response differed from the primary response.
+    Mismatch {
+        timestamp: DateTime<Utc>,
+        request_id: String,
+        route: Option<String>,
+        mirror: String,
+        fields: Vec<String>,
+    },
+}
+
+impl ProxyEvent {
+    pub fn request(record: &AccessLogRecord, route: Option<String>) -> Self {
+        ProxyEvent::Request {
+            timestamp: record.timestamp,
+            request_id: record.request_id.clone().unwrap_or_default(),
+            method: record.method.clone(),
+            path: record.path.clone(),
+            route,
+            status: record.status,
+            upstream: record.upstream.clone(),
+            duration_ms: record.duration.as_secs_f64() * 1000.0,
+            mirrors: record.mirrors.iter().cloned().collect(),
+            error: record.error.clone(),
+        }
+    }
+
+    /// Value of the `type` field, also used as the SSE event name.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProxyEvent::Request { .. } => "request",
+            ProxyEvent::Mirror { .. } => "mirror",
+            ProxyEvent::Mismatch { .. } => "mismatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<ProxyEvent>>,
+}
+
+impl EventBus {
+    /// `capacity` events are buffered per subscriber.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    /// Whether anyone listens, lets callers skip building events nobody receives.
+    pub fn is_active(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, event: ProxyEvent) {
+        // 没有订阅者时发送失败，直接丢弃
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<ProxyEvent>> {
+        self.sender.subscribe()
+    }
+}
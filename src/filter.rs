@@ -0,0 +1,285 @@
+//! In-process filters run by [`crate::DualWriteProxy`].
+//!
+//! A [`ProxyFilter`] implements any of the hooks below, all default to doing nothing. Filters
+//! run after the proxy's own processing of each phase (request id, scripts, plugins, route and
+//! header rules), so `ctx` is already populated. Request hooks are called in the order the
+//! filters were added, response and logging hooks in reverse order, so the first filter wraps
+//! all the others.
+//!
+//! Changes made by the request hooks are seen by the primary and the mirrors, except for
+//! [`ProxyFilter::upstream_request_filter`] which only affects the primary request, like the
+//! primary header rules. State private to a filter can be kept in [`ProxyCtx::extensions`].
+
+use crate::ctx::ProxyCtx;
+use async_trait::async_trait;
+use bytes::Bytes;
+use pingora::{
+    Result,
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+use std::sync::Arc;
+
+#[async_trait]
+pub trait ProxyFilter: Send + Sync {
+    /// Name used in logs.
+    fn name(&self) -> &str;
+
+    /// Called once the client request header is read. Returning `true` means the filter already
+    /// sent a response and the request is not proxied.
+    async fn request_filter(&self, _session: &mut Session, _ctx: &mut ProxyCtx) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Called before the request is sent to the primary, on every attempt.
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_request: &mut RequestHeader,
+        _ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for each chunk of the client request body, before it is copied for the mirrors.
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with the response header sent to the client, from the primary or from the cache.
+    fn response_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_response: &mut ResponseHeader,
+        _ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for each chunk of the response body.
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the request is done, `e` is set when it failed.
+    async fn logging(
+        &self,
+        _session: &mut Session,
+        _e: Option<&pingora::Error>,
+        _ctx: &mut ProxyCtx,
+    ) {
+    }
+}
+
+/// Ordered list of [`ProxyFilter`]s.
+#[derive(Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<Arc<dyn ProxyFilter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, filter: impl ProxyFilter + 'static) {
+        self.filters.push(Arc::new(filter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.filters.iter().map(|filter| filter.name()).collect()
+    }
+
+    /// Stops at the first filter that sent a response.
+    pub async fn request_filter(&self, session: &mut Session, ctx: &mut ProxyCtx) -> Result<bool> {
+        for filter in &self.filters {
+            if filter.request_filter(session, ctx).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        for filter in &self.filters {
+            filter
+                .upstream_request_filter(session, upstream_request, ctx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        for filter in &self.filters {
+            filter
+                .request_body_filter(session, body, end_of_stream, ctx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        for filter in self.filters.iter().rev() {
+            filter.response_filter(session, upstream_response, ctx)?;
+        }
+        Ok(())
+    }
+
+    pub fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut ProxyCtx,
+    ) -> Result<()> {
+        for filter in self.filters.iter().rev() {
+            filter.response_body_filter(session, body, end_of_stream, ctx)?;
+        }
+        Ok(())
+    }
+
+    pub async fn logging(
+        &self,
+        session: &mut Session,
+        e: Option<&pingora::Error>,
+        ctx: &mut ProxyCtx,
+    ) {
+        for filter in self.filters.iter().rev() {
+            filter.logging(session, e, ctx).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RetryBudgetConfig, ShutdownConfig};
+    use crate::retry::RetryBudget;
+    use crate::shutdown::ShutdownDrain;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    /// Records the hooks called on it, `respond` makes its request filter answer the request.
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+        respond: bool,
+    }
+
+    #[async_trait]
+    impl ProxyFilter for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn request_filter(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut ProxyCtx,
+        ) -> Result<bool> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} request", self.name));
+            Ok(self.respond)
+        }
+
+        fn response_filter(
+            &self,
+            _session: &mut Session,
+            upstream_response: &mut ResponseHeader,
+            _ctx: &mut ProxyCtx,
+        ) -> Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} response", self.name));
+            upstream_response.append_header("x-filter", self.name)?;
+            Ok(())
+        }
+    }
+
+    /// Returns the session with the client end of the connection, which must stay open.
+    async fn session() -> (Session, DuplexStream) {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET /users HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut session = Session::new_h1(Box::new(server));
+        session
+            .read_request()
+            .await
+            .expect("Failed to read request");
+        (session, client)
+    }
+
+    #[tokio::test]
+    async fn test_chain_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = FilterChain::new();
+        for (name, respond) in [("auth", false), ("cache", true), ("never", false)] {
+            chain.push(Recorder {
+                name,
+                calls: calls.clone(),
+                respond,
+            });
+        }
+        assert_eq!(chain.names(), ["auth", "cache", "never"]);
+
+        let (mut session, _client) = session().await;
+        let budget = RetryBudget::new(&RetryBudgetConfig::default());
+        let drain = ShutdownDrain::new(&ShutdownConfig::default());
+        let mut ctx = ProxyCtx::new(budget.track_request(), drain.track_request());
+        assert!(chain.request_filter(&mut session, &mut ctx).await.unwrap());
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        chain
+            .response_filter(&mut session, &mut response, &mut ctx)
+            .unwrap();
+        let values: Vec<_> = response.headers.get_all("x-filter").iter().collect();
+        assert_eq!(values, ["never", "cache", "auth"]);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "auth request",
+                "cache request",
+                "never response",
+                "cache response",
+                "auth response"
+            ]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+//! gRPC proxying and mirroring of unary calls.
+//!
+//! gRPC calls (`content-type: application/grpc`) are always sent to the primary over HTTP/2,
+//! whatever `primary.http_version`, and its response trailers are passed back to the client.
+//! Unary calls are mirrored like other requests, over HTTP/2, streaming calls are not mirrored.
+//! A call is unary when its method is declared so in the descriptor sets, a call of a method
+//! missing from them when its request holds a single message.
+//!
+//! The responses are compared by `grpc-status` and by their message, decoded with the
+//! descriptor sets into JSON with the proto field names, see [`GrpcDescriptors::compared_response`].
+
+use crate::config::GrpcConfig;
+use anyhow::{Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use http::header::CONTENT_TYPE;
+use http::{HeaderMap, HeaderName};
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, MessageDescriptor, MethodDescriptor, ReflectMessage,
+    SerializeOptions, Value,
+};
+use std::collections::HashSet;
+
+pub const GRPC_STATUS: HeaderName = HeaderName::from_static("grpc-status");
+
+/// Whether the request or response is a gRPC call, gRPC-Web is proxied as plain HTTP.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type == "application/grpc"
+                || content_type.starts_with("application/grpc+")
+                || content_type.starts_with("application/grpc;")
+        })
+}
+
+/// Status of a gRPC response, from the trailers or the headers of a trailers-only response.
+pub fn status(headers: &HeaderMap, trailers: &HeaderMap) -> Option<u32> {
+    trailers
+        .get(GRPC_STATUS)
+        .or_else(|| headers.get(GRPC_STATUS))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Whether the status is an error of the server (`UNKNOWN`, `DEADLINE_EXCEEDED`,
+/// `UNIMPLEMENTED`, `INTERNAL`, `UNAVAILABLE`, `DATA_LOSS`), the codes mapped to 5xx.
+pub fn is_server_error(status: u32) -> bool {
+    matches!(status, 2 | 4 | 12 | 13 | 14 | 15)
+}
+
+/// A length prefixed message of a gRPC body.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GrpcMessage<'a> {
+    /// Compressed with the `grpc-encoding` of the call.
+    pub compressed: bool,
+    pub data: &'a [u8],
+}
+
+/// Messages of a gRPC body, `None` when it does not end on a complete message.
+pub fn messages(mut body: &[u8]) -> Option<Vec<GrpcMessage<'_>>> {
+    let mut messages = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 5 {
+            return None;
+        }
+        let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        let data = body.get(5..5 + len)?;
+        messages.push(GrpcMessage {
+            compressed: body[0] == 1,
+            data,
+        });
+        body = &body[5 + len..];
+    }
+    Some(messages)
+}
+
+/// Adds the length prefix of an uncompressed message.
+pub fn frame(message: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(message.len() + 5);
+    frame.put_u8(0);
+    frame.put_u32(message.len() as u32);
+    frame.put_slice(message);
+    frame.freeze()
+}
+
+/// Services and messages of the descriptor sets.
+#[derive(Debug, Default)]
+pub struct GrpcDescriptors {
+    pool: DescriptorPool,
+    ignore_fields: HashSet<String>,
+}
+
+impl GrpcDescriptors {
+    pub fn load(config: &GrpcConfig) -> Result<Self> {
+        let mut pool = DescriptorPool::new();
+        for path in &config.descriptor_sets {
+            let bytes = std::fs::read(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            pool.decode_file_descriptor_set(bytes.as_slice())
+                .with_context(|| format!("invalid descriptor set {}", path.display()))?;
+        }
+        Ok(Self {
+            pool,
+            ignore_fields: config.ignore_fields.iter().cloned().collect(),
+        })
+    }
+
+    /// Fully qualified names of the services, for the validation.
+    pub fn services(&self) -> Vec<String> {
+        self.pool
+            .services()
+            .map(|service| service.full_name().to_string())
+            .collect()
+    }
+
+    /// Whether a message of the descriptor sets has a field with this fully qualified name.
+    pub fn has_field(&self, full_name: &str) -> bool {
+        full_name.rsplit_once('.').is_some_and(|(message, field)| {
+            self.pool
+                .get_message_by_name(message)
+                .is_some_and(|message| message.get_field_by_name(field).is_some())
+        })
+    }
+
+    /// Method called by the request path `/package.Service/Method`.
+    pub fn method(&self, path: &str) -> Option<MethodDescriptor> {
+        let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+        self.pool
+            .get_service_by_name(service)?
+            .methods()
+            .find(|descriptor| descriptor.name() == method)
+    }
+
+    /// Whether the method is declared as streaming, its requests are not buffered for the
+    /// mirrors.
+    pub fn is_streaming(&self, path: &str) -> bool {
+        self.method(path)
+            .is_some_and(|method| method.is_client_streaming() || method.is_server_streaming())
+    }
+
+    /// Whether the call is unary, by its method or, for unknown methods, by its request body.
+    pub fn is_unary(&self, path: &str, request_body: &[u8]) -> bool {
+        match self.method(path) {
+            Some(method) => !method.is_client_streaming() && !method.is_server_streaming(),
+            None => messages(request_body).is_some_and(|messages| messages.len() == 1),
+        }
+    }
+
+    /// Request message of a unary call as JSON, see [`Self::decode_response`].
+    pub fn decode_request(&self, path: &str, body: &[u8]) -> Option<serde_json::Value> {
+        self.decode(self.method(path)?.input(), body, false)
+    }
+
+    /// Response message of a unary call as JSON. `None` when the method is unknown or the body
+    /// is not a single uncompressed message, `null` when the body is empty (error responses).
+    pub fn decode_response(&self, path: &str, body: &[u8]) -> Option<serde_json::Value> {
+        self.decode(self.method(path)?.output(), body, false)
+    }
+
+    /// Response message as compared, with the ignored fields reset to their default value.
+    pub fn compared_response(&self, path: &str, body: &[u8]) -> Option<serde_json::Value> {
+        self.decode(self.method(path)?.output(), body, true)
+    }
+
+    fn decode(
+        &self,
+        descriptor: MessageDescriptor,
+        body: &[u8],
+        clear_ignored: bool,
+    ) -> Option<serde_json::Value> {
+        let data = match messages(body)?.as_slice() {
+            [] => return Some(serde_json::Value::Null),
+            [message] if !message.compressed => message.data,
+            _ => return None,
+        };
+        let mut message = DynamicMessage::decode(descriptor, data).ok()?;
+        if clear_ignored {
+            self.clear_ignored(&mut message);
+        }
+        // 保留默认值字段，缺失的字段和默认值比较时不会被当作差异
+        let options = SerializeOptions::new()
+            .use_proto_field_name(true)
+            .skip_default_fields(false)
+            .stringify_64_bit_integers(false);
+        message
+            .serialize_with_options(serde_json::value::Serializer, &options)
+            .ok()
+    }
+
+    fn clear_ignored(&self, message: &mut DynamicMessage) {
+        if self.ignore_fields.is_empty() {
+            return;
+        }
+        let fields: Vec<_> = message.descriptor().fields().collect();
+        for field in fields {
+            if self.ignore_fields.contains(field.full_name()) {
+                message.clear_field(&field);
+                continue;
+            }
+            if !message.has_field(&field) {
+                continue;
+            }
+            match message.get_field_mut(&field) {
+                Value::Message(nested) => self.clear_ignored(nested),
+                Value::List(values) => values.iter_mut().for_each(|value| self.clear_value(value)),
+                Value::Map(values) => values
+                    .values_mut()
+                    .for_each(|value| self.clear_value(value)),
+                _ => {}
+            }
+        }
+    }
+
+    fn clear_value(&self, value: &mut Value) {
+        if let Value::Message(message) = value {
+            self.clear_ignored(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use prost::Message;
+    use std::path::PathBuf;
+
+    fn descriptors() -> GrpcDescriptors {
+        GrpcDescriptors::load(&GrpcConfig {
+            descriptor_sets: vec![PathBuf::from("fixtures/grpc/users.pb")],
+            ignore_fields: vec!["users.v1.User.updated_at".to_string()],
+        })
+        .expect("Failed to load descriptor sets")
+    }
+
+    fn user(descriptors: &GrpcDescriptors, json: &str) -> Bytes {
+        let descriptor = descriptors
+            .pool
+            .get_message_by_name("users.v1.User")
+            .expect("Failed to find message");
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        let message = DynamicMessage::deserialize(descriptor, &mut deserializer)
+            .expect("Failed to build message");
+        frame(&message.encode_to_vec())
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut body = BytesMut::from(&frame(b"abc")[..]);
+        body.extend_from_slice(&frame(b"")[..]);
+        assert_eq!(
+            messages(&body),
+            Some(vec![
+                GrpcMessage {
+                    compressed: false,
+                    data: b"abc"
+                },
+                GrpcMessage {
+                    compressed: false,
+                    data: b""
+                }
+            ])
+        );
+        // 不完整的消息
+        assert_eq!(messages(&body[..6]), None);
+        assert_eq!(messages(b""), Some(vec![]));
+
+        let mut headers = HeaderMap::new();
+        assert!(!is_grpc(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc+proto"),
+        );
+        assert!(is_grpc(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc-web"),
+        );
+        assert!(!is_grpc(&headers));
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert(GRPC_STATUS, HeaderValue::from_static("5"));
+        assert_eq!(status(&headers, &trailers), Some(5));
+        assert_eq!(status(&trailers, &HeaderMap::new()), Some(5));
+        assert!(!is_server_error(5));
+        assert!(is_server_error(14));
+    }
+
+    #[test]
+    fn test_methods() {
+        let descriptors = descriptors();
+        assert_eq!(descriptors.services(), ["users.v1.UserService"]);
+        assert!(descriptors.has_field("users.v1.User.updated_at"));
+        assert!(!descriptors.has_field("users.v1.User.missing"));
+
+        let single = frame(b"\x08\x01");
+        assert!(descriptors.is_unary("/users.v1.UserService/GetUser", b""));
+        assert!(descriptors.is_streaming("/users.v1.UserService/WatchUsers"));
+        assert!(!descriptors.is_unary("/users.v1.UserService/WatchUsers", &single));
+        // 未知的方法按请求中的消息数判断
+        assert!(descriptors.is_unary("/other.Service/Get", &single));
+        assert!(!descriptors.is_unary("/other.Service/Get", b""));
+    }
+
+    #[test]
+    fn test_decode_messages() {
+        let descriptors = descriptors();
+        let path = "/users.v1.UserService/GetUser";
+        let body = user(
+            &descriptors,
+            r#"{"id":1,"name":"Alice","updatedAt":"42","roles":["admin"],"status":"STATUS_ACTIVE"}"#,
+        );
+        let decoded = descriptors
+            .decode_response(path, &body)
+            .expect("Failed to decode response");
+        assert_eq!(decoded["updated_at"], 42);
+        let decoded = descriptors
+            .compared_response(path, &body)
+            .expect("Failed to decode response");
+        assert_eq!(decoded["id"], 1);
+        assert_eq!(decoded["name"], "Alice");
+        assert_eq!(decoded["updated_at"], 0);
+        assert_eq!(decoded["status"], "STATUS_ACTIVE");
+        assert_eq!(decoded["address"], serde_json::Value::Null);
+
+        let request = descriptors
+            .decode_request(path, &frame(b"\x08\x07"))
+            .expect("Failed to decode request");
+        assert_eq!(request["id"], 7);
+        assert_eq!(
+            descriptors.decode_response(path, b""),
+            Some(serde_json::Value::Null)
+        );
+        assert_eq!(
+            descriptors.decode_response("/other.Service/Get", &body),
+            None
+        );
+    }
+}
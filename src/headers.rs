@@ -0,0 +1,240 @@
+use crate::config::HeaderRule;
+use crate::template::{Template, TemplateVars};
+use anyhow::{Result, anyhow};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use pingora::http::{RequestHeader, ResponseHeader};
+use tracing::warn;
+
+/// Header collections the rules can be applied to.
+pub trait HeaderTarget {
+    fn headers(&self) -> &HeaderMap;
+    fn set(&mut self, name: HeaderName, value: HeaderValue);
+    fn append(&mut self, name: HeaderName, value: HeaderValue);
+    fn remove(&mut self, name: &HeaderName);
+}
+
+/// Compiled list of [`HeaderRule`]s: header names and templates are validated once at startup.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewriter {
+    rules: Vec<CompiledRule>,
+}
+
+#[derive(Debug, Clone)]
+enum CompiledRule {
+    Set(HeaderName, Template),
+    Append(HeaderName, Template),
+    Remove(HeaderName),
+}
+
+impl HeaderRewriter {
+    pub fn new(rules: &[HeaderRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(match rule {
+                    HeaderRule::Set { name, value } => {
+                        CompiledRule::Set(header_name(name)?, template(value)?)
+                    }
+                    HeaderRule::Append { name, value } => {
+                        CompiledRule::Append(header_name(name)?, template(value)?)
+                    }
+                    HeaderRule::Remove { name } => CompiledRule::Remove(header_name(name)?),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn apply(&self, headers: &mut impl HeaderTarget, vars: &TemplateVars) {
+        for rule in &self.rules {
+            match rule {
+                CompiledRule::Set(name, template) => {
+                    if let Some(value) = render(template, name, vars) {
+                        headers.set(name.clone(), value);
+                    }
+                }
+                CompiledRule::Append(name, template) => {
+                    if let Some(value) = render(template, name, vars) {
+                        headers.append(name.clone(), value);
+                    }
+                }
+                CompiledRule::Remove(name) => headers.remove(name),
+            }
+        }
+    }
+}
+
+/// Makes `target` hold exactly `headers`, leaving the headers that did not change untouched.
+pub fn sync(target: &mut impl HeaderTarget, headers: &HeaderMap) {
+    let current = target.headers();
+    let removed: Vec<HeaderName> = current
+        .keys()
+        .filter(|name| !headers.contains_key(*name))
+        .cloned()
+        .collect();
+    let changed: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| current.get_all(*name) != headers.get_all(*name))
+        .cloned()
+        .collect();
+    for name in removed {
+        target.remove(&name);
+    }
+    for name in changed {
+        target.remove(&name);
+        for value in headers.get_all(&name) {
+            target.append(name.clone(), value.clone());
+        }
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| anyhow!("invalid header name {name:?}"))
+}
+
+/// Parses a header value template, its literal parts must be valid header value characters.
+fn template(value: &str) -> Result<Template> {
+    let template = Template::parse(value)?;
+    for literal in template.literals() {
+        HeaderValue::from_str(literal).map_err(|_| anyhow!("invalid header value {value:?}"))?;
+    }
+    Ok(template)
+}
+
+fn render(template: &Template, name: &HeaderName, vars: &TemplateVars) -> Option<HeaderValue> {
+    HeaderValue::from_str(&template.render(vars))
+        .inspect_err(
+            |_| warn!(request_id = %vars.request_id, "skipping invalid value for header {}", name),
+        )
+        .ok()
+}
+
+impl HeaderTarget for HeaderMap {
+    fn headers(&self) -> &HeaderMap {
+        self
+    }
+
+    fn set(&mut self, name: HeaderName, value: HeaderValue) {
+        self.insert(name, value);
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) {
+        HeaderMap::append(self, name, value);
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        HeaderMap::remove(self, name);
+    }
+}
+
+// pingora 的请求/响应头在 HeaderMap 之外还维护了大小写信息，需要通过它们自己的方法修改
+impl HeaderTarget for RequestHeader {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn set(&mut self, name: HeaderName, value: HeaderValue) {
+        let _ = self.insert_header(name, value);
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) {
+        let _ = self.append_header(name, value);
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+impl HeaderTarget for ResponseHeader {
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn set(&mut self, name: HeaderName, value: HeaderValue) {
+        let _ = self.insert_header(name, value);
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) {
+        let _ = self.append_header(name, value);
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> TemplateVars {
+        TemplateVars {
+            client_ip: "10.0.0.1".to_string(),
+            request_id: "abc".to_string(),
+            route: "users".to_string(),
+            method: "POST".to_string(),
+            host: "example.com".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_apply_rules_in_order() {
+        let rewriter = HeaderRewriter::new(&[
+            HeaderRule::Set {
+                name: "x-forwarded-for".to_string(),
+                value: "{client_ip}".to_string(),
+            },
+            HeaderRule::Append {
+                name: "via".to_string(),
+                value: "proxy/{route} ({request_id})".to_string(),
+            },
+            HeaderRule::Remove {
+                name: "cookie".to_string(),
+            },
+        ])
+        .expect("Failed to compile rules");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4"));
+        headers.insert("via", HeaderValue::from_static("1.1 cdn"));
+        headers.insert("cookie", HeaderValue::from_static("session=1"));
+        rewriter.apply(&mut headers, &vars());
+
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, ["1.1 cdn", "proxy/users (abc)"]);
+        assert!(!headers.contains_key("cookie"));
+    }
+
+    #[test]
+    fn test_apply_to_pingora_headers() {
+        let rewriter = HeaderRewriter::new(&[HeaderRule::Set {
+            name: "x-shadow".to_string(),
+            value: "{method} {host} {{literal}}".to_string(),
+        }])
+        .expect("Failed to compile rules");
+
+        let mut request = RequestHeader::build("POST", b"/users", None).unwrap();
+        rewriter.apply(&mut request, &vars());
+        assert_eq!(request.headers["x-shadow"], "POST example.com {literal}");
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        rewriter.apply(&mut response, &vars());
+        assert_eq!(response.headers["x-shadow"], "POST example.com {literal}");
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        let set = |name: &str, value: &str| {
+            HeaderRewriter::new(&[HeaderRule::Set {
+                name: name.to_string(),
+                value: value.to_string(),
+            }])
+        };
+        assert!(set("bad header", "value").is_err());
+        assert!(set("x-test", "{unknown}").is_err());
+        assert!(set("x-test", "{client_ip").is_err());
+        assert!(set("x-test", "line\nbreak").is_err());
+    }
+}
@@ -1,114 +1,826 @@
+pub mod access;
+pub mod access_log;
+pub mod admin;
+pub mod backlog;
+pub mod body;
+pub mod cache;
+pub mod circuit_breaker;
+pub mod cli;
+pub mod compare;
+pub mod compression;
+pub mod config;
+pub mod cors;
+pub mod ctx;
+pub mod events;
+pub mod filter;
+pub mod grpc;
+pub mod headers;
+pub mod metrics;
+pub mod mirror;
+pub mod mismatch;
+pub mod plugin;
+pub mod proxy_protocol;
+pub mod recording;
+pub mod replay;
+pub mod request_id;
+pub mod retry;
+pub mod rewrite;
+pub mod route;
+pub mod scripting;
+pub mod shutdown;
+pub mod telemetry;
+pub mod template;
+pub mod validate;
+pub mod websocket;
+
+use access::{ClientIpResolver, IpAccessList};
+use access_log::{AccessLog, AccessLogRecord};
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use http::HeaderName;
+use body::BodyTransform;
+use bytes::{Bytes, BytesMut};
+use cache::ResponseCache;
+use compare::Comparator;
+use compression::{Compression, ResponseCompressor};
+use config::{HttpVersion, PrimaryConfig, ProxyConfig, RequestIdConfig, WebSocketConfig};
+use ctx::ProxyCtx;
+use events::{EventBus, ProxyEvent};
+use filter::{FilterChain, ProxyFilter};
+use grpc::GrpcDescriptors;
+use headers::HeaderRewriter;
+use http::header::{
+    AUTHORIZATION, CONTENT_ENCODING, ORIGIN, SEC_WEBSOCKET_EXTENSIONS, TRANSFER_ENCODING,
+};
+use http::{HeaderMap, HeaderName, Method};
+use metrics::{ACCESS_DENIED, CACHE_LOOKUPS, CORS_PREFLIGHTS, UPSTREAM_RETRIES};
+use mirror::{MirrorOutcome, MirrorRequest, MirrorTarget};
+use opentelemetry::{KeyValue, trace::TraceContextExt};
 use pingora::{
+    ErrorType,
+    cache::{CacheKey, CacheMeta, RespCacheable, key::HashBinary},
     http::{RequestHeader, ResponseHeader},
+    lb::{LoadBalancer, selection::RoundRobin},
+    modules::http::{HttpModules, compression::ResponseCompressionBuilder},
     prelude::HttpPeer,
+    protocols::ALPN,
     proxy::{ProxyHttp, Session},
+    server::ShutdownWatch,
+    services::background::BackgroundService,
 };
-use reqwest::Url;
+use plugin::Plugins;
+use proxy_protocol::ProxiedClients;
+use recording::Recorder;
+use retry::RetryBudget;
+use rewrite::UrlRewriter;
+use route::{Route, Router};
+use scripting::{Hook, ScriptRequest, Scripts};
+use shutdown::ShutdownDrain;
 use std::collections::HashSet;
-use std::sync::Mutex;
-use tracing::info;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use template::TemplateVars;
+use tracing::{debug, error, warn};
+use websocket::WebSocketMirror;
 // pub struct SimpleProxy {}
 
 // pub struct CopyProxy {}
 pub struct DualWriteProxy {
     pub executed_requests: Mutex<HashSet<String>>,
+    primary: PrimaryConfig,
+    upstreams: LoadBalancer<RoundRobin>,
+    retry_budget: Arc<RetryBudget>,
+    mirrors: Vec<Arc<MirrorTarget>>,
+    access_log: Option<Arc<AccessLog>>,
+    request_id: RequestIdConfig,
+    mirror_max_body_size: usize,
+    router: Router,
+    request_headers: HeaderRewriter,
+    response_headers: HeaderRewriter,
+    rewrite: UrlRewriter,
+    scripts: Option<Arc<Scripts>>,
+    plugins: Plugins,
+    filters: FilterChain,
+    recorder: Option<Arc<Recorder>>,
+    comparator: Option<Arc<Comparator>>,
+    events: Option<EventBus>,
+    drain: Arc<ShutdownDrain>,
+    websocket: WebSocketConfig,
+    grpc: Arc<GrpcDescriptors>,
+    cache: Option<Arc<ResponseCache>>,
+    compression: Option<Arc<Compression>>,
+    access: IpAccessList,
+    client_ip: ClientIpResolver,
+    proxied_clients: Option<Arc<ProxiedClients>>,
+}
+
+impl DualWriteProxy {
+    pub fn new(config: &ProxyConfig) -> Result<Self> {
+        let recorder = config
+            .recording
+            .enabled
+            .then(|| Recorder::new(&config.recording).map(Arc::new))
+            .transpose()?;
+        let grpc = Arc::new(GrpcDescriptors::load(&config.grpc)?);
+        let comparator = config
+            .comparison
+            .enabled
+            .then(|| {
+                Comparator::new(&config.comparison)
+                    .map(|comparator| Arc::new(comparator.with_grpc(grpc.clone())))
+            })
+            .transpose()?;
+        let events = config
+            .admin
+            .enabled
+            .then(|| EventBus::new(config.admin.event_buffer));
+        let drain = ShutdownDrain::new(&config.shutdown);
+        let mirrors = config
+            .mirrors
+            .iter()
+            .map(|mirror| {
+                MirrorTarget::new(mirror).map(|target| {
+                    Arc::new(
+                        target
+                            .with_recorder(recorder.clone())
+                            .with_comparator(comparator.clone())
+                            .with_events(events.clone())
+                            .with_drain(Some(drain.clone())),
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        let upstreams = LoadBalancer::try_from_iter(&config.primary.backends)?;
+        let access_log = config
+            .access_log
+            .enabled
+            .then(|| AccessLog::new(&config.access_log).map(Arc::new))
+            .transpose()?;
+        let router = Router::new(&config.routes)?;
+        let plugins = Plugins::load(&config.plugins, router.names())?;
+        let cache = config
+            .cache
+            .enabled
+            .then(|| ResponseCache::new(&config.cache).map(Arc::new))
+            .transpose()?;
+        let compression = config
+            .compression
+            .enabled
+            .then(|| Arc::new(Compression::new(&config.compression)));
+        Ok(Self {
+            executed_requests: Mutex::new(HashSet::new()),
+            primary: config.primary.clone(),
+            upstreams,
+            retry_budget: RetryBudget::new(&config.primary.retry.budget),
+            mirrors,
+            access_log,
+            request_id: config.request_id.clone(),
+            mirror_max_body_size: config.mirror_max_body_size,
+            router,
+            request_headers: HeaderRewriter::new(&config.primary.headers.request)?,
+            response_headers: HeaderRewriter::new(&config.primary.headers.response)?,
+            rewrite: UrlRewriter::new(&config.primary.rewrite)?,
+            scripts: Scripts::load(&config.scripting)?,
+            plugins,
+            filters: FilterChain::new(),
+            recorder,
+            comparator,
+            events,
+            drain,
+            websocket: config.websocket.clone(),
+            grpc,
+            cache,
+            compression,
+            access: IpAccessList::new(&config.access_control).context("invalid access_control")?,
+            client_ip: ClientIpResolver::new(&config.client_ip)
+                .context("invalid client_ip.trusted_proxies")?,
+            proxied_clients: config
+                .proxy_protocol
+                .enabled
+                .then(|| Arc::new(ProxiedClients::default())),
+        })
+    }
+
+    /// Marks the error as retryable if the request has retries left and the budget allows it.
+    fn decide_retry(&self, ctx: &mut ProxyCtx, mut e: Box<pingora::Error>) -> Box<pingora::Error> {
+        if let Some(backend) = &ctx.backend {
+            ctx.failed_backends.push(backend.clone());
+        }
+        if ctx.tries > self.primary.retry.max_retries {
+            UPSTREAM_RETRIES.with_label_values(&["exhausted"]).inc();
+            e.set_retry(false);
+            return e;
+        }
+        if ctx.retry_permit.is_none() {
+            ctx.retry_permit = self.retry_budget.try_retry();
+        }
+        let decision = if ctx.retry_permit.is_some() {
+            "retry"
+        } else {
+            warn!(request_id = %ctx.request_id, "retry budget exhausted, not retrying: {}", e);
+            "budget_exhausted"
+        };
+        UPSTREAM_RETRIES.with_label_values(&[decision]).inc();
+        e.set_retry(ctx.retry_permit.is_some());
+        e
+    }
+
+    /// Applies the route and mirror rules to the request copy and sends it to every mirror.
+    fn dispatch_mirrors(&self, ctx: &mut ProxyCtx, request: MirrorRequest) {
+        for (mirror, request) in self.mirror_requests(ctx, request) {
+            let outcome = mirror.dispatch(request);
+            ctx.mirror_outcomes
+                .push((mirror.name().to_string(), outcome));
+        }
+    }
+
+    /// Opens a WebSocket to every mirror with the upgrade request, after the route and mirror
+    /// rules.
+    fn connect_websocket_mirrors(&self, ctx: &mut ProxyCtx, request: MirrorRequest) {
+        let mut websocket =
+            WebSocketMirror::new(self.websocket.max_message_size, self.websocket.queue_size);
+        for (mirror, request) in self.mirror_requests(ctx, request) {
+            let outcome = websocket.connect(&mirror, request);
+            ctx.mirror_outcomes
+                .push((mirror.name().to_string(), outcome));
+        }
+        ctx.websocket_mirror = Some(websocket).filter(|websocket| !websocket.is_empty());
+    }
+
+    /// Copies of the request for every mirror, with the route and mirror rules applied. Mirrors
+    /// vetoed by a script or plugin are left out.
+    fn mirror_requests(
+        &self,
+        ctx: &mut ProxyCtx,
+        mut request: MirrorRequest,
+    ) -> Vec<(Arc<MirrorTarget>, MirrorRequest)> {
+        if let Some(route) = &ctx.route {
+            route
+                .mirror_headers
+                .apply(&mut request.headers, &ctx.template_vars);
+            if !route.mirror_body.is_empty() && BodyTransform::is_json(&request.headers) {
+                match route.mirror_body.apply(&request.body, &ctx.template_vars) {
+                    Ok(body) => request.body = body,
+                    // 无法解析的请求体原样发送
+                    Err(e) => {
+                        warn!(request_id = %ctx.request_id, "failed to transform mirror body: {}", e)
+                    }
+                }
+            }
+        }
+        let mut requests = Vec::new();
+        for mirror in &self.mirrors {
+            let mut request = request.clone();
+            request.path_and_query = mirror.rewrite().rewrite(&request.path_and_query);
+            mirror
+                .headers()
+                .apply(&mut request.headers, &ctx.template_vars);
+            if let Some(scripts) = &self.scripts {
+                let mut script_request = ScriptRequest::from_mirror(&request, mirror.name());
+                if !scripts.run(Hook::Mirror, &mut script_request, &ctx.request_id) {
+                    ctx.mirror_outcomes
+                        .push((mirror.name().to_string(), MirrorOutcome::Vetoed));
+                    continue;
+                }
+                script_request.apply_to_mirror(&mut request);
+            }
+            if !self.plugins.is_empty() {
+                let mut plugin_request = ScriptRequest::from_mirror(&request, mirror.name());
+                if !self.plugins.on_mirror(&mut plugin_request, &ctx.request_id) {
+                    ctx.mirror_outcomes
+                        .push((mirror.name().to_string(), MirrorOutcome::Vetoed));
+                    continue;
+                }
+                plugin_request.apply_to_mirror(&mut request);
+            }
+            requests.push((mirror.clone(), request));
+        }
+        requests
+    }
+
+    /// Route of the request. A CORS preflight is matched by the method it asks for when that
+    /// route answers preflights, otherwise as the `OPTIONS` request it is.
+    fn find_route(&self, req: &RequestHeader) -> Option<Arc<Route>> {
+        let path = req.uri.path();
+        cors::is_preflight(req)
+            .then(|| cors::preflight_method(req))
+            .flatten()
+            .and_then(|method| self.router.find(&method, path))
+            .filter(|route| route.cors.is_some())
+            .or_else(|| self.router.find(&req.method, path))
+    }
+
+    /// Rejects the request of a client denied by the access control of `route` (empty for the
+    /// global lists).
+    async fn deny(
+        &self,
+        session: &mut Session,
+        ctx: &mut ProxyCtx,
+        route: &str,
+    ) -> Result<bool, Box<pingora::Error>> {
+        ACCESS_DENIED.with_label_values(&[route]).inc();
+        debug!(request_id = %ctx.request_id, client_ip = ?ctx.client_ip, route, "access denied");
+        ctx.skip_mirror = true;
+        let mut resp = ResponseHeader::build(403, Some(2))?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        resp.insert_header(self.request_id.header.clone(), &ctx.request_id)?;
+        session.write_response_header(Box::new(resp), true).await?;
+        Ok(true)
+    }
+
+    /// Adds a filter at the end of the chain, see [`filter`] for the order of the hooks.
+    pub fn with_filter(mut self, filter: impl ProxyFilter + 'static) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn mirrors(&self) -> &[Arc<MirrorTarget>] {
+        &self.mirrors
+    }
+
+    /// Live events, set when the admin listener is enabled.
+    pub fn events(&self) -> Option<&EventBus> {
+        self.events.as_ref()
+    }
+
+    pub fn comparator(&self) -> Option<&Arc<Comparator>> {
+        self.comparator.as_ref()
+    }
+
+    /// Response cache, set when `cache.enabled`.
+    pub fn cache(&self) -> Option<&Arc<ResponseCache>> {
+        self.cache.as_ref()
+    }
+
+    /// Client addresses of the PROXY protocol connections, set when `proxy_protocol.enabled`.
+    pub fn proxied_clients(&self) -> Option<&Arc<ProxiedClients>> {
+        self.proxied_clients.as_ref()
+    }
+
+    /// Drain of the mirrored writes, run as a background service on shutdown.
+    pub fn drain(&self) -> &Arc<ShutdownDrain> {
+        &self.drain
+    }
+
+    /// Threads of the proxy, started as a background service.
+    pub fn threads(&self) -> ProxyThreads {
+        ProxyThreads {
+            access_log: self.access_log.clone(),
+            recorder: self.recorder.clone(),
+            scripts: self.scripts.clone(),
+        }
+    }
+}
+
+/// Starts the access log and recording writer threads and the script reloading once the
+/// server runs.
+///
+/// `Server::run_forever` forks when running as a daemon and only the forking thread survives,
+/// so no thread may be started by [`DualWriteProxy::new`]. Lines written before wait in the
+/// writer queues.
+pub struct ProxyThreads {
+    access_log: Option<Arc<AccessLog>>,
+    recorder: Option<Arc<Recorder>>,
+    scripts: Option<Arc<Scripts>>,
+}
+
+impl ProxyThreads {
+    pub fn start(&self) -> Result<()> {
+        if let Some(access_log) = &self.access_log {
+            access_log.start_writer()?;
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.start_writer()?;
+        }
+        if let Some(scripts) = &self.scripts {
+            scripts.start_reloader()?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ProxyThreads {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        if let Err(e) = ProxyThreads::start(self) {
+            error!("failed to start the proxy threads: {:?}", e);
+        }
+    }
 }
 
 #[async_trait]
 impl ProxyHttp for DualWriteProxy {
-    type CTX = ();
+    type CTX = ProxyCtx;
+
+    fn new_ctx(&self) -> Self::CTX {
+        ProxyCtx::new(
+            self.retry_budget.track_request(),
+            self.drain.track_request(),
+        )
+    }
+
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        // pingora 默认添加的压缩模块保持关闭，压缩由我们的模块完成
+        modules.add_module(ResponseCompressionBuilder::enable(0));
+        if let Some(compression) = &self.compression {
+            modules.add_module(compression.module());
+        }
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool, Box<pingora::Error>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // 生成或沿用客户端的请求 ID，并写回请求头以便主备两路请求都携带
+        ctx.request_id = request_id::resolve(
+            &session.req_header().headers,
+            &self.request_id.header,
+            self.request_id.accept_from_client,
+        );
+        session
+            .req_header_mut()
+            .insert_header(self.request_id.header.clone(), &ctx.request_id)?;
+
+        // PROXY 协议头中的客户端地址优先于连接的对端地址，再按可信代理的转发头解析
+        ctx.client_addr = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|peer| {
+                self.proxied_clients
+                    .as_ref()
+                    .and_then(|clients| clients.get(peer))
+                    .unwrap_or(*peer)
+            });
+        ctx.client_ip = ctx.client_addr.map(|addr| {
+            self.client_ip
+                .resolve(addr.ip(), &session.req_header().headers)
+        });
+        if !self.access.allows(ctx.client_ip) {
+            return self.deny(session, ctx, "").await;
+        }
+
+        // 脚本可以在路由匹配之前改写请求
+        if let Some(scripts) = &self.scripts {
+            let mut request = ScriptRequest::from_header(session.req_header());
+            ctx.skip_mirror |= !scripts.run(Hook::Request, &mut request, &ctx.request_id);
+            request.apply_to_header(session.req_header_mut())?;
+        }
 
-    fn new_ctx(&self) -> Self::CTX {}
+        // 插件在脚本之后运行，可以直接指定路由
+        let mut selected_route = None;
+        if !self.plugins.is_empty() {
+            let mut request = ScriptRequest::from_header(session.req_header());
+            let decision = self.plugins.on_request(&mut request, &ctx.request_id);
+            ctx.skip_mirror |= decision.skip_mirror;
+            selected_route = decision.route.and_then(|name| self.router.get(&name));
+            request.apply_to_header(session.req_header_mut())?;
+        }
+
+        let req = session.req_header();
+        let preflight = cors::is_preflight(req);
+        ctx.route = selected_route.or_else(|| self.find_route(req));
+        if let Some(route) = ctx.route.clone()
+            && !route.access.allows(ctx.client_ip)
+        {
+            return self.deny(session, ctx, &route.name).await;
+        }
+        let req = session.req_header();
+        ctx.template_vars = TemplateVars {
+            client_ip: ctx.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            request_id: ctx.request_id.clone(),
+            route: ctx
+                .route
+                .as_ref()
+                .map(|route| route.name.clone())
+                .unwrap_or_default(),
+            method: req.method.to_string(),
+            host: req
+                .headers
+                .get(http::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| req.uri.host())
+                .unwrap_or_default()
+                .to_string(),
+        };
+        ctx.trace = telemetry::start_server_span(
+            &req.headers,
+            req.method.as_str(),
+            req.uri.path(),
+            ctx.route.as_ref().map(|route| route.name.as_str()),
+        );
+        ctx.trace
+            .span()
+            .set_attribute(KeyValue::new("request_id", ctx.request_id.clone()));
+        if preflight
+            && let Some(route) = &ctx.route
+            && let Some(cors) = &route.cors
+        {
+            let mut resp = cors.preflight(req);
+            resp.insert_header(self.request_id.header.clone(), &ctx.request_id)?;
+            let result = if resp.status.is_success() {
+                "allowed"
+            } else {
+                "rejected"
+            };
+            CORS_PREFLIGHTS
+                .with_label_values(&[route.name.as_str(), result])
+                .inc();
+            ctx.skip_mirror = true;
+            session.write_response_header(Box::new(resp), true).await?;
+            return Ok(true);
+        }
+        let route = ctx.route.as_ref().map(|route| route.name.as_str());
+        if let Some(recorder) = &self.recorder
+            && recorder.records(route)
+        {
+            let url = format!(
+                "http://{}{}",
+                ctx.template_vars.host,
+                req.uri.path_and_query().map_or("/", |pq| pq.as_str())
+            );
+            ctx.recording = Some(recorder.start(
+                recording::PRIMARY,
+                &ctx.request_id,
+                route,
+                &req.method,
+                url,
+                &req.headers,
+            ));
+        }
+        self.filters.request_filter(session, ctx).await
+    }
 
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
-        _ctx: &mut Self::CTX,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>, Box<pingora::Error>> {
+        ctx.tries += 1;
+        // 重试时优先选择尚未失败的后端，全部失败过则退回到任意后端
+        let backend = self
+            .upstreams
+            .select_with(b"", 256, |backend, healthy| {
+                healthy && !ctx.failed_backends.contains(backend)
+            })
+            .or_else(|| self.upstreams.select(b"", 256))
+            .ok_or_else(|| {
+                pingora::Error::explain(ErrorType::ConnectNoRoute, "no primary backend")
+            })?;
+
         // 创建上游服务器
-        let peer1 = HttpPeer::new("127.0.0.1:3000", false, "localhost".to_string());
+        let mut peer = HttpPeer::new(&backend, self.primary.tls, self.primary.sni.clone());
+        peer.options.alpn = match self.primary.http_version {
+            // gRPC 只能使用 HTTP/2
+            _ if grpc::is_grpc(&session.req_header().headers) => ALPN::H2,
+            HttpVersion::Http1 => ALPN::H1,
+            HttpVersion::Auto => ALPN::H2H1,
+            HttpVersion::Http2 => ALPN::H2,
+        };
+        if let Some(previous) = ctx.upstream_trace.take() {
+            telemetry::end_span(&previous, None, Some("retried".to_string()));
+        }
+        ctx.upstream_trace = Some(telemetry::start_client_span(
+            &ctx.trace,
+            "primary upstream".to_string(),
+            vec![
+                KeyValue::new("server.address", backend.addr.to_string()),
+                KeyValue::new("attempt", ctx.tries as i64),
+            ],
+        ));
+        ctx.backend = Some(backend);
+        ctx.upstream_started_at = Some(Instant::now());
+        Ok(Box::new(peer))
+    }
 
-        // 返回第一个peer作为主要的上游服务器
-        Ok(Box::new(peer1))
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        warn!(request_id = %ctx.request_id, "failed to connect to primary {}: {}", peer, e);
+        if let Some(upstream) = ctx.upstream_trace.take() {
+            telemetry::end_span(&upstream, None, Some(e.to_string()));
+        }
+        // 连接失败时请求尚未发出，任何方法都可以重试
+        self.decide_retry(ctx, e)
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        _client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        if let Some(upstream) = ctx.upstream_trace.take() {
+            telemetry::end_span(&upstream, None, Some(e.to_string()));
+        }
+        let method = &session.req_header().method;
+        // 已经向客户端返回了响应或请求体无法重放时不能重试
+        let replayable = session.as_ref().response_written().is_none()
+            && !session.as_ref().retry_buffer_truncated();
+        if !replayable || !(self.primary.retry.retry_non_idempotent || retry::is_idempotent(method))
+        {
+            e.set_retry(false);
+            return e;
+        }
+        self.decide_retry(ctx, e)
     }
 
     async fn upstream_request_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         upstream_request: &mut RequestHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<(), Box<pingora::Error>>
     where
         Self::CTX: Send + Sync,
     {
-        upstream_request.insert_header(HeaderName::from_static("user-content"), "dual-write")?;
+        if !self.rewrite.is_empty() {
+            let path_and_query = upstream_request
+                .uri
+                .path_and_query()
+                .map_or("/", |pq| pq.as_str());
+            let uri = self.rewrite.rewrite(path_and_query);
+            let uri = uri.parse().map_err(|e| {
+                pingora::Error::because(ErrorType::InternalError, "invalid rewritten uri", e)
+            })?;
+            upstream_request.set_uri(uri);
+        }
+
+        // 先应用主后端的规则，再应用路由的规则
+        self.request_headers
+            .apply(upstream_request, &ctx.template_vars);
+        if let Some(route) = &ctx.route {
+            route
+                .request_headers
+                .apply(upstream_request, &ctx.template_vars);
+        }
+        if let Some(scripts) = &self.scripts {
+            let mut request = ScriptRequest::from_header(upstream_request);
+            ctx.skip_mirror |= !scripts.run(Hook::UpstreamRequest, &mut request, &ctx.request_id);
+            request.apply_to_header(upstream_request)?;
+        }
+        self.filters
+            .upstream_request_filter(session, upstream_request, ctx)
+            .await?;
+        if let Some(upstream) = &ctx.upstream_trace {
+            for (name, value) in telemetry::trace_headers(upstream) {
+                upstream_request.insert_header(name, value)?;
+            }
+        }
 
         // 检查是否已经执行过双写（通过请求头标记）
         let dual_write_header = HeaderName::from_static("x-dual-write-executed");
-        if !_session
-            .req_header()
-            .headers
-            .contains_key(&dual_write_header)
+        if !ctx.skip_mirror
+            && !session
+                .req_header()
+                .headers
+                .contains_key(&dual_write_header)
         {
             // 标记已执行
-            _session
+            session
                 .req_header_mut()
                 .insert_header(dual_write_header, "true")?;
 
-            // 启动后台任务向第二个服务器发送请求
-            let scheme = "http";
-            let host = "127.0.0.1:3001";
-            let path_and_query = _session.req_header().uri.to_string();
-            let request_uri_string = format!("{scheme}://{host}{path_and_query}");
-            let request_uri = Url::parse(&request_uri_string).expect("无法解析为合法URL");
-            let request_method = _session.req_header().method.clone();
-            let request_headers = _session.req_header().headers.clone();
-
-            // 尝试读取请求体，如果失败则使用空字节
-            let request_body_bytes = _session
-                .read_request_body()
-                .await
-                .unwrap_or_default()
-                .unwrap_or_default();
-
-            tokio::spawn(async move {
-                info!(
-                    "Sending duplicate request to peer2: {:?}",
-                    request_uri.to_string()
-                );
-
-                // 创建不带代理的客户端
-                let client = reqwest::Client::builder().no_proxy().build().unwrap();
-
-                let url = request_uri;
-                info!("url: {:?}", url);
-                info!("method: {:?}", request_method);
-                info!("headers: {:?}", request_headers);
-
-                let response = client
-                    .request(request_method, url)
-                    .headers(request_headers)
-                    .body(request_body_bytes)
-                    .send()
-                    .await;
-
-                info!("response: {:?}", response);
-                match response {
-                    Ok(resp) => {
-                        info!("status: {:?}", resp.status());
-                        info!("headers: {:?}", resp.headers());
-                        match resp.text().await {
-                            Ok(text) => info!("response from 3001: {:?}", text),
-                            Err(e) => info!("error reading response: {:?}", e),
-                        }
-                    }
-                    Err(e) => info!("error sending to 3001: {:?}", e),
+            let path_and_query = session
+                .req_header()
+                .uri
+                .path_and_query()
+                .map(|pq| pq.to_string())
+                .unwrap_or_else(|| "/".to_string());
+            let request_method = session.req_header().method.clone();
+            let request_headers = session.req_header().headers.clone();
+
+            let route = ctx.route.as_ref().map(|route| route.name.clone());
+            if session.is_upgrade_req() {
+                // 升级后的连接没有可镜像的请求体，只镜像客户端的 WebSocket 消息
+                if self.websocket.mirror
+                    && websocket::is_websocket(&request_headers)
+                    && ctx.websocket_mirror.is_none()
+                {
+                    upstream_request.remove_header(&SEC_WEBSOCKET_EXTENSIONS);
+                    let request = MirrorRequest {
+                        request_id: ctx.request_id.clone(),
+                        method: request_method,
+                        path_and_query,
+                        headers: request_headers,
+                        body: Bytes::new(),
+                        trace_link: None,
+                        route,
+                        record: false,
+                        primary_response: None,
+                    };
+                    self.connect_websocket_mirrors(ctx, request);
                 }
+                return Ok(());
+            }
+            // 流式 gRPC 调用不镜像，也不缓存其请求体
+            if grpc::is_grpc(&request_headers)
+                && self.grpc.is_streaming(session.req_header().uri.path())
+            {
+                debug!(request_id = %ctx.request_id, "streaming gRPC call, not mirrored");
+                return Ok(());
+            }
+            let record = self
+                .recorder
+                .as_ref()
+                .is_some_and(|recorder| recorder.records(route.as_deref()));
+            // 缓存重新验证时主后端收到的是条件请求（HEAD 也被改为 GET），响应无法与镜像比较
+            let revalidating = ctx.cache_lookup
+                && (session.cache.maybe_cache_meta().is_some() || request_method == Method::HEAD);
+            let primary_response = self
+                .comparator
+                .as_ref()
+                .filter(|comparator| !revalidating && comparator.compares(route.as_deref()))
+                .map(|comparator| {
+                    let (capture, primary_response) = comparator.capture();
+                    ctx.response_capture = Some(capture);
+                    primary_response
+                });
+            // 请求体由 request_body_filter 收集完整后再发送到镜像服务器
+            ctx.pending_mirror = Some(MirrorRequest {
+                request_id: ctx.request_id.clone(),
+                method: request_method,
+                path_and_query,
+                headers: request_headers,
+                body: Bytes::new(),
+                trace_link: Some(ctx.trace.span().span_context().clone()),
+                route,
+                record,
+                primary_response,
             });
         }
+        // 重试时 pingora 会从重试缓冲区重新发送已读取的请求体
+        ctx.mirror_body.clear();
+        if let Some(recording) = &mut ctx.recording {
+            recording.reset_request_body();
+        }
+
+        Ok(())
+    }
 
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<pingora::Error>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        self.filters
+            .request_body_filter(session, body, end_of_stream, ctx)
+            .await?;
+        if let (Some(recording), Some(chunk)) = (&mut ctx.recording, body.as_ref()) {
+            recording.push_request_body(chunk);
+        }
+        if let Some(websocket) = &mut ctx.websocket_mirror {
+            if let Some(chunk) = body {
+                websocket.push(chunk);
+            }
+            // 客户端断开后关闭镜像连接
+            if end_of_stream {
+                ctx.websocket_mirror = None;
+            }
+            return Ok(());
+        }
+        if ctx.pending_mirror.is_none() {
+            return Ok(());
+        }
+        if let Some(chunk) = body {
+            if ctx.mirror_body.len() + chunk.len() > self.mirror_max_body_size {
+                warn!(request_id = %ctx.request_id, "request body too large, not mirrored");
+                ctx.pending_mirror = None;
+                ctx.mirror_body = BytesMut::new();
+                for mirror in &self.mirrors {
+                    ctx.mirror_outcomes
+                        .push((mirror.name().to_string(), MirrorOutcome::Dropped));
+                }
+                return Ok(());
+            }
+            ctx.mirror_body.extend_from_slice(chunk);
+        }
+        if end_of_stream && let Some(mut request) = ctx.pending_mirror.take() {
+            request.body = ctx.mirror_body.split().freeze();
+            if grpc::is_grpc(&request.headers)
+                && !self
+                    .grpc
+                    .is_unary(session.req_header().uri.path(), &request.body)
+            {
+                debug!(request_id = %ctx.request_id, "streaming gRPC call, not mirrored");
+                return Ok(());
+            }
+            self.dispatch_mirrors(ctx, request);
+        }
         Ok(())
     }
 
@@ -116,10 +828,366 @@ impl ProxyHttp for DualWriteProxy {
         &self,
         _session: &mut Session,
         upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<pingora::Error>> {
+        // 比较使用上游的原始响应，在改写响应头之前捕获
+        if let Some(capture) = &mut ctx.response_capture {
+            capture.set_header(upstream_response);
+        }
+        ctx.upstream_latency = ctx.upstream_started_at.map(|started| started.elapsed());
+        if let Some(upstream) = ctx.upstream_trace.take() {
+            telemetry::end_span(&upstream, Some(upstream_response.status.as_u16()), None);
+        }
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<pingora::Error>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // 响应头规则在缓存之后应用，缓存命中的响应同样经过这些规则
+        self.response_headers
+            .apply(upstream_response, &ctx.template_vars);
+        if let Some(route) = &ctx.route {
+            route
+                .response_headers
+                .apply(upstream_response, &ctx.template_vars);
+        }
+        if !self.plugins.is_empty() {
+            let mut headers = upstream_response.headers.clone();
+            self.plugins.on_response(
+                upstream_response.status.as_u16(),
+                &mut headers,
+                &ctx.request_id,
+            );
+            headers::sync(upstream_response, &headers);
+        }
+        self.filters
+            .response_filter(session, upstream_response, ctx)?;
+        if let Some(cors) = ctx.route.as_ref().and_then(|route| route.cors.as_ref()) {
+            cors.apply(session.req_header().headers.get(ORIGIN), upstream_response);
+        }
+        upstream_response.insert_header(self.request_id.header.clone(), &ctx.request_id)?;
+        if let Some(cache) = &self.cache {
+            if ctx.cache_lookup
+                && let Some(header) = cache.status_header()
+            {
+                let status = cache::status(&session.cache.phase());
+                upstream_response.insert_header(header.clone(), status)?;
+            }
+            if cache::invalidates(session.req_header(), upstream_response.status) {
+                let purged = cache.invalidate(session.req_header()).await;
+                if purged > 0 {
+                    debug!(request_id = %ctx.request_id, purged, "invalidated cached responses");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request_cache_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<pingora::Error>> {
+        let Some(cache) = &self.cache else {
+            return Ok(());
+        };
+        let route = ctx.route.as_ref().map(|route| &route.cache);
+        if route.is_some_and(|route| !route.enabled)
+            || !cache::is_cacheable(session.req_header())
+            || session.is_upgrade_req()
+        {
+            return Ok(());
+        }
+        cache.enable(&mut session.cache);
+        ctx.cache_lookup = true;
+        ctx.cache_ttl = route.and_then(|route| route.ttl);
+        Ok(())
+    }
+
+    fn cache_key_callback(
+        &self,
+        session: &Session,
         _ctx: &mut Self::CTX,
+    ) -> Result<CacheKey, Box<pingora::Error>> {
+        let req = session.req_header();
+        Ok(match &self.cache {
+            Some(cache) => cache.key(req),
+            None => CacheKey::default(req),
+        })
+    }
+
+    fn response_cache_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<RespCacheable, Box<pingora::Error>> {
+        let authorization = session.req_header().headers.contains_key(AUTHORIZATION);
+        Ok(cache::response_cacheable(
+            resp,
+            authorization,
+            ctx.cache_ttl,
+        ))
+    }
+
+    fn cache_vary_filter(
+        &self,
+        meta: &CacheMeta,
+        _ctx: &mut Self::CTX,
+        req: &RequestHeader,
+    ) -> Option<HashBinary> {
+        cache::variance(meta, req)
+    }
+
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
     ) -> Result<(), Box<pingora::Error>> {
-        upstream_response
-            .insert_header(HeaderName::from_static("user-content"), "response by kevin")?;
+        if let (Some(capture), Some(chunk)) = (&mut ctx.response_capture, body.as_ref()) {
+            capture.push_body(chunk);
+        }
+        // gRPC 响应在 trailers 之后才完整
+        if end_of_stream
+            && ctx
+                .response_capture
+                .as_ref()
+                .is_some_and(|capture| !capture.expects_trailers())
+            && let Some(capture) = ctx.response_capture.take()
+        {
+            capture.finish();
+        }
+        Ok(())
+    }
+
+    fn upstream_response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> Result<(), Box<pingora::Error>> {
+        if let Some(mut capture) = ctx.response_capture.take() {
+            capture.set_trailers(upstream_trailers);
+            capture.finish();
+        }
         Ok(())
     }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>, Box<pingora::Error>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        self.filters
+            .response_body_filter(session, body, end_of_stream, ctx)?;
+        if let (Some(recording), Some(chunk)) = (&mut ctx.recording, body.as_ref()) {
+            recording.push_response_body(chunk);
+        }
+        Ok(None)
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&pingora::Error>, ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+        let events = self.events.as_ref().filter(|events| events.is_active());
+        if self.access_log.is_some() || events.is_some() {
+            let record = AccessLogRecord::new(session, e, ctx);
+            if let Some(access_log) = &self.access_log {
+                access_log.log(&record);
+            }
+            if let Some(events) = events {
+                let route = ctx.route.as_ref().map(|route| route.name.clone());
+                events.publish(ProxyEvent::request(&record, route));
+            }
+        }
+        if ctx.cache_lookup {
+            CACHE_LOOKUPS
+                .with_label_values(&[cache::status(&session.cache.phase())])
+                .inc();
+        }
+        self.filters.logging(session, e, ctx).await;
+        if let Some(mut recording) = ctx.recording.take() {
+            if let Some(response) = session.response_written() {
+                // 记录的响应体在压缩之前
+                let mut headers = response.headers.clone();
+                if session
+                    .downstream_modules_ctx
+                    .get::<ResponseCompressor>()
+                    .is_some_and(|compressor| compressor.stats().is_some())
+                {
+                    headers.remove(CONTENT_ENCODING);
+                    headers.remove(TRANSFER_ENCODING);
+                }
+                recording.set_response(response.status.as_u16(), &headers);
+            }
+            recording.finish(e.map(|e| e.to_string()));
+        }
+        // 没有响应体的响应（HEAD、204 等）不一定经过 upstream_response_body_filter
+        if let Some(capture) = ctx.response_capture.take()
+            && e.is_none()
+        {
+            capture.finish();
+        }
+
+        let error = e.map(|e| e.to_string());
+        if let Some(upstream) = ctx.upstream_trace.take() {
+            telemetry::end_span(&upstream, None, error.clone());
+        }
+        let status = session.response_written().map(|resp| resp.status.as_u16());
+        telemetry::end_span(&ctx.trace, status, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CorsConfig, RouteConfig};
+
+    fn connect_error() -> Box<pingora::Error> {
+        pingora::Error::new(ErrorType::ConnectRefused)
+    }
+
+    #[test]
+    fn test_retry_fails_over_to_another_backend() {
+        let mut config = ProxyConfig::default();
+        config.primary.backends = vec!["127.0.0.1:3000".into(), "127.0.0.1:3002".into()];
+        let proxy = DualWriteProxy::new(&config).expect("Failed to create proxy");
+        let mut ctx = proxy.new_ctx();
+
+        ctx.tries = 1;
+        ctx.backend = proxy.upstreams.select(b"", 256);
+        let e = proxy.decide_retry(&mut ctx, connect_error());
+        assert!(e.retry());
+        assert_eq!(ctx.failed_backends.len(), 1);
+
+        let next = proxy
+            .upstreams
+            .select_with(b"", 256, |backend, _| {
+                !ctx.failed_backends.contains(backend)
+            })
+            .expect("No backend left");
+        assert_ne!(next, ctx.failed_backends[0]);
+    }
+
+    #[test]
+    fn test_retry_stops_after_max_retries() {
+        let mut config = ProxyConfig::default();
+        config.primary.retry.max_retries = 1;
+        let proxy = DualWriteProxy::new(&config).expect("Failed to create proxy");
+        let mut ctx = proxy.new_ctx();
+
+        ctx.tries = 1;
+        assert!(proxy.decide_retry(&mut ctx, connect_error()).retry());
+        ctx.tries = 2;
+        assert!(!proxy.decide_retry(&mut ctx, connect_error()).retry());
+    }
+
+    #[test]
+    fn test_retry_respects_budget() {
+        let mut config = ProxyConfig::default();
+        config.primary.retry.budget.ratio = 0.0;
+        config.primary.retry.budget.min_concurrency = 1;
+        let proxy = DualWriteProxy::new(&config).expect("Failed to create proxy");
+
+        let mut first = proxy.new_ctx();
+        first.tries = 1;
+        assert!(proxy.decide_retry(&mut first, connect_error()).retry());
+
+        // 预算已被第一个请求占用
+        let mut second = proxy.new_ctx();
+        second.tries = 1;
+        assert!(!proxy.decide_retry(&mut second, connect_error()).retry());
+
+        drop(first);
+        assert!(proxy.decide_retry(&mut second, connect_error()).retry());
+    }
+
+    #[test]
+    fn test_preflight_route() {
+        let mut config = ProxyConfig::default();
+        let route = |name: &str, method: Option<&str>, cors: Option<CorsConfig>| RouteConfig {
+            name: name.to_string(),
+            path_prefix: "/api".to_string(),
+            methods: method.into_iter().map(str::to_string).collect(),
+            cors,
+            ..Default::default()
+        };
+        config.routes = vec![
+            route(
+                "writes",
+                Some("POST"),
+                Some(CorsConfig {
+                    allowed_origins: vec!["https://app.example.com".to_string()],
+                    ..Default::default()
+                }),
+            ),
+            route("updates", Some("PUT"), None),
+            route("api", None, None),
+        ];
+        let proxy = DualWriteProxy::new(&config).expect("Failed to create proxy");
+        let preflight = |method: &str| {
+            let mut req = RequestHeader::build(Method::OPTIONS, b"/api/users", None).unwrap();
+            req.insert_header("origin", "https://app.example.com")
+                .unwrap();
+            req.insert_header("access-control-request-method", method)
+                .unwrap();
+            proxy.find_route(&req).map(|route| route.name.clone())
+        };
+
+        assert_eq!(preflight("POST").as_deref(), Some("writes"));
+        // PUT 的路由没有 CORS 配置，按 OPTIONS 请求匹配
+        assert_eq!(preflight("PUT").as_deref(), Some("api"));
+    }
+
+    #[test]
+    fn test_no_thread_started_before_run_forever() {
+        use crate::config::AccessLogOutput;
+
+        let dir = std::env::temp_dir().join(format!("proxy-threads-{}", std::process::id()));
+        let mut config = ProxyConfig::default();
+        config.access_log.enabled = true;
+        config.access_log.output = AccessLogOutput::File {
+            path: dir.join("access.log"),
+            max_size: 0,
+            max_files: 1,
+        };
+        config.recording.enabled = true;
+        config.recording.path = dir.join("recording.jsonl");
+        config.scripting.path = Some("fixtures/scripts/proxy.rhai".into());
+        config.tracing.enabled = true;
+
+        // daemon 在 run_forever 中 fork，之前启动的线程会丢失
+        let proxy = DualWriteProxy::new(&config).expect("Failed to create proxy");
+        let tracer = telemetry::TracerService::new(&config.tracing, proxy.drain().clone())
+            .expect("Failed to create tracer service");
+        assert!(tracer.is_some());
+        let access_log = proxy.access_log.as_ref().unwrap();
+        let recorder = proxy.recorder.as_ref().unwrap();
+        let scripts = proxy.scripts.as_ref().unwrap();
+        assert!(!access_log.writer_started());
+        assert!(!recorder.writer_started());
+        assert!(!scripts.reloader_started());
+
+        proxy.threads().start().expect("Failed to start threads");
+        assert!(access_log.writer_started());
+        assert!(recorder.writer_started());
+        assert!(scripts.reloader_started());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,139 @@
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, IntGaugeVec, register_int_counter_vec, register_int_gauge_vec};
+
+// 所有指标注册在 prometheus 默认 registry 中，由 pingora 的 prometheus_http_service 导出
+
+pub static MIRROR_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_mirror_requests_total",
+        "Mirrored requests sent to a secondary upstream, by outcome",
+        &["mirror", "outcome"]
+    )
+    .expect("register simple_proxy_mirror_requests_total")
+});
+
+pub static MIRROR_SKIPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_mirror_skipped_total",
+        "Mirrored writes not sent because the circuit breaker was open or the proxy shut down",
+        &["mirror", "action"]
+    )
+    .expect("register simple_proxy_mirror_skipped_total")
+});
+
+pub static CIRCUIT_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "simple_proxy_circuit_state",
+        "Circuit breaker state per mirror (0 = closed, 1 = open, 2 = half-open)",
+        &["mirror"]
+    )
+    .expect("register simple_proxy_circuit_state")
+});
+
+pub static CIRCUIT_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_circuit_transitions_total",
+        "Circuit breaker state transitions per mirror",
+        &["mirror", "from", "to"]
+    )
+    .expect("register simple_proxy_circuit_transitions_total")
+});
+
+pub static UPSTREAM_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_upstream_retries_total",
+        "Retry decisions for failed primary upstream attempts",
+        &["decision"]
+    )
+    .expect("register simple_proxy_upstream_retries_total")
+});
+
+pub static SCRIPT_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_script_errors_total",
+        "Script hook calls that failed or timed out, by hook",
+        &["hook"]
+    )
+    .expect("register simple_proxy_script_errors_total")
+});
+
+pub static PLUGIN_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_plugin_errors_total",
+        "WebAssembly plugin calls that trapped or ran out of fuel, by plugin and hook",
+        &["plugin", "hook"]
+    )
+    .expect("register simple_proxy_plugin_errors_total")
+});
+
+pub static MIRROR_COMPARISONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_mirror_comparisons_total",
+        "Mirror responses compared with the primary response, by result",
+        &["mirror", "result"]
+    )
+    .expect("register simple_proxy_mirror_comparisons_total")
+});
+
+pub static WEBSOCKET_MESSAGES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_websocket_mirror_messages_total",
+        "Client WebSocket messages mirrored to a secondary upstream, by outcome",
+        &["mirror", "outcome"]
+    )
+    .expect("register simple_proxy_websocket_mirror_messages_total")
+});
+
+pub static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_cache_lookups_total",
+        "Requests looked up in the response cache, by cache status",
+        &["status"]
+    )
+    .expect("register simple_proxy_cache_lookups_total")
+});
+
+pub static COMPRESSED_RESPONSES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_compressed_responses_total",
+        "Responses compressed by the proxy, by content coding",
+        &["algorithm"]
+    )
+    .expect("register simple_proxy_compressed_responses_total")
+});
+
+pub static CORS_PREFLIGHTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_cors_preflights_total",
+        "CORS preflight requests answered by the proxy, by route and result",
+        &["route", "result"]
+    )
+    .expect("register simple_proxy_cors_preflights_total")
+});
+
+pub static ACCESS_DENIED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_access_denied_total",
+        "Requests rejected by the client IP access control, by route (empty for the global lists)",
+        &["route"]
+    )
+    .expect("register simple_proxy_access_denied_total")
+});
+
+pub static LOG_LINES_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_log_lines_dropped_total",
+        "Access log and recording lines lost because the writer fell behind or failed, by reason",
+        &["log", "reason"]
+    )
+    .expect("register simple_proxy_log_lines_dropped_total")
+});
+
+pub static PROXY_PROTOCOL_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "simple_proxy_proxy_protocol_errors_total",
+        "Connections closed for a missing or invalid PROXY protocol header, by reason",
+        &["reason"]
+    )
+    .expect("register simple_proxy_proxy_protocol_errors_total")
+});
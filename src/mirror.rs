@@ -0,0 +1,603 @@
+use crate::backlog::{Backlog, BacklogEntry};
+use crate::circuit_breaker::{CircuitBreaker, Permit};
+use crate::compare::{CapturedResponse, Comparator, PrimaryResponse, RequestSummary};
+use crate::config::{HttpVersion, MirrorConfig, SkippedWrites};
+use crate::events::{EventBus, ProxyEvent};
+use crate::grpc;
+use crate::headers::HeaderRewriter;
+use crate::metrics::{MIRROR_REQUESTS, MIRROR_SKIPPED};
+use crate::recording::Recorder;
+use crate::rewrite::UrlRewriter;
+use crate::shutdown::ShutdownDrain;
+use crate::telemetry;
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::Utc;
+use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
+use http_body_util::BodyExt;
+use opentelemetry::{KeyValue, trace::SpanContext};
+use reqwest::Url;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Copy of a client request that is sent to a secondary upstream.
+#[derive(Debug, Clone)]
+pub struct MirrorRequest {
+    pub request_id: String,
+    pub method: Method,
+    pub path_and_query: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Span of the client request, the mirror span links to it.
+    pub trace_link: Option<SpanContext>,
+    pub route: Option<String>,
+    /// Whether the exchange with the mirror is recorded.
+    pub record: bool,
+    /// Primary response the mirror response is compared with.
+    pub primary_response: Option<PrimaryResponse>,
+}
+
+impl MirrorRequest {
+    /// Url of the request on the upstream at `base_url`.
+    pub fn url(&self, base_url: &str) -> Result<Url> {
+        Ok(Url::parse(&format!(
+            "{}{}",
+            base_url.trim_end_matches('/'),
+            self.path_and_query
+        ))?)
+    }
+
+    /// Builds the http request, shared by the mirrors and the replay tool.
+    pub fn into_http(mut self, client: &MirrorClient, url: Url) -> reqwest::RequestBuilder {
+        // 请求体可能被改写过，长度和分块编码由 reqwest 根据实际请求体重新设置
+        self.headers.remove(CONTENT_LENGTH);
+        self.headers.remove(TRANSFER_ENCODING);
+        let client = if grpc::is_grpc(&self.headers) {
+            &client.grpc
+        } else {
+            &client.http
+        };
+        client
+            .request(self.method, url)
+            .headers(self.headers)
+            .body(self.body)
+    }
+}
+
+/// Http client of the requests sent to the secondary upstreams.
+pub fn http_client(timeout: Duration, version: HttpVersion) -> reqwest::Result<reqwest::Client> {
+    // 创建不带代理的客户端
+    let builder = reqwest::Client::builder().no_proxy().timeout(timeout);
+    match version {
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Auto => builder,
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    }
+    .build()
+}
+
+/// Clients of a secondary upstream, gRPC calls always use HTTP/2.
+#[derive(Debug, Clone)]
+pub struct MirrorClient {
+    http: reqwest::Client,
+    grpc: reqwest::Client,
+}
+
+impl MirrorClient {
+    pub fn new(timeout: Duration, version: HttpVersion) -> reqwest::Result<Self> {
+        Ok(Self {
+            http: http_client(timeout, version)?,
+            grpc: http_client(timeout, HttpVersion::Http2)?,
+        })
+    }
+}
+
+/// What happened to the mirrored copy of a request when it was dispatched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorOutcome {
+    /// Sent in the background.
+    Sent,
+    /// Circuit open, the write was appended to the backlog.
+    Backlogged,
+    /// Circuit open, the write was only counted.
+    Skipped,
+    /// Not sent: the circuit was open and the backlog could not be written, or the body was
+    /// larger than `mirror_max_body_size`.
+    Dropped,
+    /// Not sent, vetoed by a script hook or a WebAssembly plugin.
+    Vetoed,
+}
+
+/// A secondary upstream with its own http client and circuit breaker.
+pub struct MirrorTarget {
+    name: String,
+    base_url: String,
+    timeout: Duration,
+    client: MirrorClient,
+    breaker: CircuitBreaker,
+    backlog: Option<Backlog>,
+    headers: HeaderRewriter,
+    rewrite: UrlRewriter,
+    recorder: Option<Arc<Recorder>>,
+    comparator: Option<Arc<Comparator>>,
+    events: Option<EventBus>,
+    drain: Option<Arc<ShutdownDrain>>,
+}
+
+impl MirrorTarget {
+    pub fn new(config: &MirrorConfig) -> Result<Self> {
+        let client = MirrorClient::new(config.timeout, config.http_version)?;
+        let backlog = match &config.circuit_breaker.on_open {
+            SkippedWrites::Count => None,
+            SkippedWrites::Backlog { path } => Some(Backlog::new(path)),
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            base_url: config.url.trim_end_matches('/').to_string(),
+            timeout: config.timeout,
+            client,
+            breaker: CircuitBreaker::new(&config.name, config.circuit_breaker.clone()),
+            backlog,
+            headers: HeaderRewriter::new(&config.headers)?,
+            rewrite: UrlRewriter::new(&config.rewrite)?,
+            recorder: None,
+            comparator: None,
+            events: None,
+            drain: None,
+        })
+    }
+
+    /// Records the exchanges of the requests marked with [`MirrorRequest::record`].
+    pub fn with_recorder(mut self, recorder: Option<Arc<Recorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Compares the responses of the requests carrying a [`MirrorRequest::primary_response`].
+    pub fn with_comparator(mut self, comparator: Option<Arc<Comparator>>) -> Self {
+        self.comparator = comparator;
+        self
+    }
+
+    /// Publishes the mirror responses and mismatches.
+    pub fn with_events(mut self, events: Option<EventBus>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Tracks the writes in flight so they are drained or persisted on shutdown.
+    pub fn with_drain(mut self, drain: Option<Arc<ShutdownDrain>>) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    /// Header rules for the request copy sent to this mirror.
+    pub fn headers(&self) -> &HeaderRewriter {
+        &self.headers
+    }
+
+    /// Path and query rules for the request copy sent to this mirror.
+    pub fn rewrite(&self) -> &UrlRewriter {
+        &self.rewrite
+    }
+
+    /// Sends the request in a background task, unless the circuit breaker is open or the
+    /// shutdown drain is over.
+    pub fn dispatch(self: &Arc<Self>, request: MirrorRequest) -> MirrorOutcome {
+        if self.drain.as_ref().is_some_and(|drain| drain.is_closed()) {
+            return self.persist(&request);
+        }
+        let Some(permit) = self.breaker.try_acquire() else {
+            return self.skip(&request);
+        };
+        let target = self.clone();
+        match &self.drain {
+            Some(drain) => {
+                let write = drain.track_write(self.clone(), &request);
+                let id = write.id();
+                let task = tokio::spawn(async move {
+                    target.send(request, permit).await;
+                    drop(write);
+                });
+                drain.set_task(id, task.abort_handle());
+            }
+            None => {
+                tokio::spawn(async move { target.send(request, permit).await });
+            }
+        }
+        MirrorOutcome::Sent
+    }
+
+    /// Appends a write not sent before the shutdown to the backlog.
+    pub(crate) fn persist(&self, request: &MirrorRequest) -> MirrorOutcome {
+        let backlog = self
+            .backlog
+            .as_ref()
+            .or(self.drain.as_ref().map(|drain| drain.backlog()));
+        let (outcome, action) = match backlog {
+            Some(backlog) => match backlog.append(&BacklogEntry::new(&self.name, request)) {
+                Ok(()) => (MirrorOutcome::Backlogged, "shutdown_backlog"),
+                Err(e) => {
+                    warn!(mirror = %self.name, request_id = %request.request_id, "failed to write backlog: {:?}", e);
+                    (MirrorOutcome::Dropped, "dropped")
+                }
+            },
+            None => (MirrorOutcome::Dropped, "dropped"),
+        };
+        MIRROR_SKIPPED
+            .with_label_values(&[&self.name, action])
+            .inc();
+        outcome
+    }
+
+    async fn send(&self, mut request: MirrorRequest, permit: Permit) {
+        let request_id = request.request_id.clone();
+        let url = match request.url(&self.base_url) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!(mirror = %self.name, %request_id, "invalid mirror url: {:?}", e);
+                MIRROR_REQUESTS
+                    .with_label_values(&[&self.name, "failure"])
+                    .inc();
+                self.breaker.record_failure(permit);
+                return;
+            }
+        };
+        debug!(mirror = %self.name, %request_id, "Sending duplicate request: {} {}", request.method, url);
+
+        let trace = telemetry::start_linked_span(
+            request.trace_link.take(),
+            format!("mirror {}", self.name),
+            vec![
+                KeyValue::new("http.request.method", request.method.to_string()),
+                KeyValue::new("url.full", url.to_string()),
+                KeyValue::new("request_id", request_id.clone()),
+            ],
+        );
+        for (name, value) in telemetry::trace_headers(&trace) {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                request.headers.insert(name, value);
+            }
+        }
+
+        let recorder = self.recorder.as_ref().filter(|_| request.record);
+        let mut recording = recorder.map(|recorder| {
+            let mut recording = recorder.start(
+                &self.name,
+                &request_id,
+                request.route.as_deref(),
+                &request.method,
+                url.to_string(),
+                &request.headers,
+            );
+            recording.push_request_body(&request.body);
+            recording
+        });
+
+        let comparison = self
+            .comparator
+            .clone()
+            .zip(request.primary_response.take())
+            .map(|(comparator, primary)| (comparator, primary, RequestSummary::new(&request)));
+
+        let route = request.route.clone();
+        let started_at = Instant::now();
+        let response = request.into_http(&self.client, url).send().await;
+        let mut captured = None;
+        let (mut status_code, mut error) = (None, None);
+
+        // 连接错误、超时、读取响应体出错、5xx 响应以及服务端的 gRPC 错误都视为失败
+        let failed = match response {
+            Ok(resp) => {
+                let status = resp.status();
+                status_code = Some(status.as_u16());
+                let headers = resp.headers().clone();
+                if let Some(recording) = &mut recording {
+                    recording.set_response(status.as_u16(), &headers);
+                }
+                let mut grpc_failed = false;
+                // gRPC 的状态在 trailers 中
+                let body = http::Response::from(resp).into_body().collect().await;
+                match body.map(|body| {
+                    let trailers = body.trailers().cloned().unwrap_or_default();
+                    (body.to_bytes(), trailers)
+                }) {
+                    Ok((body, trailers)) => {
+                        debug!(mirror = %self.name, %request_id, %status, "response from mirror: {:?}", String::from_utf8_lossy(&body));
+                        grpc_failed =
+                            grpc::status(&headers, &trailers).is_some_and(grpc::is_server_error);
+                        if let Some(recording) = &mut recording {
+                            recording.push_response_body(&body);
+                        }
+                        if let Some((comparator, ..)) = &comparison {
+                            captured = Some(CapturedResponse {
+                                trailers,
+                                ..CapturedResponse::new(
+                                    status.as_u16(),
+                                    headers,
+                                    body,
+                                    comparator.max_body_size(),
+                                )
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        warn!(mirror = %self.name, %request_id, "error reading response: {:?}", e);
+                        error = Some(e.to_string());
+                    }
+                }
+                telemetry::end_span(&trace, Some(status.as_u16()), error.clone());
+                status.is_server_error() || grpc_failed || error.is_some()
+            }
+            Err(e) => {
+                warn!(mirror = %self.name, %request_id, "error sending to mirror: {:?}", e);
+                telemetry::end_span(&trace, None, Some(e.to_string()));
+                if let Some(recording) = recording.take() {
+                    recording.finish(Some(e.to_string()));
+                }
+                error = Some(e.to_string());
+                true
+            }
+        };
+        if let Some(recording) = recording {
+            recording.finish(error.clone());
+        }
+
+        let outcome = if failed { "failure" } else { "success" };
+        MIRROR_REQUESTS
+            .with_label_values(&[&self.name, outcome])
+            .inc();
+        if failed {
+            self.breaker.record_failure(permit);
+        } else {
+            self.breaker.record_success(permit);
+        }
+        let events = self.events.as_ref().filter(|events| events.is_active());
+        if let Some(events) = events {
+            events.publish(ProxyEvent::Mirror {
+                timestamp: Utc::now(),
+                request_id: request_id.clone(),
+                mirror: self.name.clone(),
+                status: status_code,
+                duration_ms: started_at.elapsed().as_secs_f64() * 1000.0,
+                error,
+            });
+        }
+
+        if let (Some((comparator, primary, request)), Some(response)) = (comparison, captured) {
+            let diff = comparator
+                .compare(&self.name, request, primary, response)
+                .await;
+            if let Some(events) = events.filter(|_| !diff.is_empty()) {
+                events.publish(ProxyEvent::Mismatch {
+                    timestamp: Utc::now(),
+                    request_id,
+                    route,
+                    mirror: self.name.clone(),
+                    fields: diff.into_iter().map(|diff| diff.path).collect(),
+                });
+            }
+        }
+    }
+
+    fn skip(&self, request: &MirrorRequest) -> MirrorOutcome {
+        let (outcome, action) = match &self.backlog {
+            Some(backlog) => match backlog.append(&BacklogEntry::new(&self.name, request)) {
+                Ok(()) => (MirrorOutcome::Backlogged, "backlog"),
+                Err(e) => {
+                    warn!(mirror = %self.name, request_id = %request.request_id, "failed to write backlog: {:?}", e);
+                    (MirrorOutcome::Dropped, "dropped")
+                }
+            },
+            None => (MirrorOutcome::Skipped, "count"),
+        };
+        MIRROR_SKIPPED
+            .with_label_values(&[&self.name, action])
+            .inc();
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::CircuitState;
+    use crate::config::CircuitBreakerConfig;
+    use axum::{Router, body::Body, http::StatusCode, routing::get, routing::post};
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    async fn serve(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    fn target(name: &str, url: String, on_open: SkippedWrites) -> Arc<MirrorTarget> {
+        let config = MirrorConfig {
+            name: name.to_string(),
+            url,
+            circuit_breaker: CircuitBreakerConfig {
+                consecutive_failures: 2,
+                cooldown: Duration::from_secs(60),
+                on_open,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        Arc::new(MirrorTarget::new(&config).expect("Failed to create mirror"))
+    }
+
+    fn request(path_and_query: &str) -> MirrorRequest {
+        MirrorRequest {
+            request_id: "abc".to_string(),
+            method: Method::POST,
+            path_and_query: path_and_query.to_string(),
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"{}"),
+            trace_link: None,
+            route: None,
+            record: false,
+            primary_response: None,
+        }
+    }
+
+    // 镜像请求在后台任务中发送，轮询等待结果
+    async fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..250 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("condition not met in 5s");
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_open_the_breaker() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/users",
+            post({
+                let hits = hits.clone();
+                || async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }),
+        );
+        let path =
+            std::env::temp_dir().join(format!("mirror-backlog-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let target = target(
+            "test_server_errors",
+            serve(app).await,
+            SkippedWrites::Backlog { path: path.clone() },
+        );
+
+        assert_eq!(target.dispatch(request("/users")), MirrorOutcome::Sent);
+        wait_until(|| hits.load(Ordering::SeqCst) == 1).await;
+        assert_eq!(target.breaker().state(), CircuitState::Closed);
+        assert_eq!(target.dispatch(request("/users")), MirrorOutcome::Sent);
+        wait_until(|| target.breaker().state() == CircuitState::Open).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // 熔断期间的写入追加到 backlog，不发送给镜像
+        assert_eq!(
+            target.dispatch(request("/users?id=1")),
+            MirrorOutcome::Backlogged
+        );
+        let entries = Backlog::read_all(&path).expect("Failed to read backlog");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].mirror, "test_server_errors");
+        assert_eq!(entries[0].uri, "/users?id=1");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_url_counts_as_failure() {
+        let target = target(
+            "test_invalid_url",
+            "http://127.0.0.1:1".to_string(),
+            SkippedWrites::Count,
+        );
+
+        // 端口后再接 ":x"，拼出的 url 无法解析
+        for _ in 0..2 {
+            assert_eq!(target.dispatch(request(":x")), MirrorOutcome::Sent);
+        }
+        wait_until(|| target.breaker().state() == CircuitState::Open).await;
+        assert_eq!(
+            MIRROR_REQUESTS
+                .with_label_values(&["test_invalid_url", "failure"])
+                .get(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_read_error_opens_the_breaker() {
+        // 响应头正常返回，响应体读到一半出错
+        let app = Router::new().route(
+            "/users",
+            post(|| async {
+                let head = futures::stream::once(async { Ok("{\"id\":") });
+                let tail = futures::stream::once(async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Err(std::io::Error::other("boom"))
+                });
+                Body::from_stream(head.chain(tail))
+            }),
+        );
+        let target = target(
+            "test_body_read_error",
+            serve(app).await,
+            SkippedWrites::Count,
+        );
+
+        for _ in 0..2 {
+            assert_eq!(target.dispatch(request("/users")), MirrorOutcome::Sent);
+        }
+        wait_until(|| target.breaker().state() == CircuitState::Open).await;
+    }
+
+    #[tokio::test]
+    async fn test_skipped_writes_are_counted() {
+        let app = Router::new().route("/users", post(|| async { StatusCode::BAD_GATEWAY }));
+        let target = target("test_skipped", serve(app).await, SkippedWrites::Count);
+        for _ in 0..2 {
+            target.dispatch(request("/users"));
+        }
+        wait_until(|| target.breaker().state() == CircuitState::Open).await;
+        assert_eq!(target.dispatch(request("/users")), MirrorOutcome::Skipped);
+    }
+
+    #[tokio::test]
+    async fn test_http_client_versions() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        for (version, expected) in [
+            (HttpVersion::Http1, http::Version::HTTP_11),
+            (HttpVersion::Auto, http::Version::HTTP_11),
+            (HttpVersion::Http2, http::Version::HTTP_2),
+        ] {
+            let client =
+                http_client(Duration::from_secs(5), version).expect("Failed to build client");
+            let response = client
+                .get(format!("http://{addr}/"))
+                .send()
+                .await
+                .expect("Failed to send request");
+            assert_eq!(response.version(), expected, "{version:?}");
+        }
+    }
+}
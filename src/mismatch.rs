@@ -0,0 +1,439 @@
+//! SQLite store of the primary/mirror response mismatches found by [`crate::compare`].
+//!
+//! Besides the mismatches themselves the store counts the comparisons per route and mirror, so
+//! the summary can report mismatch rates. Retention limits are applied on every insert.
+
+use crate::compare::FieldDiff;
+use crate::config::RetentionConfig;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+PRAGMA foreign_keys = ON;
+CREATE TABLE IF NOT EXISTS mismatches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp TEXT NOT NULL,
+    request_id TEXT NOT NULL,
+    route TEXT,
+    mirror TEXT NOT NULL,
+    method TEXT NOT NULL,
+    path TEXT NOT NULL,
+    request_body TEXT NOT NULL,
+    primary_status INTEGER NOT NULL,
+    primary_body TEXT NOT NULL,
+    mirror_status INTEGER NOT NULL,
+    mirror_body TEXT NOT NULL,
+    diff TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS mismatches_timestamp ON mismatches (timestamp);
+CREATE TABLE IF NOT EXISTS mismatch_fields (
+    mismatch_id INTEGER NOT NULL REFERENCES mismatches (id) ON DELETE CASCADE,
+    field TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS mismatch_fields_mismatch ON mismatch_fields (mismatch_id);
+CREATE TABLE IF NOT EXISTS comparisons (
+    route TEXT NOT NULL,
+    mirror TEXT NOT NULL,
+    compared INTEGER NOT NULL,
+    mismatches INTEGER NOT NULL,
+    PRIMARY KEY (route, mirror)
+);
+";
+
+const COLUMNS: &str = "id, timestamp, request_id, route, mirror, method, path, request_body, \
+                       primary_status, primary_body, mirror_status, mirror_body, diff";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MismatchRecord {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    pub route: Option<String>,
+    pub mirror: String,
+    pub method: String,
+    pub path: String,
+    pub request_body: String,
+    pub primary_status: u16,
+    pub primary_body: String,
+    pub mirror_status: u16,
+    pub mirror_body: String,
+    pub diff: Vec<FieldDiff>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MismatchSummary {
+    /// Mismatches currently stored, after retention.
+    pub stored: i64,
+    pub routes: Vec<RouteSummary>,
+    /// Fields differing most often among the stored mismatches.
+    pub top_fields: Vec<FieldCount>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteSummary {
+    /// Empty for requests without a route.
+    pub route: String,
+    pub mirror: String,
+    pub compared: i64,
+    pub mismatches: i64,
+    pub mismatch_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldCount {
+    pub field: String,
+    pub count: i64,
+}
+
+pub struct MismatchStore {
+    conn: Mutex<Connection>,
+    retention: RetentionConfig,
+}
+
+impl MismatchStore {
+    pub fn open(path: &Path, retention: RetentionConfig) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("failed to open mismatch store {}", path.display()))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            retention,
+        })
+    }
+
+    /// Counts a comparison of `route` and `mirror`, storing `mismatch` when the responses
+    /// differed. Blocks on SQLite, call it from a blocking task.
+    pub fn record_comparison(
+        &self,
+        route: &str,
+        mirror: &str,
+        mismatch: Option<&MismatchRecord>,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO comparisons (route, mirror, compared, mismatches) VALUES (?1, ?2, 1, ?3)
+             ON CONFLICT (route, mirror) DO UPDATE
+             SET compared = compared + 1, mismatches = mismatches + excluded.mismatches",
+            params![route, mirror, mismatch.is_some() as i64],
+        )?;
+        if let Some(mismatch) = mismatch {
+            tx.execute(
+                &format!(
+                    "INSERT INTO mismatches ({}) VALUES (NULL, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                    COLUMNS
+                ),
+                params![
+                    timestamp(&mismatch.timestamp),
+                    mismatch.request_id,
+                    mismatch.route,
+                    mismatch.mirror,
+                    mismatch.method,
+                    mismatch.path,
+                    mismatch.request_body,
+                    mismatch.primary_status,
+                    mismatch.primary_body,
+                    mismatch.mirror_status,
+                    mismatch.mirror_body,
+                    serde_json::to_string(&mismatch.diff)?,
+                ],
+            )?;
+            let id = tx.last_insert_rowid();
+            let mut fields: Vec<_> = mismatch.diff.iter().map(FieldDiff::field).collect();
+            fields.sort();
+            fields.dedup();
+            for field in fields {
+                tx.execute(
+                    "INSERT INTO mismatch_fields (mismatch_id, field) VALUES (?1, ?2)",
+                    params![id, field],
+                )?;
+            }
+            Self::apply_retention(&tx, &self.retention)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn apply_retention(conn: &Connection, retention: &RetentionConfig) -> Result<()> {
+        if let Ok(max_age) = chrono::Duration::from_std(retention.max_age) {
+            let cutoff = Utc::now() - max_age;
+            conn.execute(
+                "DELETE FROM mismatches WHERE timestamp < ?1",
+                params![timestamp(&cutoff)],
+            )?;
+        }
+        let oldest_kept: Option<i64> = conn
+            .query_row(
+                "SELECT id FROM mismatches ORDER BY id DESC LIMIT 1 OFFSET ?1",
+                params![retention.max_entries.saturating_sub(1) as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(id) = oldest_kept {
+            conn.execute("DELETE FROM mismatches WHERE id < ?1", params![id])?;
+        }
+        Ok(())
+    }
+
+    /// Stored mismatches, oldest first, at most `limit` of the most recent ones.
+    pub fn list(&self, limit: Option<usize>) -> Result<Vec<MismatchRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM (SELECT * FROM mismatches ORDER BY id DESC LIMIT ?1) ORDER BY id",
+            COLUMNS
+        ))?;
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let rows = stmt.query_map(params![limit], |row| {
+            Ok((
+                MismatchRecord {
+                    id: row.get(0)?,
+                    timestamp: row.get::<_, String>(1)?.parse().map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            1,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        )
+                    })?,
+                    request_id: row.get(2)?,
+                    route: row.get(3)?,
+                    mirror: row.get(4)?,
+                    method: row.get(5)?,
+                    path: row.get(6)?,
+                    request_body: row.get(7)?,
+                    primary_status: row.get(8)?,
+                    primary_body: row.get(9)?,
+                    mirror_status: row.get(10)?,
+                    mirror_body: row.get(11)?,
+                    diff: Vec::new(),
+                },
+                row.get::<_, String>(12)?,
+            ))
+        })?;
+        rows.map(|row| {
+            let (mut record, diff) = row?;
+            record.diff = serde_json::from_str(&diff)?;
+            Ok(record)
+        })
+        .collect()
+    }
+
+    pub fn summary(&self, top_fields: usize) -> Result<MismatchSummary> {
+        let conn = self.conn.lock().unwrap();
+        let stored = conn.query_row("SELECT COUNT(*) FROM mismatches", [], |row| row.get(0))?;
+        let mut stmt = conn.prepare(
+            "SELECT route, mirror, compared, mismatches FROM comparisons ORDER BY route, mirror",
+        )?;
+        let routes = stmt
+            .query_map([], |row| {
+                let (compared, mismatches): (i64, i64) = (row.get(2)?, row.get(3)?);
+                Ok(RouteSummary {
+                    route: row.get(0)?,
+                    mirror: row.get(1)?,
+                    compared,
+                    mismatches,
+                    mismatch_rate: mismatches as f64 / compared.max(1) as f64,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        let mut stmt = conn.prepare(
+            "SELECT field, COUNT(*) AS count FROM mismatch_fields
+             GROUP BY field ORDER BY count DESC, field LIMIT ?1",
+        )?;
+        let top_fields = stmt
+            .query_map(params![top_fields as i64], |row| {
+                Ok(FieldCount {
+                    field: row.get(0)?,
+                    count: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(MismatchSummary {
+            stored,
+            routes,
+            top_fields,
+        })
+    }
+
+    /// Writes the stored mismatches as a JSON array.
+    pub fn export_json(&self, mut out: impl Write) -> Result<()> {
+        serde_json::to_writer_pretty(&mut out, &self.list(None)?)?;
+        writeln!(out)?;
+        Ok(())
+    }
+
+    /// Writes one CSV row per stored mismatch. The bodies are left out, the differing fields
+    /// are joined with `;`.
+    pub fn export_csv(&self, mut out: impl Write) -> Result<()> {
+        writeln!(
+            out,
+            "id,timestamp,request_id,route,mirror,method,path,primary_status,mirror_status,fields"
+        )?;
+        for record in self.list(None)? {
+            let fields: Vec<_> = record.diff.iter().map(|diff| diff.path.as_str()).collect();
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{}",
+                record.id,
+                timestamp(&record.timestamp),
+                csv_field(&record.request_id),
+                csv_field(record.route.as_deref().unwrap_or_default()),
+                csv_field(&record.mirror),
+                csv_field(&record.method),
+                csv_field(&record.path),
+                record.primary_status,
+                record.mirror_status,
+                csv_field(&fields.join(";")),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Fixed width RFC 3339 so that timestamps compare as text.
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for MismatchSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "stored mismatches: {}", self.stored)?;
+        for route in &self.routes {
+            writeln!(
+                f,
+                "  {} -> {}: {}/{} mismatched ({:.2}%)",
+                if route.route.is_empty() {
+                    "-"
+                } else {
+                    &route.route
+                },
+                route.mirror,
+                route.mismatches,
+                route.compared,
+                route.mismatch_rate * 100.0
+            )?;
+        }
+        if !self.top_fields.is_empty() {
+            writeln!(f, "top differing fields:")?;
+            for field in &self.top_fields {
+                writeln!(f, "  {}: {}", field.field, field.count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    fn store(name: &str, max_entries: usize) -> MismatchStore {
+        let path = std::env::temp_dir().join(format!("mismatch-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        MismatchStore::open(
+            &path,
+            RetentionConfig {
+                max_entries,
+                max_age: Duration::from_secs(3600),
+            },
+        )
+        .expect("Failed to open store")
+    }
+
+    fn record(request_id: &str, timestamp: DateTime<Utc>, paths: &[&str]) -> MismatchRecord {
+        MismatchRecord {
+            id: 0,
+            timestamp,
+            request_id: request_id.to_string(),
+            route: Some("users".to_string()),
+            mirror: "secondary".to_string(),
+            method: "GET".to_string(),
+            path: "/users?q=a,b".to_string(),
+            request_body: String::new(),
+            primary_status: 200,
+            primary_body: "{}".to_string(),
+            mirror_status: 200,
+            mirror_body: "{}".to_string(),
+            diff: paths
+                .iter()
+                .map(|path| FieldDiff {
+                    path: path.to_string(),
+                    primary: Value::Null,
+                    mirror: Value::Bool(true),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_store_summary_and_retention() {
+        let store = store("retention", 2);
+        let now = Utc::now();
+        store
+            .record_comparison("users", "secondary", None)
+            .expect("Failed to record comparison");
+        // 超过 max_age 的记录在下次写入时被删除
+        let old = record("old", now - chrono::Duration::hours(2), &["status"]);
+        store
+            .record_comparison("users", "secondary", Some(&old))
+            .unwrap();
+        for id in ["a", "b", "c"] {
+            let record = record(id, now, &["body.items[0].id", "body.items[1].id"]);
+            store
+                .record_comparison("users", "secondary", Some(&record))
+                .unwrap();
+        }
+
+        let stored = store.list(None).unwrap();
+        let ids: Vec<_> = stored.iter().map(|r| r.request_id.as_str()).collect();
+        assert_eq!(ids, ["b", "c"]);
+        assert_eq!(stored[0].diff.len(), 2);
+
+        let summary = store.summary(10).unwrap();
+        assert_eq!(summary.stored, 2);
+        assert_eq!(summary.routes[0].compared, 5);
+        assert_eq!(summary.routes[0].mismatches, 4);
+        assert_eq!(summary.top_fields.len(), 1);
+        assert_eq!(summary.top_fields[0].field, "body.items[].id");
+        assert_eq!(summary.top_fields[0].count, 2);
+    }
+
+    #[test]
+    fn test_export() {
+        let store = store("export", 10);
+        let record = record("a", Utc::now(), &["status", "body.name"]);
+        store
+            .record_comparison("users", "secondary", Some(&record))
+            .unwrap();
+
+        let mut csv = Vec::new();
+        store.export_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.ends_with(",a,users,secondary,GET,\"/users?q=a,b\",200,200,status;body.name"));
+
+        let mut json = Vec::new();
+        store.export_json(&mut json).unwrap();
+        let exported: Vec<MismatchRecord> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(exported[0].diff, record.diff);
+    }
+}
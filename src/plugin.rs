@@ -0,0 +1,574 @@
+//! WebAssembly filter plugins.
+//!
+//! A plugin is a core wasm module exporting `memory` and any of the hooks `on_request`,
+//! `on_response` and `on_mirror`, all of type `() -> i32`. Returning `1` from `on_request`
+//! disables mirroring for the request, returning `1` from `on_mirror` vetoes that copy, `0`
+//! continues. A plugin exporting `init(ptr: i32, len: i32) -> i32` and `alloc(len: i32) -> i32`
+//! receives its configuration as JSON when an instance is created, `init` returns `0` on success.
+//!
+//! The request or response of the current hook is accessed through functions imported from the
+//! `proxy` module. Getters write into the `(ptr, cap)` buffer and return the full length, the
+//! value is only written if it fits, so the plugin can retry with a larger buffer; `-1` means
+//! the value is not available in this hook. Setters return `0` on success and `-1` otherwise.
+//!
+//! - `log(level, ptr, len)`: level 0 debug, 1 info, 2 warn, 3 error
+//! - `get_method(ptr, cap)`, `set_method(ptr, len)`
+//! - `get_path(ptr, cap)`, `set_path(ptr, len)`: path and query
+//! - `get_header(name_ptr, name_len, ptr, cap)`, `set_header(name_ptr, name_len, ptr, len)`,
+//!   `remove_header(name_ptr, name_len)`: request headers, response headers in `on_response`
+//! - `get_body(ptr, cap)`, `set_body(ptr, len)`: only in `on_mirror`
+//! - `get_status()`: upstream status in `on_response`, `-1` otherwise
+//! - `get_mirror(ptr, cap)`: mirror target name in `on_mirror`
+//! - `set_route(ptr, len)`: selects the route by name in `on_request`
+
+use crate::config::PluginConfig;
+use crate::metrics::PLUGIN_ERRORS;
+use crate::scripting::ScriptRequest;
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, uri::PathAndQuery};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error, info, warn};
+use wasmtime::{
+    Caller, Config, Engine, Extern, Instance, Linker, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+/// Result of the `on_request` hooks.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RequestDecision {
+    pub skip_mirror: bool,
+    /// Route selected by a plugin, overrides route matching.
+    pub route: Option<String>,
+}
+
+/// Loaded plugins, called in configuration order.
+pub struct Plugins {
+    engine: Engine,
+    linker: Linker<HostState>,
+    plugins: Vec<Plugin>,
+}
+
+struct Plugin {
+    name: String,
+    module: Module,
+    config: Vec<u8>,
+    max_memory: usize,
+    max_fuel: u64,
+    routes: Arc<Vec<String>>,
+    /// Idle instances, an instance serves one hook call at a time.
+    pool: Mutex<Vec<PluginInstance>>,
+}
+
+struct PluginInstance {
+    store: Store<HostState>,
+    instance: Instance,
+}
+
+struct HostState {
+    limits: StoreLimits,
+    plugin: String,
+    routes: Arc<Vec<String>>,
+    call: CallState,
+}
+
+/// Data of the hook call in progress.
+#[derive(Debug, Default)]
+struct CallState {
+    method: Option<Method>,
+    path: Option<String>,
+    headers: HeaderMap,
+    body: Option<Bytes>,
+    status: Option<u16>,
+    mirror: Option<String>,
+    can_route: bool,
+    route: Option<String>,
+}
+
+impl Plugins {
+    /// Compiles the modules and creates a first instance of each, so invalid plugins, failing
+    /// `init` calls and memory limits that are too low are reported at startup.
+    pub fn load(configs: &[PluginConfig], routes: Vec<String>) -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let linker = linker(&engine)?;
+        let routes = Arc::new(routes);
+        let plugins = configs
+            .iter()
+            .map(|config| {
+                let module = Module::from_file(&engine, &config.path)
+                    .with_context(|| format!("failed to load plugin {}", config.name))?;
+                Ok(Plugin {
+                    name: config.name.clone(),
+                    module,
+                    config: serde_json::to_vec(&config.config)?,
+                    max_memory: config.max_memory,
+                    max_fuel: config.max_fuel,
+                    routes: routes.clone(),
+                    pool: Mutex::new(Vec::new()),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let plugins = Self {
+            engine,
+            linker,
+            plugins,
+        };
+        for plugin in &plugins.plugins {
+            let instance = plugins
+                .instantiate(plugin)
+                .with_context(|| format!("failed to instantiate plugin {}", plugin.name))?;
+            plugin.pool.lock().unwrap().push(instance);
+        }
+        Ok(plugins)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+
+    pub fn on_request(&self, request: &mut ScriptRequest, request_id: &str) -> RequestDecision {
+        let mut decision = RequestDecision::default();
+        for plugin in &self.plugins {
+            let state = CallState {
+                can_route: true,
+                ..CallState::from_request(request.clone())
+            };
+            if let Some((code, state)) = self.call(plugin, "on_request", state, request_id) {
+                decision.skip_mirror |= code == 1;
+                decision.route = state.route.clone().or(decision.route);
+                state.update_request(request);
+            }
+        }
+        decision
+    }
+
+    pub fn on_response(&self, status: u16, headers: &mut HeaderMap, request_id: &str) {
+        for plugin in &self.plugins {
+            let state = CallState {
+                headers: headers.clone(),
+                status: Some(status),
+                ..Default::default()
+            };
+            if let Some((_, state)) = self.call(plugin, "on_response", state, request_id) {
+                *headers = state.headers;
+            }
+        }
+    }
+
+    /// Returns `false` when a plugin vetoed the mirrored copy.
+    pub fn on_mirror(&self, request: &mut ScriptRequest, request_id: &str) -> bool {
+        for plugin in &self.plugins {
+            let state = CallState::from_request(request.clone());
+            if let Some((code, state)) = self.call(plugin, "on_mirror", state, request_id) {
+                state.update_request(request);
+                if code == 1 {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Calls the hook on an idle instance. Failing calls are logged and ignored, the instance is
+    /// then discarded since its state may be inconsistent.
+    fn call(
+        &self,
+        plugin: &Plugin,
+        hook: &str,
+        state: CallState,
+        request_id: &str,
+    ) -> Option<(i32, CallState)> {
+        plugin.module.get_export(hook)?;
+        let idle = plugin.pool.lock().unwrap().pop();
+        let result = idle
+            .map_or_else(|| self.instantiate(plugin), Ok)
+            .and_then(|mut instance| {
+                instance.store.data_mut().call = state;
+                instance.store.set_fuel(plugin.max_fuel)?;
+                let func = instance
+                    .instance
+                    .get_typed_func::<(), i32>(&mut instance.store, hook)?;
+                let code = func.call(&mut instance.store, ())?;
+                Ok((code, instance))
+            });
+        match result {
+            Ok((code, mut instance)) => {
+                let state = std::mem::take(&mut instance.store.data_mut().call);
+                plugin.pool.lock().unwrap().push(instance);
+                Some((code, state))
+            }
+            Err(e) => {
+                warn!(%request_id, plugin = %plugin.name, hook, "plugin call failed: {:?}", e);
+                PLUGIN_ERRORS
+                    .with_label_values(&[plugin.name.as_str(), hook])
+                    .inc();
+                None
+            }
+        }
+    }
+
+    fn instantiate(&self, plugin: &Plugin) -> Result<PluginInstance> {
+        let state = HostState {
+            limits: StoreLimitsBuilder::new()
+                .memory_size(plugin.max_memory)
+                .instances(1)
+                .build(),
+            plugin: plugin.name.clone(),
+            routes: plugin.routes.clone(),
+            call: CallState::default(),
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(plugin.max_fuel)?;
+        let instance = self.linker.instantiate(&mut store, &plugin.module)?;
+
+        if let Some(init) = instance.get_func(&mut store, "init") {
+            let init = init.typed::<(i32, i32), i32>(&store)?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| anyhow!("plugin does not export memory"))?;
+            let len = plugin.config.len() as i32;
+            let ptr = alloc.call(&mut store, len)?;
+            memory.write(&mut store, ptr as usize, &plugin.config)?;
+            let code = init.call(&mut store, (ptr, len))?;
+            if code != 0 {
+                bail!("init returned {code}");
+            }
+        }
+        Ok(PluginInstance { store, instance })
+    }
+}
+
+impl CallState {
+    fn from_request(request: ScriptRequest) -> Self {
+        Self {
+            method: Some(request.method),
+            path: Some(request.path),
+            headers: request.headers,
+            body: request.body,
+            mirror: request.mirror,
+            ..Default::default()
+        }
+    }
+
+    fn update_request(self, request: &mut ScriptRequest) {
+        if let Some(method) = self.method {
+            request.method = method;
+        }
+        if let Some(path) = self.path {
+            request.path = path;
+        }
+        request.headers = self.headers;
+        if self.body.is_some() {
+            request.body = self.body;
+        }
+    }
+}
+
+fn linker(engine: &Engine) -> Result<Linker<HostState>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(
+        "proxy",
+        "log",
+        |mut caller: Caller<'_, HostState>, level: i32, ptr: i32, len: i32| -> Result<()> {
+            let message = String::from_utf8_lossy(&read(&mut caller, ptr, len)?).into_owned();
+            let plugin = &caller.data().plugin;
+            match level {
+                0 => debug!(%plugin, "{}", message),
+                1 => info!(%plugin, "{}", message),
+                2 => warn!(%plugin, "{}", message),
+                _ => error!(%plugin, "{}", message),
+            }
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "get_method",
+        |mut caller: Caller<'_, HostState>, ptr: i32, cap: i32| {
+            let method = caller.data().call.method.as_ref().map(|m| m.to_string());
+            write_optional(&mut caller, ptr, cap, method.as_ref().map(String::as_bytes))
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_method",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let value = read(&mut caller, ptr, len)?;
+            let call = &mut caller.data_mut().call;
+            Ok(match (&call.method, Method::from_bytes(&value)) {
+                (Some(_), Ok(method)) => {
+                    call.method = Some(method);
+                    0
+                }
+                _ => -1,
+            })
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "get_path",
+        |mut caller: Caller<'_, HostState>, ptr: i32, cap: i32| {
+            let path = caller.data().call.path.clone();
+            write_optional(&mut caller, ptr, cap, path.as_ref().map(String::as_bytes))
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_path",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let value = read(&mut caller, ptr, len)?;
+            let call = &mut caller.data_mut().call;
+            Ok(match (&call.path, PathAndQuery::try_from(value)) {
+                (Some(_), Ok(path)) => {
+                    call.path = Some(path.to_string());
+                    0
+                }
+                _ => -1,
+            })
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "get_header",
+        |mut caller: Caller<'_, HostState>, name_ptr: i32, name_len: i32, ptr: i32, cap: i32| {
+            let name = read(&mut caller, name_ptr, name_len)?;
+            let value = HeaderName::from_bytes(&name)
+                .ok()
+                .and_then(|name| caller.data().call.headers.get(name).cloned());
+            write_optional(&mut caller, ptr, cap, value.as_ref().map(|v| v.as_bytes()))
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_header",
+        |mut caller: Caller<'_, HostState>, name_ptr: i32, name_len: i32, ptr: i32, len: i32| {
+            let name = read(&mut caller, name_ptr, name_len)?;
+            let value = read(&mut caller, ptr, len)?;
+            Ok(
+                match (
+                    HeaderName::from_bytes(&name),
+                    HeaderValue::from_bytes(&value),
+                ) {
+                    (Ok(name), Ok(value)) => {
+                        caller.data_mut().call.headers.insert(name, value);
+                        0
+                    }
+                    _ => -1,
+                },
+            )
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "remove_header",
+        |mut caller: Caller<'_, HostState>, name_ptr: i32, name_len: i32| {
+            let name = read(&mut caller, name_ptr, name_len)?;
+            if let Ok(name) = HeaderName::from_bytes(&name) {
+                caller.data_mut().call.headers.remove(name);
+            }
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "get_body",
+        |mut caller: Caller<'_, HostState>, ptr: i32, cap: i32| {
+            let body = caller.data().call.body.clone();
+            write_optional(&mut caller, ptr, cap, body.as_deref())
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_body",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let value = read(&mut caller, ptr, len)?;
+            let call = &mut caller.data_mut().call;
+            Ok(match call.body {
+                Some(_) => {
+                    call.body = Some(value.into());
+                    0
+                }
+                None => -1,
+            })
+        },
+    )?;
+    linker.func_wrap("proxy", "get_status", |caller: Caller<'_, HostState>| {
+        caller.data().call.status.map_or(-1, i32::from)
+    })?;
+    linker.func_wrap(
+        "proxy",
+        "get_mirror",
+        |mut caller: Caller<'_, HostState>, ptr: i32, cap: i32| {
+            let mirror = caller.data().call.mirror.clone();
+            write_optional(&mut caller, ptr, cap, mirror.as_ref().map(String::as_bytes))
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_route",
+        |mut caller: Caller<'_, HostState>, ptr: i32, len: i32| {
+            let name = String::from_utf8(read(&mut caller, ptr, len)?)?;
+            let state = caller.data_mut();
+            Ok(if state.call.can_route && state.routes.contains(&name) {
+                state.call.route = Some(name);
+                0
+            } else {
+                -1
+            })
+        },
+    )?;
+    Ok(linker)
+}
+
+fn memory(caller: &mut Caller<'_, HostState>) -> Result<wasmtime::Memory> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| anyhow!("plugin does not export memory"))
+}
+
+fn read(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> Result<Vec<u8>> {
+    let memory = memory(caller)?;
+    let mut buf = vec![0; usize::try_from(len)?];
+    memory.read(&caller, usize::try_from(ptr)?, &mut buf)?;
+    Ok(buf)
+}
+
+fn write_optional(
+    caller: &mut Caller<'_, HostState>,
+    ptr: i32,
+    cap: i32,
+    value: Option<&[u8]>,
+) -> Result<i32> {
+    let Some(value) = value else {
+        return Ok(-1);
+    };
+    if value.len() <= usize::try_from(cap)? {
+        let memory = memory(caller)?;
+        memory.write(caller, usize::try_from(ptr)?, value)?;
+    }
+    Ok(i32::try_from(value.len())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn plugin(name: &str, wat: &str, config: serde_json::Value) -> PluginConfig {
+        let path = std::env::temp_dir().join(format!("plugin-{}-{name}.wat", std::process::id()));
+        std::fs::write(&path, wat).unwrap();
+        PluginConfig {
+            name: name.to_string(),
+            path,
+            config,
+            max_memory: 1024 * 1024,
+            max_fuel: 100_000,
+        }
+    }
+
+    fn script_request(body: Option<&str>) -> ScriptRequest {
+        ScriptRequest {
+            method: Method::POST,
+            path: "/users".to_string(),
+            headers: HeaderMap::new(),
+            body: body.map(|body| Bytes::from(body.to_string())),
+            mirror: body.map(|_| "secondary".to_string()),
+        }
+    }
+
+    // 保存配置，在 on_request 中写入请求头并选择路由，带 x-skip 请求头时不镜像
+    const HEADER_PLUGIN: &str = r#"
+(module
+  (import "proxy" "set_header" (func $set_header (param i32 i32 i32 i32) (result i32)))
+  (import "proxy" "get_header" (func $get_header (param i32 i32 i32 i32) (result i32)))
+  (import "proxy" "set_route" (func $set_route (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (global $config_ptr (mut i32) (i32.const 0))
+  (global $config_len (mut i32) (i32.const 0))
+  (data (i32.const 0) "x-config")
+  (data (i32.const 16) "x-skip")
+  (data (i32.const 32) "users")
+  (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "init") (param i32 i32) (result i32)
+    (global.set $config_ptr (local.get 0))
+    (global.set $config_len (local.get 1))
+    (i32.const 0))
+  (func (export "on_request") (result i32)
+    (drop (call $set_header (i32.const 0) (i32.const 8) (global.get $config_ptr) (global.get $config_len)))
+    (drop (call $set_route (i32.const 32) (i32.const 5)))
+    (i32.ge_s (call $get_header (i32.const 16) (i32.const 6) (i32.const 0) (i32.const 0)) (i32.const 0))))
+"#;
+
+    #[test]
+    fn test_request_hook() {
+        let config = plugin("header", HEADER_PLUGIN, json!("acme"));
+        let plugins = Plugins::load(&[config], vec!["users".to_string()]).expect("Failed to load");
+
+        let mut request = script_request(None);
+        let decision = plugins.on_request(&mut request, "abc");
+        assert_eq!(request.headers["x-config"], r#""acme""#);
+        assert_eq!(decision.route.as_deref(), Some("users"));
+        assert!(!decision.skip_mirror);
+
+        request
+            .headers
+            .insert("x-skip", HeaderValue::from_static("1"));
+        assert!(plugins.on_request(&mut request, "abc").skip_mirror);
+    }
+
+    // on_mirror 中读取请求体，请求体以 "{" 开头时改写为 "{}" 并放行，否则否决
+    const BODY_PLUGIN: &str = r#"
+(module
+  (import "proxy" "get_body" (func $get_body (param i32 i32) (result i32)))
+  (import "proxy" "set_body" (func $set_body (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 512) "{}")
+  (func (export "on_mirror") (result i32)
+    (if (i32.le_s (call $get_body (i32.const 0) (i32.const 256)) (i32.const 0))
+      (then (return (i32.const 1))))
+    (if (i32.ne (i32.load8_u (i32.const 0)) (i32.const 123))
+      (then (return (i32.const 1))))
+    (drop (call $set_body (i32.const 512) (i32.const 2)))
+    (i32.const 0)))
+"#;
+
+    #[test]
+    fn test_mirror_hook() {
+        let config = plugin("body", BODY_PLUGIN, serde_json::Value::Null);
+        let plugins = Plugins::load(&[config], vec![]).expect("Failed to load");
+
+        let mut request = script_request(Some(r#"{"tenant":"acme"}"#));
+        assert!(plugins.on_mirror(&mut request, "abc"));
+        assert_eq!(request.body.as_deref(), Some(&b"{}"[..]));
+
+        let mut request = script_request(Some("plain text"));
+        assert!(!plugins.on_mirror(&mut request, "abc"));
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let looping = plugin(
+            "loop",
+            r#"(module (memory (export "memory") 1) (func (export "on_mirror") (result i32) (loop (br 0)) (i32.const 1)))"#,
+            serde_json::Value::Null,
+        );
+        let plugins = Plugins::load(&[looping], vec![]).expect("Failed to load");
+        // 燃料耗尽时调用失败，请求保持不变并继续镜像
+        let mut request = script_request(Some("{}"));
+        assert!(plugins.on_mirror(&mut request, "abc"));
+        assert!(plugins.on_mirror(&mut request, "abc"));
+
+        // 初始内存超过限制时在启动时报错
+        let large = plugin(
+            "large",
+            r#"(module (memory (export "memory") 100))"#,
+            serde_json::Value::Null,
+        );
+        assert!(Plugins::load(&[large], vec![]).is_err());
+    }
+}
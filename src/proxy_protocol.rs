@@ -0,0 +1,394 @@
+//! PROXY protocol v1 and v2 on the plain HTTP listener.
+//!
+//! pingora reads HTTP from the first byte of a connection, so when the PROXY protocol is enabled
+//! `listen` is served by a relay: it reads the header a load balancer sends ahead of the
+//! connection, connects to the proxy on `proxy_protocol.internal_listen` and copies the bytes
+//! both ways. The client address of the header is kept in [`ProxiedClients`] under the local
+//! address of the relayed connection, which is the peer address the proxy sees.
+//!
+//! Every connection must start with a header, connections without one or with a malformed one
+//! are closed. A `LOCAL` header (health checks of the load balancer) keeps the peer address.
+
+use crate::config::ProxyProtocolConfig;
+use crate::metrics::PROXY_PROTOCOL_ERRORS;
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, `PROXY UNKNOWN` with two IPv6 addresses and the CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED_LEN: usize = 16;
+
+/// Client addresses of the relayed connections, by the local address of the connection to the
+/// proxy.
+#[derive(Debug, Default)]
+pub struct ProxiedClients {
+    clients: Mutex<HashMap<SocketAddr, SocketAddr>>,
+}
+
+impl ProxiedClients {
+    /// Client behind the proxy's peer `peer`, `None` when the connection was not relayed.
+    pub fn get(&self, peer: &SocketAddr) -> Option<SocketAddr> {
+        self.clients.lock().unwrap().get(peer).copied()
+    }
+
+    fn register(self: &Arc<Self>, local: SocketAddr, client: SocketAddr) -> ClientGuard {
+        self.clients.lock().unwrap().insert(local, client);
+        ClientGuard {
+            clients: self.clone(),
+            local,
+        }
+    }
+}
+
+/// Removes the client of a relayed connection once it is closed.
+struct ClientGuard {
+    clients: Arc<ProxiedClients>,
+    local: SocketAddr,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.local);
+    }
+}
+
+/// Parses the PROXY header at the start of `buf`. `Ok(None)` when more bytes are needed, else
+/// the source address (`None` for `LOCAL`, `UNKNOWN` and non IP families) and the header length.
+pub fn parse_header(buf: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>> {
+    let prefix = buf.len().min(V2_SIGNATURE.len());
+    if buf[..prefix] == V2_SIGNATURE[..prefix] {
+        return if prefix < V2_SIGNATURE.len() {
+            Ok(None)
+        } else {
+            parse_v2(buf)
+        };
+    }
+    let prefix = buf.len().min(V1_PREFIX.len());
+    if buf[..prefix] == V1_PREFIX[..prefix] {
+        return if prefix < V1_PREFIX.len() {
+            Ok(None)
+        } else {
+            parse_v1(buf)
+        };
+    }
+    bail!("connection does not start with a PROXY protocol header")
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>> {
+    let Some(end) = buf.windows(2).position(|window| window == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            bail!("PROXY v1 header longer than {V1_MAX_LEN} bytes");
+        }
+        return Ok(None);
+    };
+    if end + 2 > V1_MAX_LEN {
+        bail!("PROXY v1 header longer than {V1_MAX_LEN} bytes");
+    }
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.as_slice() {
+        ["UNKNOWN", ..] => None,
+        [
+            family @ ("TCP4" | "TCP6"),
+            source,
+            destination,
+            source_port,
+            destination_port,
+        ] => {
+            let source: IpAddr = source.parse()?;
+            let destination: IpAddr = destination.parse()?;
+            if source.is_ipv4() != (*family == "TCP4") || source.is_ipv4() != destination.is_ipv4()
+            {
+                bail!("PROXY v1 addresses do not match the {family} family");
+            }
+            destination_port.parse::<u16>()?;
+            Some(SocketAddr::new(source, source_port.parse()?))
+        }
+        _ => bail!("invalid PROXY v1 header {line:?}"),
+    };
+    Ok(Some((source, end + 2)))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(Option<SocketAddr>, usize)>> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    if version != 2 {
+        bail!("unsupported PROXY protocol version {version}");
+    }
+    let len = V2_FIXED_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addresses = &buf[V2_FIXED_LEN..len];
+    let source = match command {
+        // LOCAL：负载均衡器自己的连接，沿用对端地址
+        0x0 => None,
+        0x1 => match buf[13] >> 4 {
+            0x1 if addresses.len() >= 12 => {
+                let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?);
+                let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                Some(SocketAddr::new(ip.into(), port))
+            }
+            0x2 if addresses.len() >= 36 => {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?);
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Some(SocketAddr::new(ip.into(), port))
+            }
+            0x1 | 0x2 => bail!("PROXY v2 address block too short"),
+            _ => None,
+        },
+        _ => bail!("unsupported PROXY v2 command {command}"),
+    };
+    Ok(Some((source, len)))
+}
+
+/// Reads the PROXY header of a connection, returns the source address and the bytes read past
+/// the header.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<(Option<SocketAddr>, BytesMut)> {
+    let mut buf = BytesMut::with_capacity(256);
+    loop {
+        if !buf.is_empty()
+            && let Some((source, len)) = parse_header(&buf)?
+        {
+            buf.advance(len);
+            return Ok((source, buf));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            bail!("connection closed before the PROXY protocol header");
+        }
+    }
+}
+
+/// Listener of `listen` relaying the connections to the proxy, see the module documentation.
+pub struct ProxyProtocolRelay {
+    /// Bound at startup, taken when the service starts.
+    listener: Mutex<Option<std::net::TcpListener>>,
+    relay: Arc<Relay>,
+}
+
+struct Relay {
+    upstream: String,
+    header_timeout: Duration,
+    clients: Arc<ProxiedClients>,
+}
+
+impl ProxyProtocolRelay {
+    /// Binds `listen` right away, so a taken address fails the startup instead of leaving the
+    /// proxy without its public listener.
+    pub fn bind(
+        listen: &str,
+        config: &ProxyProtocolConfig,
+        clients: Arc<ProxiedClients>,
+    ) -> Result<Self> {
+        let listener = std::net::TcpListener::bind(listen)
+            .with_context(|| format!("failed to bind PROXY protocol listener on {listen}"))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener: Mutex::new(Some(listener)),
+            relay: Arc::new(Relay {
+                upstream: config.internal_listen.clone(),
+                header_timeout: config.header_timeout,
+                clients,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let listener = self.listener.lock().unwrap();
+        listener.as_ref()?.local_addr().ok()
+    }
+}
+
+impl Relay {
+    async fn relay(&self, mut downstream: TcpStream, peer: SocketAddr) -> Result<()> {
+        let header = tokio::time::timeout(self.header_timeout, read_header(&mut downstream)).await;
+        let (source, rest) = match header {
+            Ok(Ok(header)) => header,
+            Ok(Err(e)) => {
+                PROXY_PROTOCOL_ERRORS.with_label_values(&["invalid"]).inc();
+                return Err(e);
+            }
+            Err(_) => {
+                PROXY_PROTOCOL_ERRORS.with_label_values(&["timeout"]).inc();
+                bail!("no PROXY protocol header within {:?}", self.header_timeout);
+            }
+        };
+        let mut upstream = TcpStream::connect(&self.upstream).await?;
+        upstream.set_nodelay(true)?;
+        downstream.set_nodelay(true)?;
+        // 先登记客户端地址再转发数据，代理读到请求时一定能查到
+        let _guard = self
+            .clients
+            .register(upstream.local_addr()?, source.unwrap_or(peer));
+        upstream.write_all(&rest).await?;
+        tokio::io::copy_bidirectional(&mut downstream, &mut upstream).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for ProxyProtocolRelay {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(listener) = self.listener.lock().unwrap().take() else {
+            return;
+        };
+        let listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("failed to register PROXY protocol listener: {:?}", e);
+                return;
+            }
+        };
+        if let Ok(addr) = listener.local_addr() {
+            info!(
+                "PROXY protocol listening on {}, relaying to {}",
+                addr, self.relay.upstream
+            );
+        }
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("failed to accept PROXY protocol connection: {:?}", e);
+                        continue;
+                    }
+                },
+                // 停止接受新连接，已建立的连接继续转发直到关闭
+                _ = shutdown.changed() => return,
+            };
+            let relay = self.relay.clone();
+            tokio::spawn(async move {
+                if let Err(e) = relay.relay(stream, peer).await {
+                    debug!("PROXY protocol connection from {} closed: {:#}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let header = b"PROXY TCP4 192.0.2.10 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (source, len) = parse_header(header).unwrap().unwrap();
+        assert_eq!(source, Some("192.0.2.10:56324".parse().unwrap()));
+        assert_eq!(&header[len..], b"GET / HTTP/1.1\r\n");
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 80\r\n";
+        let (source, _) = parse_header(header).unwrap().unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:4711".parse().unwrap()));
+        let (source, len) = parse_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!((source, len), (None, 15));
+
+        assert!(parse_header(b"PRO").unwrap().is_none());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.10").unwrap().is_none());
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.10 198.51.100.1 70000 443\r\n").is_err());
+        assert!(parse_header(&[b"PROXY ".as_slice(), &[b'1'; 120]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut addresses = vec![192, 0, 2, 10, 198, 51, 100, 1];
+        addresses.extend_from_slice(&56324u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        // 附带的 TLV 被跳过
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let header = [v2(0x1, 0x11, &addresses), b"GET /".to_vec()].concat();
+        let (source, len) = parse_header(&header).unwrap().unwrap();
+        assert_eq!(source, Some("192.0.2.10:56324".parse().unwrap()));
+        assert_eq!(&header[len..], b"GET /");
+        assert!(parse_header(&header[..20]).unwrap().is_none());
+        assert!(parse_header(&header[..8]).unwrap().is_none());
+
+        let mut addresses = Ipv6Addr::LOCALHOST.octets().to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        addresses.extend_from_slice(&[0x12, 0x67, 0x00, 0x50]);
+        let (source, _) = parse_header(&v2(0x1, 0x21, &addresses)).unwrap().unwrap();
+        assert_eq!(source, Some("[::1]:4711".parse().unwrap()));
+
+        let (source, len) = parse_header(&v2(0x0, 0x00, &[])).unwrap().unwrap();
+        assert_eq!((source, len), (None, 16));
+        assert!(parse_header(&v2(0x1, 0x11, &[192, 0, 2])).is_err());
+        assert!(parse_header(&v2(0x2, 0x11, &[])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let clients = Arc::new(ProxiedClients::default());
+        let config = ProxyProtocolConfig {
+            enabled: true,
+            internal_listen: proxy.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let relay = ProxyProtocolRelay::bind("127.0.0.1:0", &config, clients.clone())
+            .expect("Failed to bind relay");
+        let listen = relay.local_addr().unwrap();
+        // 地址已被占用时启动失败
+        assert!(ProxyProtocolRelay::bind(&listen.to_string(), &config, clients.clone()).is_err());
+        let (shutdown, watch) = tokio::sync::watch::channel(false);
+        let service = tokio::spawn(async move { relay.start(watch).await });
+
+        let mut client = TcpStream::connect(listen).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 192.0.2.10 198.51.100.1 56324 80\r\nping")
+            .await
+            .unwrap();
+        let (mut relayed, peer) = proxy.accept().await.unwrap();
+        let mut buf = [0; 4];
+        relayed.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(
+            clients.get(&peer),
+            Some("192.0.2.10:56324".parse().unwrap())
+        );
+
+        relayed.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+        drop(client);
+        drop(relayed);
+        shutdown.send(true).unwrap();
+        service.await.unwrap();
+        // 连接关闭后登记的地址被移除
+        for _ in 0..100 {
+            if clients.get(&peer).is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(clients.get(&peer).is_none());
+    }
+}
@@ -0,0 +1,566 @@
+//! Recording of the request/response pairs exchanged with the primary and the mirrors.
+//!
+//! Every exchange is written as one [`Exchange`], either as a JSON line or as an entry of a
+//! HAR document. The exchange with the primary holds the client request and the response
+//! returned to the client; the exchange with a mirror holds the copy sent to it and its
+//! response. Both carry the request id so they can be matched.
+
+use crate::access_log::{Envelope, LineWriter, RotatingFile, Sink};
+use crate::config::{RecordingConfig, RecordingFormat};
+use crate::mirror::MirrorRequest;
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::warn;
+
+/// Upstream name of the exchanges with the primary.
+pub const PRIMARY: &str = "primary";
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Exchanges queued for the writer thread, later exchanges are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+const HAR_ENVELOPE: Envelope = Envelope {
+    header: concat!(
+        r#"{"log":{"version":"1.2","creator":{"name":"simple_proxy","version":""#,
+        env!("CARGO_PKG_VERSION"),
+        "\"},\"entries\":[\n"
+    )
+    .as_bytes(),
+    separator: b",\n",
+    footer: b"]}}\n",
+};
+
+/// One request/response pair with the primary or a mirror.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Exchange {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    /// `primary` or the name of the mirror.
+    pub upstream: String,
+    pub route: Option<String>,
+    pub duration_ms: f64,
+    pub request: RecordedRequest,
+    /// Missing when no response was received.
+    pub response: Option<RecordedResponse>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: RecordedBody,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordedBody {
+    /// Body as text, base64 encoded when it is not valid UTF-8.
+    pub text: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+    /// Size of the complete body.
+    pub size: usize,
+    /// Set when only the first `max_body_size` bytes were kept.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl RecordedBody {
+    pub fn bytes(&self) -> Result<Bytes> {
+        Ok(if self.base64 {
+            STANDARD.decode(&self.text)?.into()
+        } else {
+            Bytes::from(self.text.clone())
+        })
+    }
+}
+
+impl Exchange {
+    /// Request to send again, redacted headers are left out.
+    pub fn to_request(&self) -> Result<MirrorRequest> {
+        let url = reqwest::Url::parse(&self.request.url)?;
+        let path_and_query = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_string(),
+        };
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.request.headers {
+            if value != REDACTED {
+                headers.append(
+                    HeaderName::from_bytes(name.as_bytes())?,
+                    HeaderValue::from_str(value)?,
+                );
+            }
+        }
+        Ok(MirrorRequest {
+            request_id: self.request_id.clone(),
+            method: Method::from_bytes(self.request.method.as_bytes())?,
+            path_and_query,
+            headers,
+            body: self.request.body.bytes()?,
+            trace_link: None,
+            route: self.route.clone(),
+            record: false,
+            primary_response: None,
+        })
+    }
+
+    /// Reads the exchanges of a JSONL recording, in file order.
+    pub fn read_all(path: impl AsRef<Path>) -> Result<Vec<Exchange>> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read recording {}", path.display()))?;
+        content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("invalid exchange at line {}", index + 1))
+            })
+            .collect()
+    }
+
+    /// Entry of the `log.entries` array of a HAR document, the request id, upstream and route
+    /// are kept as custom fields.
+    pub fn to_har_entry(&self) -> Value {
+        let request = &self.request;
+        let query: Vec<Value> = reqwest::Url::parse(&request.url)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(name, value)| json!({"name": name, "value": value}))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mime_type = |headers: &[(String, String)]| {
+            headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+                .map_or("", |(_, value)| value.as_str())
+                .to_string()
+        };
+
+        let mut har_request = json!({
+            "method": request.method,
+            "url": request.url,
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": har_headers(&request.headers),
+            "queryString": query,
+            "headersSize": -1,
+            "bodySize": request.body.size,
+        });
+        if request.body.size > 0 {
+            let mut post_data = json!({
+                "mimeType": mime_type(&request.headers),
+                "text": request.body.text,
+            });
+            // HAR 1.2 的 postData 没有 encoding 字段，使用自定义字段标记
+            if request.body.base64 {
+                post_data["_encoding"] = json!("base64");
+                post_data["comment"] = json!("binary body, text is base64 encoded");
+            }
+            har_request["postData"] = post_data;
+        }
+        let har_response = match &self.response {
+            Some(response) => {
+                let mut content = json!({
+                    "size": response.body.size,
+                    "mimeType": mime_type(&response.headers),
+                    "text": response.body.text,
+                });
+                if response.body.base64 {
+                    content["encoding"] = json!("base64");
+                }
+                json!({
+                    "status": response.status,
+                    "statusText": http::StatusCode::from_u16(response.status)
+                        .ok()
+                        .and_then(|status| status.canonical_reason())
+                        .unwrap_or_default(),
+                    "httpVersion": "HTTP/1.1",
+                    "cookies": [],
+                    "headers": har_headers(&response.headers),
+                    "content": content,
+                    "redirectURL": "",
+                    "headersSize": -1,
+                    "bodySize": response.body.size,
+                })
+            }
+            // HAR 要求 response 字段存在，没有响应时按浏览器的惯例记录为状态 0
+            None => json!({
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": {"size": 0, "mimeType": ""},
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+                "_error": self.error,
+            }),
+        };
+        json!({
+            "startedDateTime": self.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "time": self.duration_ms,
+            "request": har_request,
+            "response": har_response,
+            "cache": {},
+            "timings": {"send": 0, "wait": self.duration_ms, "receive": 0},
+            "_request_id": self.request_id,
+            "_upstream": self.upstream,
+            "_route": self.route,
+        })
+    }
+}
+
+fn har_headers(headers: &[(String, String)]) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| json!({"name": name, "value": value}))
+        .collect()
+}
+
+/// Writes the recorded exchanges to a size rotated file.
+pub struct Recorder {
+    format: RecordingFormat,
+    max_body_size: usize,
+    redact_headers: Vec<HeaderName>,
+    routes: Vec<String>,
+    writer: LineWriter,
+}
+
+impl Recorder {
+    pub fn new(config: &RecordingConfig) -> Result<Self> {
+        let envelope = match config.format {
+            RecordingFormat::Jsonl => None,
+            RecordingFormat::Har => Some(HAR_ENVELOPE),
+        };
+        let file = RotatingFile::open_with_envelope(
+            config.path.clone(),
+            config.max_size,
+            config.max_files,
+            envelope,
+        )?;
+        let redact_headers = config
+            .redact_headers
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            format: config.format,
+            max_body_size: config.max_body_size,
+            redact_headers,
+            routes: config.routes.clone(),
+            writer: LineWriter::new("recording", QUEUE_SIZE, Sink::File(file)),
+        })
+    }
+
+    /// Whether requests of the route are recorded.
+    pub fn records(&self, route: Option<&str>) -> bool {
+        self.routes.is_empty() || route.is_some_and(|route| self.routes.iter().any(|r| r == route))
+    }
+
+    /// Starts recording an exchange, the request body and the response are added as they are
+    /// received.
+    pub fn start(
+        self: &Arc<Self>,
+        upstream: &str,
+        request_id: &str,
+        route: Option<&str>,
+        method: &Method,
+        url: String,
+        headers: &HeaderMap,
+    ) -> Recording {
+        Recording {
+            recorder: self.clone(),
+            started_at: Instant::now(),
+            exchange: Exchange {
+                timestamp: Utc::now(),
+                request_id: request_id.to_string(),
+                upstream: upstream.to_string(),
+                route: route.map(str::to_string),
+                duration_ms: 0.0,
+                request: RecordedRequest {
+                    method: method.to_string(),
+                    url,
+                    headers: self.headers(headers),
+                    body: RecordedBody::default(),
+                },
+                response: None,
+                error: None,
+            },
+            request_body: BytesMut::new(),
+            request_size: 0,
+            response_body: BytesMut::new(),
+            response_size: 0,
+        }
+    }
+
+    pub fn write(&self, exchange: &Exchange) -> Result<()> {
+        let mut line = match self.format {
+            RecordingFormat::Jsonl => serde_json::to_vec(exchange)?,
+            RecordingFormat::Har => serde_json::to_vec(&exchange.to_har_entry())?,
+        };
+        if self.format == RecordingFormat::Jsonl {
+            line.push(b'\n');
+        }
+        self.writer.write(line);
+        Ok(())
+    }
+
+    /// Starts the writer thread, lines written before wait in its queue.
+    pub fn start_writer(&self) -> Result<()> {
+        self.writer.start()
+    }
+
+    pub fn writer_started(&self) -> bool {
+        self.writer.is_started()
+    }
+
+    /// Waits until the queued exchanges are written, once the writer is started.
+    pub fn flush(&self) {
+        self.writer.flush();
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.redact_headers.contains(name) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn body(&self, body: &[u8], size: usize) -> RecordedBody {
+        let (text, base64) = match std::str::from_utf8(body) {
+            Ok(text) => (text.to_string(), false),
+            // 截断可能切在多字节字符中间，只要剩余部分是合法的前缀就仍按文本记录
+            Err(e) if e.error_len().is_none() => (
+                String::from_utf8_lossy(&body[..e.valid_up_to()]).into_owned(),
+                false,
+            ),
+            Err(_) => (STANDARD.encode(body), true),
+        };
+        RecordedBody {
+            text,
+            base64,
+            size,
+            truncated: size > body.len(),
+        }
+    }
+}
+
+/// Exchange being recorded.
+pub struct Recording {
+    recorder: Arc<Recorder>,
+    started_at: Instant,
+    exchange: Exchange,
+    request_body: BytesMut,
+    request_size: usize,
+    response_body: BytesMut,
+    response_size: usize,
+}
+
+impl Recording {
+    pub fn push_request_body(&mut self, chunk: &[u8]) {
+        let max = self.recorder.max_body_size;
+        push_capped(&mut self.request_body, &mut self.request_size, chunk, max);
+    }
+
+    /// Drops the request body read so far, when pingora sends it again on a retry.
+    pub fn reset_request_body(&mut self) {
+        self.request_body.clear();
+        self.request_size = 0;
+    }
+
+    pub fn set_response(&mut self, status: u16, headers: &HeaderMap) {
+        self.exchange.response = Some(RecordedResponse {
+            status,
+            headers: self.recorder.headers(headers),
+            body: RecordedBody::default(),
+        });
+    }
+
+    pub fn push_response_body(&mut self, chunk: &[u8]) {
+        let max = self.recorder.max_body_size;
+        push_capped(&mut self.response_body, &mut self.response_size, chunk, max);
+    }
+
+    /// Writes the exchange, failures are only logged.
+    pub fn finish(mut self, error: Option<String>) {
+        let recorder = &self.recorder;
+        let exchange = &mut self.exchange;
+        exchange.duration_ms =
+            (self.started_at.elapsed().as_secs_f64() * 1_000_000.0).round() / 1000.0;
+        exchange.request.body = recorder.body(&self.request_body, self.request_size);
+        if let Some(response) = &mut exchange.response {
+            response.body = recorder.body(&self.response_body, self.response_size);
+        }
+        exchange.error = error;
+        if let Err(e) = recorder.write(exchange) {
+            warn!(request_id = %exchange.request_id, "failed to write recording: {:?}", e);
+        }
+    }
+}
+
+fn push_capped(buf: &mut BytesMut, size: &mut usize, chunk: &[u8], max: usize) {
+    *size += chunk.len();
+    let room = max.saturating_sub(buf.len());
+    buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn recorder(name: &str, format: RecordingFormat) -> (Arc<Recorder>, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("recording-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = RecordingConfig {
+            enabled: true,
+            format,
+            path: path.clone(),
+            max_body_size: 8,
+            routes: vec!["users".to_string()],
+            ..Default::default()
+        };
+        let recorder = Recorder::new(&config).expect("Failed to create");
+        recorder.start_writer().expect("Failed to start writer");
+        (Arc::new(recorder), path)
+    }
+
+    fn record(recorder: &Arc<Recorder>) {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let mut recording = recorder.start(
+            PRIMARY,
+            "abc",
+            Some("users"),
+            &Method::POST,
+            "http://localhost/users?x=1".to_string(),
+            &headers,
+        );
+        recording.push_request_body(b"{\"name\":");
+        recording.push_request_body(b"\"Alice\"}");
+        recording.set_response(201, &HeaderMap::new());
+        recording.push_response_body(&[0xff, 0xfe]);
+        recording.finish(None);
+    }
+
+    #[test]
+    fn test_jsonl_recording() {
+        let (recorder, path) = recorder("jsonl", RecordingFormat::Jsonl);
+        assert!(recorder.records(Some("users")));
+        assert!(!recorder.records(Some("orders")));
+        assert!(!recorder.records(None));
+        record(&recorder);
+        record(&recorder);
+        recorder.flush();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let exchanges: Vec<Exchange> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("Invalid exchange"))
+            .collect();
+        assert_eq!(exchanges.len(), 2);
+        let exchange = &exchanges[0];
+        assert_eq!(exchange.upstream, "primary");
+        assert_eq!(
+            exchange.request.headers[0],
+            ("authorization".into(), REDACTED.into())
+        );
+        assert_eq!(exchange.request.body.text, "{\"name\":");
+        assert_eq!(exchange.request.body.size, 16);
+        assert!(exchange.request.body.truncated);
+        let response = exchange.response.as_ref().unwrap();
+        assert_eq!(response.status, 201);
+        assert!(response.body.base64);
+        assert_eq!(response.body.bytes().unwrap(), &[0xff, 0xfe][..]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_har_recording() {
+        let (recorder, path) = recorder("har", RecordingFormat::Har);
+        record(&recorder);
+        record(&recorder);
+        recorder.flush();
+
+        let har: Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).expect("Invalid HAR");
+        assert_eq!(har["log"]["version"], "1.2");
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["request"]["queryString"][0]["name"], "x");
+        assert_eq!(
+            entries[0]["request"]["postData"]["mimeType"],
+            "application/json"
+        );
+        assert_eq!(entries[0]["response"]["statusText"], "Created");
+        assert_eq!(entries[0]["response"]["content"]["encoding"], "base64");
+        assert_eq!(entries[0]["_request_id"], "abc");
+        assert!(entries[0]["request"]["postData"]["_encoding"].is_null());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_har_binary_request_body() {
+        let (recorder, path) = recorder("har-binary", RecordingFormat::Har);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "content-type",
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        let mut recording = recorder.start(
+            PRIMARY,
+            "abc",
+            Some("users"),
+            &Method::PUT,
+            "http://localhost/users/1/avatar".to_string(),
+            &headers,
+        );
+        recording.push_request_body(&[0x89, b'P', b'N', b'G']);
+        recording.set_response(204, &HeaderMap::new());
+        recording.finish(None);
+        recorder.flush();
+
+        let har: Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).expect("Invalid HAR");
+        let post_data = &har["log"]["entries"][0]["request"]["postData"];
+        assert_eq!(post_data["mimeType"], "application/octet-stream");
+        assert_eq!(post_data["_encoding"], "base64");
+        assert!(post_data["comment"].is_string());
+        let text = post_data["text"].as_str().unwrap();
+        assert_eq!(STANDARD.decode(text).unwrap(), [0x89, b'P', b'N', b'G']);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,497 @@
+//! Replay of recorded traffic against one or two targets.
+//!
+//! The exchanges of a JSONL recording (see [`crate::recording`]) are sent again, in the order
+//! of their timestamps, with the same request building as the mirrors. With one target its
+//! responses are compared to the recorded ones, with two targets the responses of the second
+//! target are compared to those of the first.
+
+use crate::config::{HeaderRule, HttpVersion};
+use crate::headers::HeaderRewriter;
+use crate::mirror::{MirrorClient, MirrorRequest};
+use crate::recording::Exchange;
+use crate::template::TemplateVars;
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use http::{HeaderValue, header::HOST};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// Number of mismatches kept in the report.
+const MAX_REPORTED_MISMATCHES: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Base urls, one or two.
+    pub targets: Vec<String>,
+    /// Requests in flight at most.
+    pub concurrency: usize,
+    /// Requests started per second at most.
+    pub rate: Option<f64>,
+    /// Keep the original spacing of the requests, divided by this factor (`2.0` replays twice
+    /// as fast).
+    pub speed: Option<f64>,
+    /// Rules applied to the headers of every replayed request.
+    pub headers: Vec<HeaderRule>,
+    /// Replaces the `Host` header of the recorded requests.
+    pub host: Option<String>,
+    /// Upstream of the recorded exchanges to replay, `primary` or a mirror name.
+    pub upstream: String,
+    pub timeout: Duration,
+}
+
+/// Response of a target, or of the recording.
+#[derive(Debug, Clone)]
+struct Reply {
+    status: Option<u16>,
+    /// `None` when the body is unknown (recording truncated it) and is not compared.
+    body: Option<Bytes>,
+    latency: Duration,
+    error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReplayReport {
+    pub replayed: usize,
+    /// Exchanges that could not be replayed: truncated or invalid request.
+    pub skipped: usize,
+    /// `recording` or the url of the first target.
+    pub baseline: String,
+    pub targets: Vec<TargetReport>,
+    pub status_mismatches: usize,
+    pub body_mismatches: usize,
+    /// First mismatches, in replay order.
+    pub mismatches: Vec<Mismatch>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TargetReport {
+    pub url: String,
+    pub errors: usize,
+    pub statuses: BTreeMap<u16, usize>,
+    pub p50_ms: f64,
+    pub p99_ms: f64,
+    #[serde(skip)]
+    latencies: Vec<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub expected_status: Option<u16>,
+    pub actual_status: Option<u16>,
+    pub body_differs: bool,
+    pub error: Option<String>,
+}
+
+impl ReplayReport {
+    pub fn has_mismatches(&self) -> bool {
+        self.status_mismatches > 0 || self.body_mismatches > 0
+    }
+}
+
+pub struct Replayer {
+    options: ReplayOptions,
+    client: MirrorClient,
+    headers: HeaderRewriter,
+}
+
+impl Replayer {
+    pub fn new(options: ReplayOptions) -> Result<Self> {
+        if options.targets.is_empty() || options.targets.len() > 2 {
+            bail!("replay needs one or two targets");
+        }
+        if options.concurrency == 0 {
+            bail!("concurrency must be at least 1");
+        }
+        if options.rate.is_some_and(|rate| rate <= 0.0) {
+            bail!("rate must be positive");
+        }
+        if options.speed.is_some_and(|speed| speed <= 0.0) {
+            bail!("speed must be positive");
+        }
+        Ok(Self {
+            client: MirrorClient::new(options.timeout, HttpVersion::Auto)?,
+            headers: HeaderRewriter::new(&options.headers)?,
+            options,
+        })
+    }
+
+    pub async fn run(self: Arc<Self>, mut exchanges: Vec<Exchange>) -> ReplayReport {
+        exchanges.retain(|exchange| exchange.upstream == self.options.upstream);
+        exchanges.sort_by_key(|exchange| exchange.timestamp);
+
+        let mut report = ReplayReport {
+            baseline: match self.options.targets.as_slice() {
+                [_] => "recording".to_string(),
+                [first, ..] => first.clone(),
+                [] => unreachable!("targets are checked in new"),
+            },
+            targets: self
+                .options
+                .targets
+                .iter()
+                .map(|url| TargetReport {
+                    url: url.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let semaphore = Arc::new(Semaphore::new(self.options.concurrency));
+        let mut interval = self.options.rate.map(|rate| {
+            let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+        let start = Instant::now();
+        let first = exchanges.first().map(|exchange| exchange.timestamp);
+        let mut tasks = JoinSet::new();
+
+        for (index, exchange) in exchanges.into_iter().enumerate() {
+            let request = match self.request(&exchange) {
+                Some(request) => request,
+                None => {
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            if let (Some(speed), Some(first)) = (self.options.speed, first) {
+                let offset = (exchange.timestamp - first).to_std().unwrap_or_default();
+                tokio::time::sleep_until(start + offset.div_f64(speed)).await;
+            }
+            if let Some(interval) = &mut interval {
+                interval.tick().await;
+            }
+            let permit = semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            let replayer = self.clone();
+            tasks.spawn(async move {
+                let replies = replayer.send_all(&request).await;
+                drop(permit);
+                (index, exchange, request, replies)
+            });
+        }
+
+        let mut results = tasks.join_all().await;
+        results.sort_by_key(|(index, ..)| *index);
+        for (_, exchange, request, replies) in results {
+            report.replayed += 1;
+            for (target, reply) in report.targets.iter_mut().zip(&replies) {
+                target.latencies.push(reply.latency);
+                match reply.status {
+                    Some(status) => *target.statuses.entry(status).or_default() += 1,
+                    None => target.errors += 1,
+                }
+            }
+            let expected = match replies.as_slice() {
+                [_] => recorded_reply(&exchange),
+                [first, ..] => first.clone(),
+                [] => continue,
+            };
+            let actual = replies.last().expect("at least one target");
+            let status_differs = expected.status != actual.status;
+            let body_differs = match (&expected.body, &actual.body) {
+                (Some(expected), Some(actual)) => !same_body(expected, actual),
+                _ => false,
+            };
+            report.status_mismatches += usize::from(status_differs);
+            report.body_mismatches += usize::from(!status_differs && body_differs);
+            if (status_differs || body_differs) && report.mismatches.len() < MAX_REPORTED_MISMATCHES
+            {
+                report.mismatches.push(Mismatch {
+                    request_id: exchange.request_id.clone(),
+                    method: request.method.to_string(),
+                    path: request.path_and_query.clone(),
+                    expected_status: expected.status,
+                    actual_status: actual.status,
+                    body_differs,
+                    error: actual.error.clone().or(expected.error),
+                });
+            }
+        }
+        for target in &mut report.targets {
+            target.latencies.sort();
+            target.p50_ms = percentile(&target.latencies, 0.5);
+            target.p99_ms = percentile(&target.latencies, 0.99);
+        }
+        report
+    }
+
+    /// Request to send for the exchange, `None` when it cannot be replayed faithfully.
+    fn request(&self, exchange: &Exchange) -> Option<MirrorRequest> {
+        if exchange.request.body.truncated {
+            return None;
+        }
+        let mut request = exchange.to_request().ok()?;
+        if let Some(host) = &self.options.host {
+            request
+                .headers
+                .insert(HOST, HeaderValue::from_str(host).ok()?);
+        }
+        let vars = TemplateVars {
+            request_id: request.request_id.clone(),
+            route: request.route.clone().unwrap_or_default(),
+            method: request.method.to_string(),
+            host: request
+                .headers
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+            ..Default::default()
+        };
+        self.headers.apply(&mut request.headers, &vars);
+        Some(request)
+    }
+
+    async fn send_all(&self, request: &MirrorRequest) -> Vec<Reply> {
+        let sends = self
+            .options
+            .targets
+            .iter()
+            .map(|target| self.send(target, request.clone()));
+        futures::future::join_all(sends).await
+    }
+
+    async fn send(&self, target: &str, request: MirrorRequest) -> Reply {
+        let started = Instant::now();
+        let result = async {
+            let url = request.url(target)?;
+            let response = request.into_http(&self.client, url).send().await?;
+            let status = response.status().as_u16();
+            Ok::<_, anyhow::Error>((status, response.bytes().await?))
+        }
+        .await;
+        match result {
+            Ok((status, body)) => Reply {
+                status: Some(status),
+                body: Some(body),
+                latency: started.elapsed(),
+                error: None,
+            },
+            Err(e) => Reply {
+                status: None,
+                body: None,
+                latency: started.elapsed(),
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+fn recorded_reply(exchange: &Exchange) -> Reply {
+    let response = exchange.response.as_ref();
+    Reply {
+        status: response.map(|response| response.status),
+        body: response
+            .filter(|response| !response.body.truncated)
+            .and_then(|response| response.body.bytes().ok()),
+        latency: Duration::from_secs_f64(exchange.duration_ms / 1000.0),
+        error: exchange.error.clone(),
+    }
+}
+
+/// Bodies are compared as JSON values when both are JSON, so formatting and key order do not
+/// matter.
+fn same_body(expected: &[u8], actual: &[u8]) -> bool {
+    if expected == actual {
+        return true;
+    }
+    match (
+        serde_json::from_slice::<serde_json::Value>(expected),
+        serde_json::from_slice::<serde_json::Value>(actual),
+    ) {
+        (Ok(expected), Ok(actual)) => expected == actual,
+        _ => false,
+    }
+}
+
+fn percentile(sorted: &[Duration], ratio: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() as f64 * ratio).ceil() as usize).clamp(1, sorted.len()) - 1;
+    (sorted[index].as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "replayed {} requests, skipped {}, compared with {}",
+            self.replayed, self.skipped, self.baseline
+        )?;
+        for target in &self.targets {
+            let statuses: Vec<String> = target
+                .statuses
+                .iter()
+                .map(|(status, count)| format!("{status}: {count}"))
+                .collect();
+            writeln!(
+                f,
+                "  {}: errors {}, statuses {{{}}}, p50 {}ms, p99 {}ms",
+                target.url,
+                target.errors,
+                statuses.join(", "),
+                target.p50_ms,
+                target.p99_ms
+            )?;
+        }
+        writeln!(
+            f,
+            "status mismatches: {}, body mismatches: {}",
+            self.status_mismatches, self.body_mismatches
+        )?;
+        for mismatch in &self.mismatches {
+            let status =
+                |status: Option<u16>| status.map_or("error".to_string(), |s| s.to_string());
+            write!(
+                f,
+                "  [{}] {} {}: status {} -> {}",
+                mismatch.request_id,
+                mismatch.method,
+                mismatch.path,
+                status(mismatch.expected_status),
+                status(mismatch.actual_status)
+            )?;
+            if mismatch.body_differs {
+                write!(f, ", body differs")?;
+            }
+            if let Some(error) = &mismatch.error {
+                write!(f, " ({error})")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{RecordedBody, RecordedRequest, RecordedResponse};
+    use axum::{Router, extract::Path as UrlPath, routing::get};
+    use chrono::Utc;
+
+    fn exchange(request_id: &str, path: &str, status: u16, body: &str) -> Exchange {
+        Exchange {
+            timestamp: Utc::now(),
+            request_id: request_id.to_string(),
+            upstream: "primary".to_string(),
+            route: None,
+            duration_ms: 1.0,
+            request: RecordedRequest {
+                method: "GET".to_string(),
+                url: format!("http://localhost:8080{path}"),
+                headers: vec![("authorization".to_string(), "[REDACTED]".to_string())],
+                body: RecordedBody::default(),
+            },
+            response: Some(RecordedResponse {
+                status,
+                headers: vec![],
+                body: RecordedBody {
+                    text: body.to_string(),
+                    size: body.len(),
+                    ..Default::default()
+                },
+            }),
+            error: None,
+        }
+    }
+
+    async fn target(version: u32) -> String {
+        let app = Router::new().route(
+            "/users/{id}",
+            get(move |UrlPath(id): UrlPath<u32>| async move {
+                if id == 404 {
+                    (http::StatusCode::NOT_FOUND, String::new())
+                } else {
+                    (
+                        http::StatusCode::OK,
+                        format!(r#"{{"id": {id}, "v": {version}}}"#),
+                    )
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    fn options(targets: Vec<String>) -> ReplayOptions {
+        ReplayOptions {
+            targets,
+            concurrency: 2,
+            rate: Some(1000.0),
+            speed: None,
+            headers: vec![],
+            host: None,
+            upstream: "primary".to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_against_recording() {
+        let target = target(1).await;
+        let mut truncated = exchange("c", "/users/3", 200, "");
+        truncated.request.body.truncated = true;
+        let mut mirrored = exchange("d", "/users/4", 200, "");
+        mirrored.upstream = "secondary".to_string();
+        let exchanges = vec![
+            // JSON 格式不同但内容相同时视为一致
+            exchange("a", "/users/1", 200, r#"{"v":1,"id":1}"#),
+            exchange("b", "/users/404", 200, r#"{"id":404}"#),
+            truncated,
+            mirrored,
+        ];
+
+        let replayer = Arc::new(Replayer::new(options(vec![target])).unwrap());
+        let report = replayer.run(exchanges).await;
+        assert_eq!(report.replayed, 2);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.status_mismatches, 1);
+        assert_eq!(report.body_mismatches, 0);
+        assert_eq!(report.mismatches[0].request_id, "b");
+        assert_eq!(report.mismatches[0].actual_status, Some(404));
+        assert_eq!(report.targets[0].statuses[&200], 1);
+        assert!(report.has_mismatches());
+    }
+
+    #[tokio::test]
+    async fn test_replay_compares_two_targets() {
+        let (first, second) = (target(1).await, target(2).await);
+        let exchanges = vec![
+            exchange("a", "/users/1", 200, ""),
+            exchange("b", "/users/404", 404, ""),
+        ];
+        let replayer = Arc::new(Replayer::new(options(vec![first.clone(), second])).unwrap());
+        let report = replayer.run(exchanges).await;
+        assert_eq!(report.baseline, first);
+        assert_eq!(report.status_mismatches, 0);
+        assert_eq!(report.body_mismatches, 1);
+        assert!(report.mismatches[0].body_differs);
+        assert!(report.to_string().contains("body mismatches: 1"));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(Replayer::new(options(vec![])).is_err());
+        let mut speed = options(vec!["http://localhost".to_string()]);
+        speed.speed = Some(0.0);
+        assert!(Replayer::new(speed).is_err());
+    }
+}
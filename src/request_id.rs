@@ -0,0 +1,63 @@
+use http::HeaderMap;
+use uuid::Uuid;
+
+const MAX_LEN: usize = 128;
+
+pub fn generate() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Returns the request id sent by the client if it is acceptable, otherwise a new one.
+///
+/// Client ids are only accepted when they are short and made of visible ascii characters, so
+/// they can be safely copied into headers and log lines.
+pub fn resolve(headers: &HeaderMap, header: &str, accept_from_client: bool) -> String {
+    if !accept_from_client {
+        return generate();
+    }
+    headers
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| is_valid(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(generate)
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_accepts_client_request_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_static("client-id-1"));
+        assert_eq!(resolve(&headers, "x-request-id", true), "client-id-1");
+    }
+
+    #[test]
+    fn test_generates_when_missing_or_not_trusted() {
+        let mut headers = HeaderMap::new();
+        let generated = resolve(&headers, "x-request-id", true);
+        assert!(Uuid::parse_str(&generated).is_ok());
+
+        headers.insert("x-request-id", HeaderValue::from_static("client-id-1"));
+        assert_ne!(resolve(&headers, "x-request-id", false), "client-id-1");
+    }
+
+    #[test]
+    fn test_rejects_invalid_client_request_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_static("has space"));
+        assert_ne!(resolve(&headers, "x-request-id", true), "has space");
+
+        let long = "a".repeat(MAX_LEN + 1);
+        headers.insert("x-request-id", HeaderValue::from_str(&long).unwrap());
+        assert_ne!(resolve(&headers, "x-request-id", true), long);
+    }
+}
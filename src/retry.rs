@@ -0,0 +1,115 @@
+use crate::config::RetryBudgetConfig;
+use http::Method;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Methods that can be safely sent again after the upstream may have seen them (RFC 9110 9.2.2).
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// Limits the number of requests that are retrying at the same time to a ratio of the
+/// requests in flight, so a failing upstream doesn't get multiplied traffic (retry storm).
+pub struct RetryBudget {
+    ratio: f64,
+    min_concurrency: usize,
+    active: AtomicUsize,
+    retrying: AtomicUsize,
+}
+
+/// Held for the lifetime of a request, counts it as in flight.
+pub struct RequestGuard(Arc<RetryBudget>);
+
+/// Held by a request once it started retrying.
+pub struct RetryPermit(Arc<RetryBudget>);
+
+impl RetryBudget {
+    pub fn new(config: &RetryBudgetConfig) -> Arc<Self> {
+        Arc::new(Self {
+            ratio: config.ratio,
+            min_concurrency: config.min_concurrency,
+            active: AtomicUsize::new(0),
+            retrying: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn track_request(self: &Arc<Self>) -> RequestGuard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        RequestGuard(self.clone())
+    }
+
+    /// Returns a permit if another request may start retrying.
+    pub fn try_retry(self: &Arc<Self>) -> Option<RetryPermit> {
+        let active = self.active.load(Ordering::Relaxed);
+        let limit = ((active as f64 * self.ratio) as usize).max(self.min_concurrency);
+        self.retrying
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |retrying| {
+                (retrying < limit).then_some(retrying + 1)
+            })
+            .ok()
+            .map(|_| RetryPermit(self.clone()))
+    }
+
+    pub fn retrying(&self) -> usize {
+        self.retrying.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RetryPermit {
+    fn drop(&mut self) {
+        self.0.retrying.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotent_methods() {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(is_idempotent(&Method::DELETE));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    }
+
+    #[test]
+    fn test_budget_min_concurrency() {
+        let budget = RetryBudget::new(&RetryBudgetConfig {
+            ratio: 0.2,
+            min_concurrency: 2,
+        });
+        let _request = budget.track_request();
+        let first = budget.try_retry().expect("Failed to get permit");
+        let _second = budget.try_retry().expect("Failed to get permit");
+        assert!(budget.try_retry().is_none());
+        drop(first);
+        assert!(budget.try_retry().is_some());
+    }
+
+    #[test]
+    fn test_budget_scales_with_active_requests() {
+        let budget = RetryBudget::new(&RetryBudgetConfig {
+            ratio: 0.2,
+            min_concurrency: 1,
+        });
+        let requests: Vec<_> = (0..20).map(|_| budget.track_request()).collect();
+        let permits: Vec<_> = std::iter::from_fn(|| budget.try_retry()).take(10).collect();
+        // 20 个请求中最多 4 个可以同时重试
+        assert_eq!(permits.len(), 4);
+        assert_eq!(budget.retrying(), 4);
+        drop(permits);
+        drop(requests);
+        assert_eq!(budget.retrying(), 0);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::config::{QueryRule, UrlRewriteConfig};
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Compiled [`UrlRewriteConfig`] of an upstream.
+#[derive(Debug, Clone, Default)]
+pub struct UrlRewriter {
+    path: Vec<(Regex, String)>,
+    query: Vec<QueryRule>,
+}
+
+impl UrlRewriter {
+    pub fn new(config: &UrlRewriteConfig) -> Result<Self> {
+        let path = config
+            .path
+            .iter()
+            .map(|rule| {
+                let pattern = Regex::new(&rule.pattern)
+                    .with_context(|| format!("invalid path pattern {:?}", rule.pattern))?;
+                Ok((pattern, rule.replacement.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            path,
+            query: config.query.clone(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.path.is_empty() && self.query.is_empty()
+    }
+
+    /// Returns the rewritten path and query.
+    pub fn rewrite(&self, path_and_query: &str) -> String {
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (path_and_query, None),
+        };
+
+        let mut path = path.to_string();
+        for (pattern, replacement) in &self.path {
+            path = pattern.replace(&path, replacement.as_str()).into_owned();
+        }
+
+        // 没有查询参数规则时保持原始编码不变
+        let query = if self.query.is_empty() {
+            query.map(str::to_string)
+        } else {
+            let params = self.rewrite_query(query.unwrap_or_default());
+            (!params.is_empty()).then(|| {
+                form_urlencoded::Serializer::new(String::new())
+                    .extend_pairs(params)
+                    .finish()
+            })
+        };
+
+        match query {
+            Some(query) => format!("{path}?{query}"),
+            None => path,
+        }
+    }
+
+    fn rewrite_query(&self, query: &str) -> Vec<(String, String)> {
+        let mut params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        for rule in &self.query {
+            match rule {
+                QueryRule::Set { name, value } => {
+                    // 替换后的参数保留在第一次出现的位置
+                    let first = params.iter().position(|(key, _)| key == name);
+                    params.retain(|(key, _)| key != name);
+                    let index = first.unwrap_or(params.len());
+                    params.insert(index, (name.clone(), value.clone()));
+                }
+                QueryRule::Add { name, value } => params.push((name.clone(), value.clone())),
+                QueryRule::Remove { name } => params.retain(|(key, _)| key != name),
+                QueryRule::Rename { from, to } => {
+                    for (key, _) in params.iter_mut().filter(|(key, _)| key == from) {
+                        *key = to.clone();
+                    }
+                }
+            }
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PathRule;
+
+    fn rewriter(path: &[(&str, &str)], query: Vec<QueryRule>) -> UrlRewriter {
+        UrlRewriter::new(&UrlRewriteConfig {
+            path: path
+                .iter()
+                .map(|(pattern, replacement)| PathRule {
+                    pattern: pattern.to_string(),
+                    replacement: replacement.to_string(),
+                })
+                .collect(),
+            query,
+        })
+        .expect("Failed to compile rewrite rules")
+    }
+
+    #[test]
+    fn test_path_rewrite() {
+        let rewriter = rewriter(
+            &[
+                ("^/users", "/v2/users"),
+                (r"^/v2/users/(?P<id>\d+)$", "/v2/users/${id}/profile"),
+            ],
+            vec![],
+        );
+        assert_eq!(rewriter.rewrite("/users"), "/v2/users");
+        assert_eq!(rewriter.rewrite("/users/42"), "/v2/users/42/profile");
+        // 没有查询参数规则时原样保留查询串
+        assert_eq!(rewriter.rewrite("/users?q=a%20b"), "/v2/users?q=a%20b");
+        assert_eq!(rewriter.rewrite("/health"), "/health");
+    }
+
+    #[test]
+    fn test_query_rewrite() {
+        let rewriter = rewriter(
+            &[],
+            vec![
+                QueryRule::Rename {
+                    from: "page".to_string(),
+                    to: "offset".to_string(),
+                },
+                QueryRule::Set {
+                    name: "tag".to_string(),
+                    value: "x".to_string(),
+                },
+                QueryRule::Add {
+                    name: "source".to_string(),
+                    value: "shadow".to_string(),
+                },
+                QueryRule::Remove {
+                    name: "debug".to_string(),
+                },
+            ],
+        );
+        assert_eq!(
+            rewriter.rewrite("/users?page=2&tag=a&debug=1&tag=b"),
+            "/users?offset=2&tag=x&source=shadow"
+        );
+        assert_eq!(rewriter.rewrite("/users"), "/users?tag=x&source=shadow");
+    }
+
+    #[test]
+    fn test_invalid_pattern_is_rejected() {
+        let config = UrlRewriteConfig {
+            path: vec![PathRule {
+                pattern: "(".to_string(),
+                replacement: String::new(),
+            }],
+            query: vec![],
+        };
+        assert!(UrlRewriter::new(&config).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::access::IpAccessList;
+use crate::body::BodyTransform;
+use crate::config::{RouteCacheConfig, RouteConfig};
+use crate::cors::CorsPolicy;
+use crate::headers::HeaderRewriter;
+use anyhow::{Context, Result};
+use http::Method;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// Route compiled from its [`RouteConfig`].
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    path_prefix: String,
+    methods: Vec<Method>,
+    pub request_headers: HeaderRewriter,
+    pub response_headers: HeaderRewriter,
+    pub mirror_headers: HeaderRewriter,
+    pub mirror_body: BodyTransform,
+    pub cache: RouteCacheConfig,
+    pub cors: Option<CorsPolicy>,
+    pub access: IpAccessList,
+}
+
+/// Selects the route of a request, routes are tried in configuration order.
+#[derive(Debug, Default)]
+pub struct Router {
+    routes: Vec<Arc<Route>>,
+}
+
+impl Route {
+    pub fn new(config: &RouteConfig) -> Result<Self> {
+        let context = || format!("invalid route {:?}", config.name);
+        let methods = config
+            .methods
+            .iter()
+            .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+            .collect::<Result<_, _>>()
+            .with_context(context)?;
+        Ok(Self {
+            name: config.name.clone(),
+            path_prefix: config.path_prefix.clone(),
+            methods,
+            request_headers: HeaderRewriter::new(&config.headers.request).with_context(context)?,
+            response_headers: HeaderRewriter::new(&config.headers.response)
+                .with_context(context)?,
+            mirror_headers: HeaderRewriter::new(&config.mirror_headers).with_context(context)?,
+            mirror_body: BodyTransform::new(&config.mirror_body).with_context(context)?,
+            cache: config.cache.clone(),
+            cors: config
+                .cors
+                .as_ref()
+                .map(CorsPolicy::new)
+                .transpose()
+                .with_context(context)?,
+            access: IpAccessList::new(&config.access_control).with_context(context)?,
+        })
+    }
+
+    /// `path` must be normalized, see [`normalize_path`].
+    pub fn matches(&self, method: &Method, path: &str) -> bool {
+        prefix_matches(&self.path_prefix, path)
+            && (self.methods.is_empty() || self.methods.contains(method))
+    }
+}
+
+impl Router {
+    pub fn new(routes: &[RouteConfig]) -> Result<Self> {
+        let routes = routes
+            .iter()
+            .map(|route| Route::new(route).map(Arc::new))
+            .collect::<Result<_>>()?;
+        Ok(Self { routes })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Route>> {
+        self.routes.iter().find(|route| route.name == name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.routes.iter().map(|route| route.name.clone()).collect()
+    }
+
+    /// First route matching the request, the path is normalized first so that equivalent
+    /// spellings of a path (`//users`, `/./users`, `/%75sers`) select the same route.
+    pub fn find(&self, method: &Method, path: &str) -> Option<Arc<Route>> {
+        let path = normalize_path(path);
+        self.routes
+            .iter()
+            .find(|route| route.matches(method, &path))
+            .cloned()
+    }
+}
+
+/// `prefix` matches whole segments: `/users` matches `/users` and `/users/1`, not `/usersX`.
+pub fn prefix_matches(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+/// Path as a backend normalizing it sees it: unreserved characters percent-decoded, empty and
+/// `.` segments removed, `..` segments resolved. Other escapes (`%2F`) are kept, a trailing
+/// slash too.
+pub fn normalize_path(path: &str) -> Cow<'_, str> {
+    let needs_work = path.contains("//")
+        || path.contains('%')
+        || path
+            .split('/')
+            .any(|segment| segment == "." || segment == "..");
+    if !needs_work && (path.is_empty() || path.starts_with('/')) {
+        return Cow::Borrowed(path);
+    }
+    let mut segments: Vec<String> = Vec::new();
+    let raw: Vec<&str> = path.split('/').collect();
+    let mut trailing_slash = false;
+    for (i, segment) in raw.iter().enumerate() {
+        let segment = decode_unreserved(segment);
+        let last = i == raw.len() - 1;
+        match segment.as_str() {
+            "" | "." => trailing_slash = last && i > 0,
+            ".." => {
+                segments.pop();
+                trailing_slash = last;
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+    let mut normalized = String::with_capacity(path.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || normalized.is_empty() {
+        normalized.push('/');
+    }
+    Cow::Owned(normalized)
+}
+
+/// Decodes `%XX` escapes of ALPHA, DIGIT, `-`, `.`, `_` and `~` (RFC 3986 section 2.3).
+fn decode_unreserved(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = String::with_capacity(segment.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = segment.get(i + 1..i + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+            && (byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~'))
+        {
+            decoded.push(byte as char);
+            i += 3;
+            continue;
+        }
+        let c = segment[i..].chars().next().expect("char boundary");
+        decoded.push(c);
+        i += c.len_utf8();
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(name: &str, path_prefix: &str, methods: &[&str]) -> RouteConfig {
+        RouteConfig {
+            name: name.to_string(),
+            path_prefix: path_prefix.to_string(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_first_matching_route_wins() {
+        let router = Router::new(&[
+            route("create-user", "/users", &["post"]),
+            route("users", "/users", &[]),
+            route("all", "/", &[]),
+        ])
+        .expect("Failed to build router");
+
+        let find = |method: Method, path: &str| router.find(&method, path).unwrap().name.clone();
+        assert_eq!(find(Method::POST, "/users"), "create-user");
+        assert_eq!(find(Method::GET, "/users/1"), "users");
+        assert_eq!(find(Method::GET, "/health"), "all");
+    }
+
+    #[test]
+    fn test_no_route() {
+        let router = Router::new(&[route("users", "/users", &[])]).expect("Failed to build router");
+        assert!(router.find(&Method::GET, "/health").is_none());
+        assert!(Router::default().find(&Method::GET, "/").is_none());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/users/1"), "/users/1");
+        assert_eq!(normalize_path("//users"), "/users");
+        assert_eq!(normalize_path("/./users"), "/users");
+        assert_eq!(normalize_path("/%75sers"), "/users");
+        assert_eq!(normalize_path("/static/../users/"), "/users/");
+        assert_eq!(normalize_path("/%2e%2e/users/."), "/users/");
+        assert_eq!(normalize_path("/../.."), "/");
+        assert_eq!(normalize_path("/a%2Fb/%zz"), "/a%2Fb/%zz");
+        assert_eq!(normalize_path("/caf%C3%A9"), "/caf%C3%A9");
+    }
+
+    #[test]
+    fn test_path_bypasses_select_the_route() {
+        let router = Router::new(&[route("users", "/users", &[]), route("all", "/", &[])])
+            .expect("Failed to build router");
+        let find = |path: &str| router.find(&Method::GET, path).unwrap().name.clone();
+        for path in [
+            "/users",
+            "/users/1",
+            "//users",
+            "/./users",
+            "/%75sers",
+            "/x/../users",
+        ] {
+            assert_eq!(find(path), "users", "{path}");
+        }
+        // 前缀按整段匹配
+        assert_eq!(find("/usersX"), "all");
+        assert!(prefix_matches("/", "/users"));
+        assert!(prefix_matches("/api/", "/api/users"));
+        assert!(!prefix_matches("/api/", "/api"));
+    }
+}
@@ -0,0 +1,572 @@
+//! Rhai script hooks.
+//!
+//! The script may define `on_request()`, `on_upstream_request()` and `on_mirror()`. Each hook is
+//! called with the request bound to `this`, a map with `method`, `path` (path and query),
+//! `headers`, and for `on_mirror` also `mirror`, `body` and `json` (the parsed body, `()` when
+//! it is not JSON). Changes made to `this` are applied to the request. Returning `false` from
+//! `on_request` or `on_upstream_request` disables mirroring for the request, returning `false`
+//! from `on_mirror` vetoes the copy sent to that mirror.
+//!
+//! Scripts are sandboxed: no module imports or `eval`, and every call is limited in operations
+//! and wall clock time. A failing hook is logged and the request continues unchanged.
+
+use crate::config::ScriptingConfig;
+use crate::headers;
+use crate::metrics::SCRIPT_ERRORS;
+use crate::mirror::MirrorRequest;
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, uri::PathAndQuery};
+use pingora::http::RequestHeader;
+use rhai::{
+    AST, CallFnOptions, Dynamic, Engine, Map, Scope, module_resolvers::DummyModuleResolver,
+};
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    Request,
+    UpstreamRequest,
+    Mirror,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::Request => "on_request",
+            Hook::UpstreamRequest => "on_upstream_request",
+            Hook::Mirror => "on_mirror",
+        }
+    }
+}
+
+/// Request exposed to a hook.
+#[derive(Debug, Clone)]
+pub struct ScriptRequest {
+    pub method: Method,
+    pub path: String,
+    pub headers: HeaderMap,
+    /// Only set for `on_mirror`, the body is not read yet when the other hooks run.
+    pub body: Option<Bytes>,
+    pub mirror: Option<String>,
+}
+
+thread_local! {
+    // 当前线程上正在执行的钩子的截止时间，由 on_progress 回调检查
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Loaded script, reloaded in the background when the file changes.
+pub struct Scripts {
+    engine: Engine,
+    path: PathBuf,
+    timeout: Duration,
+    reload_interval: Duration,
+    reloading: AtomicBool,
+    script: RwLock<Script>,
+}
+
+#[derive(Clone)]
+struct Script {
+    ast: Arc<AST>,
+    modified: Option<SystemTime>,
+}
+
+impl Scripts {
+    /// Compiles the configured script, `None` when scripting is disabled.
+    pub fn load(config: &ScriptingConfig) -> Result<Option<Arc<Self>>> {
+        let Some(path) = &config.path else {
+            return Ok(None);
+        };
+        let engine = engine(config);
+        let script = compile(&engine, path)?;
+        Ok(Some(Arc::new(Self {
+            engine,
+            path: path.clone(),
+            timeout: config.timeout,
+            reload_interval: config.reload_interval,
+            reloading: AtomicBool::new(false),
+            script: RwLock::new(script),
+        })))
+    }
+
+    /// Starts the thread checking the file every `reload_interval`. It is started once the
+    /// server runs, a daemon forks in `Server::run_forever` and threads started before are lost.
+    pub fn start_reloader(self: &Arc<Self>) -> Result<()> {
+        if self.reloading.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        spawn_reloader(Arc::downgrade(self), self.reload_interval)
+    }
+
+    pub fn reloader_started(&self) -> bool {
+        self.reloading.load(Ordering::SeqCst)
+    }
+
+    /// Recompiles the script if the file changed, a script that fails to compile is ignored
+    /// and the previous version stays active.
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = modified(&self.path);
+        if modified == self.script.read().unwrap().modified {
+            return false;
+        }
+        match compile(&self.engine, &self.path) {
+            Ok(script) => {
+                info!(path = %self.path.display(), "script reloaded");
+                *self.script.write().unwrap() = script;
+                true
+            }
+            Err(e) => {
+                warn!(path = %self.path.display(), "failed to reload script, keeping the previous version: {:?}", e);
+                // 记录修改时间，避免每次检查都重新编译同一个错误的版本
+                self.script.write().unwrap().modified = modified;
+                false
+            }
+        }
+    }
+
+    /// Runs the hook if the script defines it, returns `false` when the script vetoed mirroring.
+    pub fn run(&self, hook: Hook, request: &mut ScriptRequest, request_id: &str) -> bool {
+        let script = self.script.read().unwrap().clone();
+        let defined = script
+            .ast
+            .iter_functions()
+            .any(|f| f.name == hook.name() && f.params.is_empty());
+        if !defined {
+            return true;
+        }
+
+        let mut this = request.to_dynamic();
+        DEADLINE.set(Some(Instant::now() + self.timeout));
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new().bind_this_ptr(&mut this),
+            &mut Scope::new(),
+            &script.ast,
+            hook.name(),
+            (),
+        );
+        DEADLINE.set(None);
+
+        match result
+            .map_err(|e| anyhow!("{e}"))
+            .and_then(|ret| request.update(&this).map(|_| ret))
+        {
+            Ok(ret) => !matches!(ret.as_bool(), Ok(false)),
+            Err(e) => {
+                warn!(%request_id, hook = hook.name(), "script hook failed: {:?}", e);
+                SCRIPT_ERRORS.with_label_values(&[hook.name()]).inc();
+                true
+            }
+        }
+    }
+}
+
+fn engine(config: &ScriptingConfig) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        .set_max_operations(config.max_operations)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_array_size(100_000)
+        .set_max_map_size(100_000);
+    engine.on_progress(|operations| {
+        // 每 1024 次操作检查一次时间，避免频繁读取时钟
+        if operations % 1024 != 0 {
+            return None;
+        }
+        DEADLINE
+            .get()
+            .filter(|deadline| Instant::now() > *deadline)
+            .map(|_| "script timed out".into())
+    });
+    engine.on_print(|text| info!(target: "script", "{}", text));
+    engine.on_debug(|text, _, pos| debug!(target: "script", "{} ({})", text, pos));
+    engine
+}
+
+fn compile(engine: &Engine, path: &PathBuf) -> Result<Script> {
+    let modified = modified(path);
+    let ast = engine
+        .compile_file(path.clone())
+        .map_err(|e| anyhow!("{e}"))
+        .with_context(|| format!("failed to compile script {}", path.display()))?;
+    Ok(Script {
+        ast: Arc::new(ast),
+        modified,
+    })
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn spawn_reloader(scripts: Weak<Scripts>, interval: Duration) -> Result<()> {
+    std::thread::Builder::new()
+        .name("script-reload".to_string())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(interval);
+                let Some(scripts) = scripts.upgrade() else {
+                    break;
+                };
+                scripts.reload_if_changed();
+            }
+        })?;
+    Ok(())
+}
+
+impl ScriptRequest {
+    pub fn from_header(header: &RequestHeader) -> Self {
+        Self {
+            method: header.method.clone(),
+            path: header
+                .uri
+                .path_and_query()
+                .map_or("/", |pq| pq.as_str())
+                .to_string(),
+            headers: header.headers.clone(),
+            body: None,
+            mirror: None,
+        }
+    }
+
+    pub fn from_mirror(request: &MirrorRequest, mirror: &str) -> Self {
+        Self {
+            method: request.method.clone(),
+            path: request.path_and_query.clone(),
+            headers: request.headers.clone(),
+            body: Some(request.body.clone()),
+            mirror: Some(mirror.to_string()),
+        }
+    }
+
+    /// Writes the changes back to a pingora request header.
+    pub fn apply_to_header(self, header: &mut RequestHeader) -> pingora::Result<()> {
+        if header.method != self.method {
+            header.set_method(self.method);
+        }
+        if header.uri.path_and_query().map(|pq| pq.as_str()) != Some(self.path.as_str()) {
+            let uri = self.path.parse().map_err(|e| {
+                pingora::Error::because(pingora::ErrorType::InternalError, "invalid script uri", e)
+            })?;
+            header.set_uri(uri);
+        }
+        headers::sync(header, &self.headers);
+        Ok(())
+    }
+
+    /// Writes the changes back to a mirrored request.
+    pub fn apply_to_mirror(self, request: &mut MirrorRequest) {
+        request.method = self.method;
+        request.path_and_query = self.path;
+        request.headers = self.headers;
+        if let Some(body) = self.body {
+            request.body = body;
+        }
+    }
+
+    fn to_dynamic(&self) -> Dynamic {
+        let mut map = Map::new();
+        map.insert("method".into(), self.method.to_string().into());
+        map.insert("path".into(), self.path.clone().into());
+        map.insert("headers".into(), headers_to_map(&self.headers).into());
+        if let Some(mirror) = &self.mirror {
+            map.insert("mirror".into(), mirror.clone().into());
+        }
+        if let Some(body) = &self.body {
+            map.insert(
+                "body".into(),
+                String::from_utf8_lossy(body).into_owned().into(),
+            );
+            map.insert("json".into(), self.json().unwrap_or(Dynamic::UNIT));
+        }
+        map.into()
+    }
+
+    fn json(&self) -> Option<Dynamic> {
+        let value: serde_json::Value = serde_json::from_slice(self.body.as_ref()?).ok()?;
+        rhai::serde::to_dynamic(value).ok()
+    }
+
+    /// Reads back the map modified by the script, nothing is changed if any field is invalid.
+    fn update(&mut self, this: &Dynamic) -> Result<()> {
+        let map = this
+            .read_lock::<Map>()
+            .ok_or_else(|| anyhow!("`this` is no longer a map"))?;
+        let string = |key: &str| -> Result<Option<String>> {
+            map.get(key)
+                .map(|value| {
+                    value
+                        .clone()
+                        .into_string()
+                        .map_err(|t| anyhow!("`{key}` must be a string, got {t}"))
+                })
+                .transpose()
+        };
+
+        let method = match string("method")? {
+            Some(method) => Method::from_bytes(method.as_bytes())?,
+            None => self.method.clone(),
+        };
+        let path = match string("path")? {
+            Some(path) => PathAndQuery::try_from(path.as_str())?.to_string(),
+            None => self.path.clone(),
+        };
+        let headers = match map.get("headers") {
+            Some(headers) => {
+                let headers = headers
+                    .read_lock::<Map>()
+                    .ok_or_else(|| anyhow!("`headers` must be a map"))?;
+                merge_headers(&self.headers, &headers)?
+            }
+            None => self.headers.clone(),
+        };
+        let body = match &self.body {
+            Some(_) => Some(self.updated_body(&map)?),
+            None => None,
+        };
+
+        self.method = method;
+        self.path = path;
+        self.headers = headers;
+        self.body = body;
+        Ok(())
+    }
+
+    /// The body is re-serialized from `json` when the script changed it, otherwise `body` is
+    /// used as is. An unchanged `body` keeps the original bytes, which the script only sees as
+    /// lossy UTF-8.
+    fn updated_body(&self, map: &Map) -> Result<Bytes> {
+        if let Some(json) = map.get("json").filter(|json| !json.is_unit()) {
+            let json: serde_json::Value = rhai::serde::from_dynamic(json)?;
+            let original: Option<serde_json::Value> = self
+                .body
+                .as_ref()
+                .and_then(|body| serde_json::from_slice(body).ok());
+            if original.as_ref() != Some(&json) {
+                return Ok(serde_json::to_vec(&json)?.into());
+            }
+        }
+        let Some(body) = map.get("body") else {
+            return Ok(Bytes::new());
+        };
+        let body = body
+            .clone()
+            .into_string()
+            .map_err(|t| anyhow!("`body` must be a string, got {t}"))?;
+        match &self.body {
+            Some(original) if String::from_utf8_lossy(original) == body => Ok(original.clone()),
+            _ => Ok(body.into()),
+        }
+    }
+}
+
+/// Multiple values of a header are joined with `, `.
+fn headers_to_map(headers: &HeaderMap) -> Map {
+    let mut map = Map::new();
+    for name in headers.keys() {
+        let value = headers
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        map.insert(name.as_str().into(), value.into());
+    }
+    map
+}
+
+/// Headers whose joined value is unchanged keep their original values.
+fn merge_headers(original: &HeaderMap, map: &Map) -> Result<HeaderMap> {
+    let unchanged = headers_to_map(original);
+    let mut headers = HeaderMap::new();
+    for (name, value) in map {
+        let name = HeaderName::from_bytes(name.as_bytes())?;
+        let value = value
+            .clone()
+            .into_string()
+            .map_err(|t| anyhow!("header `{name}` must be a string, got {t}"))?;
+        if unchanged
+            .get(name.as_str())
+            .is_some_and(|original| original.clone().into_string().ok() == Some(value.clone()))
+        {
+            for original in original.get_all(&name) {
+                headers.append(name.clone(), original.clone());
+            }
+        } else {
+            headers.insert(name, HeaderValue::from_str(&value)?);
+        }
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scripts(source: &str, timeout: Duration) -> (Arc<Scripts>, PathBuf) {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("script-{}-{n}.rhai", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let config = ScriptingConfig {
+            path: Some(path.clone()),
+            timeout,
+            reload_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let scripts = Scripts::load(&config)
+            .expect("Failed to load script")
+            .unwrap();
+        (scripts, path)
+    }
+
+    fn mirror_request(body: &str) -> ScriptRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        headers.append("accept", HeaderValue::from_static("text/html"));
+        headers.append("accept", HeaderValue::from_static("application/json"));
+        ScriptRequest {
+            method: Method::POST,
+            path: "/users".to_string(),
+            headers,
+            body: Some(Bytes::from(body.to_string())),
+            mirror: Some("secondary".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_mirror_hook_vetoes_and_rewrites() {
+        let (scripts, path) = scripts(
+            r#"
+fn on_mirror() {
+    if !(this.json.tenant in ["acme", "globex"]) {
+        return false;
+    }
+    this.path = "/v2" + this.path;
+    this.headers["x-tenant"] = this.json.tenant;
+    this.json.source = this.mirror;
+}
+"#,
+            Duration::from_secs(1),
+        );
+
+        let mut request = mirror_request(r#"{"tenant":"other"}"#);
+        assert!(!scripts.run(Hook::Mirror, &mut request, "abc"));
+
+        let mut request = mirror_request(r#"{"tenant":"acme"}"#);
+        assert!(scripts.run(Hook::Mirror, &mut request, "abc"));
+        assert_eq!(request.path, "/v2/users");
+        assert_eq!(request.headers["x-tenant"], "acme");
+        // 未修改的多值请求头保持原样
+        assert_eq!(request.headers.get_all("accept").iter().count(), 2);
+        let body: serde_json::Value = serde_json::from_slice(&request.body.unwrap()).unwrap();
+        assert_eq!(body["source"], "secondary");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mirror_hook_keeps_binary_body() {
+        let (scripts, path) = scripts(
+            r#"
+fn on_mirror() {
+    this.headers["x-mirrored"] = "1";
+}
+"#,
+            Duration::from_secs(1),
+        );
+        // protobuf 或 gzip 等非 UTF-8 请求体
+        let body = Bytes::from_static(&[0x00, 0x00, 0x00, 0x00, 0x03, 0x0a, 0x01, 0xff]);
+        let mut request = mirror_request("");
+        request.body = Some(body.clone());
+        assert!(scripts.run(Hook::Mirror, &mut request, "abc"));
+        assert_eq!(request.headers["x-mirrored"], "1");
+        assert_eq!(request.body, Some(body));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_request_hook_rewrites_header() {
+        let (scripts, path) = scripts(
+            r#"
+fn on_request() {
+    this.headers.remove("cookie");
+    this.method = "PUT";
+    this.path = "/v2" + this.path;
+}
+"#,
+            Duration::from_secs(1),
+        );
+        let mut header = RequestHeader::build("POST", b"/users?id=1", None).unwrap();
+        header.insert_header("cookie", "session=1").unwrap();
+        header.insert_header("x-keep", "1").unwrap();
+
+        let mut request = ScriptRequest::from_header(&header);
+        assert!(scripts.run(Hook::Request, &mut request, "abc"));
+        // 未定义的钩子不做任何修改
+        assert!(scripts.run(Hook::UpstreamRequest, &mut request, "abc"));
+        request
+            .apply_to_header(&mut header)
+            .expect("Failed to apply");
+
+        assert_eq!(header.method, Method::PUT);
+        assert_eq!(header.uri, "/v2/users?id=1");
+        assert!(!header.headers.contains_key("cookie"));
+        assert_eq!(header.headers["x-keep"], "1");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_limits_and_errors_fail_open() {
+        let (scripts, path) = scripts(
+            r#"
+fn on_request() { loop { } }
+fn on_mirror() { this.headers["bad header"] = "x"; false }
+"#,
+            Duration::from_millis(10),
+        );
+        let started = Instant::now();
+        let mut request = mirror_request("{}");
+        assert!(scripts.run(Hook::Request, &mut request, "abc"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // 钩子出错时请求保持不变，也不会阻止镜像
+        assert!(scripts.run(Hook::Mirror, &mut request, "abc"));
+        assert!(!request.headers.contains_key("bad header"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reload_on_change() {
+        let (scripts, path) = scripts(r#"fn on_mirror() { false }"#, Duration::from_secs(1));
+        assert!(!scripts.run(Hook::Mirror, &mut mirror_request("{}"), "abc"));
+
+        // 修改时间精度可能较粗，显式设置一个不同的修改时间
+        std::fs::write(&path, r#"fn on_mirror() { true }"#).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(scripts.reload_if_changed());
+        assert!(scripts.run(Hook::Mirror, &mut mirror_request("{}"), "abc"));
+
+        // 编译失败时保留之前的版本
+        std::fs::write(&path, "fn on_mirror( {").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(20))
+            .unwrap();
+        assert!(!scripts.reload_if_changed());
+        assert!(scripts.run(Hook::Mirror, &mut mirror_request("{}"), "abc"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
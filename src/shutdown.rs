@@ -0,0 +1,275 @@
+//! Graceful shutdown of the mirrored writes.
+//!
+//! On a graceful terminate (`SIGTERM`) or a graceful upgrade (`SIGQUIT`, sent when a new
+//! instance starts with `serve -u`), pingora stops accepting connections and lets the running
+//! requests finish during its grace period. [`ShutdownDrain`] then waits, up to
+//! `shutdown.drain_timeout`, for the client requests and the mirrored writes in flight. Writes
+//! still pending at the deadline, and writes dispatched after it, are appended to the backlog of
+//! their mirror (`on_open: backlog`, else `shutdown.backlog`) for `simple_proxy queue drain`.
+//!
+//! A pending write is aborted before it is persisted, a mirror that already received it gets it
+//! again when the backlog is drained. `SIGINT` exits at once without draining.
+
+use crate::backlog::Backlog;
+use crate::config::ShutdownConfig;
+use crate::mirror::{MirrorOutcome, MirrorRequest, MirrorTarget};
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+pub struct ShutdownDrain {
+    timeout: Duration,
+    backlog: Backlog,
+    requests: AtomicUsize,
+    next_write: AtomicU64,
+    writes: Mutex<HashMap<u64, PendingWrite>>,
+    /// Set once the deadline passed, later writes go to the backlog.
+    closed: AtomicBool,
+}
+
+struct PendingWrite {
+    target: Arc<MirrorTarget>,
+    /// Copy of the request without its trace link and primary response.
+    request: MirrorRequest,
+    task: Option<AbortHandle>,
+}
+
+/// Held by a client request until its context is dropped.
+pub struct InFlightRequest(Arc<ShutdownDrain>);
+
+/// Held by the task sending a mirrored write.
+pub struct InFlightWrite {
+    drain: Arc<ShutdownDrain>,
+    id: u64,
+}
+
+/// Runs the drain when pingora broadcasts the shutdown.
+pub struct DrainService(pub Arc<ShutdownDrain>);
+
+impl ShutdownDrain {
+    pub fn new(config: &ShutdownConfig) -> Arc<Self> {
+        Arc::new(Self {
+            timeout: config.drain_timeout,
+            backlog: Backlog::new(&config.backlog),
+            requests: AtomicUsize::new(0),
+            next_write: AtomicU64::new(0),
+            writes: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Backlog of the mirrors without their own.
+    pub fn backlog(&self) -> &Backlog {
+        &self.backlog
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    pub fn track_request(self: &Arc<Self>) -> InFlightRequest {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        InFlightRequest(self.clone())
+    }
+
+    /// Registers a write about to be sent, [`Self::set_task`] adds its task once spawned.
+    pub(crate) fn track_write(
+        self: &Arc<Self>,
+        target: Arc<MirrorTarget>,
+        request: &MirrorRequest,
+    ) -> InFlightWrite {
+        let id = self.next_write.fetch_add(1, Ordering::Relaxed);
+        let request = MirrorRequest {
+            request_id: request.request_id.clone(),
+            method: request.method.clone(),
+            path_and_query: request.path_and_query.clone(),
+            headers: request.headers.clone(),
+            body: request.body.clone(),
+            trace_link: None,
+            route: request.route.clone(),
+            record: false,
+            primary_response: None,
+        };
+        self.writes.lock().unwrap().insert(
+            id,
+            PendingWrite {
+                target,
+                request,
+                task: None,
+            },
+        );
+        InFlightWrite {
+            drain: self.clone(),
+            id,
+        }
+    }
+
+    pub(crate) fn set_task(&self, write: u64, task: AbortHandle) {
+        // 任务可能已经完成并移除了自己
+        if let Some(pending) = self.writes.lock().unwrap().get_mut(&write) {
+            pending.task = Some(task);
+        }
+    }
+
+    /// Client requests and mirrored writes in flight.
+    pub fn in_flight(&self) -> (usize, usize) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.writes.lock().unwrap().len(),
+        )
+    }
+
+    /// Waits until nothing is in flight or the drain timeout, then persists the pending writes.
+    /// Returns the number of persisted writes.
+    pub async fn drain(&self) -> usize {
+        let deadline = Instant::now() + self.timeout;
+        let (requests, writes) = self.in_flight();
+        info!(
+            requests,
+            writes, "Draining mirrored writes for up to {:?}", self.timeout
+        );
+        while self.in_flight() != (0, 0) && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        self.close()
+    }
+
+    /// Stops tracking writes, aborts the pending ones and appends them to the backlog.
+    fn close(&self) -> usize {
+        self.closed.store(true, Ordering::Release);
+        let pending: Vec<PendingWrite> = {
+            let mut writes = self.writes.lock().unwrap();
+            let mut pending: Vec<(u64, PendingWrite)> = writes.drain().collect();
+            // 按发送顺序写入 backlog
+            pending.sort_by_key(|(id, _)| *id);
+            pending.into_iter().map(|(_, write)| write).collect()
+        };
+        let mut persisted = 0;
+        for write in &pending {
+            if let Some(task) = &write.task {
+                task.abort();
+            }
+            if write.target.persist(&write.request) == MirrorOutcome::Backlogged {
+                persisted += 1;
+            }
+        }
+        let (requests, _) = self.in_flight();
+        if persisted < pending.len() {
+            warn!(
+                "Shutdown drain lost {} mirrored writes",
+                pending.len() - persisted
+            );
+        }
+        info!(
+            persisted,
+            requests, "Shutdown drain done, pending writes appended to the backlog"
+        );
+        persisted
+    }
+}
+
+impl InFlightWrite {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for InFlightWrite {
+    fn drop(&mut self) {
+        self.drain.writes.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl BackgroundService for DrainService {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+        self.0.drain().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MirrorConfig;
+    use http::{HeaderMap, Method};
+
+    fn request(request_id: &str) -> MirrorRequest {
+        MirrorRequest {
+            request_id: request_id.to_string(),
+            method: Method::POST,
+            path_and_query: "/users".to_string(),
+            headers: HeaderMap::new(),
+            body: r#"{"name":"Alice"}"#.into(),
+            trace_link: None,
+            route: None,
+            record: false,
+            primary_response: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_persists_pending_writes() {
+        let path = std::env::temp_dir().join(format!("shutdown-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // 接受连接但从不响应的 mirror
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let drain = ShutdownDrain::new(&ShutdownConfig {
+            drain_timeout: Duration::from_millis(200),
+            backlog: path.clone(),
+        });
+        let config = MirrorConfig {
+            url: format!("http://{addr}"),
+            ..Default::default()
+        };
+        let mirror = Arc::new(
+            MirrorTarget::new(&config)
+                .expect("Failed to create mirror")
+                .with_drain(Some(drain.clone())),
+        );
+
+        let request_guard = drain.track_request();
+        assert_eq!(mirror.dispatch(request("first")), MirrorOutcome::Sent);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(drain.in_flight(), (1, 1));
+
+        assert_eq!(drain.drain().await, 1);
+        assert!(drain.is_closed());
+        // 截止时间之后的写入直接进入 backlog
+        assert_eq!(mirror.dispatch(request("late")), MirrorOutcome::Backlogged);
+        drop(request_guard);
+        assert_eq!(drain.in_flight(), (0, 0));
+
+        let entries = Backlog::read_all(&path).expect("Failed to read backlog");
+        let ids: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.request_id.as_str())
+            .collect();
+        assert_eq!(ids, ["first", "late"]);
+        assert_eq!(entries[0].mirror, "secondary");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
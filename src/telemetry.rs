@@ -0,0 +1,359 @@
+use crate::config::TracingConfig;
+use crate::shutdown::ShutdownDrain;
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use http::HeaderMap;
+use opentelemetry::{
+    Context, KeyValue, global,
+    propagation::TextMapPropagator,
+    trace::{Link, SpanContext, SpanKind, Status, TraceContextExt, Tracer},
+};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const TRACER_NAME: &str = "simple_proxy";
+
+/// Installs the global tracer provider exporting spans over OTLP/HTTP once the server runs,
+/// and flushes it on a graceful shutdown.
+///
+/// The provider starts the thread of the batch span processor, so it is only built after
+/// `Server::run_forever` forked the daemon; until then spans are created by the no-op global
+/// tracer. `run_forever` exits the process without dropping anything, the spans still batched
+/// are exported once the [`ShutdownDrain`] is done.
+pub struct TracerService {
+    config: TracingConfig,
+    drain: Arc<ShutdownDrain>,
+}
+
+impl TracerService {
+    /// Returns `None` when tracing is disabled.
+    pub fn new(config: &TracingConfig, drain: Arc<ShutdownDrain>) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        // 导出器在 fork 之后才创建，先检查地址以便启动时报错
+        reqwest::Url::parse(&config.endpoint)
+            .with_context(|| format!("invalid tracing.endpoint {:?}", config.endpoint))?;
+        Ok(Some(Self {
+            config: config.clone(),
+            drain,
+        }))
+    }
+
+    async fn install(&self) -> Option<SdkTracerProvider> {
+        let config = self.config.clone();
+        // 导出器使用阻塞的 HTTP 客户端
+        match tokio::task::spawn_blocking(move || build_provider(&config)).await {
+            Ok(Ok(provider)) => {
+                global::set_tracer_provider(provider.clone());
+                info!("Exporting spans to {}", self.config.endpoint);
+                Some(provider)
+            }
+            Ok(Err(e)) => {
+                error!("failed to build the tracer provider: {:?}", e);
+                None
+            }
+            Err(e) => {
+                error!("failed to build the tracer provider: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn shutdown_after_drain(&self, provider: SdkTracerProvider, mut shutdown: ShutdownWatch) {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+        // 等待请求和镜像写入排空，它们的 span 也要导出
+        while !self.drain.is_closed() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+            Ok(Ok(())) => info!("Tracer provider shut down, pending spans exported"),
+            Ok(Err(e)) => warn!("failed to shut down the tracer provider: {}", e),
+            Err(e) => warn!("failed to shut down the tracer provider: {}", e),
+        }
+    }
+}
+
+fn build_provider(config: &TracingConfig) -> Result<SdkTracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.endpoint)
+        .with_timeout(config.export_timeout)
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build())
+}
+
+#[async_trait]
+impl BackgroundService for TracerService {
+    async fn start(&self, shutdown: ShutdownWatch) {
+        if let Some(provider) = self.install().await {
+            self.shutdown_after_drain(provider, shutdown).await;
+        }
+    }
+}
+
+/// Starts the span of a client request, continuing the trace from its `traceparent` header.
+///
+/// The span is named after the method and the matched route, never the path, which would give
+/// a span name per resource (`/users/123`); the path is kept in `url.path`.
+pub fn start_server_span(
+    headers: &HeaderMap,
+    method: &str,
+    path: &str,
+    route: Option<&str>,
+) -> Context {
+    let parent = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    let tracer = global::tracer(TRACER_NAME);
+    let name = match route {
+        Some(route) => format!("{method} {route}"),
+        None => method.to_string(),
+    };
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method.to_string()),
+        KeyValue::new("url.path", path.to_string()),
+    ];
+    if let Some(route) = route {
+        attributes.push(KeyValue::new("route", route.to_string()));
+    }
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    parent.with_span(span)
+}
+
+/// Starts a client span for a call to an upstream, as a child of `parent`.
+pub fn start_client_span(parent: &Context, name: String, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, parent);
+    parent.with_span(span)
+}
+
+/// Starts a client span in a new trace, linked to the span of the originating request.
+///
+/// Used for mirrored calls, which outlive the client request and must not extend its trace.
+pub fn start_linked_span(
+    link: Option<SpanContext>,
+    name: String,
+    attributes: Vec<KeyValue>,
+) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let links = link
+        .filter(|span_context| span_context.is_valid())
+        .map(|span_context| vec![Link::with_context(span_context)])
+        .unwrap_or_default();
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Client)
+        .with_attributes(attributes)
+        .with_links(links)
+        .start_with_context(&tracer, &Context::new());
+    Context::new().with_span(span)
+}
+
+/// Returns the `traceparent` / `tracestate` headers for the span of `cx`.
+pub fn trace_headers(cx: &Context) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    TraceContextPropagator::new().inject_context(cx, &mut headers);
+    headers
+}
+
+/// Records the response status on the span of `cx` and ends it.
+pub fn end_span(cx: &Context, status_code: Option<u16>, error: Option<String>) {
+    let span = cx.span();
+    if let Some(status_code) = status_code {
+        span.set_attribute(KeyValue::new(
+            "http.response.status_code",
+            status_code as i64,
+        ));
+    }
+    match error {
+        Some(error) => span.set_status(Status::error(error)),
+        None if status_code.is_some_and(|code| code >= 500) => span.set_status(Status::error("")),
+        None => {}
+    }
+    span.end();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+
+    #[test]
+    fn test_spans_and_propagation() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        global::set_tracer_provider(provider.clone());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        let server = start_server_span(&headers, "POST", "/users/123", Some("users"));
+        let unrouted = start_server_span(&HeaderMap::new(), "GET", "/health", None);
+        end_span(&unrouted, Some(200), None);
+        let primary = start_client_span(&server, "primary upstream".to_string(), vec![]);
+        let mirror = start_linked_span(
+            Some(server.span().span_context().clone()),
+            "mirror secondary".to_string(),
+            vec![],
+        );
+
+        // 注入到上游请求的 traceparent 指向上游 span
+        let injected = trace_headers(&primary);
+        let primary_span_id = primary.span().span_context().span_id().to_string();
+        assert!(injected["traceparent"].contains("4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert!(injected["traceparent"].contains(&primary_span_id));
+
+        end_span(&mirror, Some(201), None);
+        end_span(&primary, Some(502), None);
+        end_span(&server, Some(502), None);
+
+        let spans = exporter.get_finished_spans().expect("Failed to get spans");
+        let find = |name: &str| spans.iter().find(|span| span.name == name).unwrap();
+        let server_span = find("POST users");
+        assert!(
+            server_span
+                .attributes
+                .contains(&KeyValue::new("url.path", "/users/123"))
+        );
+        find("GET");
+        let primary_span = find("primary upstream");
+        let mirror_span = find("mirror secondary");
+
+        assert_eq!(
+            server_span.span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(server_span.parent_span_id.to_string(), "00f067aa0ba902b7");
+        assert_eq!(
+            primary_span.parent_span_id,
+            server_span.span_context.span_id()
+        );
+        assert!(matches!(primary_span.status, Status::Error { .. }));
+        // 镜像 span 在新的 trace 中，通过 link 关联到客户端请求
+        assert_ne!(
+            mirror_span.span_context.trace_id(),
+            server_span.span_context.trace_id()
+        );
+        assert_eq!(
+            mirror_span.links.links[0].span_context.span_id(),
+            server_span.span_context.span_id()
+        );
+        provider.shutdown().unwrap();
+    }
+
+    type Received = Arc<std::sync::Mutex<Vec<(String, usize)>>>;
+
+    /// 本地 OTLP/HTTP collector 替身，记录收到请求的 content-type 和大小
+    async fn collector() -> (TracingConfig, Received) {
+        use axum::{
+            Router, body::Bytes, extract::State, http::HeaderMap as Headers, routing::post,
+        };
+
+        let received: Received = Arc::default();
+        let app = Router::new()
+            .route(
+                "/v1/traces",
+                post(
+                    |State(received): State<Received>, headers: Headers, body: Bytes| async move {
+                        let content_type = headers
+                            .get("content-type")
+                            .and_then(|v| v.to_str().ok())
+                            .unwrap_or_default()
+                            .to_string();
+                        received.lock().unwrap().push((content_type, body.len()));
+                    },
+                ),
+            )
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let config = TracingConfig {
+            enabled: true,
+            endpoint: format!("http://{addr}/v1/traces"),
+            ..Default::default()
+        };
+        (config, received)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_to_collector() {
+        let (config, received) = collector().await;
+        let provider = build_provider(&config).expect("Failed to build provider");
+        tokio::task::spawn_blocking(move || {
+            use opentelemetry::trace::{Span, TracerProvider};
+            let tracer = provider.tracer("test");
+            tracer.start("exported span").end();
+            provider.force_flush().expect("Failed to flush");
+            provider.shutdown().unwrap();
+        })
+        .await
+        .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, "application/x-protobuf");
+        assert!(received[0].1 > 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown_flushes_batched_spans() {
+        use crate::config::ShutdownConfig;
+        use opentelemetry::trace::{Span, TracerProvider};
+
+        let (config, received) = collector().await;
+        let drain = ShutdownDrain::new(&ShutdownConfig {
+            drain_timeout: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let service = TracerService::new(&config, drain.clone())
+            .expect("Failed to create service")
+            .unwrap();
+        let provider = service.install().await.expect("Failed to install provider");
+        provider.tracer("test").start("batched span").end();
+        let (shutdown, watch) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move { service.shutdown_after_drain(provider, watch).await });
+
+        // 批量导出器默认 5 秒导出一次，关闭前还没有发送
+        assert!(received.lock().unwrap().is_empty());
+        shutdown.send(true).unwrap();
+        drain.drain().await;
+        task.await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{Result, bail};
+
+/// Values available to templates in header rules and body transforms, resolved per request.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+    pub client_ip: String,
+    pub request_id: String,
+    pub route: String,
+    pub method: String,
+    pub host: String,
+}
+
+/// String with `{var}` placeholders, `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Var(Var),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    ClientIp,
+    RequestId,
+    Route,
+    Method,
+    Host,
+}
+
+impl Template {
+    pub fn parse(value: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        bail!("unclosed template variable in {value:?}");
+                    };
+                    let var = match &rest[..end] {
+                        "client_ip" => Var::ClientIp,
+                        "request_id" => Var::RequestId,
+                        "route" => Var::Route,
+                        "method" => Var::Method,
+                        "host" => Var::Host,
+                        other => bail!("unknown template variable {{{other}}} in {value:?}"),
+                    };
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Var(var));
+                    chars = rest[end + 1..].chars();
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Self(segments))
+    }
+
+    /// Literal parts of the template, used to validate them up front.
+    pub fn literals(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(|segment| match segment {
+            Segment::Literal(literal) => Some(literal.as_str()),
+            Segment::Var(_) => None,
+        })
+    }
+
+    pub fn render(&self, vars: &TemplateVars) -> String {
+        let mut value = String::new();
+        for segment in &self.0 {
+            value.push_str(match segment {
+                Segment::Literal(literal) => literal,
+                Segment::Var(Var::ClientIp) => &vars.client_ip,
+                Segment::Var(Var::RequestId) => &vars.request_id,
+                Segment::Var(Var::Route) => &vars.route,
+                Segment::Var(Var::Method) => &vars.method,
+                Segment::Var(Var::Host) => &vars.host,
+            });
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let vars = TemplateVars {
+            request_id: "abc".to_string(),
+            route: "users".to_string(),
+            ..Default::default()
+        };
+        let template = Template::parse("{route}/{request_id} {{literal}}").unwrap();
+        assert_eq!(template.render(&vars), "users/abc {literal}");
+    }
+
+    #[test]
+    fn test_invalid_template_is_rejected() {
+        assert!(Template::parse("{unknown}").is_err());
+        assert!(Template::parse("{client_ip").is_err());
+    }
+}
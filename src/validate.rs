@@ -0,0 +1,646 @@
+//! Pre-deploy checks of a [`ProxyConfig`], run by `simple_proxy validate-config`.
+//!
+//! Checks are static (address syntax, regexes, header rules, route shadowing, references to
+//! routes, TLS and other referenced files, scripts and plugins compile) unless probing is asked
+//! for, which connects to every upstream. Nothing is written: stores, logs and recordings are
+//! not opened.
+
+use crate::access::{ClientIpResolver, IpAccessList};
+use crate::config::{HeaderRule, ProxyConfig, UrlRewriteConfig};
+use crate::grpc::GrpcDescriptors;
+use crate::headers::HeaderRewriter;
+use crate::plugin::Plugins;
+use crate::rewrite::UrlRewriter;
+use crate::route::{Route, prefix_matches};
+use crate::scripting::Scripts;
+use http::HeaderName;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Configuration item, such as `routes[1] (users)`.
+    pub location: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationReport {
+    pub findings: Vec<Finding>,
+}
+
+impl ValidationReport {
+    pub fn has_errors(&self) -> bool {
+        self.count(Severity::Error) > 0
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .count()
+    }
+
+    fn push(
+        &mut self,
+        severity: Severity,
+        location: impl Into<String>,
+        message: impl Into<String>,
+    ) {
+        self.findings.push(Finding {
+            severity,
+            location: location.into(),
+            message: message.into(),
+        });
+    }
+
+    fn error(&mut self, location: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, location, message);
+    }
+
+    fn warning(&mut self, location: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, location, message);
+    }
+
+    /// Records the error of a check with its whole context chain, on one line since regex
+    /// errors span several.
+    fn check<T>(&mut self, location: impl Into<String>, result: anyhow::Result<T>) {
+        if let Err(e) = result {
+            let message = format!("{e:#}");
+            self.error(
+                location,
+                message.split_whitespace().collect::<Vec<_>>().join(" "),
+            );
+        }
+    }
+}
+
+/// Runs the static checks.
+pub fn validate(config: &ProxyConfig) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    check_listeners(config, &mut report);
+    check_primary(config, &mut report);
+    check_mirrors(config, &mut report);
+    check_routes(config, &mut report);
+    check_extensions(config, &mut report);
+    report
+}
+
+/// Connects to every primary backend and mirror, an upstream that cannot be reached within
+/// `timeout` is an error.
+pub async fn probe(config: &ProxyConfig, timeout: Duration, report: &mut ValidationReport) {
+    let mut targets = Vec::new();
+    for (i, backend) in config.primary.backends.iter().enumerate() {
+        targets.push((format!("primary.backends[{i}]"), backend.clone()));
+    }
+    for (i, mirror) in config.mirrors.iter().enumerate() {
+        let address = Url::parse(&mirror.url).ok().and_then(|url| {
+            let host = url.host_str()?.to_string();
+            Some(format!("{}:{}", host, url.port_or_known_default()?))
+        });
+        if let Some(address) = address {
+            targets.push((mirror_location(i, &mirror.name), address));
+        }
+    }
+
+    let probes = targets.into_iter().map(|(location, address)| async move {
+        let started = Instant::now();
+        let result = tokio::time::timeout(timeout, TcpStream::connect(&address)).await;
+        (location, address, result, started.elapsed())
+    });
+    for (location, address, result, elapsed) in futures::future::join_all(probes).await {
+        match result {
+            Ok(Ok(_)) => report.push(
+                Severity::Info,
+                location,
+                format!(
+                    "{address} reachable in {:.1}ms",
+                    elapsed.as_secs_f64() * 1000.0
+                ),
+            ),
+            Ok(Err(e)) => report.error(location, format!("{address} unreachable: {e}")),
+            Err(_) => report.error(
+                location,
+                format!("{address} did not answer within {timeout:?}"),
+            ),
+        }
+    }
+}
+
+fn mirror_location(index: usize, name: &str) -> String {
+    format!("mirrors[{index}] ({name})")
+}
+
+fn check_listeners(config: &ProxyConfig, report: &mut ValidationReport) {
+    let mut listeners = vec![("listen", Some(config.listen.as_str()))];
+    listeners.push((
+        "tls.listen",
+        config.tls.as_ref().map(|tls| tls.listen.as_str()),
+    ));
+    listeners.push(("metrics_listen", config.metrics_listen.as_deref()));
+    if config.proxy_protocol.enabled {
+        listeners.push((
+            "proxy_protocol.internal_listen",
+            Some(config.proxy_protocol.internal_listen.as_str()),
+        ));
+    }
+    if config.admin.enabled {
+        listeners.push(("admin.listen", Some(config.admin.listen.as_str())));
+    }
+    let mut seen: Vec<(&str, SocketAddr)> = Vec::new();
+    for (location, address) in listeners {
+        let Some(address) = address else { continue };
+        match address.parse::<SocketAddr>() {
+            Ok(addr) => {
+                if let Some((other, _)) = seen.iter().find(|(_, seen)| *seen == addr) {
+                    report.error(location, format!("{address} is already used by {other}"));
+                }
+                seen.push((location, addr));
+            }
+            Err(_) => report.error(location, format!("{address:?} is not an IP:port address")),
+        }
+    }
+
+    if let Some(tls) = &config.tls {
+        for (location, path) in [("tls.cert", &tls.cert), ("tls.key", &tls.key)] {
+            if !path.is_file() {
+                report.error(location, format!("{} does not exist", path.display()));
+            }
+        }
+    }
+}
+
+fn check_primary(config: &ProxyConfig, report: &mut ValidationReport) {
+    let primary = &config.primary;
+    if primary.backends.is_empty() {
+        report.error("primary.backends", "no backend");
+    }
+    for (i, backend) in primary.backends.iter().enumerate() {
+        if let Err(message) = check_host_port(backend) {
+            report.error(format!("primary.backends[{i}]"), message);
+        }
+    }
+    if primary.tls && primary.sni.is_empty() {
+        report.warning("primary.sni", "tls is enabled without an sni");
+    }
+    check_header_rules("primary.headers.request", &primary.headers.request, report);
+    check_header_rules(
+        "primary.headers.response",
+        &primary.headers.response,
+        report,
+    );
+    check_rewrite("primary.rewrite", &primary.rewrite, report);
+}
+
+/// `host:port` with a valid port and host name, or an IP address and port.
+fn check_host_port(address: &str) -> Result<(), String> {
+    if address.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    let Some((host, port)) = address.rsplit_once(':') else {
+        return Err(format!("{address:?} has no port, expected host:port"));
+    };
+    if port.parse::<u16>().is_err() {
+        return Err(format!("{address:?} has an invalid port"));
+    }
+    let valid_host = !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid_host {
+        return Err(format!("{address:?} has an invalid host"));
+    }
+    Ok(())
+}
+
+fn check_mirrors(config: &ProxyConfig, report: &mut ValidationReport) {
+    let mut names = HashSet::new();
+    for (i, mirror) in config.mirrors.iter().enumerate() {
+        let location = mirror_location(i, &mirror.name);
+        if mirror.name.is_empty() {
+            report.error(&location, "name is empty");
+        } else if !names.insert(mirror.name.as_str()) {
+            report.error(&location, "duplicate mirror name");
+        }
+        match Url::parse(&mirror.url) {
+            Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+                report.error(
+                    &location,
+                    format!("url scheme {:?} is not http or https", url.scheme()),
+                );
+            }
+            Ok(url) if url.host_str().is_none() => report.error(&location, "url has no host"),
+            Ok(url) if !matches!(url.path(), "" | "/") => report.warning(
+                &location,
+                format!(
+                    "url path {:?} is prepended to every request path",
+                    url.path()
+                ),
+            ),
+            Ok(_) => {}
+            Err(e) => report.error(&location, format!("invalid url {:?}: {e}", mirror.url)),
+        }
+        check_header_rules(&format!("{location}.headers"), &mirror.headers, report);
+        check_rewrite(&format!("{location}.rewrite"), &mirror.rewrite, report);
+    }
+}
+
+fn check_header_rules(location: &str, rules: &[HeaderRule], report: &mut ValidationReport) {
+    report.check(location, HeaderRewriter::new(rules));
+}
+
+fn check_rewrite(location: &str, config: &UrlRewriteConfig, report: &mut ValidationReport) {
+    report.check(location, UrlRewriter::new(config));
+}
+
+fn check_routes(config: &ProxyConfig, report: &mut ValidationReport) {
+    let mut names = HashSet::new();
+    let location = |i: usize| format!("routes[{i}] ({})", config.routes[i].name);
+    for (i, route) in config.routes.iter().enumerate() {
+        if route.name.is_empty() {
+            report.error(location(i), "name is empty");
+        } else if !names.insert(route.name.as_str()) {
+            report.error(location(i), "duplicate route name");
+        }
+        report.check(location(i), Route::new(route));
+        if !route.path_prefix.is_empty() && !route.path_prefix.starts_with('/') {
+            report.error(
+                location(i),
+                format!(
+                    "unreachable, path_prefix {:?} does not start with /",
+                    route.path_prefix
+                ),
+            );
+        }
+
+        // 路由按配置顺序匹配，前面更宽的路由会遮蔽后面的路由
+        let methods = method_set(&route.methods);
+        for (j, earlier) in config.routes[..i].iter().enumerate() {
+            if !prefix_matches(&earlier.path_prefix, &route.path_prefix) {
+                continue;
+            }
+            let earlier_methods = method_set(&earlier.methods);
+            match (&earlier_methods, &methods) {
+                (None, _) => {
+                    report.error(
+                        location(i),
+                        format!("unreachable, shadowed by {}", location(j)),
+                    );
+                    break;
+                }
+                (Some(earlier), Some(methods)) if methods.is_subset(earlier) => {
+                    report.error(
+                        location(i),
+                        format!("unreachable, shadowed by {}", location(j)),
+                    );
+                    break;
+                }
+                (Some(earlier), methods) => {
+                    let mut shadowed: Vec<_> = earlier
+                        .iter()
+                        .filter(|method| methods.as_ref().is_none_or(|m| m.contains(*method)))
+                        .cloned()
+                        .collect();
+                    if !shadowed.is_empty() {
+                        shadowed.sort();
+                        report.warning(
+                            location(i),
+                            format!(
+                                "{} requests are matched by {} first",
+                                shadowed.join(", "),
+                                location(j)
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    let referenced = [
+        ("recording.routes", &config.recording.routes),
+        ("comparison.routes", &config.comparison.routes),
+    ];
+    for (location, routes) in referenced {
+        for route in routes {
+            if !names.contains(route.as_str()) {
+                report.error(location, format!("route {route:?} is not defined"));
+            }
+        }
+    }
+}
+
+/// Upper cased methods, `None` for all methods.
+fn method_set(methods: &[String]) -> Option<HashSet<String>> {
+    (!methods.is_empty()).then(|| methods.iter().map(|m| m.to_ascii_uppercase()).collect())
+}
+
+fn check_extensions(config: &ProxyConfig, report: &mut ValidationReport) {
+    if let Some(path) = &config.scripting.path {
+        if path.is_file() {
+            report.check("scripting.path", Scripts::load(&config.scripting));
+        } else {
+            report.error(
+                "scripting.path",
+                format!("{} does not exist", path.display()),
+            );
+        }
+    }
+
+    let mut names = HashSet::new();
+    for (i, plugin) in config.plugins.iter().enumerate() {
+        if !names.insert(plugin.name.as_str()) {
+            report.error(
+                format!("plugins[{i}] ({})", plugin.name),
+                "duplicate plugin name",
+            );
+        }
+    }
+    if !config.plugins.is_empty() {
+        let routes = config
+            .routes
+            .iter()
+            .map(|route| route.name.clone())
+            .collect();
+        report.check("plugins", Plugins::load(&config.plugins, routes));
+    }
+
+    let comparison = &config.comparison;
+    if comparison.enabled {
+        if config.mirrors.is_empty() {
+            report.warning("comparison", "enabled without mirrors");
+        }
+        for name in &comparison.compare_headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                report.error(
+                    "comparison.compare_headers",
+                    format!("invalid header name {name:?}"),
+                );
+            }
+        }
+    }
+
+    let grpc = &config.grpc;
+    if !grpc.descriptor_sets.is_empty() {
+        match GrpcDescriptors::load(grpc) {
+            Ok(descriptors) => {
+                for name in &grpc.ignore_fields {
+                    if !descriptors.has_field(name) {
+                        report.warning(
+                            "grpc.ignore_fields",
+                            format!("{name} is not a field of the descriptor sets"),
+                        );
+                    }
+                }
+            }
+            Err(e) => report.error("grpc.descriptor_sets", format!("{e:#}")),
+        }
+        if !config.h2c && !config.tls.as_ref().is_some_and(|tls| tls.http2) {
+            report.warning("grpc", "gRPC clients need HTTP/2, enable h2c or tls.http2");
+        }
+    }
+
+    let cache = &config.cache;
+    if cache.enabled {
+        for name in &cache.key.headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                report.error("cache.key.headers", format!("invalid header name {name:?}"));
+            }
+        }
+        if !cache.status_header.is_empty()
+            && HeaderName::from_bytes(cache.status_header.as_bytes()).is_err()
+        {
+            report.error(
+                "cache.status_header",
+                format!("invalid header name {:?}", cache.status_header),
+            );
+        }
+    } else {
+        for (i, route) in config.routes.iter().enumerate() {
+            if route.cache.ttl.is_some() {
+                report.warning(
+                    format!("routes[{i}] ({})", route.name),
+                    "cache.ttl is set but the cache is disabled",
+                );
+            }
+        }
+    }
+
+    report.check("access_control", IpAccessList::new(&config.access_control));
+    report.check(
+        "client_ip.trusted_proxies",
+        ClientIpResolver::new(&config.client_ip),
+    );
+    if config.proxy_protocol.enabled
+        && config
+            .proxy_protocol
+            .internal_listen
+            .parse::<SocketAddr>()
+            .is_ok_and(|addr| !addr.ip().is_loopback())
+    {
+        report.warning(
+            "proxy_protocol.internal_listen",
+            "not a loopback address, clients reaching it directly skip the PROXY protocol",
+        );
+    }
+
+    let compression = &config.compression;
+    if compression.enabled {
+        let levels = &compression.levels;
+        for (name, level, max) in [
+            ("gzip", levels.gzip, 9),
+            ("br", levels.br, 11),
+            ("zstd", levels.zstd, 22),
+        ] {
+            if !(1..=max).contains(&level) {
+                report.error(
+                    format!("compression.levels.{name}"),
+                    format!("level {level} is not between 1 and {max}"),
+                );
+            }
+        }
+        if compression.algorithms.is_empty() || compression.content_types.is_empty() {
+            report.warning(
+                "compression",
+                "no response is compressed, algorithms or content_types is empty",
+            );
+        }
+    }
+
+    if config.admin.enabled
+        && config.admin.token.is_none()
+        && let Ok(addr) = config.admin.listen.parse::<SocketAddr>()
+        && !addr.ip().is_loopback()
+    {
+        report.error(
+            "admin.listen",
+            format!("{addr} is not a loopback address, set admin.token"),
+        );
+    }
+    if config.admin.enabled
+        && let Some(dir) = &config.admin.ui_dir
+        && !dir.join("index.html").is_file()
+    {
+        report.warning(
+            "admin.ui_dir",
+            format!(
+                "{} has no index.html, build the UI with `yarn build` in ui/",
+                dir.display()
+            ),
+        );
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "info",
+        })
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            writeln!(
+                f,
+                "{:<7} {}: {}",
+                finding.severity, finding.location, finding.message
+            )?;
+        }
+        writeln!(
+            f,
+            "{} errors, {} warnings",
+            self.count(Severity::Error),
+            self.count(Severity::Warning)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MirrorConfig, PathRule, RouteConfig};
+
+    fn route(name: &str, path_prefix: &str, methods: &[&str]) -> RouteConfig {
+        RouteConfig {
+            name: name.to_string(),
+            path_prefix: path_prefix.to_string(),
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn messages(report: &ValidationReport, severity: Severity) -> Vec<String> {
+        report
+            .findings
+            .iter()
+            .filter(|finding| finding.severity == severity)
+            .map(|finding| format!("{}: {}", finding.location, finding.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_fixture_is_valid() {
+        let config = ProxyConfig::load("fixtures/proxy.yml").expect("Failed to load config");
+        let report = validate(&config);
+        assert!(!report.has_errors(), "{report}");
+    }
+
+    #[test]
+    fn test_validate_reports_errors() {
+        let mut config = ProxyConfig {
+            metrics_listen: Some("0.0.0.0:8080".to_string()),
+            ..Default::default()
+        };
+        config.primary.backends = vec!["127.0.0.1:3000".to_string(), "backend".to_string()];
+        config.primary.rewrite.path.push(PathRule {
+            pattern: "(".to_string(),
+            replacement: String::new(),
+        });
+        config.mirrors.push(MirrorConfig {
+            name: "secondary".to_string(),
+            url: "ftp://example.com".to_string(),
+            ..Default::default()
+        });
+        config.routes = vec![
+            route("api", "/api", &["GET"]),
+            route("users", "/api/users", &[]),
+            route("reads", "/api/users/me", &["get"]),
+            route("all", "/", &[]),
+            route("never", "/static", &[]),
+        ];
+        config.routes[1].cache.ttl = Some(Duration::from_secs(60));
+        config.recording.routes = vec!["orders".to_string()];
+        config.grpc.descriptor_sets = vec!["fixtures/grpc/users.pb".into()];
+        config.grpc.ignore_fields = vec!["users.v1.User.created_at".to_string()];
+        config.compression.enabled = true;
+        config.compression.levels.br = 12;
+        config.access_control.deny = vec!["10.0.0.0/33".to_string()];
+        config.admin.enabled = true;
+        config.admin.listen = "0.0.0.0:9000".to_string();
+
+        let report = validate(&config);
+        assert_eq!(
+            messages(&report, Severity::Error),
+            [
+                "metrics_listen: 0.0.0.0:8080 is already used by listen",
+                r#"primary.backends[1]: "backend" has no port, expected host:port"#,
+                r#"primary.rewrite: invalid path pattern "(": regex parse error: ( ^ error: unclosed group"#,
+                "mirrors[1] (secondary): duplicate mirror name",
+                r#"mirrors[1] (secondary): url scheme "ftp" is not http or https"#,
+                "routes[2] (reads): unreachable, shadowed by routes[0] (api)",
+                "routes[4] (never): unreachable, shadowed by routes[3] (all)",
+                r#"recording.routes: route "orders" is not defined"#,
+                r#"access_control: invalid CIDR range "10.0.0.0/33": invalid IP address syntax"#,
+                "compression.levels.br: level 12 is not between 1 and 11",
+                "admin.listen: 0.0.0.0:9000 is not a loopback address, set admin.token",
+            ]
+        );
+        assert_eq!(
+            messages(&report, Severity::Warning),
+            [
+                "routes[1] (users): GET requests are matched by routes[0] (api) first",
+                "grpc.ignore_fields: users.v1.User.created_at is not a field of the descriptor sets",
+                "grpc: gRPC clients need HTTP/2, enable h2c or tls.http2",
+                "routes[1] (users): cache.ttl is set but the cache is disabled",
+            ]
+        );
+        assert!(report.to_string().ends_with("11 errors, 4 warnings\n"));
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut config = ProxyConfig::default();
+        config.primary.backends = vec![open.to_string()];
+        config.mirrors[0].url = format!("http://{closed}");
+
+        let mut report = ValidationReport::default();
+        probe(&config, Duration::from_secs(1), &mut report).await;
+        assert_eq!(report.findings[0].severity, Severity::Info);
+        assert_eq!(report.findings[1].severity, Severity::Error);
+        assert!(report.findings[1].message.contains("unreachable"));
+    }
+}
@@ -0,0 +1,446 @@
+//! WebSocket proxying and mirroring of the client messages.
+//!
+//! pingora passes an upgraded connection through once the primary answered `101 Switching
+//! Protocols`: the bytes flowing in both directions go through the body filters and no HTTP
+//! mirror is sent. With `websocket.mirror`, a WebSocket connection is opened to every mirror with
+//! the upgrade request (after the route and mirror rules), the client frames are decoded in
+//! `request_body_filter` and their messages are sent to the mirrors. What the mirrors send back
+//! is read and discarded.
+//!
+//! `Sec-WebSocket-Extensions` is removed from the primary request when mirroring so that no
+//! compression is negotiated and the client frames can be read.
+
+use crate::circuit_breaker::Permit;
+use crate::metrics::{MIRROR_REQUESTS, MIRROR_SKIPPED, WEBSOCKET_MESSAGES};
+use crate::mirror::{MirrorOutcome, MirrorRequest, MirrorTarget};
+use bytes::{Buf, BytesMut};
+use futures::{SinkExt, StreamExt};
+use http::HeaderMap;
+use http::header::{
+    CONNECTION, CONTENT_LENGTH, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, TRANSFER_ENCODING, UPGRADE,
+};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use tracing::{debug, warn};
+
+/// Handshake headers set by the WebSocket client of the mirror connection.
+const HANDSHAKE_HEADERS: [http::HeaderName; 9] = [
+    HOST,
+    CONNECTION,
+    UPGRADE,
+    CONTENT_LENGTH,
+    TRANSFER_ENCODING,
+    SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_VERSION,
+    SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_ACCEPT,
+];
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+
+/// Whether the request asks for a WebSocket upgrade.
+pub fn is_websocket(headers: &HeaderMap) -> bool {
+    headers
+        .get(UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Incremental decoder of the frames sent by a WebSocket client (RFC 6455, section 5).
+pub struct FrameParser {
+    buffer: BytesMut,
+    /// Opcode and payload so far of a fragmented message.
+    message: Option<(u8, BytesMut)>,
+    /// Payload bytes left of a frame that is not mirrored.
+    skip: usize,
+    /// The fragmented message being read is too large and is not mirrored.
+    oversized: bool,
+    max_message_size: usize,
+    dropped: usize,
+}
+
+impl FrameParser {
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            message: None,
+            skip: 0,
+            oversized: false,
+            max_message_size,
+            dropped: 0,
+        }
+    }
+
+    /// Returns the text, binary and close messages completed by `chunk`. Ping and pong frames
+    /// are left out, the mirror connection answers its own pings.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Message> {
+        self.buffer.extend_from_slice(chunk);
+        let mut messages = Vec::new();
+        loop {
+            if self.skip > 0 {
+                let skipped = self.skip.min(self.buffer.len());
+                self.buffer.advance(skipped);
+                self.skip -= skipped;
+                if self.skip > 0 {
+                    break;
+                }
+            }
+            let Some(FrameHeader {
+                fin,
+                opcode,
+                header_len,
+                len,
+                mask,
+            }) = self.header()
+            else {
+                break;
+            };
+
+            let is_data = opcode < OPCODE_CLOSE;
+            let current = self.message.as_ref().map_or(0, |(_, data)| data.len());
+            if is_data && (self.oversized || current.saturating_add(len) > self.max_message_size) {
+                // 超过大小限制的消息不缓存，直接跳过
+                self.buffer.advance(header_len);
+                self.skip = len;
+                self.message = None;
+                self.oversized = !fin;
+                if fin {
+                    self.dropped += 1;
+                }
+                continue;
+            }
+            if self.buffer.len() < header_len + len {
+                break;
+            }
+            self.buffer.advance(header_len);
+            let mut payload = self.buffer.split_to(len);
+            if let Some(mask) = mask {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+
+            match opcode {
+                OPCODE_CONTINUATION => {
+                    if let Some((_, data)) = &mut self.message {
+                        data.extend_from_slice(&payload);
+                    }
+                    if fin && let Some((opcode, data)) = self.message.take() {
+                        messages.extend(self.message(opcode, data));
+                    }
+                }
+                OPCODE_TEXT | OPCODE_BINARY if fin => {
+                    messages.extend(self.message(opcode, payload))
+                }
+                OPCODE_TEXT | OPCODE_BINARY => self.message = Some((opcode, payload)),
+                OPCODE_CLOSE => messages.push(Message::Close(None)),
+                _ => {}
+            }
+        }
+        messages
+    }
+
+    /// Messages left out so far because they were too large or not valid UTF-8 text.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Header of the next frame, once it is complete.
+    fn header(&self) -> Option<FrameHeader> {
+        let buffer = &self.buffer[..];
+        if buffer.len() < 2 {
+            return None;
+        }
+        let fin = buffer[0] & 0x80 != 0;
+        let opcode = buffer[0] & 0x0f;
+        let masked = buffer[1] & 0x80 != 0;
+        let (len, mut header_len) = match buffer[1] & 0x7f {
+            126 => (
+                u16::from_be_bytes(buffer.get(2..4)?.try_into().ok()?) as usize,
+                4,
+            ),
+            127 => {
+                let len = u64::from_be_bytes(buffer.get(2..10)?.try_into().ok()?);
+                (usize::try_from(len).unwrap_or(usize::MAX), 10)
+            }
+            len => (len as usize, 2),
+        };
+        let mask = if masked {
+            let mask = buffer.get(header_len..header_len + 4)?.try_into().ok()?;
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        Some(FrameHeader {
+            fin,
+            opcode,
+            header_len,
+            len,
+            mask,
+        })
+    }
+
+    fn message(&mut self, opcode: u8, payload: BytesMut) -> Option<Message> {
+        if opcode == OPCODE_BINARY {
+            return Some(Message::Binary(payload.freeze()));
+        }
+        match Utf8Bytes::try_from(payload) {
+            Ok(text) => Some(Message::Text(text)),
+            Err(_) => {
+                self.dropped += 1;
+                None
+            }
+        }
+    }
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    header_len: usize,
+    /// Payload length.
+    len: usize,
+    mask: Option<[u8; 4]>,
+}
+
+/// Mirror connections of a proxied WebSocket, closed when dropped.
+pub struct WebSocketMirror {
+    parser: FrameParser,
+    connections: Vec<(String, mpsc::Sender<Message>)>,
+    queue_size: usize,
+    dropped: usize,
+}
+
+impl WebSocketMirror {
+    pub fn new(max_message_size: usize, queue_size: usize) -> Self {
+        Self {
+            parser: FrameParser::new(max_message_size),
+            connections: Vec::new(),
+            queue_size: queue_size.max(1),
+            dropped: 0,
+        }
+    }
+
+    /// Opens the connection to the mirror in the background, unless its circuit is open.
+    /// Messages pushed meanwhile wait in the queue.
+    pub fn connect(&mut self, target: &Arc<MirrorTarget>, request: MirrorRequest) -> MirrorOutcome {
+        let Some(permit) = target.breaker().try_acquire() else {
+            MIRROR_SKIPPED
+                .with_label_values(&[target.name(), "count"])
+                .inc();
+            return MirrorOutcome::Skipped;
+        };
+        let (sender, receiver) = mpsc::channel(self.queue_size);
+        self.connections.push((target.name().to_string(), sender));
+        tokio::spawn(run(target.clone(), request, permit, receiver));
+        MirrorOutcome::Sent
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Decodes a chunk of the client stream and queues its messages to every mirror.
+    pub fn push(&mut self, chunk: &[u8]) {
+        let messages = self.parser.push(chunk);
+        let dropped = self.parser.dropped() - self.dropped;
+        self.dropped = self.parser.dropped();
+        for (name, sender) in &self.connections {
+            if dropped > 0 {
+                WEBSOCKET_MESSAGES
+                    .with_label_values(&[name, "too_large"])
+                    .inc_by(dropped as u64);
+            }
+            for message in &messages {
+                // 镜像连接跟不上时丢弃消息，不阻塞客户端
+                if sender.try_send(message.clone()).is_err() {
+                    WEBSOCKET_MESSAGES
+                        .with_label_values(&[name, "dropped"])
+                        .inc();
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the mirror and sends it the queued messages until the client side is done.
+async fn run(
+    target: Arc<MirrorTarget>,
+    request: MirrorRequest,
+    permit: Permit,
+    mut messages: mpsc::Receiver<Message>,
+) {
+    let name = target.name();
+    let request_id = request.request_id.clone();
+    let connected = async {
+        let mut url = request.url(target.base_url())?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme)
+            .map_err(|_| anyhow::anyhow!("cannot use {scheme} for {url}"))?;
+        let mut handshake = url.as_str().into_client_request()?;
+        for (header, value) in &request.headers {
+            if !HANDSHAKE_HEADERS.contains(header) {
+                handshake
+                    .headers_mut()
+                    .append(header.clone(), value.clone());
+            }
+        }
+        let (stream, _) = tokio::time::timeout(
+            target.timeout(),
+            tokio_tungstenite::connect_async(handshake),
+        )
+        .await??;
+        anyhow::Ok(stream)
+    };
+    let stream = match connected.await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!(mirror = %name, %request_id, "failed to open websocket to mirror: {:#}", e);
+            MIRROR_REQUESTS.with_label_values(&[name, "failure"]).inc();
+            target.breaker().record_failure(permit);
+            return;
+        }
+    };
+    MIRROR_REQUESTS.with_label_values(&[name, "success"]).inc();
+    target.breaker().record_success(permit);
+    debug!(mirror = %name, %request_id, "websocket to mirror opened");
+
+    let (mut sink, mut stream) = stream.split();
+    // 镜像服务器发回的消息直接丢弃
+    let reader = tokio::spawn(async move { while let Some(Ok(_)) = stream.next().await {} });
+    while let Some(message) = messages.recv().await {
+        let close = matches!(message, Message::Close(_));
+        if let Err(e) = sink.send(message).await {
+            debug!(mirror = %name, %request_id, "websocket to mirror closed: {}", e);
+            WEBSOCKET_MESSAGES
+                .with_label_values(&[name, "failure"])
+                .inc();
+            break;
+        }
+        WEBSOCKET_MESSAGES.with_label_values(&[name, "sent"]).inc();
+        if close {
+            break;
+        }
+    }
+    let _ = sink.close().await;
+    reader.abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MirrorConfig;
+    use http::Method;
+    use std::time::Duration;
+
+    /// Masked client frame.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match payload.len() {
+            len @ 0..126 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_parse_frames() {
+        let mut parser = FrameParser::new(200);
+        let mut stream = frame(true, OPCODE_TEXT, b"hello");
+        stream.extend(frame(false, OPCODE_BINARY, &[1, 2]));
+        stream.extend(frame(true, 0x9, b"ping"));
+        stream.extend(frame(true, OPCODE_CONTINUATION, &[3]));
+        stream.extend(frame(true, OPCODE_TEXT, &[b'x'; 300]));
+        stream.extend(frame(true, OPCODE_TEXT, &[b'y'; 130]));
+        stream.extend(frame(true, OPCODE_CLOSE, &[]));
+
+        // 按任意边界切分的数据流
+        let mut messages = Vec::new();
+        for chunk in stream.chunks(7) {
+            messages.extend(parser.push(chunk));
+        }
+        assert_eq!(
+            messages,
+            [
+                Message::text("hello"),
+                Message::binary(vec![1, 2, 3]),
+                Message::text("y".repeat(130)),
+                Message::Close(None),
+            ]
+        );
+        assert_eq!(parser.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_messages() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut websocket = tokio_tungstenite::accept_async(stream)
+                .await
+                .expect("Failed to accept websocket");
+            while let Some(Ok(message)) = websocket.next().await {
+                received.send(message).unwrap();
+            }
+        });
+
+        let target = Arc::new(
+            MirrorTarget::new(&MirrorConfig {
+                url: format!("http://{addr}"),
+                ..Default::default()
+            })
+            .expect("Failed to create mirror"),
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(UPGRADE, "websocket".parse().unwrap());
+        headers.insert(
+            SEC_WEBSOCKET_KEY,
+            "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap(),
+        );
+        let request = MirrorRequest {
+            request_id: "ws-1".to_string(),
+            method: Method::GET,
+            path_and_query: "/ws".to_string(),
+            headers,
+            body: Default::default(),
+            trace_link: None,
+            route: None,
+            record: false,
+            primary_response: None,
+        };
+
+        let mut mirror = WebSocketMirror::new(1024, 16);
+        assert_eq!(mirror.connect(&target, request), MirrorOutcome::Sent);
+        mirror.push(&frame(true, OPCODE_TEXT, b"first"));
+        mirror.push(&frame(true, OPCODE_BINARY, b"second"));
+        drop(mirror);
+
+        let mut messages = Vec::new();
+        while let Ok(Some(message)) =
+            tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await
+        {
+            messages.push(message);
+        }
+        assert_eq!(messages[0], Message::text("first"));
+        assert_eq!(messages[1], Message::binary(b"second".to_vec()));
+    }
+}
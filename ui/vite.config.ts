@@ -28,6 +28,11 @@ export default defineConfig({
   },
   server: {
     port: 5173,
+    // Admin listener of the proxy (`admin.listen` in its config)
+    proxy: {
+      '/api': 'http://127.0.0.1:9000',
+      '/metrics': 'http://127.0.0.1:9000',
+    },
   },
   // Vitest configuration
   test: {